@@ -1,16 +1,31 @@
+use std::ffi::OsString;
 use std::path::PathBuf;
 
-use clap::Parser;
-
+use clap::{CommandFactory, Parser};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 #[command(rename_all = "kebab-case")]
 pub enum CargoXtestData {
     XtestData {
+        /// Keep the temporary directory, with the unpacked crate and data, for inspection.
+        #[arg(long, global = true, default_value = "false")]
+        keep_tmp: bool,
+        /// How to print the result of the command.
+        #[arg(long, global = true, value_enum, default_value = "human")]
+        format: Format,
         #[command(subcommand)]
         cmd: XtaskCommand,
-    }
+    },
+}
+
+/// The output format of a command.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Messages on `stderr`, and the paths or variables to use in scripts on `stdout`.
+    Human,
+    /// A single JSON object with the outcome, printed to `stdout` when the command finishes.
+    Json,
 }
 
 #[derive(Parser, Debug)]
@@ -30,11 +45,24 @@ pub enum XtaskCommand {
         path: PathBuf,
         /// If we should allow a dirty repository.
         ///
-        /// This will fail to do the right thing if any test data is dirty. Unlike cargo, this
-        /// _will_ write a custom `vcs_info` file to use. However, all test data must a reachable
-        /// within the tree given by the current VCS (otherwise it wouldn't be part of the pack).
+        /// This will fail to do the right thing if any test data is dirty, since the pack has the
+        /// committed data. Dirty test data is an error without this flag and a warning with it.
+        /// Unlike cargo, this _will_ write a custom `vcs_info` file to use. However, all test data
+        /// must a reachable within the tree given by the current VCS (otherwise it wouldn't be part
+        /// of the pack).
         #[arg(long, default_value = "false")]
         allow_dirty: bool,
+        /// Include the data of this many commits, starting at `HEAD`, in the pack.
+        ///
+        /// By default only the data of the packaged commit is included.
+        #[arg(long)]
+        depth: Option<u32>,
+        /// The number of parallel jobs of the inner `cargo` invocations.
+        ///
+        /// Passed on as `--jobs` when packaging and to the `cargo test` of the unpacked crate. By
+        /// default cargo chooses the number of jobs.
+        #[arg(long, short = 'j')]
+        jobs: Option<u32>,
     },
     /// Pack the source data, but do not run the full integration test.
     ///
@@ -47,11 +75,25 @@ pub enum XtaskCommand {
         path: PathBuf,
         /// If we should allow a dirty repository.
         ///
-        /// This will fail to do the right thing if any test data is dirty. Unlike cargo, this
-        /// _will_ write a custom `vcs_info` file to use. However, all test data must a reachable
-        /// within the tree given by the current VCS (otherwise it wouldn't be part of the pack).
+        /// This will fail to do the right thing if any test data is dirty, since the pack has the
+        /// committed data. Dirty test data is an error without this flag and a warning with it.
+        /// Unlike cargo, this _will_ write a custom `vcs_info` file to use. However, all test data
+        /// must a reachable within the tree given by the current VCS (otherwise it wouldn't be part
+        /// of the pack).
         #[arg(long, default_value = "false")]
         allow_dirty: bool,
+        /// Include the data of this many commits, starting at `HEAD`, in the pack.
+        ///
+        /// By default only the data of the packaged commit is included.
+        #[arg(long)]
+        depth: Option<u32>,
+        /// Write the pack objects into this directory of the crate, before packaging it.
+        ///
+        /// The `.crate` archive then contains its own test data and can be tested offline without
+        /// a pack artifact. The directory must not be ignored by `.gitignore` or excluded from the
+        /// package. Mind the size limit of the registry, crates.io accepts at most 10 MiB.
+        #[arg(long)]
+        embed: Option<PathBuf>,
     },
     /// _Only_ perform the download step.
     ///
@@ -67,6 +109,19 @@ pub enum XtaskCommand {
         /// name, version, and target directory.
         output: Option<PathBuf>,
     },
+    /// List the data that the tests of a crate archive would fetch.
+    ///
+    /// This runs the tests of the unpacked crate without any test data and collects the resources
+    /// that each test setup reports, without downloading anything.
+    Plan {
+        /// The path to the source crate archive.
+        path: PathBuf,
+        /// Print only the plan as JSON.
+        ///
+        /// Use `--format json` instead for the plan as part of the report of the command.
+        #[arg(long, default_value = "false")]
+        json: bool,
+    },
     /// Test a crate archive.
     ///
     /// This command may download the test archive data.
@@ -80,5 +135,230 @@ pub enum XtaskCommand {
         /// Overwrite path to the downloaded `pack-artifact`.
         #[arg(id = "pack-artifact", long)]
         pack_artifact: Option<PathBuf>,
+        /// The number of parallel jobs of the `cargo test` of the unpacked crate.
+        #[arg(long, short = 'j')]
+        jobs: Option<u32>,
+    },
+    /// Test a crate that was published to crates.io.
+    ///
+    /// This downloads the `.crate` archive of the version, checks it against the checksum in the
+    /// registry index, and then continues as `crate-test`.
+    TestPublished {
+        /// The name of the crate.
+        name: String,
+        /// The exact version to test.
+        version: String,
+        /// Overwrite path to the downloaded `pack-artifact`.
+        #[arg(id = "pack-artifact", long)]
+        pack_artifact: Option<PathBuf>,
+        /// Only print the locations that would be downloaded.
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+        /// The number of parallel jobs of the `cargo test` of the unpacked crate.
+        #[arg(long, short = 'j')]
+        jobs: Option<u32>,
     },
 }
+
+impl XtaskCommand {
+    /// The name of the subcommand, as typed on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            XtaskCommand::Test { .. } => "test",
+            XtaskCommand::Package { .. } => "package",
+            XtaskCommand::FetchArtifacts { .. } => "fetch-artifacts",
+            XtaskCommand::Plan { .. } => "plan",
+            XtaskCommand::CrateTest { .. } => "crate-test",
+            XtaskCommand::TestPublished { .. } => "test-published",
+        }
+    }
+}
+
+impl CargoXtestData {
+    /// Parse the arguments as `cargo xtest-data …` or as a direct call of `cargo-xtest-data …`.
+    ///
+    /// Cargo passes the name of the subcommand as the first argument to an installed subcommand.
+    /// The direct call may omit it.
+    pub fn parse_args(
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Result<Self, clap::Error> {
+        let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        if args.get(1).map_or(true, |arg| arg != "xtest-data") {
+            let at = args.len().min(1);
+            args.insert(at, OsString::from("xtest-data"));
+        }
+
+        let parsed = Self::try_parse_from(args)?;
+        let CargoXtestData::XtestData { format, cmd, .. } = &parsed;
+        // Each would print its own JSON document to `stdout`.
+        if let (Format::Json, XtaskCommand::Plan { json: true, .. }) = (format, cmd) {
+            return Err(Self::command().error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "`plan --json` can not be used with `--format json`, which already reports the plan",
+            ));
+        }
+
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn command(args: &[&str]) -> XtaskCommand {
+        let CargoXtestData::XtestData { cmd, .. } = CargoXtestData::parse_args(args).unwrap();
+        cmd
+    }
+
+    #[test]
+    fn cargo_subcommand_invocation() {
+        let cmd = command(&[
+            "cargo-xtest-data",
+            "xtest-data",
+            "crate-test",
+            "./foo-1.2.3.crate",
+        ]);
+        assert!(matches!(
+            cmd,
+            XtaskCommand::CrateTest { path, pack_artifact: None, jobs: None }
+                if path == Path::new("./foo-1.2.3.crate")
+        ));
+
+        let cmd = command(&["cargo-xtest-data", "crate-test", "--jobs", "2"]);
+        assert!(matches!(cmd, XtaskCommand::CrateTest { jobs: Some(2), .. }));
+    }
+
+    #[test]
+    fn direct_invocation() {
+        let cmd = command(&["cargo-xtest-data", "test", "--depth", "2"]);
+        assert!(matches!(
+            cmd,
+            XtaskCommand::Test { path, allow_dirty: false, depth: Some(2), jobs: None }
+                if path == Path::new(".")
+        ));
+
+        let cmd = command(&["cargo-xtest-data", "test", "-j", "4"]);
+        assert!(matches!(cmd, XtaskCommand::Test { jobs: Some(4), .. }));
+    }
+
+    #[test]
+    fn published_crate() {
+        let cmd = command(&[
+            "cargo-xtest-data",
+            "test-published",
+            "xtest-data",
+            "1.0.0-beta.5",
+        ]);
+        assert!(matches!(
+            cmd,
+            XtaskCommand::TestPublished { name, version, pack_artifact: None, dry_run: false, jobs: None }
+                if name == "xtest-data" && version == "1.0.0-beta.5"
+        ));
+
+        let cmd = command(&[
+            "cargo-xtest-data",
+            "test-published",
+            "--dry-run",
+            "foo",
+            "0.1.0",
+        ]);
+        assert!(matches!(
+            cmd,
+            XtaskCommand::TestPublished { dry_run: true, .. }
+        ));
+
+        let cmd = command(&[
+            "cargo-xtest-data",
+            "test-published",
+            "-j",
+            "1",
+            "foo",
+            "0.1.0",
+        ]);
+        assert!(matches!(
+            cmd,
+            XtaskCommand::TestPublished { jobs: Some(1), .. }
+        ));
+    }
+
+    #[test]
+    fn package_with_embedded_objects() {
+        let cmd = command(&["cargo-xtest-data", "package", "--embed", "tests/xtest-data"]);
+        assert!(matches!(
+            cmd,
+            XtaskCommand::Package { embed: Some(embed), .. }
+                if embed == Path::new("tests/xtest-data")
+        ));
+    }
+
+    #[test]
+    fn keep_tmp_anywhere() {
+        for args in [
+            &["cargo-xtest-data", "--keep-tmp", "crate-test"][..],
+            &["cargo-xtest-data", "xtest-data", "crate-test", "--keep-tmp"][..],
+        ] {
+            let parsed = CargoXtestData::parse_args(args).unwrap();
+            assert!(matches!(
+                parsed,
+                CargoXtestData::XtestData { keep_tmp: true, .. }
+            ));
+        }
+
+        let parsed = CargoXtestData::parse_args(["cargo-xtest-data", "crate-test"]).unwrap();
+        assert!(matches!(
+            parsed,
+            CargoXtestData::XtestData {
+                keep_tmp: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn json_format() {
+        let parsed =
+            CargoXtestData::parse_args(["cargo-xtest-data", "package", "--format", "json"])
+                .unwrap();
+        assert!(matches!(
+            parsed,
+            CargoXtestData::XtestData { format: Format::Json, cmd, .. }
+                if cmd.name() == "package"
+        ));
+
+        let parsed = CargoXtestData::parse_args(["cargo-xtest-data", "package"]).unwrap();
+        assert!(matches!(
+            parsed,
+            CargoXtestData::XtestData {
+                format: Format::Human,
+                ..
+            }
+        ));
+
+        assert!(
+            CargoXtestData::parse_args(["cargo-xtest-data", "--format", "xml", "test"]).is_err()
+        );
+    }
+
+    #[test]
+    fn plan_json_is_one_document() {
+        let cmd = command(&["cargo-xtest-data", "plan", "--json", "foo-0.1.0.crate"]);
+        assert!(matches!(cmd, XtaskCommand::Plan { json: true, .. }));
+
+        let plan = |format| {
+            let args = [
+                "cargo-xtest-data",
+                "--format",
+                format,
+                "plan",
+                "--json",
+                "foo.crate",
+            ];
+            CargoXtestData::parse_args(args).map(drop)
+        };
+        assert!(plan("human").is_ok());
+        let err = plan("json").unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+}
@@ -1,90 +1,185 @@
 mod args;
+mod report;
 mod target;
 mod task;
 mod util;
 
 use self::args::{CargoXtestData, XtaskCommand};
+use self::report::Report;
 use self::util::{anchor_error, as_io_error, undiagnosed_io_error, LocatedError};
 
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::{env, fs};
 
-use clap::Parser;
 use tempfile::TempDir;
 
-// Use the same host-binary as is building us.
+// Use the same host-binary as is building us, unless we run as a subcommand of another.
 const CARGO: &'static str = env!("CARGO");
 
+/// Environment variables that select the toolchain, target, and flags of a build.
+const BUILD_ENV: &[&str] = &[
+    "RUSTUP_TOOLCHAIN",
+    "RUSTC",
+    "RUSTC_WRAPPER",
+    "RUSTFLAGS",
+    "CARGO_ENCODED_RUSTFLAGS",
+    "RUSTDOCFLAGS",
+    "CARGO_BUILD_TARGET",
+];
+
+/// A `cargo` command that builds as the one that invoked us would.
+///
+/// Packagers validate a crate under the flags of their distribution, the inner build must not
+/// silently differ from them.
+fn cargo() -> Command {
+    cargo_with_env(|name| env::var_os(name))
+}
+
+fn cargo_with_env(var: impl Fn(&str) -> Option<OsString>) -> Command {
+    let mut cmd = Command::new(var("CARGO").unwrap_or_else(|| CARGO.into()));
+
+    for &name in BUILD_ENV {
+        if let Some(value) = var(name) {
+            cmd.env(name, value);
+        }
+    }
+
+    cmd
+}
+
+/// The argument that bounds the parallel jobs of an inner `cargo` command, if any.
+fn jobs_arg(jobs: Option<u32>) -> Option<String> {
+    jobs.map(|jobs| format!("--jobs={}", jobs))
+}
+
 fn main() -> Result<(), LocatedError> {
     let mut private_tempdir = None;
-    let CargoXtestData::XtestData { cmd } = CargoXtestData::parse();
+    let CargoXtestData::XtestData {
+        keep_tmp,
+        format,
+        cmd,
+    } = CargoXtestData::parse_args(env::args_os()).unwrap_or_else(|err| err.exit());
+
+    let mut report = Report::new(format, cmd.name());
+    let result = run(cmd, &mut private_tempdir, &mut report);
+
+    if let Some(kept) = keep_tmpdir(private_tempdir, keep_tmp) {
+        eprintln!("Kept temporary directory:\t{}", kept.display());
+    }
+
+    report.finish(result)
+}
 
+fn run(
+    cmd: XtaskCommand,
+    private_tempdir: &mut Option<TempDir>,
+    report: &mut Report,
+) -> Result<(), LocatedError> {
     match cmd {
-        XtaskCommand::Test { path, allow_dirty } => {
-            let source = target::LocalSource::with_simple_repository(&path).with_dirty(allow_dirty);
+        XtaskCommand::Test {
+            path,
+            allow_dirty,
+            depth,
+            jobs,
+        } => {
+            let source = target::LocalSource::with_simple_repository(&path)
+                .with_dirty(allow_dirty)
+                .with_pack_depth(depth)
+                .with_jobs(jobs);
             let target = target::Target::from_dir(&source)?;
+            // Fail before packaging when the pack can not be archived.
+            target.cargo.archive_method()?;
 
-            let tmp = mk_tmpdir(&mut private_tempdir, &target);
+            let tmp = mk_tmpdir(private_tempdir, &target.expected_dir_name());
             let package = task::pack::pack(&source, &target, &tmp)?;
 
             let packed = task::artifacts::pack(&package.pack_path, &target, &tmp)?;
             let unpacked = task::artifacts::unpack(&packed, &target, &tmp)?;
 
-            let test =
-                task::test::test(&package.crate_, &target, &unpacked, &package.vcs_info, &tmp)?;
+            let vcs_info = &package.vcs_info;
+            let test = task::test::test(&package.crate_, &unpacked, vcs_info, &tmp, jobs)?;
 
             let output = task::output::write_artifacts(&source, &target, &packed)?;
-            eprintln!("Test success: {:?}", test);
-            eprintln!("Package:\t{}", package.crate_.path.display());
-            eprint!("Created:\t");
-            println!("{}", output.display());
+            report.tested(&test);
+            report.package(&package.crate_.path);
+            report.created(&output);
             Ok(())
         }
-        XtaskCommand::Package { path, allow_dirty } => {
-            let source = target::LocalSource::with_simple_repository(&path).with_dirty(allow_dirty);
+        XtaskCommand::Package {
+            path,
+            allow_dirty,
+            depth,
+            embed,
+        } => {
+            let source = target::LocalSource::with_simple_repository(&path)
+                .with_dirty(allow_dirty)
+                .with_pack_depth(depth)
+                .with_embed(embed);
             let target = target::Target::from_dir(&source)?;
+            // Fail before packaging when the pack can not be archived.
+            target.cargo.archive_method()?;
 
-            let tmp = mk_tmpdir(&mut private_tempdir, &target);
+            let tmp = mk_tmpdir(private_tempdir, &target.expected_dir_name());
             let packed = task::pack::pack(&source, &target, &tmp)?;
 
             let archive = task::artifacts::pack(&packed.pack_path, &target, &tmp)?;
             let output = task::output::write_artifacts(&source, &target, &archive)?;
 
             // FIXME: print instructions
-            eprint!("Created:\t");
-            println!("{}", output.display());
+            report.created(&output);
             Ok(())
         }
         XtaskCommand::CrateTest {
             path,
             pack_artifact,
+            jobs,
         } => {
             // Prepare the sources, crate etc.
             let source = target::CrateSource {
                 path: path.to_owned(),
             };
 
-            let target = target::Target::from_crate(&source)?;
-            let tmp = mk_tmpdir(&mut private_tempdir, &target);
+            crate_test(&source, pack_artifact, jobs, private_tempdir, report)
+        }
+        XtaskCommand::TestPublished {
+            name,
+            version,
+            pack_artifact,
+            dry_run,
+            jobs,
+        } => {
+            let published = task::dl::Published { name, version };
 
-            let archive = match pack_artifact {
-                None => {
-                    let download = task::dl::download(&target, &tmp)?;
-                    download.artifact
-                }
-                // FIXME(clean code): we shouldn't build something from `task` but rather have the
-                // task return an agreed-on interface data type.
-                Some(artifact) => task::artifacts::PackedArtifacts {
-                    path: artifact.to_owned(),
-                },
+            if dry_run {
+                report.download(published.index_url()?);
+                report.download(published.crate_url());
+                return Ok(());
+            }
+
+            let download = tempfile::tempdir().map_err(anchor_error())?;
+            let source = target::CrateSource {
+                path: task::dl::download_published(&published, download.path())?,
             };
 
-            let unpack = task::artifacts::unpack(&archive, &target, &tmp)?;
+            crate_test(&source, pack_artifact, jobs, private_tempdir, report)
+        }
+        XtaskCommand::Plan { path, json } => {
+            let source = target::CrateSource {
+                path: path.to_owned(),
+            };
+
+            let target = target::Target::from_crate(&source)?;
+            let tmp = mk_tmpdir(private_tempdir, &source.top_level_dir()?);
+            let plan = task::plan::plan(&source, &target, &tmp)?;
 
-            let test =
-                task::test::test(&source, &target, &unpack, &target::VcsInfo::FromCrate, &tmp)?;
+            if json {
+                println!("{}", plan.to_json()?);
+            } else {
+                report.plan(&plan);
+            }
 
-            eprintln!("Test successful: {:?}", test);
             Ok(())
         }
         XtaskCommand::FetchArtifacts {
@@ -98,7 +193,13 @@ fn main() -> Result<(), LocatedError> {
             };
 
             let target = target::Target::from_crate(&source)?;
-            let tmp = mk_tmpdir(&mut private_tempdir, &target);
+            // Fail before any download when the pack can not be retrieved or unpacked.
+            target.cargo.archive_method()?;
+            if pack_artifact.is_none() {
+                target.cargo.artifact_location()?;
+            }
+
+            let tmp = mk_tmpdir(private_tempdir, &source.top_level_dir()?);
 
             let archive = match pack_artifact {
                 None => {
@@ -129,22 +230,56 @@ fn main() -> Result<(), LocatedError> {
             let tmpdir = tempfile::tempdir().map_err(anchor_error())?;
             let tempdir = core::mem::ManuallyDrop::new(tmpdir);
 
-            eprintln!("Environment variables to set:");
-            println!(
-                "export CARGO_XTEST_DATA_TMPDIR=\"{}\"",
-                tempdir.path().display()
-            );
-            println!(
-                "export CARGO_XTEST_DATA_PACK_OBJECTS=\"{}\"",
-                location.display()
-            );
+            report.env("CARGO_XTEST_DATA_TMPDIR", tempdir.path());
+            report.env("CARGO_XTEST_DATA_PACK_OBJECTS", &location);
 
             Ok(())
         }
     }
 }
 
-fn mk_tmpdir(private_tempdir: &mut Option<TempDir>, target: &target::Target) -> PathBuf {
+/// Test a crate archive, with the pack artifact downloaded unless one is provided.
+fn crate_test(
+    source: &target::CrateSource,
+    pack_artifact: Option<PathBuf>,
+    jobs: Option<u32>,
+    private_tempdir: &mut Option<TempDir>,
+    report: &mut Report,
+) -> Result<(), LocatedError> {
+    let target = target::Target::from_crate(source)?;
+    // Fail before any download or test when the pack can not be retrieved or unpacked.
+    target.cargo.archive_method()?;
+    if pack_artifact.is_none() {
+        target.cargo.artifact_location()?;
+    }
+
+    let tmp = mk_tmpdir(private_tempdir, &source.top_level_dir()?);
+
+    let archive = match pack_artifact {
+        None => {
+            let download = task::dl::download(&target, &tmp)?;
+            download.artifact
+        }
+        // FIXME(clean code): we shouldn't build something from `task` but rather have the
+        // task return an agreed-on interface data type.
+        Some(artifact) => task::artifacts::PackedArtifacts { path: artifact },
+    };
+
+    let unpack = task::artifacts::unpack(&archive, &target, &tmp)?;
+
+    let test = task::test::test(source, &unpack, &target::VcsInfo::FromCrate, &tmp, jobs)?;
+
+    report.tested(&test);
+    Ok(())
+}
+
+/// Persist the temporary directory that we created, if requested.
+fn keep_tmpdir(private_tempdir: Option<TempDir>, keep: bool) -> Option<PathBuf> {
+    private_tempdir.filter(|_| keep).map(TempDir::keep)
+}
+
+/// A temporary directory, which is a workspace with the package in `member` if we create it.
+fn mk_tmpdir(private_tempdir: &mut Option<TempDir>, member: &Path) -> PathBuf {
     env::var_os("TMPDIR").map_or_else(
         || {
             let temp = TempDir::with_prefix_in("xtest-data-", "target")
@@ -159,7 +294,7 @@ fn mk_tmpdir(private_tempdir: &mut Option<TempDir>, target: &target::Target) ->
 [workspace]
 members = ["{}"]
 "#,
-                target.expected_dir_name().display()
+                member.display()
             );
             fs::write(temp.path().join("Cargo.toml"), boundary)
                 .expect("to create a workspace boundary if the package has non");
@@ -169,3 +304,65 @@ members = ["{}"]
         PathBuf::from,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{cargo_with_env, keep_tmpdir, CARGO};
+    use std::ffi::OsStr;
+    use tempfile::TempDir;
+
+    #[test]
+    fn build_env_is_forwarded() {
+        let cmd = cargo_with_env(|name| match name {
+            "RUSTFLAGS" => Some("-C target-cpu=native".into()),
+            "RUSTUP_TOOLCHAIN" => Some("nightly".into()),
+            "CARGO_BUILD_TARGET" => Some("aarch64-unknown-linux-gnu".into()),
+            "CARGO_XTEST_DATA_FETCH" => Some("1".into()),
+            _ => None,
+        });
+
+        assert_eq!(cmd.get_program(), OsStr::new(CARGO));
+        let mut envs: Vec<_> = cmd.get_envs().collect();
+        envs.sort();
+        assert_eq!(
+            envs,
+            [
+                (
+                    OsStr::new("CARGO_BUILD_TARGET"),
+                    Some(OsStr::new("aarch64-unknown-linux-gnu"))
+                ),
+                (
+                    OsStr::new("RUSTFLAGS"),
+                    Some(OsStr::new("-C target-cpu=native"))
+                ),
+                (OsStr::new("RUSTUP_TOOLCHAIN"), Some(OsStr::new("nightly"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn cargo_of_the_caller() {
+        let cmd = cargo_with_env(|name| match name {
+            "CARGO" => Some("/opt/rust/bin/cargo".into()),
+            _ => None,
+        });
+
+        assert_eq!(cmd.get_program(), OsStr::new("/opt/rust/bin/cargo"));
+        assert_eq!(cmd.get_envs().count(), 0);
+    }
+
+    #[test]
+    fn kept_tmpdir_survives() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().to_owned();
+        let kept = keep_tmpdir(Some(temp), true);
+        assert_eq!(kept.as_deref(), Some(path.as_path()));
+        assert!(path.is_dir());
+        std::fs::remove_dir(&path).unwrap();
+
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().to_owned();
+        assert_eq!(keep_tmpdir(Some(temp), false), None);
+        assert!(!path.exists());
+    }
+}
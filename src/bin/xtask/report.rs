@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use tinyjson::JsonValue;
+
+use crate::args::Format;
+use crate::task::plan::Plan;
+use crate::task::test::TestResult;
+use crate::util::{anchor_error, as_io_error, LocatedError};
+
+/// The result of a command, as it is reported to the user.
+///
+/// In the human format each step is printed as it is recorded: messages to `stderr`, and the
+/// values to use in scripts, such as created paths, to `stdout`. In the JSON format nothing is
+/// printed until the command finishes, then a single object is written to `stdout`.
+pub struct Report {
+    format: Format,
+    command: &'static str,
+    started: Instant,
+    /// The paths produced by the command, by their role.
+    paths: Vec<(&'static str, PathBuf)>,
+    /// The counts of the tests of the crate, when they ran and passed.
+    tested: Option<JsonValue>,
+    /// Environment variables to set for a later test run.
+    env: Vec<(&'static str, PathBuf)>,
+    /// Locations that would be downloaded.
+    downloads: Vec<String>,
+    plan: Option<JsonValue>,
+}
+
+impl Report {
+    pub fn new(format: Format, command: &'static str) -> Self {
+        Report {
+            format,
+            command,
+            started: Instant::now(),
+            paths: vec![],
+            tested: None,
+            env: vec![],
+            downloads: vec![],
+            plan: None,
+        }
+    }
+
+    fn human(&self) -> bool {
+        self.format == Format::Human
+    }
+
+    /// The `.crate` archive that was packaged.
+    pub fn package(&mut self, path: &Path) {
+        if self.human() {
+            eprintln!("Package:\t{}", path.display());
+        }
+
+        self.paths.push(("package", path.to_owned()));
+    }
+
+    /// The pack artifact that was written to its output location.
+    pub fn created(&mut self, path: &Path) {
+        if self.human() {
+            eprint!("Created:\t");
+            println!("{}", path.display());
+        }
+
+        self.paths.push(("created", path.to_owned()));
+    }
+
+    pub fn tested(&mut self, test: &TestResult) {
+        if self.human() {
+            eprintln!(
+                "Test successful: {} passed, {} ignored",
+                test.passed, test.ignored
+            );
+        }
+
+        let count = |n: usize| JsonValue::Number(n as f64);
+        let mut counts = HashMap::new();
+        counts.insert("passed".to_string(), count(test.passed));
+        counts.insert("failed".to_string(), count(test.failed));
+        counts.insert("ignored".to_string(), count(test.ignored));
+        self.tested = Some(JsonValue::Object(counts));
+    }
+
+    pub fn env(&mut self, name: &'static str, value: &Path) {
+        if self.human() {
+            if self.env.is_empty() {
+                eprintln!("Environment variables to set:");
+            }
+
+            println!("export {}=\"{}\"", name, value.display());
+        }
+
+        self.env.push((name, value.to_owned()));
+    }
+
+    pub fn download(&mut self, location: String) {
+        if self.human() {
+            if self.downloads.is_empty() {
+                eprintln!("Would download:");
+            }
+
+            println!("{}", location);
+        }
+
+        self.downloads.push(location);
+    }
+
+    pub fn plan(&mut self, plan: &Plan) {
+        if self.human() {
+            print!("{}", plan);
+        }
+
+        self.plan = Some(plan.to_json_value());
+    }
+
+    /// Finish the command, printing the JSON object with its outcome if requested.
+    pub fn finish(self, result: Result<(), LocatedError>) -> Result<(), LocatedError> {
+        if !self.human() {
+            println!("{}", self.to_json(result.as_ref().err())?);
+        }
+
+        result
+    }
+
+    pub fn to_json(&self, error: Option<&LocatedError>) -> Result<String, LocatedError> {
+        fn strings<T: ToString>(items: impl Iterator<Item = (&'static str, T)>) -> JsonValue {
+            JsonValue::Object(
+                items
+                    .map(|(key, value)| (key.to_string(), JsonValue::String(value.to_string())))
+                    .collect(),
+            )
+        }
+
+        let mut report = HashMap::new();
+        report.insert(
+            "command".to_string(),
+            JsonValue::String(self.command.to_string()),
+        );
+        report.insert("success".to_string(), JsonValue::Boolean(error.is_none()));
+        report.insert(
+            "error".to_string(),
+            error.map_or(JsonValue::Null, |err| JsonValue::String(err.to_string())),
+        );
+        report.insert(
+            "elapsed_secs".to_string(),
+            JsonValue::Number(self.started.elapsed().as_secs_f64()),
+        );
+        report.insert(
+            "paths".to_string(),
+            strings(self.paths.iter().map(|(key, path)| (*key, path.display()))),
+        );
+        report.insert(
+            "tests_passed".to_string(),
+            JsonValue::Boolean(self.tested.is_some()),
+        );
+        report.insert(
+            "tests".to_string(),
+            self.tested.clone().unwrap_or(JsonValue::Null),
+        );
+        report.insert(
+            "env".to_string(),
+            strings(self.env.iter().map(|(key, path)| (*key, path.display()))),
+        );
+        report.insert(
+            "downloads".to_string(),
+            JsonValue::Array(
+                self.downloads
+                    .iter()
+                    .cloned()
+                    .map(JsonValue::String)
+                    .collect(),
+            ),
+        );
+        report.insert(
+            "plan".to_string(),
+            self.plan.clone().unwrap_or(JsonValue::Null),
+        );
+
+        JsonValue::Object(report)
+            .format()
+            .map_err(as_io_error)
+            .map_err(anchor_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_as_json() {
+        // The same steps as recorded by the `package` command.
+        let mut report = Report::new(Format::Json, "package");
+        report.created(Path::new("target/xtest-data/foo-0.1.0.xtest-data"));
+
+        let json: JsonValue = report.to_json(None).unwrap().parse().unwrap();
+        let json: &HashMap<_, _> = json.get().unwrap();
+
+        assert_eq!(json["command"], JsonValue::String("package".into()));
+        assert_eq!(json["success"], JsonValue::Boolean(true));
+        assert_eq!(json["error"], JsonValue::Null);
+        assert_eq!(json["tests_passed"], JsonValue::Boolean(false));
+        assert_eq!(json["tests"], JsonValue::Null);
+        assert!(matches!(json["elapsed_secs"], JsonValue::Number(secs) if secs >= 0.0));
+
+        let paths: &HashMap<_, _> = json["paths"].get().unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(
+            paths["created"],
+            JsonValue::String("target/xtest-data/foo-0.1.0.xtest-data".into())
+        );
+    }
+
+    #[test]
+    fn test_counts_as_json() {
+        let mut report = Report::new(Format::Json, "crate-test");
+        report.tested(&TestResult {
+            passed: 3,
+            failed: 0,
+            ignored: 1,
+            failures: vec![],
+        });
+
+        let json: JsonValue = report.to_json(None).unwrap().parse().unwrap();
+        let json: &HashMap<_, _> = json.get().unwrap();
+        assert_eq!(json["tests_passed"], JsonValue::Boolean(true));
+
+        let tests: &HashMap<_, _> = json["tests"].get().unwrap();
+        assert_eq!(tests["passed"], JsonValue::Number(3.0));
+        assert_eq!(tests["failed"], JsonValue::Number(0.0));
+        assert_eq!(tests["ignored"], JsonValue::Number(1.0));
+    }
+
+    #[test]
+    fn failure_as_json() {
+        let report = Report::new(Format::Json, "package");
+        let err = anchor_error()(as_io_error("no pack archive"));
+
+        let json: JsonValue = report.to_json(Some(&err)).unwrap().parse().unwrap();
+        let json: &HashMap<_, _> = json.get().unwrap();
+
+        assert_eq!(json["success"], JsonValue::Boolean(false));
+        assert!(
+            matches!(&json["error"], JsonValue::String(msg) if msg.contains("no pack archive"))
+        );
+    }
+}
@@ -4,11 +4,12 @@ use crate::util::GoodOutput;
 use super::{anchor_error, as_io_error, undiagnosed_io_error, LocatedError};
 
 use std::collections::HashMap;
-use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::{fmt, io};
 
 use serde::Serialize;
+use tinyjson::JsonValue;
 use toml::Value;
 
 /// A local file tree containing a source folder.
@@ -16,6 +17,12 @@ pub struct LocalSource {
     pub cargo: PathBuf,
     /// Allow this source tree to be dirty? May be best-effort.
     pub dirty: bool,
+    /// The number of commits of history to include in the pack objects.
+    pub pack_depth: Option<u32>,
+    /// A path in the crate to write the pack objects to, so they are packaged with it.
+    pub embed: Option<PathBuf>,
+    /// The number of parallel jobs of the inner `cargo` invocations.
+    pub jobs: Option<u32>,
 }
 
 /// A local path to a `.crate` archive.
@@ -28,6 +35,79 @@ pub enum VcsInfo {
     Overwrite { path: PathBuf },
 }
 
+impl CrateSource {
+    /// Check that the archive exists and decompresses, which `tar` would report less clearly.
+    fn check_compressed(&self) -> Result<(), LocatedError> {
+        Command::new("gunzip")
+            .arg("-t")
+            .arg(&self.path)
+            .success()
+            .map_err(|_| {
+                anchor_error()(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "`{}` is not a gzip compressed crate archive",
+                        self.path.display()
+                    ),
+                ))
+            })
+    }
+
+    /// The single top-level directory of the archive.
+    ///
+    /// Cargo names it `name-version` but a repackaged or renamed archive may differ, so it is read
+    /// from the archive itself.
+    pub fn top_level_dir(&self) -> Result<PathBuf, LocatedError> {
+        top_level_dir_of(&self.listing()?)
+    }
+
+    /// The paths of all entries in the archive, one per line.
+    fn listing(&self) -> Result<String, LocatedError> {
+        self.check_compressed()?;
+        // Read from the file, the listing may exceed a pipe while we write the archive to one.
+        let mut tar = Command::new("tar");
+        tar.arg("-tzf").arg(&self.path);
+        let listing = GoodOutput::output(&mut tar).map_err(anchor_error())?;
+        Ok(String::from_utf8_lossy(&listing.stdout).into_owned())
+    }
+
+    /// The files of the archive below `dir`, relative to its top-level directory.
+    pub fn files_in(&self, dir: &Path) -> Result<Vec<PathBuf>, LocatedError> {
+        let listing = self.listing()?;
+        let top = top_level_dir_of(&listing)?;
+
+        Ok(listing
+            .lines()
+            .filter(|line| !line.ends_with('/'))
+            .filter_map(|line| Path::new(line).strip_prefix(&top).ok())
+            .filter(|path| path.starts_with(dir))
+            .map(Path::to_path_buf)
+            .collect())
+    }
+
+    /// The contents of one file in the archive.
+    fn read(&self, path: &Path) -> Result<Vec<u8>, LocatedError> {
+        self.check_compressed()?;
+        let mut tar = Command::new("tar");
+        tar.arg("-xzOf").arg(&self.path).arg(path);
+        let output = GoodOutput::output(&mut tar).map_err(anchor_error())?;
+        Ok(output.stdout)
+    }
+}
+
+impl VcsInfo {
+    /// Read the pinned commit, for a crate unpacked into `extracted`.
+    pub fn commit(&self, extracted: &Path) -> Result<String, LocatedError> {
+        let path = match self {
+            VcsInfo::FromCrate => extracted.join(".cargo_vcs_info.json"),
+            VcsInfo::Overwrite { path } => path.to_owned(),
+        };
+
+        let vcs_info = std::fs::read_to_string(path).map_err(anchor_error())?;
+        commit_of_vcs_info(&vcs_info)
+    }
+}
+
 /// Full target information.
 #[derive(Debug)]
 pub struct Target {
@@ -51,9 +131,41 @@ pub struct Metadata {
     pub pack_archive: Option<ArchiveMethod>,
     /// Artifact URL template.
     pub pack_artifact: Option<String>,
-    /// Relative path of location for pack objects.
+    /// Relative path of location for pack objects, as a template.
     /// Suggested: `target/xtest-data` or `target/xtest-data-pack`.
+    ///
+    /// This is rendered only once the commit is known, see [`Metadata::pack_objects`].
     pub pack_objects: Option<String>,
+    /// The size in bytes of the largest blob to include in the pack objects.
+    ///
+    /// Larger blobs are left out, they must be provided by a reference repository instead.
+    pub pack_objects_max_blob: Option<u64>,
+    /// The size in bytes of the largest pack artifact to download.
+    pub pack_artifact_max_size: Option<u64>,
+}
+
+/// The information available to the `pack-objects` template.
+#[derive(Serialize)]
+struct PackObjectsContext<'lt> {
+    #[serde(flatten)]
+    target: &'lt TargetStatic,
+    /// The commit for which objects are packed.
+    commit: &'lt str,
+}
+
+/// The context of a template, with the environment variables that it refers to as `{env.NAME}`.
+#[derive(Serialize)]
+struct EnvContext<'lt, T> {
+    #[serde(flatten)]
+    inner: &'lt T,
+    env: HashMap<String, String>,
+}
+
+/// A key of `package.metadata.xtest-data` that is required but missing.
+#[derive(Debug)]
+enum MetadataError {
+    NoPackArchive,
+    NoPackArtifact,
 }
 
 /// Determine how the pack objects are archived.
@@ -67,6 +179,9 @@ impl LocalSource {
         LocalSource {
             cargo: path.join("Cargo.toml"),
             dirty: false,
+            pack_depth: None,
+            embed: None,
+            jobs: None,
         }
     }
 
@@ -74,6 +189,63 @@ impl LocalSource {
         LocalSource { dirty, ..self }
     }
 
+    pub fn with_pack_depth(self, pack_depth: Option<u32>) -> Self {
+        LocalSource { pack_depth, ..self }
+    }
+
+    pub fn with_embed(self, embed: Option<PathBuf>) -> Self {
+        LocalSource { embed, ..self }
+    }
+
+    pub fn with_jobs(self, jobs: Option<u32>) -> Self {
+        LocalSource { jobs, ..self }
+    }
+
+    /// Find the `[workspace.package]` table of the workspace containing this source, if any.
+    ///
+    /// Follows an explicit `package.workspace` key, otherwise searches the parent directories for
+    /// a manifest with a `[workspace]` table like cargo does.
+    pub fn workspace_package(&self) -> Result<Option<Value>, LocatedError> {
+        let read_manifest = |path: &Path| -> Result<Value, LocatedError> {
+            let toml = std::fs::read_to_string(path).map_err(anchor_error())?;
+            toml::de::from_str(&toml)
+                .map_err(as_io_error)
+                .map_err(anchor_error())
+        };
+
+        let member = read_manifest(&self.cargo)?;
+        let crate_dir = self.cargo.parent().unwrap_or_else(|| Path::new("."));
+
+        if member.get("workspace").is_some() {
+            return Ok(package_of_workspace(&member));
+        }
+
+        let explicit = member
+            .get("package")
+            .and_then(|package| package.get("workspace"))
+            .and_then(Value::as_str);
+
+        if let Some(root) = explicit {
+            let root = read_manifest(&crate_dir.join(root).join("Cargo.toml"))?;
+            return Ok(package_of_workspace(&root));
+        }
+
+        let crate_dir = crate_dir.canonicalize().map_err(anchor_error())?;
+        for ancestor in crate_dir.ancestors().skip(1) {
+            let candidate = ancestor.join("Cargo.toml");
+            if !candidate.is_file() {
+                continue;
+            }
+
+            let root = read_manifest(&candidate)?;
+            if root.get("workspace").is_some() {
+                return Ok(package_of_workspace(&root));
+            }
+        }
+
+        Ok(None)
+    }
+
     pub fn target_directory(&self, _: &Target) -> PathBuf {
         // FIXME: use metadata for actual target directory.
         self.cargo.parent().unwrap().join("target/xtest-data")
@@ -83,53 +255,86 @@ impl LocalSource {
 impl Target {
     pub(crate) fn from_dir(spec: &LocalSource) -> Result<Self, LocatedError> {
         let toml = std::fs::read(&spec.cargo).map_err(anchor_error())?;
-        Self::from_toml(&toml)
+        let workspace = spec.workspace_package()?;
+        Self::from_toml_in_workspace(&toml, workspace.as_ref())
     }
 
     pub(crate) fn from_crate(archive: &CrateSource) -> Result<Self, LocatedError> {
-        let crate_tar = Command::new("gunzip")
-            .arg("-c")
-            .arg(&archive.path)
-            .output()
-            .map_err(anchor_error())?
-            .stdout;
-
-        let toml = Command::new("tar")
-            .arg("-O")
-            .args(["--extract", "--file", "-", "--wildcards", "*/Cargo.toml"])
-            .input_output(&crate_tar)
-            .map_err(anchor_error())?;
+        let listing = archive.listing()?;
+        let top = top_level_dir_of(&listing)?;
+        let toml = archive.read(&top.join("Cargo.toml"))?;
 
-        Self::from_toml(&toml.stdout)
+        // The normalized manifest in a crate archive has all workspace inheritance resolved.
+        let original = top.join("Cargo.toml.orig");
+        if !listing.lines().any(|entry| Path::new(entry) == original) {
+            return Self::from_toml(&toml);
+        }
+
+        // But it may have rewritten or dropped the fields we care about.
+        let original = archive.read(&original)?;
+        let toml = prefer_original(&toml, &original)?;
+        Self::from_toml(toml.as_bytes())
     }
 
     pub(crate) fn from_toml(toml: &[u8]) -> Result<Self, LocatedError> {
+        Self::from_toml_in_workspace(toml, None)
+    }
+
+    /// Parse a manifest, resolving fields inherited from the `[workspace.package]` table.
+    pub(crate) fn from_toml_in_workspace(
+        toml: &[u8],
+        workspace: Option<&Value>,
+    ) -> Result<Self, LocatedError> {
         let toml = core::str::from_utf8(toml).map_err(anchor_error())?;
 
-        let toml: Value = toml::de::from_str(toml)
+        let mut toml: Value = toml::de::from_str(toml)
             .map_err(as_io_error)
             .map_err(anchor_error())?;
 
         let package = toml
-            .get("package")
+            .get_mut("package")
             .ok_or_else(undiagnosed_io_error())
             .map_err(anchor_error())?;
-        let name = package
-            .get("name")
-            .ok_or_else(undiagnosed_io_error())
-            .map_err(anchor_error())?
-            .as_str()
-            .ok_or_else(undiagnosed_io_error())
-            .map_err(anchor_error())?
-            .to_owned();
-        let version = package
-            .get("version")
-            .ok_or_else(undiagnosed_io_error())
-            .map_err(anchor_error())?
-            .as_str()
-            .ok_or_else(undiagnosed_io_error())
-            .map_err(anchor_error())?
-            .to_owned();
+
+        if let Some(package) = package.as_table_mut() {
+            for (key, value) in package.iter_mut() {
+                if !is_workspace_inherited(value) {
+                    continue;
+                }
+
+                let inherited = workspace
+                    .and_then(|workspace| workspace.get(key.as_str()))
+                    .ok_or_else(|| {
+                        let err = io::Error::new(
+                            io::ErrorKind::Other,
+                            format!(
+                                "`package.{}` is inherited but the workspace does not define `workspace.package.{}`",
+                                key, key
+                            ),
+                        );
+                        anchor_error()(err)
+                    })?;
+
+                *value = inherited.clone();
+            }
+        }
+
+        let package = &*package;
+        let string_field = |key: &str| {
+            package
+                .get(key)
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+                .ok_or_else(|| {
+                    let err = io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("`package.{}` must be a string", key),
+                    );
+                    anchor_error()(err)
+                })
+        };
+        let name = string_field("name")?;
+        let version = string_field("version")?;
 
         let mut target = Target {
             env: TargetStatic {
@@ -158,13 +363,126 @@ impl Target {
         format!("{}-{}.crate", &self.env.name, &self.env.version).into()
     }
 
+    /// The `package.repository` of the target, if any.
+    pub fn repository(&self) -> Option<&str> {
+        self.env.extra.get("repository")?.as_str()
+    }
+
     pub fn expected_dir_name(&self) -> PathBuf {
         format!("{}-{}", &self.env.name, &self.env.version).into()
     }
 }
 
+/// Take `package.repository` and `package.metadata.xtest-data` from the manifest as written.
+///
+/// Values inherited from a workspace can not be resolved in the archive, the normalized manifest
+/// is kept for those.
+fn prefer_original(normalized: &[u8], original: &[u8]) -> Result<String, LocatedError> {
+    let parse = |toml: &[u8]| -> Result<Value, LocatedError> {
+        let toml = core::str::from_utf8(toml).map_err(anchor_error())?;
+        toml::de::from_str(toml)
+            .map_err(as_io_error)
+            .map_err(anchor_error())
+    };
+
+    let mut normalized = parse(normalized)?;
+    let original = parse(original)?;
+
+    let package = normalized
+        .get_mut("package")
+        .and_then(Value::as_table_mut)
+        .ok_or_else(undiagnosed_io_error())
+        .map_err(anchor_error())?;
+    let original = match original.get("package") {
+        Some(original) => original,
+        None => {
+            return toml::to_string(&normalized)
+                .map_err(as_io_error)
+                .map_err(anchor_error())
+        }
+    };
+
+    if let Some(repository) = original.get("repository").filter(|value| value.is_str()) {
+        package.insert("repository".into(), repository.clone());
+    }
+
+    let xtest_data = original
+        .get("metadata")
+        .and_then(|metadata| metadata.get("xtest-data"))
+        .filter(|value| value.is_table());
+    if let Some(xtest_data) = xtest_data {
+        let metadata = package
+            .entry("metadata")
+            .or_insert_with(|| Value::Table(Default::default()));
+        if let Some(metadata) = metadata.as_table_mut() {
+            metadata.insert("xtest-data".into(), xtest_data.clone());
+        }
+    }
+
+    toml::to_string(&normalized)
+        .map_err(as_io_error)
+        .map_err(anchor_error())
+}
+
+/// Find the one directory containing all entries of a tar listing.
+fn top_level_dir_of(listing: &str) -> Result<PathBuf, LocatedError> {
+    let mut top = None;
+    for entry in listing.lines() {
+        let first = Path::new(entry)
+            .components()
+            .find_map(|component| match component {
+                std::path::Component::Normal(name) => Some(name),
+                _ => None,
+            });
+
+        let first = match first {
+            Some(first) => first,
+            None => continue,
+        };
+
+        match &top {
+            None => top = Some(PathBuf::from(first)),
+            Some(top) if top.as_os_str() == first => {}
+            Some(top) => {
+                let err = io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "The crate archive has more than one top-level directory: `{}` and `{}`",
+                        top.display(),
+                        Path::new(first).display()
+                    ),
+                );
+                return Err(anchor_error()(err));
+            }
+        }
+    }
+
+    top.ok_or_else(undiagnosed_io_error())
+        .map_err(anchor_error())
+}
+
+/// Check for the `{ workspace = true }` form of a package field.
+fn is_workspace_inherited(value: &Value) -> bool {
+    value
+        .get("workspace")
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+fn package_of_workspace(root: &Value) -> Option<Value> {
+    root.get("workspace")?.get("package").cloned()
+}
+
 impl Metadata {
     pub(crate) fn from_value(val: &Value, target: &Target) -> Result<Self, LocatedError> {
+        Self::from_value_with_env(val, target, |name| std::env::var(name).ok())
+    }
+
+    fn from_value_with_env(
+        val: &Value,
+        target: &Target,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, LocatedError> {
         let mut table = val
             .as_table()
             .ok_or_else(|| {
@@ -175,8 +493,6 @@ impl Metadata {
             .clone();
 
         let mut meta = Metadata::default();
-        let mut template = tinytemplate::TinyTemplate::new();
-        let (artifact_src, object_src);
 
         if let Some(archive) = table.remove("pack-archive") {
             match archive.as_str() {
@@ -184,7 +500,10 @@ impl Metadata {
                     meta.pack_archive = Some(ArchiveMethod::TarGz);
                 }
                 _ => {
-                    let err = io::Error::new(io::ErrorKind::Other, "Unknown archive method");
+                    let err = io::Error::new(
+                        io::ErrorKind::Other,
+                        "Unknown archive method in `package.metadata.xtest-data.pack-archive`, expected \"tar:gz\"",
+                    );
                     return Err(anchor_error()(err));
                 }
             }
@@ -192,12 +511,7 @@ impl Metadata {
 
         if let Some(artifact) = table.remove("pack-artifact") {
             if let Some(artifact) = artifact.as_str() {
-                artifact_src = artifact.to_string();
-                let _ = template.add_template("__main__", &artifact_src);
-                let artifact = template
-                    .render("__main__", &target.env)
-                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
-                    .map_err(anchor_error())?;
+                let artifact = render("pack-artifact", artifact, &target.env, &var)?;
                 meta.pack_artifact = Some(artifact);
             } else {
                 let err = io::Error::new(
@@ -210,13 +524,7 @@ impl Metadata {
 
         if let Some(objects) = table.remove("pack-objects") {
             if let Some(objects) = objects.as_str() {
-                object_src = objects.to_string();
-                let _ = template.add_template("__main__", &object_src);
-                let objects = template
-                    .render("__main__", &target.env)
-                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
-                    .map_err(anchor_error())?;
-                meta.pack_objects = Some(objects);
+                meta.pack_objects = Some(objects.to_string());
             } else {
                 let err = io::Error::new(
                     io::ErrorKind::Other,
@@ -226,6 +534,538 @@ impl Metadata {
             }
         }
 
+        if let Some(max_blob) = table.remove("pack-objects-max-blob") {
+            match max_blob.as_integer().filter(|&max| max >= 0) {
+                Some(max) => meta.pack_objects_max_blob = Some(max as u64),
+                None => {
+                    let err = io::Error::new(
+                        io::ErrorKind::Other,
+                        "Bad value for `pack-objects-max-blob`, expected a number of bytes",
+                    );
+                    return Err(anchor_error()(err));
+                }
+            }
+        }
+
+        if let Some(max_size) = table.remove("pack-artifact-max-size") {
+            match max_size.as_integer().filter(|&max| max >= 0) {
+                Some(max) => meta.pack_artifact_max_size = Some(max as u64),
+                None => {
+                    let err = io::Error::new(
+                        io::ErrorKind::Other,
+                        "Bad value for `pack-artifact-max-size`, expected a number of bytes",
+                    );
+                    return Err(anchor_error()(err));
+                }
+            }
+        }
+
         Ok(meta)
     }
+
+    /// The method of the pack archive, required to create or unpack one.
+    pub(crate) fn archive_method(&self) -> Result<&ArchiveMethod, LocatedError> {
+        self.pack_archive
+            .as_ref()
+            .ok_or_else(|| anchor_error()(MetadataError::NoPackArchive))
+    }
+
+    /// The location of the pack archive, required to download it.
+    pub(crate) fn artifact_location(&self) -> Result<&str, LocatedError> {
+        self.pack_artifact
+            .as_deref()
+            .ok_or_else(|| anchor_error()(MetadataError::NoPackArtifact))
+    }
+
+    /// Render the relative path for pack objects of a commit.
+    ///
+    /// In addition to the package fields, the template may refer to `{commit}`. This allows
+    /// objects of different commits to coexist in one shared directory.
+    pub(crate) fn pack_objects(
+        &self,
+        target: &TargetStatic,
+        commit: &str,
+    ) -> Result<Option<String>, LocatedError> {
+        let objects = match &self.pack_objects {
+            None => return Ok(None),
+            Some(objects) => objects,
+        };
+
+        let context = PackObjectsContext { target, commit };
+        let var = |name: &str| std::env::var(name).ok();
+        render("pack-objects", objects, &context, &var).map(Some)
+    }
+}
+
+/// Render the template of the metadata key `field`.
+///
+/// Besides the fields of the `context`, the template may refer to an environment variable as
+/// `{env.NAME}`, which must be set.
+fn render(
+    field: &str,
+    source: &str,
+    context: &impl Serialize,
+    var: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, LocatedError> {
+    let mut env = HashMap::new();
+    for name in env_references(source) {
+        let value = var(name).ok_or_else(|| {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "The `{}` template refers to `{{env.{}}}` but the environment variable `{}` is not set",
+                    field, name, name
+                ),
+            );
+            anchor_error()(err)
+        })?;
+        env.insert(name.to_string(), value);
+    }
+
+    let mut template = tinytemplate::TinyTemplate::new();
+    template
+        .add_template("__main__", source)
+        .map_err(as_io_error)
+        .map_err(anchor_error())?;
+    let context = EnvContext {
+        inner: context,
+        env,
+    };
+    template
+        .render("__main__", &context)
+        .map_err(as_io_error)
+        .map_err(anchor_error())
+}
+
+/// The names of the environment variables that a template refers to as `{env.NAME}`.
+fn env_references(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split('}').next())
+        // A value may be followed by a formatter, as in `{env.NAME | unescaped}`.
+        .filter_map(|value| value.split('|').next()?.trim().strip_prefix("env."))
+        .collect()
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::NoPackArchive => write!(
+                f,
+                r#"No `metadata.xtest-data.pack-archive` specified in `Cargo.toml`.
+Add the archive method to the `[package.metadata.xtest-data]` table, for example:
+pack-archive = "tar:gz""#
+            ),
+            MetadataError::NoPackArtifact => write!(
+                f,
+                r#"No `metadata.xtest-data.pack-artifact` specified in `Cargo.toml`.
+Add the URL template of the archive to the `[package.metadata.xtest-data]` table, for example:
+pack-artifact = "{{repository}}/releases/download/v{{version}}/xtest-data.tar.gz"
+Otherwise, download the archive yourself and pass it with `--pack-artifact`."#
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+fn commit_of_vcs_info(vcs_info: &str) -> Result<String, LocatedError> {
+    let vcs: JsonValue = vcs_info
+        .parse()
+        .map_err(as_io_error)
+        .map_err(anchor_error())?;
+    let key = |value: &JsonValue, key: &str| -> Option<JsonValue> {
+        value.get::<HashMap<_, _>>()?.get(key).cloned()
+    };
+
+    key(&vcs, "git")
+        .and_then(|git| key(&git, "sha1"))
+        .and_then(|sha1| sha1.get::<String>().cloned())
+        .ok_or_else(undiagnosed_io_error())
+        .map_err(anchor_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        commit_of_vcs_info, top_level_dir_of, CrateSource, LocalSource, Metadata, MetadataError,
+        Target,
+    };
+    use std::path::Path;
+    use std::process::Command;
+
+    /// Create a `.crate` archive with the files under one top-level directory.
+    fn crate_archive(tmp: &Path, dir: &str, files: &[(&str, &str)]) -> CrateSource {
+        let inner = tmp.join(dir);
+        std::fs::create_dir(&inner).unwrap();
+        for (name, content) in files {
+            let path = inner.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        let path = tmp.join("foo-1.2.3.crate");
+        let status = Command::new("tar")
+            .arg("-C")
+            .arg(tmp)
+            .arg("-czf")
+            .arg(&path)
+            .arg(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        CrateSource { path }
+    }
+
+    #[test]
+    fn crate_with_renamed_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let manifest = "[package]\nname = \"foo\"\nversion = \"1.2.3\"\n";
+        let source = crate_archive(tmp.path(), "renamed", &[("Cargo.toml", manifest)]);
+
+        let target = Target::from_crate(&source).unwrap();
+        assert_eq!(target.env.name, "foo");
+        assert_eq!(target.expected_dir_name(), Path::new("foo-1.2.3"));
+        assert_eq!(source.top_level_dir().unwrap(), Path::new("renamed"));
+    }
+
+    #[test]
+    fn listing_larger_than_a_pipe() {
+        let tmp = tempfile::tempdir().unwrap();
+        let names: Vec<_> = (0..2000)
+            .map(|idx| format!("tests/fixtures/a-rather-long-file-name-{:04}.bin", idx))
+            .collect();
+        let files: Vec<_> = names.iter().map(|name| (name.as_str(), "")).collect();
+        let source = crate_archive(tmp.path(), "foo-1.2.3", &files);
+
+        let files = source.files_in(Path::new("tests")).unwrap();
+        assert_eq!(files.len(), 2000);
+    }
+
+    #[test]
+    fn missing_archive() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = CrateSource {
+            path: tmp.path().join("missing-1.2.3.crate"),
+        };
+
+        let err = source.top_level_dir().unwrap_err().to_string();
+        assert!(
+            err.contains("is not a gzip compressed crate archive"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn files_in_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = crate_archive(
+            tmp.path(),
+            "foo-1.2.3",
+            &[
+                ("Cargo.toml", ""),
+                ("tests/xtest-data/pack-0123.pack", "PACK"),
+                ("tests/xtest-data/pack-0123.idx", "IDX"),
+                ("tests/data.bin", ""),
+            ],
+        );
+
+        let mut files = source.files_in(Path::new("tests/xtest-data")).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                Path::new("tests/xtest-data/pack-0123.idx"),
+                Path::new("tests/xtest-data/pack-0123.pack"),
+            ]
+        );
+        assert!(source.files_in(Path::new("xtest-data")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn original_manifest_is_preferred() {
+        let tmp = tempfile::tempdir().unwrap();
+        let normalized = r#"
+[package]
+name = "foo"
+version = "1.2.3"
+repository = "https://example.com/normalized"
+"#;
+        let original = r#"
+[package]
+name = "foo"
+version = "1.2.3"
+repository = "https://example.com/original"
+edition.workspace = true
+
+[package.metadata.xtest-data]
+pack-artifact = "{repository}/xtest-data.tar.gz"
+"#;
+        let source = crate_archive(
+            tmp.path(),
+            "foo-1.2.3",
+            &[("Cargo.toml", normalized), ("Cargo.toml.orig", original)],
+        );
+
+        let target = Target::from_crate(&source).unwrap();
+        assert_eq!(target.repository(), Some("https://example.com/original"));
+        assert_eq!(
+            target.cargo.pack_artifact.as_deref(),
+            Some("https://example.com/original/xtest-data.tar.gz")
+        );
+    }
+
+    #[test]
+    fn missing_metadata_is_named() {
+        let toml = r#"
+[package]
+name = "foo"
+version = "1.2.3"
+
+[package.metadata.xtest-data]
+pack-objects = "target/xtest-data"
+"#;
+        let target = Target::from_toml(toml.as_bytes()).unwrap();
+
+        let err = target.cargo.archive_method().unwrap_err().to_string();
+        assert!(
+            err.contains("`metadata.xtest-data.pack-archive`"),
+            "{}",
+            err
+        );
+        let err = target.cargo.artifact_location().unwrap_err().to_string();
+        assert!(
+            err.contains("`metadata.xtest-data.pack-artifact`"),
+            "{}",
+            err
+        );
+
+        let toml = format!("{}pack-archive = \"zip\"\n", toml);
+        let err = format!("{:?}", Target::from_toml(toml.as_bytes()).unwrap_err());
+        assert!(err.contains("Unknown archive method"), "{}", err);
+    }
+
+    #[test]
+    fn pack_objects_max_blob() {
+        let toml = r#"
+[package]
+name = "foo"
+version = "1.2.3"
+
+[package.metadata.xtest-data]
+pack-objects-max-blob = 1048576
+"#;
+        let target = Target::from_toml(toml.as_bytes()).unwrap();
+        assert_eq!(target.cargo.pack_objects_max_blob, Some(1 << 20));
+        assert_eq!(target.cargo.pack_artifact_max_size, None);
+
+        let size = toml.replace("pack-objects-max-blob", "pack-artifact-max-size");
+        let target = Target::from_toml(size.as_bytes()).unwrap();
+        assert_eq!(target.cargo.pack_artifact_max_size, Some(1 << 20));
+
+        let toml = toml.replace("1048576", "\"1M\"");
+        let err = format!("{:?}", Target::from_toml(toml.as_bytes()).unwrap_err());
+        assert!(err.contains("pack-objects-max-blob"), "{}", err);
+    }
+
+    #[test]
+    fn metadata_errors_name_the_key() {
+        let archive = MetadataError::NoPackArchive.to_string();
+        assert!(
+            archive.contains("`metadata.xtest-data.pack-archive`"),
+            "{}",
+            archive
+        );
+        assert!(
+            archive.contains(r#"pack-archive = "tar:gz""#),
+            "{}",
+            archive
+        );
+
+        let artifact = MetadataError::NoPackArtifact.to_string();
+        assert!(
+            artifact.contains("`metadata.xtest-data.pack-artifact`"),
+            "{}",
+            artifact
+        );
+        assert!(
+            artifact.contains(r#"pack-artifact = "{repository}/"#),
+            "{}",
+            artifact
+        );
+        assert!(!archive.contains("``") && !artifact.contains("``"));
+    }
+
+    #[test]
+    fn crate_with_two_directories() {
+        let listing = "foo-1.2.3/\nfoo-1.2.3/Cargo.toml\n./other/Cargo.toml\n";
+        assert!(top_level_dir_of(listing).is_err());
+
+        let listing = "foo-1.2.3/Cargo.toml\nfoo-1.2.3/src/lib.rs\n";
+        assert_eq!(top_level_dir_of(listing).unwrap(), Path::new("foo-1.2.3"));
+    }
+
+    #[test]
+    fn repository_inherited_from_workspace() {
+        let workspace = tempfile::tempdir().unwrap();
+        let member = workspace.path().join("member");
+        std::fs::create_dir(&member).unwrap();
+
+        std::fs::write(
+            workspace.path().join("Cargo.toml"),
+            r#"
+[workspace]
+members = ["member"]
+
+[workspace.package]
+repository = "https://example.com/repo"
+"#,
+        )
+        .unwrap();
+
+        std::fs::write(
+            member.join("Cargo.toml"),
+            r#"
+[package]
+name = "member"
+version = "0.1.0"
+repository.workspace = true
+
+[package.metadata.xtest-data]
+pack-artifact = "{repository}/xtest-data.tar.gz"
+"#,
+        )
+        .unwrap();
+
+        let source = LocalSource::with_simple_repository(&member);
+        let target = Target::from_dir(&source).unwrap();
+
+        assert_eq!(
+            target.cargo.pack_artifact.as_deref(),
+            Some("https://example.com/repo/xtest-data.tar.gz")
+        );
+    }
+
+    #[test]
+    fn version_inherited_from_workspace() {
+        let workspace = tempfile::tempdir().unwrap();
+        let member = workspace.path().join("crates/member");
+        std::fs::create_dir_all(&member).unwrap();
+
+        std::fs::write(
+            workspace.path().join("Cargo.toml"),
+            r#"
+[workspace]
+members = ["crates/member"]
+
+[workspace.package]
+version = "2.0.0"
+"#,
+        )
+        .unwrap();
+
+        std::fs::write(
+            member.join("Cargo.toml"),
+            r#"
+[package]
+name = "member"
+version = { workspace = true }
+
+[package.metadata.xtest-data]
+pack-artifact = "https://example.com/{name}-{version}.tar.gz"
+"#,
+        )
+        .unwrap();
+
+        let source = LocalSource::with_simple_repository(&member);
+        let target = Target::from_dir(&source).unwrap();
+
+        assert_eq!(target.env.version, "2.0.0");
+        assert_eq!(
+            target.cargo.pack_artifact.as_deref(),
+            Some("https://example.com/member-2.0.0.tar.gz")
+        );
+
+        // Without the workspace the version can not be resolved, which is named as the cause.
+        let toml = std::fs::read(member.join("Cargo.toml")).unwrap();
+        let err = format!("{:?}", Target::from_toml(&toml).unwrap_err());
+        assert!(err.contains("`package.version` is inherited"), "{}", err);
+
+        let err = format!(
+            "{:?}",
+            Target::from_toml(b"[package]\nname = \"member\"\nversion = 2\n").unwrap_err()
+        );
+        assert!(
+            err.contains("`package.version` must be a string"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn pack_objects_by_commit() {
+        let target = Target::from_toml(
+            br#"
+[package]
+name = "objects"
+version = "0.1.0"
+
+[package.metadata.xtest-data]
+pack-objects = "target/xtest-data/{name}-{commit}"
+"#,
+        )
+        .unwrap();
+
+        let objects = target.cargo.pack_objects(&target.env, "0123abcd").unwrap();
+        assert_eq!(
+            objects.as_deref(),
+            Some("target/xtest-data/objects-0123abcd")
+        );
+    }
+
+    #[test]
+    fn artifact_from_environment() {
+        let toml = r#"
+[package]
+name = "foo"
+version = "0.1.0"
+
+[package.metadata.xtest-data]
+pack-artifact = "https://{env.XTEST_DATA_BUCKET}/{ env.CI_COMMIT_SHA }/foo-{version}.tar.gz"
+"#;
+        let target =
+            Target::from_toml(b"[package]\nname = \"foo\"\nversion = \"0.1.0\"\n").unwrap();
+        let package: toml::Value = toml::from_str(toml).unwrap();
+        let meta = &package["package"]["metadata"]["xtest-data"];
+
+        let var = |name: &str| match name {
+            "XTEST_DATA_BUCKET" => Some("bucket.example.com".to_string()),
+            "CI_COMMIT_SHA" => Some("0123abcd".to_string()),
+            _ => None,
+        };
+        let meta = Metadata::from_value_with_env(meta, &target, var).unwrap();
+        assert_eq!(
+            meta.pack_artifact.as_deref(),
+            Some("https://bucket.example.com/0123abcd/foo-0.1.0.tar.gz")
+        );
+
+        let package: toml::Value = toml::from_str(toml).unwrap();
+        let meta = &package["package"]["metadata"]["xtest-data"];
+        let err = Metadata::from_value_with_env(meta, &target, |_| None).unwrap_err();
+        let err = format!("{:?}", err);
+        assert!(
+            err.contains("`pack-artifact` template refers to `{env.XTEST_DATA_BUCKET}`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn commit_from_vcs_info() {
+        let vcs_info = r#"{ "git": { "sha1": "0123abcd" }, "path_in_vcs": "" }"#;
+        assert_eq!(commit_of_vcs_info(vcs_info).unwrap(), "0123abcd");
+        assert!(commit_of_vcs_info(r#"{ "path_in_vcs": "" }"#).is_err());
+    }
 }
@@ -6,5 +6,7 @@ pub mod dl;
 pub mod output;
 /// A `cargo package` that runs all relevant tests, and adds vcs_info_data when dirty.
 pub mod pack;
+/// Based on a crate archive, collect the data its tests would fetch.
+pub mod plan;
 /// Based on a crate archive and CI archive, unpack and retest.
 pub mod test;
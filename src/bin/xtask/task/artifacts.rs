@@ -29,7 +29,7 @@ pub struct UnpackedArchive {
 
 #[derive(Debug)]
 enum PackError {
-    NoPackSpecification,
+    MissingCommit { commit: String },
 }
 
 pub fn pack(
@@ -37,11 +37,7 @@ pub fn pack(
     target: &Target,
     tmp: &Path,
 ) -> Result<PackedArtifacts, LocatedError> {
-    let ArchiveMethod::TarGz = target
-        .cargo
-        .pack_archive
-        .as_ref()
-        .ok_or_else(|| anchor_error()(PackError::NoPackSpecification))?;
+    let ArchiveMethod::TarGz = target.cargo.archive_method()?;
 
     // Invert: tar -C /tmp --extract --file -
     let create_tar = Command::new("tar")
@@ -73,11 +69,7 @@ pub fn unpack(
     target: &Target,
     tmp: &Path,
 ) -> Result<UnpackedArchive, LocatedError> {
-    let ArchiveMethod::TarGz = target
-        .cargo
-        .pack_archive
-        .as_ref()
-        .ok_or_else(|| anchor_error()(PackError::NoPackSpecification))?;
+    let ArchiveMethod::TarGz = target.cargo.archive_method()?;
 
     // gunzip -c target/package/xtest-data-0.0.2.crate
     let crate_tar = Command::new("gunzip")
@@ -102,12 +94,132 @@ pub fn unpack(
     Ok(UnpackedArchive { path: target })
 }
 
+/// Check that the objects of the pack artifact materialize the crate's pinned commit.
+///
+/// A stale artifact from a different release would otherwise provide the wrong data, or fail deep
+/// within the test run. The objects are imported into a scratch repository within `tmp`.
+pub fn verify_commit(
+    archive: &UnpackedArchive,
+    commit: &str,
+    tmp: &Path,
+) -> Result<(), LocatedError> {
+    let repo = tmp.join("xtest-data-verify");
+    let _ = std::fs::remove_dir_all(&repo);
+
+    Command::new("git")
+        .args(["init", "--bare", "--quiet"])
+        .arg(&repo)
+        .success()
+        .map_err(anchor_error())?;
+
+    for entry in std::fs::read_dir(&archive.path).map_err(anchor_error())? {
+        let path = entry.map_err(anchor_error())?.path();
+        if path.extension().map_or(true, |ext| ext != "pack") {
+            continue;
+        }
+
+        let pack = std::fs::read(&path).map_err(anchor_error())?;
+        Command::new("git")
+            .arg("--git-dir")
+            .arg(&repo)
+            .args(["unpack-objects", "-q"])
+            .input_output(&pack)
+            .map_err(anchor_error())?;
+    }
+
+    // The commit and its root tree, which is all we need to check out any path.
+    let found = Command::new("git")
+        .arg("--git-dir")
+        .arg(&repo)
+        .args(["cat-file", "-e"])
+        .arg(format!("{}^{{tree}}", commit))
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map_err(anchor_error())?;
+
+    let _ = std::fs::remove_dir_all(&repo);
+
+    if !found.success() {
+        return Err(anchor_error()(PackError::MissingCommit {
+            commit: commit.to_owned(),
+        }));
+    }
+
+    Ok(())
+}
+
 impl fmt::Display for PackError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
-            PackError::NoPackSpecification => write!(f, "No `` specified in `Cargo.toml`"),
+            PackError::MissingCommit { commit } => write!(
+                f,
+                "The pack artifact does not contain the crate's commit `{}`, it may be from a different release",
+                commit
+            ),
         }
     }
 }
 
 impl std::error::Error for PackError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_commit, UnpackedArchive};
+    use std::path::Path;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?}", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    #[test]
+    fn mismatched_pack_is_rejected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path().join("repo");
+        std::fs::create_dir(&repo).unwrap();
+        git(&repo, &["init", "--quiet"]);
+        let commit = |content: &str| {
+            std::fs::write(repo.join("data.bin"), content).unwrap();
+            git(&repo, &["add", "--all"]);
+            let message = ["commit", "--quiet", "--message", content];
+            let identity = ["-c", "user.name=x", "-c", "user.email=x@localhost"];
+            git(&repo, &[&identity[..], &message[..]].concat());
+            git(&repo, &["rev-parse", "HEAD"])
+        };
+
+        let packed = commit("packed");
+        let released = commit("released");
+
+        // Pack only the objects of the first commit.
+        let packs = tmp.path().join("artifacts");
+        std::fs::create_dir(&packs).unwrap();
+        let objects = Command::new("git")
+            .args(["rev-list", "--objects", "--no-object-names", "-n", "1"])
+            .arg(&packed)
+            .current_dir(&repo)
+            .output()
+            .unwrap()
+            .stdout;
+        let mut pack = Command::new("git")
+            .arg("pack-objects")
+            .arg(packs.join("xtest-data"))
+            .current_dir(&repo)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(pack.stdin.as_mut().unwrap(), &objects).unwrap();
+        pack.stdin = None;
+        assert!(pack.wait().unwrap().success());
+
+        let archive = UnpackedArchive { path: packs };
+        verify_commit(&archive, &packed, tmp.path()).unwrap();
+        assert!(verify_commit(&archive, &released, tmp.path()).is_err());
+    }
+}
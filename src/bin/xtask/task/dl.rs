@@ -1,10 +1,16 @@
 //! Fetch files for a packed file.
 use core::fmt;
-use std::path::Path;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use tinyjson::JsonValue;
 
 use crate::{
     target::Target,
-    util::{anchor_error, LocatedError},
+    util::{anchor_error, GoodOutput, LocatedError},
 };
 
 use super::artifacts::PackedArtifacts;
@@ -16,93 +22,641 @@ pub struct Download {
 
 #[derive(Debug)]
 enum DlError {
-    NoArtifactLocation,
+    /// The redirects did not end within [`MAX_REDIRECTS`].
     TooManyRedirects {
         location: String,
-        response: ureq::Response,
+        limit: u32,
+    },
+    /// A redirect that we can not follow, such as one without a target.
+    Redirect {
+        location: String,
+        status: HttpStatus,
+    },
+    /// The artifact does not exist, commonly a wrong URL template or a missing upload.
+    NotFound {
+        location: String,
+        status: HttpStatus,
+    },
+    /// The artifact is not accessible without credentials.
+    Unauthorized {
+        location: String,
+        status: HttpStatus,
+    },
+    /// The server failed, which may well be transient.
+    ServerError {
+        location: String,
+        status: HttpStatus,
     },
+    /// Any other rejection of our request.
     BadRequest {
         location: String,
-        response: ureq::Response,
+        status: HttpStatus,
     },
+    /// Network access was refused by `CARGO_NET_OFFLINE`.
+    Offline {
+        location: String,
+    },
+    /// A local artifact, by path or `file://` URL, does not exist.
+    NoFile {
+        location: String,
+    },
+    Truncated {
+        location: String,
+        expected: u64,
+        got: u64,
+    },
+    /// The artifact is larger than we are willing to write to disk.
+    TooLarge {
+        location: String,
+        limit: u64,
+    },
+    Corrupted {
+        location: String,
+    },
+    /// The registry index does not list the requested version.
+    NotPublished {
+        location: String,
+        version: String,
+    },
+    /// The name can not be that of a published crate.
+    InvalidName {
+        name: String,
+    },
+    /// The downloaded archive is not the one that the registry index describes.
+    ChecksumMismatch {
+        location: String,
+        expected: String,
+        got: String,
+    },
+}
+
+/// A version of a crate that was published to crates.io.
+pub struct Published {
+    pub name: String,
+    pub version: String,
+}
+
+/// Where crates.io serves the archives of published crates.
+const CRATES_IO_DOWNLOAD: &str = "https://static.crates.io/crates";
+/// The sparse index of crates.io, which records the checksum of each archive.
+const CRATES_IO_INDEX: &str = "https://index.crates.io";
+
+#[derive(Debug)]
+struct HttpStatus {
+    code: u16,
+    text: String,
+    /// The beginning of the response body, which often explains the error.
+    body: String,
+}
+
+/// How many redirects we follow, a CDN commonly adds one or two.
+const MAX_REDIRECTS: u32 = 10;
+
+/// The size in bytes of the largest artifact that we download, unless configured otherwise.
+const DEFAULT_MAX_SIZE: u64 = 4 << 30;
+
+/// How long a download may stall, without receiving any data.
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How much of an error response body we read at most.
+const ERROR_BODY_LIMIT: u64 = 8 * 1024;
+
+/// Count the bytes that actually made it to the file.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
 }
 
 pub fn download(target: &Target, tmp: &Path) -> Result<Download, LocatedError> {
-    match &target.cargo.pack_artifact {
-        None => Err(anchor_error()(DlError::NoArtifactLocation)),
-        Some(archive) => {
-            let request = ureq::get(archive);
-            let response = request.call().map_err(anchor_error())?;
-
-            // Turn HTTP into actions for us.
-            // Success = continue, 300-400 report actionable errors, rest non-actionable one.
-            match response.status() {
-                200..=299 => {}
-                300..=399 => {
-                    return Err(anchor_error()(DlError::TooManyRedirects {
-                        location: archive.to_string(),
-                        response,
-                    }));
-                }
-                400..=499 => {
-                    return Err(anchor_error()(DlError::BadRequest {
-                        location: archive.to_string(),
-                        response,
-                    }));
-                }
-                _ => {
-                    return Err(anchor_error()(DlError::BadRequest {
-                        location: archive.to_string(),
-                        response,
-                    }));
-                }
-            }
-
-            let artifact = tmp.join("_vcs_file.tar.gz");
-            let mut reader = response.into_reader();
-
-            // We can write over the file
-            let mut writer = std::fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(&artifact)
-                .map_err(anchor_error())?;
-
-            std::io::copy(&mut reader, &mut writer).map_err(anchor_error())?;
-            Ok(Download {
-                artifact: PackedArtifacts { path: artifact },
+    let archive = target.cargo.artifact_location()?;
+    let artifact = tmp.join("_vcs_file.tar.gz");
+    let limit = max_size(target, |name| std::env::var(name).ok())?;
+    match local_path(archive) {
+        Some(path) => copy_local(archive, &path, &artifact, limit)?,
+        None => fetch(archive, &artifact, limit)?,
+    }
+
+    Ok(Download {
+        artifact: PackedArtifacts { path: artifact },
+    })
+}
+
+/// Download a published crate into `dir`, checked against the checksum of the registry index.
+pub fn download_published(published: &Published, dir: &Path) -> Result<PathBuf, LocatedError> {
+    download_published_from(published, CRATES_IO_DOWNLOAD, CRATES_IO_INDEX, dir)
+}
+
+fn download_published_from(
+    published: &Published,
+    crates: &str,
+    index: &str,
+    dir: &Path,
+) -> Result<PathBuf, LocatedError> {
+    let expected = published_checksum(published, &published.index_url_in(index)?)?;
+
+    let location = published.crate_url_in(crates);
+    let path = dir.join(format!("{}-{}.crate", published.name, published.version));
+    fetch(&location, &path, DEFAULT_MAX_SIZE)?;
+
+    let archive = std::fs::read(&path).map_err(anchor_error())?;
+    let digest = Command::new("sha256sum")
+        .input_output(&archive)
+        .map_err(anchor_error())?;
+    let got = String::from_utf8_lossy(&digest.stdout);
+    let got = got.split_whitespace().next().unwrap_or_default();
+
+    if !got.eq_ignore_ascii_case(&expected) {
+        return Err(anchor_error()(DlError::ChecksumMismatch {
+            location,
+            expected,
+            got: got.to_string(),
+        }));
+    }
+
+    Ok(path)
+}
+
+/// The checksum of a published version, from its line in the sparse index.
+fn published_checksum(published: &Published, location: &str) -> Result<String, LocatedError> {
+    let agent = agent_for(location, |name| std::env::var(name).ok())?;
+    let response = match agent.get(location).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(err) => return Err(DlError::from_transport(location, err)),
+    };
+
+    let response = DlError::from_status(location, response).map_err(anchor_error())?;
+    let lines = response.into_string().map_err(anchor_error())?;
+
+    for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: JsonValue = line.parse().map_err(anchor_error())?;
+        let entry = match entry.get::<HashMap<String, JsonValue>>() {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        let field = |key: &str| entry.get(key).and_then(|value| value.get::<String>());
+        if field("vers") == Some(&published.version) {
+            if let Some(cksum) = field("cksum") {
+                return Ok(cksum.clone());
+            }
+        }
+    }
+
+    Err(anchor_error()(DlError::NotPublished {
+        location: location.to_string(),
+        version: published.version.clone(),
+    }))
+}
+
+impl Published {
+    /// The location of the `.crate` archive on crates.io.
+    pub fn crate_url(&self) -> String {
+        self.crate_url_in(CRATES_IO_DOWNLOAD)
+    }
+
+    /// The location of the index file that lists all versions of the crate.
+    pub fn index_url(&self) -> Result<String, LocatedError> {
+        self.index_url_in(CRATES_IO_INDEX)
+    }
+
+    fn crate_url_in(&self, base: &str) -> String {
+        format!(
+            "{}/{}/{}-{}.crate",
+            base, self.name, self.name, self.version
+        )
+    }
+
+    /// See <https://doc.rust-lang.org/cargo/reference/registry-index.html#index-files>.
+    fn index_url_in(&self, base: &str) -> Result<String, LocatedError> {
+        // Only ASCII names are published, which also makes the byte slices below valid.
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if self.name.is_empty() || !self.name.chars().all(valid) {
+            return Err(anchor_error()(DlError::InvalidName {
+                name: self.name.clone(),
+            }));
+        }
+
+        let name = self.name.to_ascii_lowercase();
+        let prefix = match name.len() {
+            0..=2 => name.len().to_string(),
+            3 => format!("3/{}", &name[..1]),
+            _ => format!("{}/{}", &name[..2], &name[2..4]),
+        };
+
+        Ok(format!("{}/{}/{}", base, prefix, name))
+    }
+}
+
+/// The file that a `file://` URL or a plain path names, `None` for any other URL.
+fn local_path(location: &str) -> Option<PathBuf> {
+    match location.split_once("://") {
+        None => Some(PathBuf::from(location)),
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("file") => {
+            // The host, if any, must be the local one.
+            let path = rest.strip_prefix("localhost").unwrap_or(rest);
+            Some(PathBuf::from(path))
+        }
+        Some(_) => None,
+    }
+}
+
+/// Copy an artifact that is available without a network, such as one built offline.
+///
+/// The same `limit` applies as to a download, a local file is not necessarily trusted more.
+fn copy_local(location: &str, path: &Path, dest: &Path, limit: u64) -> Result<(), LocatedError> {
+    let not_found = |err: io::Error| {
+        if err.kind() == io::ErrorKind::NotFound {
+            anchor_error()(DlError::NoFile {
+                location: location.to_string(),
             })
+        } else {
+            anchor_error()(err)
+        }
+    };
+
+    let len = std::fs::metadata(path).map_err(not_found)?.len();
+    if len > limit {
+        return Err(anchor_error()(DlError::TooLarge {
+            location: location.to_string(),
+            limit,
+        }));
+    }
+
+    std::fs::copy(path, dest).map_err(not_found)?;
+    Ok(())
+}
+
+/// The size limit of the artifact, from `CARGO_XTEST_DATA_MAX_DOWNLOAD` or the metadata.
+fn max_size(target: &Target, var: impl Fn(&str) -> Option<String>) -> Result<u64, LocatedError> {
+    match var("CARGO_XTEST_DATA_MAX_DOWNLOAD") {
+        Some(max) => max.trim().parse().map_err(|err| {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "`CARGO_XTEST_DATA_MAX_DOWNLOAD` must be a number of bytes, not `{}`: {}",
+                    max, err
+                ),
+            );
+            anchor_error()(err)
+        }),
+        None => Ok(target
+            .cargo
+            .pack_artifact_max_size
+            .unwrap_or(DEFAULT_MAX_SIZE)),
+    }
+}
+
+/// Download a gzip compressed file from `location` into `dest`, of at most `limit` bytes.
+///
+/// A larger file is removed again.
+fn fetch(location: &str, dest: &Path, limit: u64) -> Result<(), LocatedError> {
+    let agent = agent_for(location, |name| std::env::var(name).ok())?;
+    let request = agent.get(location);
+    let response = match request.call() {
+        Ok(response) => response,
+        // Error status codes are still answers that we can interpret.
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(err) => return Err(DlError::from_transport(location, err)),
+    };
+
+    let response = DlError::from_status(location, response).map_err(anchor_error())?;
+
+    let expected = response
+        .header("Content-Length")
+        .and_then(|len| len.trim().parse::<u64>().ok());
+
+    let too_large = || {
+        let _ = std::fs::remove_file(dest);
+        anchor_error()(DlError::TooLarge {
+            location: location.to_string(),
+            limit,
+        })
+    };
+    if expected.map_or(false, |expected| expected > limit) {
+        return Err(too_large());
+    }
+
+    // One more byte than allowed tells a file of exactly the limit from a larger one.
+    let mut reader = response.into_reader().take(limit.saturating_add(1));
+
+    // We can write over the file
+    let writer = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(dest)
+        .map_err(anchor_error())?;
+
+    let mut writer = CountingWriter {
+        inner: writer,
+        count: 0,
+    };
+
+    match std::io::copy(&mut reader, &mut writer) {
+        Ok(_) => {}
+        // The reader notices a connection that closed before the announced length.
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && expected.is_some() => {}
+        Err(err) => return Err(anchor_error()(err)),
+    }
+
+    if writer.count > limit {
+        return Err(too_large());
+    }
+
+    match expected {
+        Some(expected) if expected != writer.count => {
+            return Err(anchor_error()(DlError::Truncated {
+                location: location.to_string(),
+                expected,
+                got: writer.count,
+            }));
+        }
+        Some(_) => {}
+        // Without a length we can not tell a dropped connection from a complete one. At
+        // least the compression stream must be intact, as it detects truncation itself.
+        None => {
+            Command::new("gzip")
+                .arg("-t")
+                .arg(dest)
+                .success()
+                .map_err(|_| {
+                    anchor_error()(DlError::Corrupted {
+                        location: location.to_string(),
+                    })
+                })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// An agent for `location`, going through the proxy that the environment configures for it.
+///
+/// Fails instead when `CARGO_NET_OFFLINE` forbids network access, like it does for cargo. This
+/// takes precedence over `CARGO_XTEST_DATA_FETCH`.
+fn agent_for(
+    location: &str,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<ureq::Agent, LocatedError> {
+    let offline = var("CARGO_NET_OFFLINE").map_or(false, |offline| {
+        let offline = offline.trim();
+        offline == "1" || offline.eq_ignore_ascii_case("true")
+    });
+    if offline {
+        return Err(anchor_error()(DlError::Offline {
+            location: location.to_string(),
+        }));
+    }
+
+    let mut builder = ureq::AgentBuilder::new()
+        .redirects(MAX_REDIRECTS)
+        .timeout_read(READ_TIMEOUT);
+    if let Some(proxy) = proxy_for(location, var) {
+        builder = builder.proxy(ureq::Proxy::new(proxy).map_err(anchor_error())?);
+    }
+    Ok(builder.build())
+}
+
+/// The proxy for `location` by the conventional variables, looked up with `var`.
+///
+/// The scheme specific `https_proxy` and `http_proxy` take precedence over `all_proxy`. As with
+/// curl, the lower case name of each variable is preferred. Hosts listed in `no_proxy` are
+/// reached directly.
+fn proxy_for(location: &str, var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let lookup = |name: &str| {
+        let non_empty = |value: &String| !value.trim().is_empty();
+        var(name)
+            .filter(non_empty)
+            .or_else(|| var(&name.to_ascii_uppercase()).filter(non_empty))
+    };
+
+    let (scheme, rest) = location.split_once("://")?;
+    let authority = rest
+        .split(|c| c == '/' || c == '?' || c == '#')
+        .next()
+        .unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+
+    if let Some(no_proxy) = lookup("no_proxy") {
+        if is_excluded(host, &no_proxy) {
+            return None;
+        }
+    }
+
+    let specific = match scheme.to_ascii_lowercase().as_str() {
+        "https" => lookup("https_proxy"),
+        "http" => lookup("http_proxy"),
+        _ => return None,
+    };
+
+    specific.or_else(|| lookup("all_proxy"))
+}
+
+/// Whether a `no_proxy` list names the host, one of its parent domains, or is `*`.
+fn is_excluded(host: &str, no_proxy: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    no_proxy
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+
+            let entry = entry.trim_start_matches("*.").trim_start_matches('.');
+            // An entry may name a port, which we ignore. Do not mistake an IPv6 address for one.
+            let entry = match entry.rsplit_once(':') {
+                Some((name, port)) if !name.contains(':') && port.parse::<u16>().is_ok() => name,
+                _ => entry,
+            };
+            let entry = entry.trim_start_matches('[').trim_end_matches(']');
+            let entry = entry.to_ascii_lowercase();
+
+            match host.strip_suffix(entry.as_str()) {
+                Some("") => true,
+                Some(subdomain) => subdomain.ends_with('.'),
+                None => false,
+            }
+        })
+}
+
+impl DlError {
+    /// Recognize the end of redirects among the failures to get any response.
+    #[track_caller]
+    fn from_transport(location: &str, err: ureq::Error) -> LocatedError {
+        if err.kind() == ureq::ErrorKind::TooManyRedirects {
+            return anchor_error()(DlError::TooManyRedirects {
+                location: location.to_string(),
+                limit: MAX_REDIRECTS,
+            });
         }
+
+        anchor_error()(err)
+    }
+
+    /// Turn HTTP into actions for us.
+    ///
+    /// Success = continue, everything else is an error with advice tailored to its class.
+    fn from_status(location: &str, response: ureq::Response) -> Result<ureq::Response, Self> {
+        if let 200..=299 = response.status() {
+            return Ok(response);
+        }
+
+        let location = location.to_string();
+        let code = response.status();
+        let text = response.status_text().to_string();
+
+        // Only a bounded prefix, error bodies should be short but we must not trust that.
+        let mut body = vec![];
+        let _ = response
+            .into_reader()
+            .take(ERROR_BODY_LIMIT)
+            .read_to_end(&mut body);
+        let body = String::from_utf8_lossy(&body).trim().to_string();
+
+        let status = HttpStatus { code, text, body };
+
+        Err(match status.code {
+            // The agent follows all other redirects.
+            300..=399 => DlError::Redirect { location, status },
+            401 | 403 => DlError::Unauthorized { location, status },
+            404 | 410 => DlError::NotFound { location, status },
+            500..=599 => DlError::ServerError { location, status },
+            _ => DlError::BadRequest { location, status },
+        })
     }
 }
 
 impl fmt::Display for DlError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self {
-            DlError::NoArtifactLocation => write!(f, "No `` specified in `Cargo.toml`"),
-            DlError::TooManyRedirects { location, response } => {
+            DlError::TooManyRedirects { location, limit } => {
                 write!(
                     f,
                     r#"Server sent too many redirects following artifact location {}.
+We follow at most {} redirects, the server may redirect in a loop. Try following it with your browser?"#,
+                    location, limit,
+                )
+            }
+            DlError::Redirect { location, status } => {
+                write!(
+                    f,
+                    r#"Server sent a redirect that can not be followed for artifact location {}.
 Try following it with your browser?
-Technical details: {status} {status_text}"#,
-                    location,
-                    status = response.status(),
-                    status_text = response.status_text(),
+Technical details: {}"#,
+                    location, status,
+                )
+            }
+            DlError::NotFound { location, status } => {
+                write!(
+                    f,
+                    r#"No artifact found at {}.
+Check that the `pack-artifact` template renders the correct URL for this version, and that the artifact was uploaded there.
+Technical details: {}"#,
+                    location, status,
                 )
             }
-            DlError::BadRequest { location, response } => {
+            DlError::Unauthorized { location, status } => {
+                write!(
+                    f,
+                    r#"Access to artifact location {} was denied.
+The artifact must be publicly readable. Otherwise download it yourself and pass it with `--pack-artifact`.
+Technical details: {}"#,
+                    location, status,
+                )
+            }
+            DlError::ServerError { location, status } => {
+                write!(
+                    f,
+                    r#"The server failed to provide artifact location {}.
+This is likely transient, try again later.
+Technical details: {}"#,
+                    location, status,
+                )
+            }
+            DlError::BadRequest { location, status } => {
                 write!(
                     f,
                     r#"Bad request following artifact location {}
-Technical details: {status} {status_text}
-{text}"#,
+Technical details: {}"#,
+                    location, status,
+                )
+            }
+            DlError::TooLarge { location, limit } => {
+                write!(
+                    f,
+                    r#"Download of artifact location {} exceeds the limit of {} bytes.
+Check that the `pack-artifact` template renders the correct URL. Raise the limit with `pack-artifact-max-size` in `[package.metadata.xtest-data]` or `CARGO_XTEST_DATA_MAX_DOWNLOAD`."#,
+                    location, limit,
+                )
+            }
+            DlError::Offline { location } => {
+                write!(
+                    f,
+                    r#"Refusing to download artifact location {} because `CARGO_NET_OFFLINE` is set.
+Download the archive yourself and pass it with `--pack-artifact`, or point `pack-artifact` at a local file."#,
                     location,
-                    status = response.status(),
-                    status_text = response.status_text(),
-                    // FIXME: actual, optional response text?
-                    text = "<server response could not be read>",
+                )
+            }
+            DlError::NoFile { location } => {
+                write!(
+                    f,
+                    r#"No artifact file found at {}.
+Check that the `pack-artifact` template renders the correct path, and that the artifact was built there."#,
+                    location,
+                )
+            }
+            DlError::Truncated {
+                location,
+                expected,
+                got,
+            } => {
+                write!(
+                    f,
+                    r#"Download of artifact location {} ended early.
+The server announced {} bytes but only {} bytes were received."#,
+                    location, expected, got,
+                )
+            }
+            DlError::Corrupted { location } => {
+                write!(
+                    f,
+                    r#"Download of artifact location {} is not a valid gzip archive.
+The connection may have closed before the transfer completed."#,
+                    location,
+                )
+            }
+            DlError::NotPublished { location, version } => {
+                write!(
+                    f,
+                    r#"The registry index at {} does not list version {}.
+Check the spelling of the name and version of the crate."#,
+                    location, version,
+                )
+            }
+            DlError::InvalidName { name } => {
+                write!(
+                    f,
+                    r#"`{}` is not the name of a published crate.
+Crate names consist only of ASCII letters, digits, `-` and `_`."#,
+                    name,
+                )
+            }
+            DlError::ChecksumMismatch {
+                location,
+                expected,
+                got,
+            } => {
+                write!(
+                    f,
+                    r#"Download of crate location {} does not match the checksum of the registry index.
+Expected sha256 {} but got {}."#,
+                    location, expected, got,
                 )
             }
         }
@@ -110,3 +664,435 @@ Technical details: {status} {status_text}
 }
 
 impl std::error::Error for DlError {}
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code, self.text)?;
+
+        if self.body.is_empty() {
+            write!(f, "\n<server sent no response text>")
+        } else {
+            write!(f, "\n{}", self.body)
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        agent_for, copy_local, download, download_published_from, fetch, max_size, proxy_for,
+        DlError, Published,
+    };
+    use crate::target::Target;
+
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::Path;
+    use std::thread::JoinHandle;
+
+    /// Answer exactly one request with a canned response, then close the connection.
+    ///
+    /// The thread yields the beginning of the request it answered.
+    fn respond_once(
+        response: impl AsRef<[u8]> + Send + 'static,
+    ) -> (SocketAddr, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let len = stream.read(&mut request).unwrap_or(0);
+            let _ = stream.write_all(response.as_ref());
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        (addr, server)
+    }
+
+    fn serve_once(response: &'static [u8]) -> Target {
+        let (addr, _) = respond_once(response);
+        artifact_at(&format!("http://{}/xtest-data.tar.gz", addr))
+    }
+
+    fn artifact_at(location: &str) -> Target {
+        let toml = format!(
+            r#"
+[package]
+name = "dl-test"
+version = "0.0.0"
+
+[package.metadata.xtest-data]
+pack-artifact = "{}"
+"#,
+            location
+        );
+
+        Target::from_toml(toml.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn local_artifact_is_copied() {
+        let artifacts = tempfile::tempdir().unwrap();
+        let local = artifacts.path().join("xtest-data.tar.gz");
+        std::fs::write(&local, "artifact").unwrap();
+        let local = local.to_str().unwrap();
+
+        for location in [format!("file://{}", local), local.to_string()] {
+            let tmp = tempfile::tempdir().unwrap();
+            let download = download(&artifact_at(&location), tmp.path()).unwrap();
+            let content = std::fs::read_to_string(download.artifact.path).unwrap();
+            assert_eq!(content, "artifact", "{}", location);
+        }
+
+        let missing = format!("file://{}/missing.tar.gz", artifacts.path().display());
+        let tmp = tempfile::tempdir().unwrap();
+        let err = download(&artifact_at(&missing), tmp.path())
+            .err()
+            .expect("a missing artifact");
+        let err = err.to_string();
+        assert!(err.contains("No artifact file found"), "{}", err);
+
+        let dest = tmp.path().join("xtest-data.tar.gz");
+        let err = copy_local(local, Path::new(local), &dest, 4)
+            .expect_err("an artifact beyond the limit");
+        let err = err.to_string();
+        assert!(err.contains("exceeds the limit of 4 bytes"), "{}", err);
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn truncated_body_is_rejected() {
+        let target = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nonly a few bytes");
+        let tmp = tempfile::tempdir().unwrap();
+
+        let err = download(&target, tmp.path())
+            .err()
+            .expect("a truncated download");
+        let err = err.to_string();
+        assert!(err.contains("ended early"), "{}", err);
+        assert!(err.contains("announced 100 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn redirect_is_followed() {
+        let (artifact, server) =
+            respond_once(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nartifact");
+        let moved = format!(
+            "HTTP/1.1 302 Found\r\nLocation: http://{}/cdn/xtest-data.tar.gz\r\nContent-Length: 0\r\n\r\n",
+            artifact
+        );
+        let (redirect, _) = respond_once(moved);
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("xtest-data.tar.gz");
+
+        let location = format!("http://{}/xtest-data.tar.gz", redirect);
+        fetch(&location, &dest, 1024).unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "artifact");
+
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("GET /cdn/xtest-data.tar.gz"),
+            "{}",
+            request
+        );
+    }
+
+    #[test]
+    fn redirect_loop_is_too_many() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let moved = format!(
+            "HTTP/1.1 302 Found\r\nLocation: http://{}/again\r\nContent-Length: 0\r\n\r\n",
+            addr
+        );
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0; 1024]);
+                let _ = stream.write_all(moved.as_bytes());
+            }
+        });
+        let tmp = tempfile::tempdir().unwrap();
+
+        let location = format!("http://{}/xtest-data.tar.gz", addr);
+        let err = fetch(&location, &tmp.path().join("xtest-data.tar.gz"), 1024)
+            .expect_err("a redirect loop");
+        let err = err.to_string();
+        assert!(err.contains("too many redirects"), "{}", err);
+        assert!(err.contains("at most 10 redirects"), "{}", err);
+    }
+
+    #[test]
+    fn download_beyond_limit() {
+        // Without a length the limit is only noticed while streaming.
+        let target = serve_once(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n0123456789abcdef");
+        let tmp = tempfile::tempdir().unwrap();
+        let limit = max_size(&target, |_| Some("8".to_string())).unwrap();
+        let location = target.cargo.artifact_location().unwrap();
+        let dest = tmp.path().join("xtest-data.tar.gz");
+
+        let err = fetch(location, &dest, limit).expect_err("a download beyond the limit");
+        let err = err.to_string();
+        assert!(err.contains("exceeds the limit of 8 bytes"), "{}", err);
+        assert!(!dest.exists());
+
+        // An announced length is rejected before reading.
+        let target = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 16\r\n\r\n0123456789abcdef");
+        let location = target.cargo.artifact_location().unwrap();
+        let err = fetch(location, &dest, 8).expect_err("a download beyond the limit");
+        let err = err.to_string();
+        assert!(err.contains("exceeds the limit of 8 bytes"), "{}", err);
+
+        assert_eq!(
+            max_size(&target, |_| None).unwrap(),
+            super::DEFAULT_MAX_SIZE
+        );
+        let err = max_size(&target, |_| Some("4G".to_string())).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("`CARGO_XTEST_DATA_MAX_DOWNLOAD`"), "{}", err);
+        assert!(err.contains("not `4G`"), "{}", err);
+    }
+
+    #[test]
+    fn error_body_is_reported() {
+        let target = serve_once(
+            b"HTTP/1.1 403 Forbidden\r\nContent-Length: 54\r\n\r\n<Error><Code>AccessDenied</Code>Bucket policy</Error>\n",
+        );
+        let tmp = tempfile::tempdir().unwrap();
+
+        let err = download(&target, tmp.path())
+            .err()
+            .expect("a denied download");
+        let err = err.to_string();
+        assert!(err.contains("was denied"), "{}", err);
+        assert!(err.contains("<Code>AccessDenied</Code>"), "{}", err);
+    }
+
+    #[test]
+    fn status_classes() {
+        let message = |code, text| {
+            let response = ureq::Response::new(code, text, "").unwrap();
+            DlError::from_status("https://example.com/a.tar.gz", response)
+                .err()
+                .map(|err| err.to_string())
+        };
+
+        assert!(message(200, "OK").is_none());
+
+        let redirect = message(300, "Multiple Choices").unwrap();
+        assert!(redirect.contains("can not be followed"), "{}", redirect);
+
+        let not_found = message(404, "Not Found").unwrap();
+        assert!(
+            not_found.contains("`pack-artifact` template"),
+            "{}",
+            not_found
+        );
+        assert!(
+            not_found.contains("https://example.com/a.tar.gz"),
+            "{}",
+            not_found
+        );
+
+        let unauthorized = message(403, "Forbidden").unwrap();
+        assert!(unauthorized.contains("denied"), "{}", unauthorized);
+
+        let server = message(503, "Service Unavailable").unwrap();
+        assert!(server.contains("transient"), "{}", server);
+        assert!(server.contains("503 Service Unavailable"), "{}", server);
+
+        let bad = message(418, "I'm a teapot").unwrap();
+        assert!(bad.contains("Bad request"), "{}", bad);
+    }
+
+    /// Lookup in a fixed environment.
+    fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn proxy_by_scheme() {
+        let vars = env(&[
+            ("HTTPS_PROXY", "http://secure.proxy:3128"),
+            ("http_proxy", "http://plain.proxy:3128"),
+            ("ALL_PROXY", "http://any.proxy:3128"),
+        ]);
+
+        let https = proxy_for("https://github.com/a/b/x.tar.gz", &vars);
+        assert_eq!(https.as_deref(), Some("http://secure.proxy:3128"));
+        let http = proxy_for("http://example.com/x.tar.gz", &vars);
+        assert_eq!(http.as_deref(), Some("http://plain.proxy:3128"));
+        assert_eq!(proxy_for("ftp://example.com/x.tar.gz", &vars), None);
+
+        let all = env(&[("ALL_PROXY", "http://any.proxy:3128")]);
+        let https = proxy_for("https://github.com/a/b/x.tar.gz", all);
+        assert_eq!(https.as_deref(), Some("http://any.proxy:3128"));
+
+        assert_eq!(proxy_for("https://github.com/x.tar.gz", env(&[])), None);
+    }
+
+    #[test]
+    fn no_proxy_exclusions() {
+        let vars = env(&[
+            ("https_proxy", "http://proxy:3128"),
+            (
+                "no_proxy",
+                "localhost, .internal.example,mirror.test:8443,::1",
+            ),
+        ]);
+        let excluded = |location| proxy_for(location, &vars).is_none();
+
+        assert!(excluded("https://localhost/x.tar.gz"));
+        assert!(excluded("https://internal.example/x.tar.gz"));
+        assert!(excluded("https://ci.INTERNAL.example:8080/x.tar.gz"));
+        assert!(excluded("https://mirror.test/x.tar.gz"));
+        assert!(excluded("https://user@[::1]:8443/x.tar.gz"));
+
+        assert!(!excluded("https://notinternal.example/x.tar.gz"));
+        assert!(!excluded("https://github.com/x.tar.gz"));
+        assert!(!excluded("https://localhost.github.com/x.tar.gz"));
+
+        let everything = env(&[("https_proxy", "http://proxy:3128"), ("NO_PROXY", "*")]);
+        assert_eq!(proxy_for("https://github.com/x.tar.gz", everything), None);
+    }
+
+    #[test]
+    fn offline_refuses_network() {
+        let location = "https://example.com/xtest-data.tar.gz";
+        let offline = env(&[
+            ("CARGO_NET_OFFLINE", "true"),
+            ("CARGO_XTEST_DATA_FETCH", "yes"),
+        ]);
+        let err = agent_for(location, offline).expect_err("no network access");
+        let err = err.to_string();
+        assert!(err.contains("`CARGO_NET_OFFLINE` is set"), "{}", err);
+
+        assert!(agent_for(location, env(&[("CARGO_NET_OFFLINE", "1")])).is_err());
+        assert!(agent_for(location, env(&[("CARGO_NET_OFFLINE", "false")])).is_ok());
+        assert!(agent_for(location, env(&[("CARGO_XTEST_DATA_FETCH", "yes")])).is_ok());
+    }
+
+    #[test]
+    fn download_goes_through_proxy() {
+        let (proxy, server) = respond_once(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let proxy = format!("http://{}", proxy);
+        let location = "http://artifacts.example/xtest-data.tar.gz";
+
+        let agent = agent_for(location, |name| {
+            if name == "http_proxy" {
+                Some(proxy.clone())
+            } else {
+                None
+            }
+        })
+        .unwrap();
+        let body = agent.get(location).call().unwrap().into_string().unwrap();
+        assert_eq!(body, "ok");
+
+        let request = server.join().unwrap();
+        assert!(
+            request.starts_with("GET http://artifacts.example/"),
+            "{}",
+            request
+        );
+    }
+
+    #[test]
+    fn excluded_download_is_direct() {
+        let (direct, server) = respond_once(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        // Nothing listens there, a proxied request would fail.
+        let unused = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("http://{}", unused.local_addr().unwrap());
+        drop(unused);
+        let location = format!("http://{}/xtest-data.tar.gz", direct);
+
+        let agent = agent_for(&location, |name| match name {
+            "http_proxy" => Some(proxy.clone()),
+            "no_proxy" => Some("127.0.0.1".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        let body = agent.get(&location).call().unwrap().into_string().unwrap();
+        assert_eq!(body, "ok");
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /xtest-data.tar.gz"), "{}", request);
+    }
+
+    #[test]
+    fn published_locations() {
+        let published = |name: &str| Published {
+            name: name.to_string(),
+            version: "1.0.0".to_string(),
+        };
+
+        let xtest = published("xtest-data");
+        assert_eq!(
+            xtest.crate_url(),
+            "https://static.crates.io/crates/xtest-data/xtest-data-1.0.0.crate"
+        );
+        let index_url = |name| published(name).index_url().map_err(|err| err.to_string());
+        assert_eq!(
+            index_url("xtest-data").unwrap(),
+            "https://index.crates.io/xt/es/xtest-data"
+        );
+        assert_eq!(index_url("a").unwrap(), "https://index.crates.io/1/a");
+        assert_eq!(index_url("ab").unwrap(), "https://index.crates.io/2/ab");
+        assert_eq!(index_url("Abc").unwrap(), "https://index.crates.io/3/a/abc");
+
+        for invalid in ["", "äöü", "a/b"] {
+            let err = index_url(invalid).unwrap_err();
+            assert!(err.contains("not the name of a published crate"), "{}", err);
+        }
+    }
+
+    /// Serve the index of a crate with two versions, where only the second has a valid checksum.
+    fn published_checksum(version: &str) -> Result<(), String> {
+        const INDEX: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 210\r\n\r\n\
+{\"name\":\"foo\",\"vers\":\"0.1.0\",\"cksum\":\"0000000000000000000000000000000000000000000000000000000000000000\"}\n\
+{\"name\":\"foo\",\"vers\":\"0.2.0\",\"cksum\":\"ac1c15fc6b29f3fa1d02d334864db09f40eb7527d2c20125aaf7847b74fb57bd\"}\n";
+        let (index, _) = respond_once(INDEX);
+        let (crates, _) =
+            respond_once(b"HTTP/1.1 200 OK\r\nContent-Length: 18\r\n\r\nnot really a crate");
+        let tmp = tempfile::tempdir().unwrap();
+
+        let published = Published {
+            name: "foo".to_string(),
+            version: version.to_string(),
+        };
+
+        let index = format!("http://{}", index);
+        let crates = format!("http://{}", crates);
+        download_published_from(&published, &crates, &index, tmp.path())
+            .map(|path| assert!(path.ends_with("foo-0.2.0.crate")))
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn published_crate_is_verified() {
+        published_checksum("0.2.0").unwrap();
+
+        let err = published_checksum("0.1.0").unwrap_err();
+        assert!(err.contains("does not match the checksum"), "{}", err);
+
+        let err = published_checksum("0.3.0").unwrap_err();
+        assert!(err.contains("does not list version 0.3.0"), "{}", err);
+    }
+}
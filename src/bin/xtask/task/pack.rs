@@ -1,11 +1,14 @@
 //! Module to create packfile and associated data for a source repository.
 use crate::target::{CrateSource, LocalSource, Target, VcsInfo};
-use crate::util::{anchor_error, as_io_error, GoodOutput, LocatedError};
-use crate::CARGO;
+use crate::util::{anchor_error, as_io_error, GoodOutput, LocatedError, ParseOutput};
+use crate::{cargo, jobs_arg};
 
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use tinyjson::JsonValue;
+
 use super::artifacts::UnpackedArchive;
 
 pub struct PackedData {
@@ -16,12 +19,19 @@ pub struct PackedData {
 
 const GIT: &'static str = "git";
 
+/// The largest `.crate` archive that crates.io accepts by default.
+const CRATES_IO_MAX_SIZE: u64 = 10 << 20;
+
 pub(crate) fn pack(
     repo: &LocalSource,
     target: &Target,
     tmp: &Path,
 ) -> Result<PackedData, LocatedError> {
     let filename = target.expected_crate_name();
+    let pack_depth = repo.pack_depth;
+    let embed = repo.embed.clone();
+    let dirty = repo.dirty;
+    let jobs = repo.jobs;
     let repo = repo
         .cargo
         .parent()
@@ -29,8 +39,7 @@ pub(crate) fn pack(
         .map_err(anchor_error())?
         .canonicalize()
         .map_err(anchor_error())?;
-    // FIXME: read cargo metadata for sub folder?
-    let crate_path = Path::new("target/package").join(filename);
+    let crate_path = target_directory(&repo)?.join("package").join(filename);
 
     let commit = Command::new(GIT)
         .arg("--git-dir")
@@ -51,19 +60,49 @@ pub(crate) fn pack(
         .map_err(as_io_error)
         .map_err(anchor_error())?;
 
-    let packdir = repo.join("target").join("xtest-data");
+    let packdir = match (&embed, target.cargo.pack_objects(&target.env, commit)?) {
+        (Some(embed), _) => repo.join(embed),
+        (None, Some(objects)) => repo.join(objects),
+        (None, None) => repo.join("target").join("xtest-data"),
+    };
+    std::fs::create_dir_all(&packdir).map_err(anchor_error())?;
+    // The archive lists the files relative to the manifest, `--embed` may also be absolute.
+    let embed = embed
+        .map(|_| relative_to_crate(&repo, &packdir))
+        .transpose()?;
 
-    Command::new(CARGO)
+    cargo()
+        .current_dir(&repo)
         .args(["test"])
+        .args(jobs_arg(jobs))
         .env("CARGO_XTEST_DATA_PACK_OBJECTS", &packdir)
+        .envs(dirty.then(|| ("CARGO_XTEST_DATA_ALLOW_DIRTY", "1")))
+        .envs(pack_depth.map(|depth| ("CARGO_XTEST_DATA_PACK_DEPTH", depth.to_string())))
+        .envs(
+            target
+                .cargo
+                .pack_objects_max_blob
+                .map(|max| ("CARGO_XTEST_DATA_PACK_MAX_BLOB", max.to_string())),
+        )
         .success()
         .map_err(anchor_error())?;
 
-    Command::new(CARGO)
+    cargo()
+        .current_dir(&repo)
         .args(["package", "--allow-dirty", "--no-verify"])
+        .args(jobs_arg(jobs))
         .success()
         .map_err(anchor_error())?;
 
+    if let Some(embed) = &embed {
+        check_embedded(
+            &CrateSource {
+                path: crate_path.clone(),
+            },
+            embed,
+        )?;
+    }
+
     let vcs_info = tmp.join(".xtest_vcs_info.json");
     let vcs_info_data = format!(
         r#"{{ "git": {{ "sha1": "{}" }}, "path_in_vcs": "" }}"#,
@@ -80,3 +119,157 @@ pub(crate) fn pack(
         crate_: CrateSource { path: crate_path },
     })
 }
+
+/// The target directory that `cargo package` writes to, from `cargo metadata`.
+///
+/// This respects `CARGO_TARGET_DIR`, `build.target-dir` and the workspace of the crate.
+fn target_directory(repo: &Path) -> Result<PathBuf, LocatedError> {
+    let metadata = GoodOutput::output(cargo().current_dir(repo).args([
+        "metadata",
+        "--format-version",
+        "1",
+        "--no-deps",
+    ]))
+    .and_then(ParseOutput::into_string)
+    .map_err(anchor_error())?;
+    let metadata: JsonValue = metadata.parse().map_err(anchor_error())?;
+    match metadata["target_directory"].get::<String>() {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "The metadata of the crate at `{}` has no target directory",
+                    repo.display()
+                ),
+            );
+            Err(anchor_error()(err))
+        }
+    }
+}
+
+/// The path of `dir` within the crate at `repo`, which must contain it.
+fn relative_to_crate(repo: &Path, dir: &Path) -> Result<PathBuf, LocatedError> {
+    let dir = dir.canonicalize().map_err(anchor_error())?;
+    match dir.strip_prefix(repo) {
+        Ok(relative) => Ok(relative.to_owned()),
+        Err(_) => {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "The directory `{}` to embed the pack objects in is not part of the crate at `{}`",
+                    dir.display(),
+                    repo.display()
+                ),
+            );
+            Err(anchor_error()(err))
+        }
+    }
+}
+
+/// Check that the pack objects written to `embed` made it into the crate archive.
+///
+/// Cargo leaves out files that are ignored by `.gitignore` or by the `include` and `exclude`
+/// lists of the manifest. The objects also count towards the size limit of the registry.
+fn check_embedded(crate_: &CrateSource, embed: &Path) -> Result<(), LocatedError> {
+    if crate_.files_in(embed)?.is_empty() {
+        let err = io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "The pack objects in `{}` are not part of the packaged crate, check that they are \
+                 not excluded by `.gitignore` or `package.exclude`",
+                embed.display()
+            ),
+        );
+        return Err(anchor_error()(err));
+    }
+
+    let size = std::fs::metadata(&crate_.path)
+        .map_err(anchor_error())?
+        .len();
+    if size > CRATES_IO_MAX_SIZE {
+        eprintln!(
+            "Warning: the packaged crate has {} bytes, more than the {} bytes crates.io accepts",
+            size, CRATES_IO_MAX_SIZE
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pack;
+    use crate::target::{LocalSource, Target};
+    use std::path::Path;
+    use std::process::Command;
+
+    /// A crate in a repository of its own, whose test writes a pack file.
+    fn repository(dir: &Path) {
+        let files = [
+            (
+                "Cargo.toml",
+                "[package]\nname = \"embedded\"\nversion = \"0.1.0\"\nedition = \"2018\"\n[workspace]\n",
+            ),
+            (".gitignore", "/target\n"),
+            ("src/lib.rs", ""),
+            (
+                "tests/pack.rs",
+                r#"#[test] fn pack() {
+    let objects = std::env::var_os("CARGO_XTEST_DATA_PACK_OBJECTS").unwrap();
+    std::fs::write(std::path::Path::new(&objects).join("pack-0.pack"), "PACK").unwrap();
+}
+"#,
+            ),
+        ];
+        for (name, content) in files.iter() {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        for args in [
+            &["init", "--quiet"][..],
+            &["add", "."],
+            &["-c", "user.name=xtest", "-c", "user.email=xtest@localhost"],
+        ] {
+            let mut cmd = Command::new("git");
+            cmd.current_dir(dir).args(args);
+            if args[0] == "-c" {
+                cmd.args(["commit", "--quiet", "-m", "Initial"]);
+            }
+            assert!(cmd.status().unwrap().success(), "git {:?}", args);
+        }
+    }
+
+    #[test]
+    fn embed_absolute_path() {
+        let dir = tempfile::tempdir().unwrap();
+        repository(dir.path());
+        let tmp = tempfile::tempdir().unwrap();
+        // The crate is packaged outside of `<repo>/target`, the pack must find it there.
+        let target_dir = tempfile::tempdir().unwrap();
+        let config = format!("[build]\ntarget-dir = {:?}\n", target_dir.path());
+        std::fs::create_dir(dir.path().join(".cargo")).unwrap();
+        std::fs::write(dir.path().join(".cargo/config.toml"), config).unwrap();
+
+        let embed = dir.path().join("tests/xtest-data");
+        let source = LocalSource::with_simple_repository(dir.path()).with_embed(Some(embed));
+        let target = Target::from_dir(&source).unwrap();
+        let packed = pack(&source, &target, tmp.path()).unwrap();
+        assert!(packed.crate_.path.starts_with(target_dir.path()));
+
+        let files = packed
+            .crate_
+            .files_in(Path::new("tests/xtest-data"))
+            .unwrap();
+        assert_eq!(files, [Path::new("tests/xtest-data/pack-0.pack")]);
+
+        let outside = tempfile::tempdir().unwrap();
+        let source = LocalSource::with_simple_repository(dir.path())
+            .with_embed(Some(outside.path().to_owned()));
+        let err = pack(&source, &target, tmp.path()).err().unwrap();
+        let err = err.to_string();
+        assert!(err.contains("is not part of the crate"), "{}", err);
+    }
+}
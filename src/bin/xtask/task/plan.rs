@@ -0,0 +1,140 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use tinyjson::JsonValue;
+
+use crate::cargo;
+use crate::target::{CrateSource, Target, VcsInfo};
+use crate::util::{anchor_error, as_io_error, undiagnosed_io_error, GoodOutput, LocatedError};
+
+/// The prefix of lines in which the library reports a resource it would have fetched.
+const PLAN_PREFIX: &str = "xtest-data-plan: ";
+
+/// The data requirements of a crate archive.
+#[derive(Debug)]
+pub struct Plan {
+    /// The repository from which the data is fetched.
+    pub repository: Option<String>,
+    /// The commit recorded in the crate's vcs info.
+    pub commit: String,
+    /// All resources registered by any of the tests, sorted.
+    pub resources: BTreeSet<PathBuf>,
+}
+
+/// Determine the resources the tests of a crate archive would fetch.
+///
+/// Registrations are only known to the test code itself. So we run the tests of the unpacked
+/// crate without any pack objects, where each setup reports its resources before it fails.
+pub fn plan(crate_: &CrateSource, target: &Target, tmp: &Path) -> Result<Plan, LocatedError> {
+    let extracted = super::test::extract(crate_, tmp)?;
+
+    let commit = VcsInfo::FromCrate.commit(&extracted)?;
+
+    let mut cmd = cargo();
+    cmd.current_dir(&extracted)
+        .args(["test", "--no-fail-fast", "--", "--nocapture"])
+        .env("CARGO_XTEST_DATA_TMPDIR", tmp)
+        .env_remove("CARGO_XTEST_DATA_FETCH")
+        .env_remove("CARGO_XTEST_DATA_PACK_OBJECTS")
+        .env_remove("CARGO_XTEST_VCS_INFO");
+    cmd.mute_stdout();
+    // Tests requiring data are expected to fail here, only the report is relevant.
+    let output = cmd.output().map_err(anchor_error())?;
+    let report = String::from_utf8_lossy(&output.stderr);
+    let resources = parse_report(&report);
+
+    if resources.is_empty() && !output.status.success() {
+        eprintln!("{}", report);
+        return Err(undiagnosed_io_error()()).map_err(anchor_error());
+    }
+
+    Ok(Plan {
+        repository: target.repository().map(str::to_owned),
+        commit,
+        resources,
+    })
+}
+
+impl Plan {
+    pub fn to_json(&self) -> Result<String, LocatedError> {
+        self.to_json_value()
+            .format()
+            .map_err(as_io_error)
+            .map_err(anchor_error())
+    }
+
+    pub fn to_json_value(&self) -> JsonValue {
+        let mut plan = HashMap::new();
+        plan.insert(
+            "repository".to_string(),
+            self.repository
+                .clone()
+                .map_or(JsonValue::Null, JsonValue::String),
+        );
+        plan.insert("commit".to_string(), JsonValue::String(self.commit.clone()));
+        plan.insert(
+            "resources".to_string(),
+            JsonValue::Array(
+                self.resources
+                    .iter()
+                    .map(|path| JsonValue::String(path.display().to_string()))
+                    .collect(),
+            ),
+        );
+
+        JsonValue::Object(plan)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(repository) = &self.repository {
+            writeln!(f, "Repository:\t{}", repository)?;
+        }
+
+        writeln!(f, "Commit:\t{}", self.commit)?;
+        for path in &self.resources {
+            writeln!(f, "Resource:\t{}", path.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collect the resources from the lines reported by all tests.
+fn parse_report(report: &str) -> BTreeSet<PathBuf> {
+    report
+        .lines()
+        .filter_map(|line| line.strip_prefix(PLAN_PREFIX))
+        .filter_map(|line| {
+            let (_commit, path) = line.split_once(' ')?;
+            Some(PathBuf::from(path))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_lines_are_collected() {
+        let report = "\
+running 2 tests
+xtest-data-plan: 0123abcd tests/data.zip
+xtest-data-plan: 0123abcd tests/with space/file.txt
+thread 'fetch' panicked at src/lib.rs:580:21:
+xtest-data-plan: 0123abcd tests/data.zip
+";
+
+        let resources: Vec<_> = parse_report(report).into_iter().collect();
+        assert_eq!(
+            resources,
+            [
+                PathBuf::from("tests/data.zip"),
+                PathBuf::from("tests/with space/file.txt"),
+            ]
+        );
+    }
+}
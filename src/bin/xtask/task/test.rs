@@ -1,46 +1,52 @@
-use std::{path::Path, process::Command};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
-use crate::target::{CrateSource, Target, VcsInfo};
-use crate::util::{anchor_error, GoodOutput, LocatedError};
-use crate::CARGO;
+use crate::target::{CrateSource, VcsInfo};
+use crate::util::{anchor_error, as_io_error, GoodOutput, LocatedError};
+use crate::{cargo, jobs_arg};
 
 use super::artifacts::UnpackedArchive;
 
-#[derive(Debug)]
-pub struct TestResult {}
+/// The tests of a crate, summed over all its test binaries.
+#[derive(Debug, Default, PartialEq)]
+pub struct TestResult {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    /// The names of the failed tests.
+    pub failures: Vec<String>,
+}
 
 pub fn test(
     crate_: &CrateSource,
-    target: &Target,
     // FIXME: relax to not use `PackedData` but an optional vcs override and pack path.
     pack: &UnpackedArchive,
     vcs_info: &VcsInfo,
     tmp: &Path,
+    jobs: Option<u32>,
 ) -> Result<TestResult, LocatedError> {
-    let extracted = tmp.join(target.expected_dir_name());
-    // Try to remove it but ignore failure.
-    let _ = std::fs::remove_dir_all(&extracted).map_err(anchor_error());
-
-    // gunzip -c target/package/xtest-data-0.0.2.crate
-    let crate_tar = Command::new("gunzip")
-        .arg("-c")
-        .arg(&crate_.path)
-        .output()
-        .map_err(anchor_error())?
-        .stdout;
+    let extracted = extract(crate_, tmp)?;
+    let commit = vcs_info.commit(&extracted)?;
+    super::artifacts::verify_commit(pack, &commit, tmp)?;
 
-    // tar -C /tmp --extract --file -
-    Command::new("tar")
-        .arg("-C")
-        .arg(&tmp)
-        .args(["--extract", "--file", "-"])
-        .input_output(&crate_tar)
-        .map_err(anchor_error())?;
+    run(&mut command(&extracted, pack, vcs_info, tmp, jobs))
+}
 
+/// The `cargo test` of the crate unpacked to `extracted`, with its data from `pack`.
+fn command(
+    extracted: &Path,
+    pack: &UnpackedArchive,
+    vcs_info: &VcsInfo,
+    tmp: &Path,
+    jobs: Option<u32>,
+) -> Command {
     // TMPDIR=/tmp CARGO_XTEST_DATA_FETCH=1 cargo test  -- --nocapture
-    Command::new(CARGO)
-        .current_dir(&extracted)
-        .args(["test", "--no-fail-fast", "--release", "--", "--nocapture"])
+    let mut cmd = cargo();
+    cmd.current_dir(extracted)
+        .arg("test")
+        .args(jobs_arg(jobs))
+        .args(["--no-fail-fast", "--release", "--", "--nocapture"])
         // FIXME! Woah, we may actually have found a caching bug here! When compiling via this
         // source we got outdated binaries that did not reflect the *dirty* changes introduced in
         // the source archive?
@@ -56,7 +62,7 @@ pub fn test(
         //
         // Anyways we'd like to share the compilation cache.
         // .env("CARGO_TARGET_DIR", repo.join("target"))
-        .env("CARGO_XTEST_DATA_TMPDIR", &tmp)
+        .env("CARGO_XTEST_DATA_TMPDIR", tmp)
         .env("CARGO_XTEST_DATA_PACK_OBJECTS", &pack.path)
         .envs({
             if let VcsInfo::Overwrite { path } = vcs_info {
@@ -64,9 +70,185 @@ pub fn test(
             } else {
                 None
             }
-        })
-        .success()
+        });
+
+    cmd
+}
+
+/// Run `cargo test`, passing its output on to `stderr` while counting the tests.
+///
+/// A failed run is an error, which names the failed tests.
+fn run(cmd: &mut Command) -> Result<TestResult, LocatedError> {
+    let mut child = cmd.stdout(Stdio::piped()).spawn().map_err(anchor_error())?;
+
+    let mut result = TestResult::default();
+    let stdout = io::BufReader::new(child.stdout.take().unwrap());
+    for line in stdout.lines() {
+        let line = line.map_err(anchor_error())?;
+        // Same as the muted `stdout` of other commands.
+        let _ = writeln!(io::stderr(), "{}", line);
+        result.parse_line(&line);
+    }
+
+    let status = child.wait().map_err(anchor_error())?;
+    if !status.success() {
+        let err = match result.failures.is_empty() {
+            true => format!("Testing the crate failed with {}", status),
+            false => format!("Tests of the crate failed: {}", result.failures.join(", ")),
+        };
+        return Err(anchor_error()(as_io_error(err)));
+    }
+
+    Ok(result)
+}
+
+impl TestResult {
+    /// Add a line of the human readable output of libtest.
+    fn parse_line(&mut self, line: &str) {
+        if let Some(summary) = line.strip_prefix("test result: ") {
+            // Such as `ok. 3 passed; 0 failed; 1 ignored; 0 measured; 0 filtered out; ...`
+            let counts = summary.split_once(". ").map_or("", |(_, counts)| counts);
+            for count in counts.split("; ") {
+                let mut words = count.split(' ');
+                let number = words.next().and_then(|number| number.parse::<usize>().ok());
+                match (number, words.next()) {
+                    (Some(n), Some("passed")) => self.passed += n,
+                    (Some(n), Some("failed")) => self.failed += n,
+                    (Some(n), Some("ignored")) => self.ignored += n,
+                    _ => {}
+                }
+            }
+        } else if let Some(name) = line
+            .strip_prefix("test ")
+            .and_then(|test| test.strip_suffix(" ... FAILED"))
+        {
+            self.failures.push(name.to_string());
+        }
+    }
+}
+
+/// Unpack the crate archive into a fresh directory within `tmp`.
+pub fn extract(crate_: &CrateSource, tmp: &Path) -> Result<PathBuf, LocatedError> {
+    // Not necessarily `target.expected_dir_name()`, the archive may have been renamed.
+    let extracted = tmp.join(crate_.top_level_dir()?);
+    // Try to remove it but ignore failure.
+    let _ = std::fs::remove_dir_all(&extracted).map_err(anchor_error());
+
+    // gunzip -c target/package/xtest-data-0.0.2.crate
+    let crate_tar = Command::new("gunzip")
+        .arg("-c")
+        .arg(&crate_.path)
+        .output()
+        .map_err(anchor_error())?
+        .stdout;
+
+    // tar -C /tmp --extract --file -
+    Command::new("tar")
+        .arg("-C")
+        .arg(tmp)
+        .args(["--extract", "--file", "-"])
+        .input_output(&crate_tar)
         .map_err(anchor_error())?;
 
-    Ok(TestResult {})
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command, run, TestResult};
+    use crate::cargo;
+    use crate::target::VcsInfo;
+    use crate::task::artifacts::UnpackedArchive;
+    use std::path::Path;
+
+    #[test]
+    fn parse_libtest_output() {
+        let mut result = TestResult::default();
+        let output = "\
+running 3 tests
+test passes ... ok
+test breaks ... FAILED
+test later ... ignored
+
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.01s
+
+running 1 test
+test tests::data ... ok
+
+test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.00s
+";
+        output.lines().for_each(|line| result.parse_line(line));
+
+        assert_eq!(
+            result,
+            TestResult {
+                passed: 2,
+                failed: 1,
+                ignored: 1,
+                failures: vec!["breaks".to_string()],
+            }
+        );
+    }
+
+    /// Test a crate with the given library source.
+    fn test_crate(lib: &str) -> Result<TestResult, String> {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            // Its own workspace, wherever the temporary directory is.
+            "[package]\nname = \"mixed\"\nversion = \"0.0.0\"\nedition = \"2018\"\n[workspace]\n",
+        )
+        .unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), lib).unwrap();
+
+        let mut cmd = cargo();
+        cmd.current_dir(dir.path())
+            .args(["test", "--offline", "--lib"])
+            .env("CARGO_TARGET_DIR", dir.path().join("target"));
+        run(&mut cmd).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn counts_of_a_crate() {
+        let result = test_crate(
+            "#[test] fn one() {}\n#[test] fn two() {}\n#[test] #[ignore] fn three() {}\n",
+        );
+        assert_eq!(
+            result.unwrap(),
+            TestResult {
+                passed: 2,
+                failed: 0,
+                ignored: 1,
+                failures: vec![],
+            }
+        );
+
+        let err = test_crate("#[test] fn one() {}\n#[test] fn broken() { panic!() }\n");
+        let err = err.unwrap_err();
+        assert!(err.contains("Tests of the crate failed: broken"), "{}", err);
+    }
+
+    #[test]
+    fn jobs_are_forwarded() {
+        let pack = UnpackedArchive {
+            path: "pack".into(),
+        };
+        let tmp = Path::new("tmp");
+        let args = |jobs| {
+            let cmd = command(
+                Path::new("foo-0.1.0"),
+                &pack,
+                &VcsInfo::FromCrate,
+                tmp,
+                jobs,
+            );
+            cmd.get_args().map(|arg| arg.to_owned()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(args(Some(4))[..2], ["test", "--jobs=4"]);
+        assert!(!args(None)
+            .iter()
+            .any(|arg| arg.to_string_lossy().starts_with("--jobs")));
+    }
 }
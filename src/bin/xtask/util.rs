@@ -1,6 +1,6 @@
 use std::error::Error;
-use std::io;
 use std::process::{Command, Output, Stdio};
+use std::{fmt, io};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -9,6 +9,12 @@ pub struct LocatedError {
     inner: io::Error,
 }
 
+impl fmt::Display for LocatedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at {})", self.inner, self.location)
+    }
+}
+
 pub trait GoodOutput {
     /// Ensure that no data is accidentally printed to (inherited) `stdout`.
     /// Where available this may be achieved by redirecting to `stderr` instead.
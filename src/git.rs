@@ -1,15 +1,51 @@
 use std::ffi::{OsStr, OsString};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use crate::inconclusive;
+use crate::{inconclusive, GitVersion};
 
 /// How we access `git` repositories.
 #[derive(Debug)]
-pub(crate) struct Git {
+pub(crate) struct Git<'hook> {
     bin: PathBuf,
+    /// The time after which a single invocation of `git` is killed.
+    pub timeout: Duration,
+    /// Show the output of `git` instead of discarding it.
+    pub verbose: bool,
+    /// Configuration passed as `-c key=value` to every invocation.
+    pub config: Vec<(String, String)>,
+    /// Environment variables set for every invocation, in addition to our own.
+    pub env: Vec<(OsString, OsString)>,
+    /// How often a fetch that failed for a likely transient reason is retried.
+    pub fetch_retries: u32,
+    /// The version reported by `git --version`, if it could be parsed.
+    pub version: Option<GitVersion>,
+    /// Called with the redacted command line of each invocation before it is spawned.
+    pub on_command: Option<CommandHook<'hook>>,
 }
 
+/// A caller provided callback, which is opaque to `Debug`.
+pub(crate) struct CommandHook<'hook>(pub Box<dyn Fn(&str) + 'hook>);
+
+/// The default for [`Git::fetch_retries`].
+pub(crate) const DEFAULT_FETCH_RETRIES: u32 = 3;
+
+/// The first version whose `sparse-checkout set` reads patterns from `--stdin`.
+pub(crate) const SPARSE_STDIN: GitVersion = GitVersion {
+    major: 2,
+    minor: 25,
+    patch: 0,
+};
+
+/// The delay before the first retry of a fetch, doubled for each further one.
+const FETCH_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The default for [`Git::timeout`], generous enough for a fetch of large data.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
 /// A bare repository created by us.
 pub(crate) struct ShallowBareRepository {
     path: PathBuf,
@@ -18,8 +54,27 @@ pub(crate) struct ShallowBareRepository {
 /// The repository containing the manifest of the crate to integration test.
 pub(crate) struct CrateDir {
     path: PathBuf,
+    /// The path of the manifest directory relative to the root of the working tree.
+    prefix: PathBuf,
+}
+
+/// A worktree that we checked out from a shared bare repository.
+///
+/// When this is dropped while panicking, such as when a test fails, the worktree is removed
+/// together with its administrative entry in the bare repository. Otherwise the files remain in
+/// place as the caller may continue to use paths into the worktree.
+#[derive(Debug)]
+pub(crate) struct Worktree {
+    git: PathBuf,
+    repo: PathBuf,
+    path: PathBuf,
+    /// A worktree at a location chosen by the caller is kept even when panicking.
+    persistent: bool,
+    /// The time of the slow checkout, if `sparse-checkout` could not select all paths.
+    slow_checkout: Option<Duration>,
 }
 
+#[derive(Debug)]
 pub(crate) struct FileWaitLock {
     lock: std::fs::File,
 }
@@ -30,16 +85,265 @@ pub(crate) struct Origin {
 
 /// A git commit ID.
 /// This is treated as opaque string data. Usually it's a Sha1 hash (20 byte, hex-encoded).
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct CommitId(String);
 
 pub(crate) enum PathSpec<'lt> {
     Path(&'lt Path),
+    /// A pattern with the wildcards of a `:(glob)` pathspec.
+    Glob(&'lt Path),
 }
 
-impl Git {
+impl Git<'_> {
     pub fn new() -> Result<Self, impl std::fmt::Display> {
-        which::which("git").map(|bin| Git { bin })
+        which::which("git").map(|bin| {
+            let mut git = Git {
+                bin,
+                timeout: DEFAULT_TIMEOUT,
+                verbose: false,
+                config: vec![],
+                env: vec![],
+                fetch_retries: DEFAULT_FETCH_RETRIES,
+                version: None,
+                on_command: None,
+            };
+            git.version = git.query_version();
+            git
+        })
+    }
+
+    fn query_version(&self) -> Option<GitVersion> {
+        let mut cmd = self.command();
+        cmd.arg("--version");
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(self.quiet());
+
+        let exit = self.output(&mut cmd).ok()?;
+        if !exit.status.success() {
+            return None;
+        }
+
+        parse_version(&String::from_utf8_lossy(&exit.stdout))
+    }
+
+    /// Expand an abbreviated commit ID with the objects of the repository at `reference`.
+    pub fn resolve_in(&self, reference: &Path, head: &CommitId) -> CommitId {
+        let mut cmd = self.command();
+        cmd.arg("-C");
+        cmd.arg(reference);
+        cmd.stdout(self.quiet());
+        cmd.stderr(Stdio::piped());
+        resolve_commit(self, cmd, head)
+    }
+
+    /// Explain a failure of `feature` by the version of git, if it is older than `required`.
+    pub fn explain_version(&self, feature: &str, required: GitVersion) -> Option<String> {
+        self.version
+            .filter(|version| *version < required)
+            .map(|version| {
+                format!(
+                    "your git is {}, {} needs {}.{}+",
+                    version, feature, required.major, required.minor
+                )
+            })
+    }
+
+    /// Extract the paths of `treeish` from the archive of the remote `url` into `dest`.
+    ///
+    /// This needs no repository, but the server must support `git archive --remote`. To archive
+    /// a commit that is not named by a ref it must also set `uploadArchive.allowUnreachable`.
+    pub fn remote_archive(
+        &self,
+        url: &OsStr,
+        treeish: &str,
+        paths: &[PathSpec<'_>],
+        dest: &Path,
+    ) -> io::Result<()> {
+        let mut remote = OsString::from("--remote=");
+        remote.push(url);
+
+        let mut archive = self.command();
+        archive.args(["archive", "--format=tar"]);
+        archive.arg(remote);
+        archive.arg(treeish);
+        archive.arg("--");
+        archive.args(paths.iter().map(PathSpec::to_string));
+        archive.stdin(Stdio::null());
+        archive.stdout(Stdio::piped());
+        archive.stderr(Stdio::piped());
+
+        let mut archiving = self.spawn(&mut archive)?;
+        let stream = archiving.stdout.take().expect("Spawned with stdio-piped");
+        let extracting = Command::new("tar")
+            .arg("-x")
+            .arg("-C")
+            .arg(dest)
+            .stdin(Stdio::from(stream))
+            .stdout(self.quiet())
+            .stderr(self.quiet())
+            .spawn();
+        let extracted = match extracting {
+            Ok(extracting) => self.wait(extracting),
+            Err(err) => {
+                let _ = archiving.kill();
+                Err(err)
+            }
+        };
+        let archived = self.wait(archiving)?;
+
+        if !archived.status.success() {
+            let stderr = String::from_utf8_lossy(&archived.stderr);
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                stderr.trim().to_string(),
+            ));
+        }
+
+        match extracted?.status.success() {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "tar failed to extract the archive",
+            )),
+        }
+    }
+
+    /// The stream for output that we do not inspect, shown only when verbose.
+    pub fn quiet(&self) -> Stdio {
+        if self.verbose {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        }
+    }
+
+    /// A command that never blocks on an interactive prompt for credentials.
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.bin);
+        cmd.env("GIT_TERMINAL_PROMPT", "0");
+        // The credential manager of Git for Windows opens a dialog of its own otherwise.
+        cmd.env("GCM_INTERACTIVE", "never");
+        // Empty, not unset, so that git and ssh skip any configured program and fail instead.
+        cmd.env("GIT_ASKPASS", "");
+        cmd.env("SSH_ASKPASS", "");
+        // May replace the askpass programs, but a prompt would block until the timeout.
+        for (key, value) in &self.env {
+            if key != "GIT_TERMINAL_PROMPT" && key != "GCM_INTERACTIVE" {
+                cmd.env(key, value);
+            }
+        }
+        for (key, value) in &self.config {
+            cmd.arg("-c");
+            cmd.arg(format!("{}={}", key, value));
+        }
+        cmd
+    }
+
+    /// Describe a command for the log, without configuration values that may be credentials.
+    fn redacted(&self, cmd: &Command) -> String {
+        let mut line = format!("{:?}", cmd);
+        for (key, value) in &self.config {
+            if is_secret_key(key) {
+                let arg = format!("{:?}", format!("{}={}", key, value));
+                let hidden = format!("{:?}", format!("{}=<redacted>", key));
+                line = line.replace(&arg, &hidden);
+            }
+        }
+        for (key, value) in &self.env {
+            if is_secret_key(&key.to_string_lossy()) {
+                let var = format!("{}={:?}", key.to_string_lossy(), value);
+                let hidden = format!("{}={:?}", key.to_string_lossy(), "<redacted>");
+                line = line.replace(&var, &hidden);
+            }
+        }
+        line
+    }
+
+    /// Start a command created by [`Git::command()`].
+    ///
+    /// Every invocation of `git` goes through here, so it is shown when verbose and reported to
+    /// the hook of the caller.
+    pub fn spawn(&self, cmd: &mut Command) -> io::Result<Child> {
+        if self.verbose || self.on_command.is_some() {
+            let line = self.redacted(cmd);
+            if self.verbose {
+                eprintln!("xtest-data: {}", line);
+            }
+            if let Some(CommandHook(hook)) = &self.on_command {
+                hook(&line);
+            }
+        }
+
+        cmd.spawn()
+    }
+
+    /// Like `Command::output` but kills the process when it exceeds the timeout.
+    pub fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        cmd.stdin(Stdio::null());
+        let child = self.spawn(cmd)?;
+        self.wait(child)
+    }
+
+    /// Like `Command::status` but kills the process when it exceeds the timeout.
+    pub fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        self.output(cmd).map(|output| output.status)
+    }
+
+    /// Like `Child::wait_with_output` but kills the process when it exceeds the timeout.
+    pub fn wait(&self, mut child: Child) -> io::Result<Output> {
+        fn read_in_background(
+            mut pipe: impl Read + Send + 'static,
+        ) -> JoinHandle<io::Result<Vec<u8>>> {
+            std::thread::spawn(move || {
+                let mut buffer = vec![];
+                pipe.read_to_end(&mut buffer)?;
+                Ok(buffer)
+            })
+        }
+
+        fn collect(reader: Option<JoinHandle<io::Result<Vec<u8>>>>) -> io::Result<Vec<u8>> {
+            reader.map_or(Ok(vec![]), |reader| {
+                reader.join().expect("Reading a pipe does not panic")
+            })
+        }
+
+        // Read concurrently, a full pipe would otherwise block the process.
+        let stdout = child.stdout.take().map(read_in_background);
+        let stderr = child.stderr.take().map(read_in_background);
+
+        let deadline = Instant::now() + self.timeout;
+        let mut backoff = Duration::from_millis(1);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                // The readers are detached, the pipes may be held open by children of git.
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("git did not finish within {:?}", self.timeout),
+                ));
+            }
+
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(Duration::from_millis(50));
+        };
+
+        let output = Output {
+            status,
+            stdout: collect(stdout)?,
+            stderr: collect(stderr)?,
+        };
+
+        // Diagnostics are otherwise only shown on failure.
+        if self.verbose {
+            eprint!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+
+        Ok(output)
     }
 
     /// Prepare `path` as a shallow clone of `origin`.
@@ -58,15 +362,54 @@ impl Git {
             cmd.args(["symbolic-ref", "HEAD"]);
         }
 
-        cmd.status()
+        self.status(&mut cmd)
             .unwrap_or_else(|mut err| inconclusive(&mut err));
 
-        let content = format!("{}\n", head.0);
-        std::fs::write(repo.path.join("shallow"), content)
-            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        // An abbreviated commit is marked once it was resolved.
+        if !head.is_abbreviated() {
+            repo.add_shallow(head);
+        }
 
         repo
     }
+
+    /// The object IDs that files under `root` would have as blobs, exactly as in the commit.
+    pub fn hash_objects(&self, root: &Path, files: &[PathBuf]) -> Vec<String> {
+        let mut cmd = self.command();
+        cmd.current_dir(root);
+        cmd.args(["hash-object", "--no-filters", "--stdin-paths"]);
+        cmd.stdin(Stdio::piped());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+
+        let mut input = String::new();
+        for file in files {
+            input.push_str(&format!("{}\n", file.display()));
+        }
+
+        let mut running = self
+            .spawn(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        let mut stdin = running.stdin.take().expect("Spawned with stdio-piped");
+        // Written concurrently, git answers each path before reading all of them.
+        std::thread::spawn(move || {
+            use std::io::Write;
+            let _ = stdin.write_all(input.as_bytes());
+        });
+
+        let exit = self
+            .wait(running)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        if !exit.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+            inconclusive(&mut "Git operation was not successful");
+        }
+
+        String::from_utf8_lossy(&exit.stdout)
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
 }
 
 impl From<&'_ str> for CommitId {
@@ -81,30 +424,138 @@ impl From<&'_ str> for CommitId {
     }
 }
 
+impl CommitId {
+    /// A commit ID that may be abbreviated, as a human would write it.
+    ///
+    /// It must be resolved with [`ShallowBareRepository::resolve_commit()`] before use.
+    pub fn abbreviated(st: &str) -> CommitId {
+        let st = st.trim();
+        assert!(
+            st.len() >= 4 && st.bytes().all(|b| b.is_ascii_hexdigit()),
+            "Not a hexadecimal commit ID of at least 4 digits: {}",
+            st
+        );
+        CommitId(st.to_ascii_lowercase())
+    }
+
+    pub fn is_abbreviated(&self) -> bool {
+        self.0.len() < 40
+    }
+}
+
 impl CrateDir {
-    pub fn new(path: &str, git: &Git) -> Self {
-        let dir = CrateDir {
-            path: Path::new(path).to_owned(),
+    pub fn new(path: &Path, git: &Git) -> Self {
+        Self::open(path, git).unwrap_or_else(|mut err| inconclusive(&mut err))
+    }
+
+    /// Like [`CrateDir::new()`], but describe why the directory can not be used.
+    pub fn open(path: &Path, git: &Git) -> Result<Self, String> {
+        let mut dir = CrateDir {
+            path: path.to_owned(),
+            prefix: PathBuf::new(),
         };
 
+        // The crate may live in a subdirectory of the repository.
         let mut cmd = dir.exec(git);
-        cmd.args(["status", "--short"]);
-        cmd.status()
-            .unwrap_or_else(|mut err| inconclusive(&mut err));
-        dir
+        cmd.args(["rev-parse", "--show-prefix"]);
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let exit = git.output(&mut cmd).map_err(|err| err.to_string())?;
+
+        if !exit.status.success() {
+            let stderr = String::from_utf8_lossy(&exit.stderr);
+            let descriptor = format!("Git operation was not successful\n{}", stderr.trim_end());
+            return Err(maybe_gitdir(&exit, &descriptor));
+        }
+
+        let prefix = String::from_utf8(exit.stdout).map_err(|err| err.to_string())?;
+        dir.prefix = PathBuf::from(prefix.trim_end_matches('\n'));
+
+        // Files only count as tracked once committed, which every later step relies on. In a
+        // fresh repository those steps would fail with obscure messages about `HEAD`.
+        let mut cmd = dir.exec(git);
+        cmd.args(["rev-parse", "--verify", "--quiet", "HEAD^{commit}"]);
+        cmd.stdout(Stdio::null());
+        cmd.stderr(git.quiet());
+        let unborn = !git
+            .status(&mut cmd)
+            .map_err(|err| err.to_string())?
+            .success();
+        if unborn {
+            return Err(format!(
+                "The repository at {} has no commits yet; commit your fixtures first",
+                path.display()
+            ));
+        }
+
+        Ok(dir)
     }
 
     pub fn exec(&self, git: &Git) -> Command {
-        let mut cmd = Command::new(&git.bin);
+        let mut cmd = git.command();
         cmd.current_dir(&self.path);
         // Ensure we open _no_ handles.
         // Override this later if necessary.
-        cmd.stdout(Stdio::null());
-        cmd.stderr(Stdio::null());
+        cmd.stdout(git.quiet());
+        cmd.stderr(git.quiet());
         cmd
     }
 
-    pub fn tracked(&self, git: &Git, paths: &mut dyn Iterator<Item = PathSpec<'_>>) {
+    /// Write the blob with the object ID `oid` to the file `dest`.
+    pub fn write_blob(&self, git: &Git, oid: &str, dest: &Path) {
+        write_blob(git, self.exec(git), oid, dest)
+    }
+
+    /// Whether the commit at `HEAD` contains `path`, relative to the manifest.
+    pub fn contains(&self, git: &Git, path: &Path) -> bool {
+        tree_contains(git, self.exec(git), "HEAD", &self.prefix.join(path))
+    }
+
+    /// The tracked files matching a glob `pattern`, relative to the crate directory.
+    pub fn glob(&self, git: &Git, pattern: &Path) -> Vec<PathBuf> {
+        list_glob(git, self.exec(git), pattern, None)
+    }
+
+    /// Fail if a path is untracked or ignored, or with `require_clean` has uncommitted changes.
+    pub fn tracked(
+        &self,
+        git: &Git,
+        paths: &mut dyn Iterator<Item = PathSpec<'_>>,
+        require_clean: bool,
+    ) {
+        if let Some((path, mut reason)) = self.first_unclean(git, paths, require_clean) {
+            eprintln!("{}", path);
+            inconclusive(&mut reason);
+        }
+    }
+
+    /// Find a path that is untracked or ignored, with a description of the problem.
+    pub fn first_untracked(
+        &self,
+        git: &Git,
+        paths: &mut dyn Iterator<Item = PathSpec<'_>>,
+    ) -> Option<(String, &'static str)> {
+        self.first_unclean(git, paths, false)
+    }
+
+    /// Find a path that is untracked, ignored, or optionally modified.
+    pub fn first_unclean(
+        &self,
+        git: &Git,
+        paths: &mut dyn Iterator<Item = PathSpec<'_>>,
+        reject_modified: bool,
+    ) -> Option<(String, &'static str)> {
+        self.try_first_unclean(git, paths, reject_modified)
+            .unwrap_or_else(|mut err| inconclusive(&mut err))
+    }
+
+    /// Like [`CrateDir::first_unclean()`], but describe why git could not tell.
+    pub fn try_first_unclean(
+        &self,
+        git: &Git,
+        paths: &mut dyn Iterator<Item = PathSpec<'_>>,
+        reject_modified: bool,
+    ) -> Result<Option<(String, &'static str)>, String> {
         let mut cmd = self.exec(git);
         cmd.stdout(Stdio::piped());
         cmd.args([
@@ -112,76 +563,216 @@ impl CrateDir {
             "--no-renames",
             "--ignored=matching",
             "--porcelain=v2",
-            "--short",
             "-z",
         ]);
         cmd.arg("--");
         let mut any = false;
+        // Registered paths are relative to the manifest, not to the root of the repository.
         cmd.args(paths.map(|st| {
             any = true;
-            st.to_string()
+            st.in_dir(&self.prefix)
         }));
 
         if !any {
-            return;
+            return Ok(None);
         }
 
-        let output = cmd
-            .output()
-            .unwrap_or_else(|mut err| inconclusive(&mut err));
-        let items =
-            String::from_utf8(output.stdout).unwrap_or_else(|mut err| inconclusive(&mut err));
-        for item in items.split('\0') {
-            if item.starts_with('!') {
-                eprintln!("{}", item);
-                inconclusive(&mut "Your test depends on ignored file(s)");
-            } else if item.starts_with('?') {
-                eprintln!("{}", item);
-                inconclusive(&mut "Your test depends on untracked file(s)");
-            }
+        cmd.stderr(Stdio::piped());
+        let output = git.output(&mut cmd).map_err(|err| err.to_string())?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("`git status` failed\n{}", stderr.trim_end()));
         }
+
+        let items = String::from_utf8(output.stdout).map_err(|err| err.to_string())?;
+        Ok(parse_status_v2(&items)
+            .into_iter()
+            .find_map(|entry| match entry {
+                StatusEntry::Tracked(path) if reject_modified => Some((
+                    path.to_owned(),
+                    "Your test depends on file(s) with uncommitted changes",
+                )),
+                StatusEntry::Tracked(_) => None,
+                StatusEntry::Ignored(path) => {
+                    Some((path.to_owned(), "Your test depends on ignored file(s)"))
+                }
+                StatusEntry::Untracked(path) => {
+                    Some((path.to_owned(), "Your test depends on untracked file(s)"))
+                }
+            }))
     }
 
     pub fn pack_objects(
         &self,
         git: &Git,
         paths: &mut dyn Iterator<Item = PathSpec<'_>>,
+        objects: &[&str],
         pack_name: OsString,
+        depth: usize,
+        max_blob: Option<u64>,
     ) {
         let _lock = FileWaitLock::for_git_dir(&self.path);
 
+        // Registered paths are relative to the manifest, the tree is that of the repository.
+        let prefixed: Vec<_> = paths
+            .map(|spec| match spec {
+                PathSpec::Path(path) => (self.prefix.join(path), false),
+                PathSpec::Glob(pattern) => (self.prefix.join(pattern), true),
+            })
+            .collect();
+        // A glob must be expanded to the files it matches before, see `CrateDir::glob`.
         let PathSpecFilter {
             simple_filter,
             complex_paths,
-        } = paths.collect();
-        let sparse = self.sparse_rev_list(git, &simple_filter);
+        } = prefixed
+            .iter()
+            .map(|(path, glob)| match glob {
+                false => PathSpec::Path(path),
+                true => PathSpec::Glob(path),
+            })
+            .collect();
+        let mut sparse = self.sparse_rev_list(git, &simple_filter, depth, max_blob);
+        // Objects registered by their ID are packed regardless of the tree they appear in.
+        for object in objects {
+            sparse.extend_from_slice(object.as_bytes());
+            sparse.push(b'\n');
+        }
 
         if !complex_paths.is_empty() {
             inconclusive(&mut "Sorry, paths too complex to pack reliably");
         }
 
+        let pack_name = Path::new(&pack_name);
+        let single = is_pack_file(pack_name);
+        // Only pack what earlier runs did not. Comparing object IDs, instead of the paths or the
+        // commit, stays correct when fixtures are added or removed in between.
+        let existing = if !single {
+            self.dir_contents(git, pack_name)
+        } else if pack_name.exists() {
+            self.pack_contents(git, pack_name)
+        } else {
+            vec![]
+        };
+        let mut known: std::collections::HashSet<_> = existing.iter().map(String::as_str).collect();
+        let wanted = String::from_utf8_lossy(&sparse).into_owned();
+        let missing: Vec<_> = wanted.lines().filter(|id| known.insert(id)).collect();
+        if missing.is_empty() {
+            return;
+        }
+
+        let mut input = vec![];
+        // A single file is shared by all builds, it must keep the objects of the previous ones.
+        let previous = if single { &existing[..] } else { &[] };
+        for id in previous.iter().map(String::as_str).chain(missing) {
+            input.extend_from_slice(id.as_bytes());
+            input.push(b'\n');
+        }
+        let partial = pack_name.with_extension("pack.xtest-data-partial");
+
         let mut cmd = self.exec(git);
         cmd.args(["pack-objects"]);
-        cmd.arg(Path::new(&pack_name).join("xtest-data"));
+        if single {
+            cmd.arg("--stdout");
+            let file =
+                std::fs::File::create(&partial).unwrap_or_else(|mut err| inconclusive(&mut err));
+            cmd.stdout(file);
+        } else {
+            cmd.arg(pack_name.join("xtest-data"));
+        }
         cmd.stdin(Stdio::piped());
         cmd.stderr(Stdio::piped());
 
-        let mut running = cmd.spawn().unwrap_or_else(|mut err| inconclusive(&mut err));
+        let mut running = git
+            .spawn(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
         let stdin = running.stdin.as_mut().expect("Spawned with stdio-piped");
-        std::io::Write::write_all(stdin, &sparse).unwrap_or_else(|mut err| inconclusive(&mut err));
+        std::io::Write::write_all(stdin, &input).unwrap_or_else(|mut err| inconclusive(&mut err));
         running.stdin = None;
 
-        let exit = running
-            .wait_with_output()
+        let exit = git
+            .wait(running)
             .unwrap_or_else(|mut err| inconclusive(&mut err));
 
         if !exit.status.success() {
             eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
             inconclusive(&mut "Git operation was not successful");
         }
+
+        if single {
+            std::fs::rename(&partial, pack_name).unwrap_or_else(|mut err| inconclusive(&mut err));
+        }
+    }
+
+    /// The IDs of the objects in the indexed pack files of a directory.
+    fn dir_contents(&self, git: &Git, dir: &Path) -> Vec<String> {
+        let entries = std::fs::read_dir(dir).unwrap_or_else(|mut err| inconclusive(&mut err));
+        entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "idx"))
+            .flat_map(|index| self.index_contents(git, &index))
+            .collect()
+    }
+
+    /// The IDs of the objects in a pack file.
+    fn pack_contents(&self, git: &Git, pack: &Path) -> Vec<String> {
+        let index = pack.with_extension("pack.xtest-data-idx");
+        let mut cmd = self.exec(git);
+        cmd.args(["index-pack", "-o"]);
+        cmd.arg(&index);
+        cmd.arg(pack);
+        cmd.stderr(Stdio::piped());
+        let exit = git
+            .output(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        if !exit.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+            inconclusive(&mut format!(
+                "The pack file {} could not be read",
+                pack.display()
+            ));
+        }
+
+        let ids = self.index_contents(git, &index);
+        let _ = std::fs::remove_file(&index);
+        // Newer versions of git also write a reverse index beside it.
+        let _ = std::fs::remove_file(index.with_extension("rev"));
+        ids
+    }
+
+    /// The IDs of the objects in the pack of an index file.
+    fn index_contents(&self, git: &Git, index: &Path) -> Vec<String> {
+        let mut cmd = self.exec(git);
+        cmd.arg("show-index");
+        cmd.stdin(std::fs::File::open(index).unwrap_or_else(|mut err| inconclusive(&mut err)));
+        cmd.stdout(Stdio::piped());
+        // Not `Git::output`, which closes the standard input.
+        let exit = git
+            .spawn(&mut cmd)
+            .and_then(|child| git.wait(child))
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        if !exit.status.success() {
+            inconclusive(&mut "Git operation was not successful");
+        }
+
+        // Each line is the offset, the object ID, and possibly a checksum.
+        String::from_utf8_lossy(&exit.stdout)
+            .lines()
+            .filter_map(|line| line.split(' ').nth(1))
+            .map(str::to_owned)
+            .collect()
     }
 
-    fn sparse_rev_list(&self, git: &Git, paths: &[PathSpec<'_>]) -> Vec<u8> {
+    /// List the objects of the `depth` most recent commits, with blobs filtered to `paths`.
+    ///
+    /// Blobs larger than `max_blob` bytes are omitted as well.
+    fn sparse_rev_list(
+        &self,
+        git: &Git,
+        paths: &[PathSpec<'_>],
+        depth: usize,
+        max_blob: Option<u64>,
+    ) -> Vec<u8> {
         let CommitId(oid) = self
             .hash_sparse_oid(git, paths)
             .unwrap_or_else(|mut err| inconclusive(&mut err));
@@ -189,14 +780,15 @@ impl CrateDir {
         let list_for = |filterspec| {
             let mut cmd = self.exec(git);
             // Shallow, and sparse filtered, list of objects.
-            cmd.args(["rev-list", "-n", "1", "--objects", "--no-object-names"]);
+            cmd.args(["rev-list", "--objects", "--no-object-names"]);
+            cmd.arg(format!("--max-count={}", depth));
             cmd.arg(filterspec);
             cmd.arg("HEAD");
             cmd.stdout(Stdio::piped());
             cmd.stderr(Stdio::piped());
 
-            let exit = cmd
-                .output()
+            let exit = git
+                .output(&mut cmd)
                 .unwrap_or_else(|mut err| inconclusive(&mut err));
             if !exit.status.success() {
                 eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
@@ -206,7 +798,16 @@ impl CrateDir {
             exit.stdout
         };
 
-        let mut objects = list_for(format!("--filter=sparse:oid={oid}", oid = oid));
+        let sparse = match max_blob {
+            None => format!("--filter=sparse:oid={oid}", oid = oid),
+            // The limit excludes blobs of exactly its size.
+            Some(max) => format!(
+                "--filter=combine:sparse:oid={oid}+blob:limit={limit}",
+                oid = oid,
+                limit = max + 1
+            ),
+        };
+        let mut objects = list_for(sparse);
         let mut treeish = list_for("--filter=blob:none".into());
 
         objects.append(&mut treeish);
@@ -220,17 +821,30 @@ impl CrateDir {
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
 
-        let mut running = cmd.spawn().unwrap_or_else(|mut err| inconclusive(&mut err));
+        // The blob is interpreted as sparse-checkout patterns, one per line. When the checkout
+        // will use cone mode then the pack must contain everything the cone includes.
+        let patterns = if all_trees(git, self.exec(git), "HEAD", paths) {
+            cone_patterns(paths)
+        } else {
+            paths
+                .iter()
+                .map(|path| {
+                    let path = path.as_encompassing_path().expect("Only simple paths");
+                    format!("{}\n", path.display())
+                })
+                .collect()
+        };
+
+        let mut running = git
+            .spawn(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
         let stdin = running.stdin.as_mut().expect("Spawned with stdio-piped");
-        for path in paths {
-            use std::io::Write;
-            let path = path.as_encompassing_path().expect("Only simple paths");
-            write!(stdin, "{}\0", path.display()).unwrap_or_else(|mut err| inconclusive(&mut err));
-        }
+        std::io::Write::write_all(stdin, patterns.as_bytes())
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
 
         running.stdin = None;
-        let exit = running
-            .wait_with_output()
+        let exit = git
+            .wait(running)
             .unwrap_or_else(|mut err| inconclusive(&mut err));
 
         if !exit.status.success() {
@@ -283,103 +897,511 @@ impl<'lt> std::iter::FromIterator<PathSpec<'lt>> for PathSpecFilter<'lt> {
 
 impl ShallowBareRepository {
     pub fn exec(&self, git: &Git) -> Command {
-        let mut cmd = Command::new(&git.bin);
+        let mut cmd = git.command();
         cmd.arg("--git-dir");
         cmd.arg(&self.path);
         // Ensure we open _no_ handles.
         // Override this later if necessary.
-        cmd.stdout(Stdio::null());
+        cmd.stdout(git.quiet());
         cmd.stderr(Stdio::piped());
         cmd
     }
 
-    pub fn unpack(&self, git: &Git, packs: &OsString) {
-        let _lock = FileWaitLock::for_git_dir(&self.path);
+    /// Write the blob with the object ID `oid` to the file `dest`.
+    pub fn write_blob(&self, git: &Git, oid: &str, dest: &Path) {
+        write_blob(git, self.exec(git), oid, dest)
+    }
 
-        let opendir = std::fs::read_dir(packs).unwrap_or_else(|mut err| inconclusive(&mut err));
+    /// Whether the commit `head` contains `path`, relative to the root of the repository.
+    pub fn contains(&self, git: &Git, head: &CommitId, path: &Path) -> bool {
+        tree_contains(git, self.exec(git), &head.0, path)
+    }
 
-        for entry in opendir.filter_map(Result::ok) {
-            if !entry
-                .path()
-                .to_str()
-                .map_or(false, |st| st.ends_with("pack"))
-            {
-                continue;
-            }
+    /// Fetch the objects of a commit from a local repository, such as a warm mirror.
+    pub fn fetch_reference(&self, git: &Git, reference: &Path, head: &CommitId) {
+        let _lock = FileWaitLock::for_git_dir(&self.path);
 
-            let mut file =
-                std::fs::File::open(entry.path()).unwrap_or_else(|mut err| inconclusive(&mut err));
+        let mut cmd = git.command();
+        cmd.arg("-C");
+        cmd.arg(reference);
+        cmd.args(["rev-parse", "--absolute-git-dir"]);
+        cmd.stdout(git.quiet());
+        cmd.stderr(git.quiet());
 
-            let mut git = self.exec(git);
-            git.args(["unpack-objects", "-r"]);
-            git.stdin(Stdio::piped());
+        let status = git
+            .status(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        if !status.success() {
+            inconclusive(&mut format!(
+                "The reference repository {} is not a git directory",
+                reference.display()
+            ));
+        }
 
-            let mut cmd = git.spawn().unwrap_or_else(|mut err| inconclusive(&mut err));
-            let mut stdin = cmd.stdin.as_mut().expect("Supplied with Stdio::piped");
+        let mut backoff = FETCH_BACKOFF;
+        let mut retries = git.fetch_retries;
+        loop {
+            let mut cmd = self.exec(git);
+            cmd.args(["fetch", "--no-tags", "--depth", "1"]);
+            cmd.arg(reference);
+            cmd.arg(head);
+            let exit = git
+                .output(&mut cmd)
+                .unwrap_or_else(|mut err| inconclusive(&mut err));
+            if exit.status.success() {
+                return;
+            }
 
-            std::io::copy(&mut file, &mut stdin).unwrap_or_else(|mut err| inconclusive(&mut err));
-            std::io::Write::flush(stdin).unwrap_or_else(|mut err| inconclusive(&mut err));
-            // Flush and close.
-            cmd.stdin = None;
+            let stderr = String::from_utf8_lossy(&exit.stderr);
+            if is_unadvertised_object_refused(&stderr) {
+                eprintln!("{}", stderr);
+                inconclusive(&mut format!(
+                    "The reference repository {} does not serve the commit {} because it is not the tip of a branch or tag.\n\
+                     Allow this with `git -C {} config uploadpack.allowReachableSHA1InWant true`, or create a tag for the commit.",
+                    reference.display(),
+                    head,
+                    reference.display()
+                ));
+            }
 
-            let exit = cmd
-                .wait_with_output()
-                .unwrap_or_else(|mut err| inconclusive(&mut err));
-            if !exit.status.success() {
-                eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+            if retries == 0 || !is_transient_failure(&stderr) {
+                eprintln!("{}", stderr);
                 inconclusive(&mut "Git operation was not successful");
             }
+
+            retries -= 1;
+            std::thread::sleep(backoff);
+            backoff *= 2;
         }
     }
 
-    // Known false positive in initializatioon of `complex_paths`.
-    // We need to take ownership of `path` in a branch.
-    #[allow(clippy::unnecessary_filter_map)]
-    pub fn checkout(
-        &self,
-        git: &Git,
-        worktree: &Path,
-        head: &CommitId,
-        paths: &mut dyn Iterator<Item = PathSpec<'_>>,
-    ) {
+    /// Create a commit whose tree is the directory `path` within the tree of `head`.
+    ///
+    /// A crate published from a subdirectory of its repository registers paths relative to that
+    /// directory. Checking out this commit presents the files at exactly those paths.
+    pub fn subtree_commit(&self, git: &Git, head: &CommitId, path: &Path) -> CommitId {
         let _lock = FileWaitLock::for_git_dir(&self.path);
 
-        let PathSpecFilter {
-            simple_filter,
-            complex_paths,
-        } = paths.collect();
+        let mut cmd = self.exec(git);
+        cmd.args(["rev-parse", "--verify"]);
+        cmd.arg(format!("{}:{}", head, path.display()));
+        cmd.stdout(Stdio::piped());
+        let exit = git
+            .output(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        if !exit.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+            inconclusive(&mut format!(
+                "The crate directory `{}` is not a tree in the commit {}",
+                path.display(),
+                head
+            ));
+        }
+        let tree = String::from_utf8_lossy(&exit.stdout).trim().to_owned();
 
+        // A fixed identity and date, so that the same subtree always yields the same commit.
         let mut cmd = self.exec(git);
-        cmd.args(["worktree", "add", "--no-checkout"]);
-        cmd.arg(worktree);
-        cmd.arg(head);
-        let exit = cmd
-            .output()
+        for who in ["AUTHOR", "COMMITTER"] {
+            cmd.env(format!("GIT_{}_NAME", who), "xtest-data");
+            cmd.env(format!("GIT_{}_EMAIL", who), "xtest-data@localhost");
+            cmd.env(format!("GIT_{}_DATE", who), "@0 +0000");
+        }
+        cmd.args(["commit-tree", "-m"]);
+        cmd.arg(format!("{} of {}", path.display(), head));
+        cmd.arg(tree);
+        cmd.stdout(Stdio::piped());
+        let exit = git
+            .output(&mut cmd)
             .unwrap_or_else(|mut err| inconclusive(&mut err));
         if !exit.status.success() {
             eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
             inconclusive(&mut "Git operation was not successful");
         }
 
-        // First setup sparse-checkout
-        // Note that this is in beta and not supported, so let's fallback if necessary.
-        let try_sparse_checkout = || -> std::io::Result<()> {
-            let mut cmd = self.exec(git);
-            cmd.arg("--work-tree");
-            cmd.arg(worktree);
-            cmd.args(["sparse-checkout", "--no-cone", "set", "--stdin"]);
-            cmd.stdin(Stdio::piped());
-            let mut running = cmd.spawn()?;
-            let stdin = running.stdin.as_mut().expect("Spawned with stdio-piped");
-            for path in &simple_filter {
-                let simple = path.as_encompassing_path().unwrap().display().to_string();
-                use std::io::Write;
+        CommitId::from(&*String::from_utf8_lossy(&exit.stdout))
+    }
+
+    /// Expand an abbreviated commit ID with the objects of this repository.
+    pub fn resolve_commit(&self, git: &Git, head: &CommitId) -> CommitId {
+        resolve_commit(git, self.exec(git), head)
+    }
+
+    /// Describe how `git replace` or grafts alter the commit `head`, if they are in play.
+    ///
+    /// Git substitutes replaced objects transparently, so a checkout of a replaced commit succeeds
+    /// with a tree that is not the one its ID pins. We compare the tree recorded in the commit
+    /// object itself with the tree git resolves for it.
+    pub fn replaced_commit(&self, git: &Git, head: &CommitId) -> Option<String> {
+        let mut cmd = self.exec(git);
+        cmd.args(["for-each-ref", "--format=%(refname)", "refs/replace/"]);
+        cmd.stdout(Stdio::piped());
+        let exit = git
+            .output(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        let replaced = exit.status.success() && !exit.stdout.is_empty();
+        let grafted = self.path.join("info").join("grafts").exists();
+        if !replaced && !grafted {
+            return None;
+        }
+
+        let pinned = self.commit_tree(git, head, false);
+        let resolved = self.commit_tree(git, head, true);
+        if pinned != resolved {
+            return Some(format!(
+                "the commit {} is replaced in {}, its tree {} is checked out instead of the pinned tree {}",
+                head,
+                self.path.display(),
+                resolved.as_deref().unwrap_or("(none)"),
+                pinned.as_deref().unwrap_or("(none)"),
+            ));
+        }
+
+        Some(format!(
+            "the repository {} has replace refs or grafts, which may alter the history of the commit {}",
+            self.path.display(),
+            head,
+        ))
+    }
+
+    /// The tree named in the commit object `head`, optionally as altered by replace refs.
+    fn commit_tree(&self, git: &Git, head: &CommitId, replace: bool) -> Option<String> {
+        let mut cmd = self.exec(git);
+        if !replace {
+            cmd.arg("--no-replace-objects");
+        }
+        cmd.args(["cat-file", "commit"]);
+        cmd.arg(head);
+        cmd.stdout(Stdio::piped());
+        let exit = git.output(&mut cmd).ok()?;
+        if !exit.status.success() {
+            return None;
+        }
+
+        let commit = String::from_utf8_lossy(&exit.stdout);
+        let tree = commit.lines().next()?.strip_prefix("tree ")?;
+        Some(tree.to_owned())
+    }
+
+    /// Record `head` as a boundary of the history that we have.
+    pub fn mark_shallow(&self, head: &CommitId) {
+        let _lock = FileWaitLock::for_git_dir(&self.path);
+        self.add_shallow(head);
+    }
+
+    /// Like [`Self::mark_shallow()`], with the lock already held.
+    ///
+    /// The repository is shared by all builds, which may be of different commits. So the commit
+    /// is added to those already recorded, and the file is replaced as a whole such that git
+    /// running concurrently never reads it partially written.
+    fn add_shallow(&self, head: &CommitId) {
+        let shallow = self.path.join("shallow");
+        let mut content = std::fs::read_to_string(&shallow).unwrap_or_default();
+        if content.lines().any(|line| line == head.0) {
+            return;
+        }
+
+        content.push_str(&head.0);
+        content.push('\n');
+        let partial = self.path.join("xtest-data-shallow.tmp");
+        std::fs::write(&partial, content).unwrap_or_else(|mut err| inconclusive(&mut err));
+        std::fs::rename(&partial, &shallow).unwrap_or_else(|mut err| inconclusive(&mut err));
+    }
+
+    /// Import all pack files in a directory, or a single pack file, returning the number of
+    /// objects they contained.
+    pub fn unpack(&self, git: &Git, packs: &OsString) -> usize {
+        let _lock = FileWaitLock::for_git_dir(&self.path);
+        let mut objects = 0;
+
+        let packfiles: Vec<_> = if is_pack_file(Path::new(packs)) {
+            vec![PathBuf::from(packs)]
+        } else {
+            let opendir = std::fs::read_dir(packs).unwrap_or_else(|mut err| inconclusive(&mut err));
+            opendir
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "pack"))
+                .collect()
+        };
+
+        if packfiles.is_empty() {
+            inconclusive(&mut format!(
+                "The pack objects directory {} contains no `.pack` files",
+                Path::new(packs).display()
+            ));
+        }
+
+        for packfile in packfiles {
+            let mut file =
+                std::fs::File::open(packfile).unwrap_or_else(|mut err| inconclusive(&mut err));
+
+            // The pack header: signature, version, and the number of objects.
+            let mut header = [0u8; 12];
+            std::io::Read::read_exact(&mut file, &mut header)
+                .unwrap_or_else(|mut err| inconclusive(&mut err));
+            let count = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+            objects += count as usize;
+
+            let mut unpack = self.exec(git);
+            unpack.args(["unpack-objects", "-r"]);
+            unpack.stdin(Stdio::piped());
+
+            let mut cmd = git
+                .spawn(&mut unpack)
+                .unwrap_or_else(|mut err| inconclusive(&mut err));
+            let mut stdin = cmd.stdin.as_mut().expect("Supplied with Stdio::piped");
+
+            std::io::Write::write_all(stdin, &header)
+                .unwrap_or_else(|mut err| inconclusive(&mut err));
+            std::io::copy(&mut file, &mut stdin).unwrap_or_else(|mut err| inconclusive(&mut err));
+            std::io::Write::flush(stdin).unwrap_or_else(|mut err| inconclusive(&mut err));
+            // Flush and close.
+            cmd.stdin = None;
+
+            let exit = git
+                .wait(cmd)
+                .unwrap_or_else(|mut err| inconclusive(&mut err));
+            if !exit.status.success() {
+                eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+                inconclusive(&mut "Git operation was not successful");
+            }
+        }
+
+        objects
+    }
+
+    /// Estimate the size of the objects reachable from `head` that are available to us.
+    ///
+    /// This requires `git rev-list --disk-usage`, returning `None` with older versions of git.
+    pub fn disk_usage(&self, git: &Git, head: &CommitId) -> Option<u64> {
+        let mut cmd = self.exec(git);
+        cmd.args([
+            "rev-list",
+            "--objects",
+            "--disk-usage",
+            "--missing=allow-any",
+        ]);
+        cmd.arg(&head.0);
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(git.quiet());
+
+        let exit = git.output(&mut cmd).ok()?;
+        if !exit.status.success() {
+            return None;
+        }
+
+        String::from_utf8(exit.stdout).ok()?.trim().parse().ok()
+    }
+
+    /// Remove a previous checkout at `path`, whether it is one of our worktrees or not.
+    pub fn clear_worktree(&self, git: &Git, path: &Path) {
+        let mut cmd = self.exec(git);
+        cmd.args(["worktree", "remove", "--force"]);
+        cmd.arg(path);
+        cmd.stderr(git.quiet());
+        // Fails if this is not a worktree of ours, which we handle below.
+        let _ = git.status(&mut cmd);
+
+        if path.exists() {
+            // The read-only attribute of Windows also prevents removing the file.
+            #[cfg(windows)]
+            let _ = crate::set_readonly(path, false);
+            std::fs::remove_dir_all(path).unwrap_or_else(|mut err| inconclusive(&mut err));
+        }
+    }
+
+    /// Remove the checkouts in `dir` whose names start with `prefix`, except the `keep` latest.
+    ///
+    /// The `current` checkout counts towards the latest and is never removed. Neither is any
+    /// checkout that a build still uses.
+    pub fn remove_checkouts(
+        &self,
+        git: &Git,
+        dir: &Path,
+        prefix: &str,
+        current: &Path,
+        keep: usize,
+    ) {
+        let _lock = FileWaitLock::for_git_dir(&self.path);
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let mut checkouts: Vec<_> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+            .filter(|entry| entry.file_type().map_or(false, |ty| ty.is_dir()))
+            .map(|entry| entry.path())
+            .filter(|path| path != current)
+            .map(|path| {
+                let modified = path.metadata().and_then(|meta| meta.modified()).ok();
+                (modified, path)
+            })
+            .collect();
+        // The latest first.
+        checkouts.sort_by(|a, b| b.cmp(a));
+
+        for (_, path) in checkouts.into_iter().skip(keep.saturating_sub(1)) {
+            let lock = match FileWaitLock::unused_checkout(&path) {
+                Some(lock) => lock,
+                None => continue,
+            };
+            self.clear_worktree(git, &path);
+            let _ = std::fs::remove_file(FileWaitLock::checkout_lock_path(&path));
+            drop(lock);
+        }
+    }
+
+    /// The commit checked out in each registered worktree, by the path of the worktree.
+    fn worktree_heads(&self, git: &Git) -> Vec<(PathBuf, String)> {
+        let mut cmd = self.exec(git);
+        cmd.args(["worktree", "list", "--porcelain"]);
+        cmd.stdout(Stdio::piped());
+        let exit = git
+            .output(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        if !exit.status.success() {
+            return vec![];
+        }
+
+        parse_worktree_list(&String::from_utf8_lossy(&exit.stdout))
+    }
+
+    /// The commit of the worktree registered at `path`, in any state.
+    fn worktree_head(&self, git: &Git, path: &Path) -> Option<String> {
+        let path = path.canonicalize().ok()?;
+        self.worktree_heads(git)
+            .into_iter()
+            .find(|(worktree, _)| worktree.canonicalize().map_or(false, |w| w == path))
+            .map(|(_, commit)| commit)
+    }
+
+    /// Whether `path` is registered as a worktree of ours, even if it is damaged.
+    pub fn is_worktree(&self, git: &Git, path: &Path) -> bool {
+        self.worktree_head(git, path).is_some()
+    }
+
+    /// Whether `path` is a worktree of ours with `head` checked out, which git still recognizes.
+    ///
+    /// Such a worktree is reused by [`Self::checkout()`] instead of being added again.
+    pub fn is_intact_worktree(&self, git: &Git, path: &Path, head: &CommitId) -> bool {
+        if self.worktree_head(git, path).as_deref() != Some(&*head.0) {
+            return false;
+        }
+
+        // The worktree itself may be damaged, for example by removing its `.git` file. Its
+        // directory may even have been removed and created again, as an empty one.
+        if !path.join(".git").is_file() {
+            return false;
+        }
+
+        let mut cmd = Self::exec_in(git, path);
+        cmd.args(["rev-parse", "--verify", "HEAD"]);
+        cmd.stdout(Stdio::piped());
+        git.output(&mut cmd).map_or(false, |exit| {
+            exit.status.success() && String::from_utf8_lossy(&exit.stdout).trim() == head.0
+        })
+    }
+
+    /// Remove the administrative entries of worktrees whose directories no longer exist.
+    ///
+    /// Such entries are left behind when the temporary directory of previous runs is cleaned.
+    pub fn prune_worktrees(&self, git: &Git) {
+        let mut cmd = self.exec(git);
+        cmd.args(["worktree", "prune"]);
+        let exit = git
+            .output(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        if !exit.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+            inconclusive(&mut "Git operation was not successful");
+        }
+    }
+
+    // Known false positive in initializatioon of `complex_paths`.
+    // We need to take ownership of `path` in a branch.
+    #[allow(clippy::unnecessary_filter_map)]
+    pub fn checkout(
+        &self,
+        git: &Git,
+        worktree: &Path,
+        head: &CommitId,
+        paths: &mut dyn Iterator<Item = PathSpec<'_>>,
+        additive: bool,
+    ) -> Worktree {
+        let _lock = FileWaitLock::for_git_dir(&self.path);
+        let reuse = self.is_intact_worktree(git, worktree, head);
+        if !reuse && self.is_worktree(git, worktree) {
+            // A damaged worktree, or one of another commit, must not block adding it again.
+            self.clear_worktree(git, worktree);
+        }
+
+        self.prune_worktrees(git);
+        self.disable_conversion();
+
+        let PathSpecFilter {
+            simple_filter,
+            complex_paths,
+        } = paths.collect();
+
+        if !reuse {
+            let mut cmd = self.exec(git);
+            cmd.args(["worktree", "add", "--no-checkout"]);
+            cmd.arg(worktree);
+            cmd.arg(head);
+            let exit = git
+                .output(&mut cmd)
+                .unwrap_or_else(|mut err| inconclusive(&mut err));
+            if !exit.status.success() {
+                eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+                inconclusive(&mut "Git operation was not successful");
+            }
+        }
+
+        // From here on, a failure must not leave the registration behind.
+        let mut guard = Worktree {
+            git: git.bin.clone(),
+            repo: self.path.clone(),
+            path: worktree.to_owned(),
+            persistent: false,
+            slow_checkout: None,
+        };
+
+        // First setup sparse-checkout
+        // Note that this is in beta and not supported, so let's fallback if necessary. The
+        // configuration is made from within the worktree so that it stays private to it, instead
+        // of applying to every worktree of the shared bare repository.
+        //
+        // When every path is a directory we use cone mode. It matches by directory prefix instead
+        // of evaluating each pattern against each path in the tree, which is the difference
+        // between linear and quadratic work in large repositories. Note that a cone also includes
+        // the files directly inside the parent directories of each registered directory.
+        //
+        // Additive patterns are never in cone mode, in which a later build could not add a file.
+        // In a reused worktree they are added to the patterns that it already has.
+        let cone = !additive && all_trees(git, self.exec(git), &head.0, &simple_filter);
+        let try_sparse_checkout = || -> std::io::Result<()> {
+            let mut cmd = Self::exec_in(git, worktree);
+            if additive && reuse {
+                cmd.args(["sparse-checkout", "add"]);
+            } else {
+                cmd.args(["sparse-checkout", "set"]);
+                cmd.arg(if cone { "--cone" } else { "--no-cone" });
+            }
+            cmd.arg("--stdin");
+            cmd.stdin(Stdio::piped());
+            let mut running = git.spawn(&mut cmd)?;
+            let stdin = running.stdin.as_mut().expect("Spawned with stdio-piped");
+            for path in &simple_filter {
+                let simple = path.as_encompassing_path().unwrap().display().to_string();
+                use std::io::Write;
                 // > This includes interpreting pathnames that begin with a double quote (") as C-style quoted strings.
                 // Since there is no NUL separation (yet?) we use this.
                 writeln!(stdin, "{}", simple).unwrap_or_else(|mut err| inconclusive(&mut err));
             }
             running.stdin = None;
-            let exit = running.wait_with_output()?;
+            let exit = git.wait(running)?;
             if !exit.status.success() {
                 return Err(std::io::ErrorKind::Other.into());
             }
@@ -391,19 +1413,26 @@ impl ShallowBareRepository {
                 "Version of Git appears to not support sparse-checkout: {}",
                 err
             );
+            if let Some(explanation) = git.explain_version("sparse-checkout --stdin", SPARSE_STDIN)
+            {
+                eprintln!("Indeed, {}", explanation);
+            }
             let mut all_again = simple_filter.into_iter().chain(complex_paths);
-            return self.checkout_fallback_slow(git, worktree, head, &mut all_again);
+            let slow = Instant::now();
+            self.checkout_fallback_slow(git, worktree, head, &mut all_again);
+            guard.slow_checkout = Some(slow.elapsed());
+            Self::verify_symlinks(git, worktree);
+            return guard;
         }
 
-        let mut cmd = self.exec(git);
-        cmd.arg("--work-tree");
-        cmd.arg(worktree);
+        let mut cmd = Self::exec_in(git, worktree);
+        cmd.args(["-c", "core.autocrlf=false"]);
         cmd.arg("checkout");
         cmd.arg("--force");
         cmd.arg(&head.0);
         cmd.stderr(Stdio::piped());
-        let exit = cmd
-            .output()
+        let exit = git
+            .output(&mut cmd)
             .unwrap_or_else(|mut err| inconclusive(&mut err));
 
         if !exit.status.success() {
@@ -411,7 +1440,88 @@ impl ShallowBareRepository {
             inconclusive(&mut "Git operation was not successful");
         }
 
-        self.checkout_fallback_slow(git, worktree, head, &mut complex_paths.into_iter());
+        if !complex_paths.is_empty() {
+            let slow = Instant::now();
+            self.checkout_fallback_slow(git, worktree, head, &mut complex_paths.into_iter());
+            guard.slow_checkout = Some(slow.elapsed());
+        }
+
+        Self::verify_symlinks(git, worktree);
+        guard
+    }
+
+    /// Ensure that committed symlinks were checked out as symlinks.
+    ///
+    /// Without symlink support, such as on Windows unless `core.symlinks` is enabled, git writes
+    /// the link target into a plain file instead. A test would then silently read the wrong data.
+    fn verify_symlinks(git: &Git, worktree: &Path) {
+        let mut cmd = Self::exec_in(git, worktree);
+        cmd.args(["ls-files", "--stage", "-z"]);
+        cmd.stdout(Stdio::piped());
+        let exit = git
+            .output(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        if !exit.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+            inconclusive(&mut "Git operation was not successful");
+        }
+
+        let stage = String::from_utf8_lossy(&exit.stdout);
+        for path in symlinks_in_stage(&stage) {
+            // Paths outside the sparse checkout are not in the worktree at all.
+            let is_file = match std::fs::symlink_metadata(worktree.join(path)) {
+                Ok(meta) => !meta.file_type().is_symlink(),
+                Err(_) => false,
+            };
+
+            if is_file {
+                inconclusive(&mut format!(
+                    "The symlink `{}` was checked out as a plain file. Enable `core.symlinks` for git, on Windows this also requires permission to create symlinks.",
+                    path
+                ));
+            }
+        }
+    }
+
+    /// Check out files exactly as the committed blobs.
+    ///
+    /// The repository attributes take precedence over any `.gitattributes` in the tree. Hence no
+    /// line ending conversion, re-encoding, or filter (such as LFS) alters the test data.
+    fn disable_conversion(&self) {
+        let info = self.path.join("info");
+        std::fs::create_dir_all(&info).unwrap_or_else(|mut err| inconclusive(&mut err));
+        std::fs::write(
+            info.join("attributes"),
+            "* -text -filter -ident !working-tree-encoding\n",
+        )
+        .unwrap_or_else(|mut err| inconclusive(&mut err));
+    }
+
+    /// Run a command within a worktree of this repository.
+    ///
+    /// Git must not search above the worktree for a repository. Should the worktree be missing its
+    /// `.git` file, which a concurrent build may have just removed, the command would otherwise
+    /// run against whichever repository encloses the data directory.
+    fn exec_in(git: &Git, worktree: &Path) -> Command {
+        let mut cmd = git.command();
+        cmd.current_dir(worktree);
+        if let Some(parent) = worktree.parent() {
+            cmd.env("GIT_CEILING_DIRECTORIES", parent);
+        }
+        cmd.stdout(git.quiet());
+        cmd.stderr(Stdio::piped());
+        cmd
+    }
+
+    /// The files of the commit `head` in a checkout at `worktree` that match a glob `pattern`.
+    pub fn glob(
+        &self,
+        git: &Git,
+        worktree: &Path,
+        head: &CommitId,
+        pattern: &Path,
+    ) -> Vec<PathBuf> {
+        list_glob(git, Self::exec_in(git, worktree), pattern, Some(head))
     }
 
     /// A version of `checkout` that uses checkout and a list pathspecs from stdin to determine the
@@ -427,19 +1537,22 @@ impl ShallowBareRepository {
         let mut cmd = self.exec(git);
         cmd.arg("--work-tree");
         cmd.arg(worktree);
+        cmd.args(["-c", "core.autocrlf=false"]);
         cmd.args(["checkout", "--no-guess", "--force"]);
         cmd.args(["--pathspec-from-file=-", "--pathspec-file-nul"]);
         cmd.arg(&head.0);
         cmd.stdin(Stdio::piped());
-        let mut running = cmd.spawn().unwrap_or_else(|mut err| inconclusive(&mut err));
+        let mut running = git
+            .spawn(&mut cmd)
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
         let stdin = running.stdin.as_mut().expect("Spawned with stdio-piped");
         for path in paths {
             use std::io::Write;
             write!(stdin, "{}\0", path).unwrap_or_else(|mut err| inconclusive(&mut err));
         }
         running.stdin = None;
-        let exit = running
-            .wait_with_output()
+        let exit = git
+            .wait(running)
             .unwrap_or_else(|mut err| inconclusive(&mut err));
         if !exit.status.success() {
             eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
@@ -448,11 +1561,403 @@ impl ShallowBareRepository {
     }
 }
 
+impl Worktree {
+    /// Keep the files for inspection even if the test fails.
+    pub fn persistent(mut self) -> Self {
+        self.persistent = true;
+        self
+    }
+
+    /// The time spent checking out paths one by one, if that was necessary.
+    pub fn slow_checkout(&self) -> Option<Duration> {
+        self.slow_checkout
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        if self.persistent || !std::thread::panicking() {
+            return;
+        }
+
+        // Best effort, we are already failing.
+        let _ = Command::new(&self.git)
+            .arg("--git-dir")
+            .arg(&self.repo)
+            .args(["worktree", "remove", "--force"])
+            .arg(&self.path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+/// The path of one entry of `git status --porcelain=v2 -z`.
+#[derive(Debug, PartialEq, Eq)]
+enum StatusEntry<'lt> {
+    /// A tracked path, which is only listed when it has changes that are staged or not.
+    Tracked(&'lt str),
+    Untracked(&'lt str),
+    Ignored(&'lt str),
+}
+
+/// Parse the NUL separated records of `git status --porcelain=v2 -z`.
+///
+/// Each record is a type marker followed by a fixed number of space separated fields, the path
+/// being the last field which may itself contain spaces. Renamed and copied entries are followed
+/// by a separate record with their original path.
+fn parse_status_v2(status: &str) -> Vec<StatusEntry<'_>> {
+    let mut records = status.split('\0').filter(|record| !record.is_empty());
+    let mut entries = vec![];
+
+    while let Some(record) = records.next() {
+        // The number of fields, including the type marker, before the path.
+        let fields = match record.as_bytes()[0] {
+            b'#' => continue,
+            b'?' | b'!' => 1,
+            b'1' => 8,
+            b'2' => 9,
+            b'u' => 10,
+            _ => inconclusive(&mut format!("Unrecognized git status entry: {}", record)),
+        };
+
+        let path = record
+            .splitn(fields + 1, ' ')
+            .nth(fields)
+            .unwrap_or_else(|| {
+                inconclusive(&mut format!("Malformed git status entry: {}", record))
+            });
+
+        entries.push(match record.as_bytes()[0] {
+            b'?' => StatusEntry::Untracked(path),
+            b'!' => StatusEntry::Ignored(path),
+            b'2' => {
+                // Skip the original path of the rename or copy.
+                let _ = records.next();
+                StatusEntry::Tracked(path)
+            }
+            _ => StatusEntry::Tracked(path),
+        });
+    }
+
+    entries
+}
+
+/// Check if all paths refer to directories at `head`, which allows a cone sparse-checkout.
+fn all_trees(git: &Git, mut cmd: Command, head: &str, paths: &[PathSpec<'_>]) -> bool {
+    let paths: Vec<_> = paths
+        .iter()
+        .filter_map(PathSpec::as_encompassing_path)
+        .collect();
+
+    if paths.is_empty() || paths.iter().any(|path| path.as_os_str().is_empty()) {
+        return false;
+    }
+
+    cmd.args(["cat-file", "--batch-check=%(objecttype)"]);
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+
+    let mut input = String::new();
+    for path in &paths {
+        match path.to_str() {
+            Some(path) => input.push_str(&format!("{}:{}\n", head, path)),
+            None => return false,
+        }
+    }
+
+    let exit = (|| {
+        let mut running = git.spawn(&mut cmd)?;
+        let stdin = running.stdin.as_mut().expect("Spawned with stdio-piped");
+        std::io::Write::write_all(stdin, input.as_bytes())?;
+        running.stdin = None;
+        git.wait(running)
+    })();
+
+    match exit {
+        // One line per path, with the type or a message for a missing object.
+        Ok(exit) if exit.status.success() => String::from_utf8_lossy(&exit.stdout)
+            .lines()
+            .all(|kind| kind == "tree"),
+        _ => false,
+    }
+}
+
+/// The patterns equivalent to a cone sparse-checkout of directories.
+///
+/// A cone contains the registered directories recursively, as well as all files (but not
+/// directories) at the top level and in every parent directory of a registered directory.
+fn cone_patterns(dirs: &[PathSpec<'_>]) -> String {
+    let dirs: Vec<&Path> = dirs
+        .iter()
+        .filter_map(PathSpec::as_encompassing_path)
+        .collect();
+    // Directories nested in another registered one are already included recursively.
+    let recursive: Vec<&Path> = dirs
+        .iter()
+        .copied()
+        .filter(|dir| {
+            !dirs
+                .iter()
+                .any(|other| other != dir && dir.starts_with(other))
+        })
+        .collect();
+
+    // Sorted so that a parent comes before its children, later patterns take precedence.
+    let mut cone = std::collections::BTreeMap::new();
+    for dir in &recursive {
+        for parent in dir.ancestors().skip(1) {
+            if !parent.as_os_str().is_empty() {
+                cone.entry(parent).or_insert(false);
+            }
+        }
+
+        cone.insert(*dir, true);
+    }
+
+    let mut patterns = String::from("/*\n!/*/\n");
+    for (dir, recursive) in cone {
+        patterns.push_str(&format!("/{}/\n", dir.display()));
+        if !recursive {
+            patterns.push_str(&format!("!/{}/*/\n", dir.display()));
+        }
+    }
+
+    patterns
+}
+
+/// The files matching a glob `pattern`, relative to the directory of `cmd`, in sorted order.
+///
+/// Lists the index, overlaid with the files of `tree` if given. This covers the files of the
+/// commit in a worktree whose index does not contain those outside of the sparse checkout.
+fn list_glob(git: &Git, mut cmd: Command, pattern: &Path, tree: Option<&CommitId>) -> Vec<PathBuf> {
+    cmd.args(["ls-files", "-z"]);
+    if let Some(tree) = tree {
+        cmd.arg(format!("--with-tree={}", tree.0));
+    }
+    cmd.arg("--");
+    cmd.arg(format!(":(glob){}", pattern.display()));
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let exit = git
+        .output(&mut cmd)
+        .unwrap_or_else(|mut err| inconclusive(&mut err));
+
+    if !exit.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+        inconclusive(&mut "Git operation was not successful");
+    }
+
+    let mut files: Vec<_> = String::from_utf8_lossy(&exit.stdout)
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Whether the tree of `commit` has an entry at `path`, relative to the root of the repository.
+///
+/// A missing path is an answer, an unknown commit or a broken repository is not. `ls-tree` tells
+/// them apart by its status where `cat-file -e` fails for both.
+fn tree_contains(git: &Git, mut cmd: Command, commit: &str, path: &Path) -> bool {
+    cmd.args(["ls-tree", "--full-tree", commit, "--"]);
+    cmd.arg(path);
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let exit = git
+        .output(&mut cmd)
+        .unwrap_or_else(|mut err| inconclusive(&mut err));
+
+    if !exit.status.success() {
+        inconclusive(&mut format!(
+            "Could not look for `{}` in the commit `{}`: {}",
+            path.display(),
+            commit,
+            String::from_utf8_lossy(&exit.stderr).trim()
+        ));
+    }
+
+    !exit.stdout.is_empty()
+}
+
+/// Write a blob from the repository of `cmd` into a file.
+fn write_blob(git: &Git, mut cmd: Command, oid: &str, dest: &Path) {
+    cmd.args(["cat-file", "blob", oid]);
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let exit = git
+        .output(&mut cmd)
+        .unwrap_or_else(|mut err| inconclusive(&mut err));
+
+    if !exit.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&exit.stderr));
+        inconclusive(&mut format!(
+            "The object `{}` is not a blob in the repository",
+            oid
+        ));
+    }
+
+    std::fs::write(dest, exit.stdout).unwrap_or_else(|mut err| inconclusive(&mut err));
+}
+
+/// The full ID of the one commit that an abbreviated ID names.
+fn resolve_commit(git: &Git, mut cmd: Command, head: &CommitId) -> CommitId {
+    if !head.is_abbreviated() {
+        return head.clone();
+    }
+
+    cmd.args(["rev-parse", "--verify"]);
+    cmd.arg(format!("{}^{{commit}}", head.0));
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let exit = git
+        .output(&mut cmd)
+        .unwrap_or_else(|mut err| inconclusive(&mut err));
+
+    let stderr = String::from_utf8_lossy(&exit.stderr);
+    if stderr.contains("ambiguous") {
+        inconclusive(&mut format!(
+            "The abbreviated commit `{}` is ambiguous, give more digits",
+            head
+        ));
+    }
+
+    if !exit.status.success() {
+        eprintln!("{}", stderr);
+        inconclusive(&mut format!(
+            "The abbreviated commit `{}` names no commit in the data",
+            head
+        ));
+    }
+
+    CommitId::from(&*String::from_utf8_lossy(&exit.stdout))
+}
+
+/// Paths of symlinks, from the NUL-separated output of `git ls-files --stage -z`.
+fn symlinks_in_stage(stage: &str) -> impl Iterator<Item = &str> {
+    stage.split_terminator('\0').filter_map(|entry| {
+        // <mode> SP <object> SP <stage> TAB <file>
+        let (info, path) = entry.split_once('\t')?;
+        if info.starts_with("120000 ") {
+            Some(path)
+        } else {
+            None
+        }
+    })
+}
+
+/// The path and checked out commit of each worktree, from `git worktree list --porcelain`.
+///
+/// The entry of the bare repository itself has no commit and is skipped.
+fn parse_worktree_list(list: &str) -> Vec<(PathBuf, String)> {
+    let mut worktrees = vec![];
+    let mut path = None;
+    for line in list.lines() {
+        if let Some(worktree) = line.strip_prefix("worktree ") {
+            path = Some(PathBuf::from(worktree));
+        } else if let Some(head) = line.strip_prefix("HEAD ") {
+            if let Some(path) = path.take() {
+                worktrees.push((path, head.to_string()));
+            }
+        }
+    }
+    worktrees
+}
+
+/// The version from the output of `git --version`.
+///
+/// Vendors append their own suffix, such as `git version 2.17.1.windows.2` or `git version
+/// 2.39.3 (Apple Git-145)`, and release candidates are named `2.25.0-rc1` or `2.25.0.rc1`.
+fn parse_version(output: &str) -> Option<GitVersion> {
+    let version = output.trim().strip_prefix("git version ")?;
+    let version = version.split_whitespace().next()?;
+    let mut numbers = version
+        .split(|ch| ch == '.' || ch == '-')
+        .map(str::parse::<u32>);
+
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next()?.ok()?;
+    let patch = numbers.next().and_then(Result::ok).unwrap_or(0);
+
+    Some(GitVersion {
+        major,
+        minor,
+        patch,
+    })
+}
+
+/// Whether pack objects are a single file instead of a directory of them.
+///
+/// Determined by the `.pack` extension, since a file that is to be written does not exist yet.
+pub(crate) fn is_pack_file(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "pack") && !path.is_dir()
+}
+
+/// Whether a failed fetch may succeed when repeated, judging by the messages of git.
+///
+/// Authentication failures and missing refs are permanent, while dropped connections and server
+/// errors usually are not.
+fn is_transient_failure(stderr: &str) -> bool {
+    const TRANSIENT: &[&str] = &[
+        "connection reset",
+        "connection refused",
+        "connection timed out",
+        "operation timed out",
+        "temporary failure in name resolution",
+        "the remote end hung up unexpectedly",
+        "early eof",
+        "rpc failed",
+        "unexpected disconnect",
+        "returned error: 5",
+    ];
+
+    let stderr = stderr.to_ascii_lowercase();
+    TRANSIENT.iter().any(|message| stderr.contains(message))
+}
+
+/// Whether a fetch by commit ID failed because the server only serves the tips of refs.
+///
+/// Fetching any reachable commit is allowed by servers of protocol version 2, or by those with
+/// `uploadpack.allowReachableSHA1InWant` or `uploadpack.allowAnySHA1InWant` enabled.
+fn is_unadvertised_object_refused(stderr: &str) -> bool {
+    const REFUSED: &[&str] = &[
+        "does not allow request for unadvertised object",
+        "not our ref",
+    ];
+
+    let stderr = stderr.to_ascii_lowercase();
+    REFUSED.iter().any(|message| stderr.contains(message))
+}
+
+/// Whether the value of a configuration key is likely a credential, such as an auth header.
+fn is_secret_key(key: &str) -> bool {
+    const SECRETS: &[&str] = &[
+        "password",
+        "token",
+        "secret",
+        "auth",
+        "extraheader",
+        "cookie",
+        "credential",
+    ];
+
+    let key = key.to_ascii_lowercase();
+    SECRETS.iter().any(|secret| key.contains(secret))
+}
+
 fn inconclusive_but_maybe_gitdir(
     output: &std::process::Output,
     descriptor: &mut dyn std::fmt::Display,
 ) {
-    struct WithSuspicionNoVcsPresent<'lf>(&'lf mut dyn std::fmt::Display);
+    inconclusive(&mut maybe_gitdir(output, descriptor));
+}
+
+/// Describe a failure, with a hint when git did not find a repository at all.
+fn maybe_gitdir(output: &std::process::Output, descriptor: &dyn std::fmt::Display) -> String {
+    struct WithSuspicionNoVcsPresent<'lf>(&'lf dyn std::fmt::Display);
 
     impl std::fmt::Display for WithSuspicionNoVcsPresent<'_> {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -480,23 +1985,81 @@ fn inconclusive_but_maybe_gitdir(
             && st.contains("/)")
             && st.contains("GIT_DISCOVERY_ACROSS_FILESYSTEM")
         {
-            inconclusive(&mut WithSuspicionNoVcsPresent(descriptor));
+            return WithSuspicionNoVcsPresent(descriptor).to_string();
         }
     }
 
-    inconclusive(descriptor);
+    descriptor.to_string()
 }
 
 impl FileWaitLock {
     pub fn for_git_dir(path: &Path) -> Self {
+        Self::at(&Self::git_dir_lock_path(path))
+    }
+
+    /// The lock file next to a git directory, or one in the temporary directory.
+    ///
+    /// The latter is used when the git directory is at, or directly below, the root of the file
+    /// system or the working directory, where we should not create files. It is named by a stable
+    /// hash of the path, so all users of the same git directory agree on it. Only the parent of
+    /// the path is resolved, which exists before and after the git directory is created.
+    fn git_dir_lock_path(path: &Path) -> PathBuf {
+        match path.parent() {
+            Some(parent) if parent.parent().is_some() => parent.join("xtest-data.lock"),
+            parent => {
+                let parent = match parent.filter(|parent| !parent.as_os_str().is_empty()) {
+                    Some(parent) => parent.canonicalize(),
+                    None => std::env::current_dir(),
+                };
+                let path = match (parent, path.file_name()) {
+                    (Ok(parent), Some(name)) => parent.join(name),
+                    _ => path.to_owned(),
+                };
+                let hash = crate::stable_hash(path.to_string_lossy().as_bytes());
+                let name = format!("xtest-data-{:016x}.lock", hash);
+                std::env::temp_dir().join(name)
+            }
+        }
+    }
+
+    /// Exclusive access to a checkout directory, through a lock file next to it.
+    pub fn for_checkout(path: &Path) -> Self {
+        Self::at(&Self::checkout_lock_path(path))
+    }
+
+    /// Shared access to a checkout directory, which only excludes [`Self::unused_checkout()`].
+    pub fn in_use(path: &Path) -> Self {
+        let lock = std::fs::File::create(Self::checkout_lock_path(path))
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        // Not the method of `File` with the same name, which is too recent.
+        fs2::FileExt::lock_shared(&lock).unwrap_or_else(|mut err| inconclusive(&mut err));
+
+        FileWaitLock { lock }
+    }
+
+    /// Exclusive access to a checkout directory, unless it is in use.
+    pub fn unused_checkout(path: &Path) -> Option<Self> {
+        use fs2::FileExt;
+
+        let lock = std::fs::File::create(Self::checkout_lock_path(path)).ok()?;
+        lock.try_lock_exclusive().ok()?;
+        Some(FileWaitLock { lock })
+    }
+
+    fn checkout_lock_path(path: &Path) -> PathBuf {
+        let mut name = path
+            .file_name()
+            .expect("Checkout directory should have a name")
+            .to_owned();
+        name.push(".xtest-data.lock");
+        path.with_file_name(name)
+    }
+
+    fn at(fslock_path: &Path) -> Self {
         use fs2::FileExt;
-        let fslock_path = path
-            .parent()
-            .expect("Clone directory should not be root")
-            .join("xtest-data.lock");
 
         let lock =
-            std::fs::File::create(&fslock_path).unwrap_or_else(|mut err| inconclusive(&mut err));
+            std::fs::File::create(fslock_path).unwrap_or_else(|mut err| inconclusive(&mut err));
         lock.lock_exclusive()
             .unwrap_or_else(|mut err| inconclusive(&mut err));
 
@@ -506,8 +2069,7 @@ impl FileWaitLock {
 
 impl Drop for FileWaitLock {
     fn drop(&mut self) {
-        use fs2::FileExt;
-        if let Err(_) = self.lock.unlock() {
+        if fs2::FileExt::unlock(&self.lock).is_err() {
             // Otherwise we'd block indefinitely in this process?
             std::process::abort();
         }
@@ -515,11 +2077,19 @@ impl Drop for FileWaitLock {
 }
 
 impl PathSpec<'_> {
+    /// The pathspec for this path relative to a subdirectory of the repository.
+    pub fn in_dir(&self, prefix: &Path) -> String {
+        match self {
+            PathSpec::Path(path) => format!(":(top,literal){}", prefix.join(path).display()),
+            PathSpec::Glob(pattern) => format!(":(top,glob){}", prefix.join(pattern).display()),
+        }
+    }
+
     /// For git sparse checkout.
     pub fn as_encompassing_path(&self) -> Option<&Path> {
         match self {
             PathSpec::Path(path) => Some(path),
-            // Should return None for a glob-filtered path since that is not supported.
+            PathSpec::Glob(_) => None,
         }
     }
 }
@@ -530,10 +2100,959 @@ impl std::convert::AsRef<OsStr> for CommitId {
     }
 }
 
+impl std::fmt::Debug for CommandHook<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("CommandHook")
+    }
+}
+
+impl core::fmt::Display for CommitId {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 impl core::fmt::Display for PathSpec<'_> {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             PathSpec::Path(path) => write!(f, ":(top,literal){}", path.display()),
+            PathSpec::Glob(pattern) => write!(f, ":(top,glob){}", pattern.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{parse_status_v2, parse_version, parse_worktree_list, symlinks_in_stage};
+    use super::{CommitId, CrateDir, FileWaitLock, Git, PathSpec, ShallowBareRepository};
+    use super::{StatusEntry, SPARSE_STDIN};
+    use std::panic::AssertUnwindSafe;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    /// A fresh repository in the system's temporary directory, removed on drop.
+    pub(crate) struct Repository(pub PathBuf);
+
+    impl Repository {
+        pub fn new() -> Self {
+            let path = crate::unique_dir(&std::env::temp_dir(), "xtest-data-repo").unwrap();
+            let repo = Repository(path);
+            repo.git(&["init", "--quiet"]);
+            repo
+        }
+
+        pub fn write(&self, path: &str, content: &str) {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        pub fn commit(&self) {
+            self.git(&["add", "--all"]);
+            self.git(&[
+                "-c",
+                "user.name=xtest-data",
+                "-c",
+                "user.email=xtest-data@localhost",
+                "commit",
+                "--quiet",
+                "--message",
+                "fixtures",
+            ]);
+        }
+
+        /// The commit at `HEAD`.
+        pub fn head(&self) -> CommitId {
+            let head = Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .current_dir(&self.0)
+                .output()
+                .unwrap();
+            CommitId::from(&*String::from_utf8(head.stdout).unwrap())
+        }
+
+        pub fn git(&self, args: &[&str]) {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(&self.0)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {:?}", args);
+        }
+    }
+
+    impl Drop for Repository {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn tracked_in_subdirectory_crate() {
+        let repo = Repository::new();
+        repo.write("sub/crate/tests/data.bin", "data");
+        repo.commit();
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let manifest = repo.0.join("sub/crate");
+        let dir = CrateDir::new(&manifest, &git);
+        assert_eq!(dir.prefix, Path::new("sub/crate/"));
+
+        // Does not panic, the file is tracked relative to the manifest.
+        let path = Path::new("tests/data.bin");
+        dir.tracked(&git, &mut std::iter::once(PathSpec::Path(path)), false);
+    }
+
+    #[test]
+    fn contains_in_subdirectory_crate() {
+        let repo = Repository::new();
+        repo.write("sub/crate/tests/data.bin", "data");
+        repo.commit();
+        repo.write("sub/crate/tests/new.bin", "new");
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let dir = CrateDir::new(&repo.0.join("sub/crate"), &git);
+
+        assert!(dir.contains(&git, Path::new("tests/data.bin")));
+        assert!(dir.contains(&git, Path::new("tests")));
+        // Only committed paths are in the tree, and a prefix of a name is not a match.
+        assert!(!dir.contains(&git, Path::new("tests/new.bin")));
+        assert!(!dir.contains(&git, Path::new("tests/data")));
+        assert!(!dir.contains(&git, Path::new("tests/missing.bin")));
+    }
+
+    #[test]
+    fn contains_without_commit() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let dir = CrateDir::new(&repo.0, &git);
+        // A branch without commits, so that `HEAD` names nothing.
+        repo.git(&["symbolic-ref", "HEAD", "refs/heads/unborn"]);
+        let report = crate::tests::capture_report(AssertUnwindSafe(|| {
+            dir.contains(&git, Path::new("tests/data.bin"));
+        }));
+
+        assert!(
+            report.contains("Could not look for `tests/data.bin`"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn untracked_in_subdirectory_crate() {
+        let repo = Repository::new();
+        repo.write("sub/crate/tests/data.bin", "data");
+        repo.commit();
+        repo.write("sub/crate/tests/new.bin", "new");
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let manifest = repo.0.join("sub/crate");
+        let dir = CrateDir::new(&manifest, &git);
+
+        let path = Path::new("tests/new.bin");
+        dir.tracked(&git, &mut std::iter::once(PathSpec::Path(path)), false);
+    }
+
+    #[test]
+    fn status_records() {
+        let status = concat!(
+            "1 .M N... 100644 100644 100644 3b18e51 3b18e51 tests/with space.bin\0",
+            "2 R. N... 100644 100644 100644 3b18e51 3b18e51 R100 tests/new.bin\0tests/? old.bin\0",
+            "? tests/?untracked\0",
+            "! target/ignored\0",
+        );
+
+        assert_eq!(
+            parse_status_v2(status),
+            [
+                StatusEntry::Tracked("tests/with space.bin"),
+                StatusEntry::Tracked("tests/new.bin"),
+                StatusEntry::Untracked("tests/?untracked"),
+                StatusEntry::Ignored("target/ignored"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tracked_path_starting_with_question_mark() {
+        let repo = Repository::new();
+        repo.write("tests/?data.bin", "data");
+        repo.write("tests/!data.bin", "data");
+        repo.commit();
+        // Modified entries are reported, with their path last.
+        repo.write("tests/?data.bin", "changed");
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let dir = CrateDir::new(&repo.0, &git);
+        let paths = [Path::new("tests/?data.bin"), Path::new("tests/!data.bin")];
+        dir.tracked(
+            &git,
+            &mut paths.iter().map(|path| PathSpec::Path(path)),
+            false,
+        );
+    }
+
+    /// The ids of all objects in the pack files written into `dir`.
+    fn packed_objects(dir: &Path) -> String {
+        let mut ids = String::new();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |ext| ext != "idx") {
+                continue;
+            }
+
+            let output = Command::new("git")
+                .arg("show-index")
+                .stdin(std::fs::File::open(path).unwrap())
+                .output()
+                .unwrap();
+            ids.push_str(&String::from_utf8(output.stdout).unwrap());
         }
+        ids
+    }
+
+    #[test]
+    fn deeper_pack_includes_history() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "old");
+        repo.commit();
+        repo.write("tests/data.bin", "new");
+        repo.commit();
+
+        let old = Command::new("git")
+            .args(["rev-parse", "HEAD~1:tests/data.bin"])
+            .current_dir(&repo.0)
+            .output()
+            .unwrap();
+        let old = String::from_utf8(old.stdout).unwrap();
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let dir = CrateDir::new(&repo.0, &git);
+        let path = Path::new("tests/data.bin");
+        let pack = |depth| {
+            let packs = repo.0.join(format!("pack-{}", depth));
+            std::fs::create_dir(&packs).unwrap();
+            let mut paths = std::iter::once(PathSpec::Path(path));
+            dir.pack_objects(
+                &git,
+                &mut paths,
+                &[],
+                packs.clone().into_os_string(),
+                depth,
+                None,
+            );
+            packed_objects(&packs)
+        };
+
+        assert!(!pack(1).contains(old.trim()));
+        assert!(pack(2).contains(old.trim()));
+    }
+
+    #[test]
+    fn repack_only_adds_missing_objects() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.write("tests/more.bin", "more");
+        repo.commit();
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let dir = CrateDir::new(&repo.0, &git);
+        let packs = repo.0.join("packs");
+        std::fs::create_dir(&packs).unwrap();
+        let pack = |paths: &[&str]| {
+            let mut paths = paths.iter().map(|path| PathSpec::Path(Path::new(path)));
+            let name = packs.clone().into_os_string();
+            dir.pack_objects(&git, &mut paths, &[], name, 1, None);
+        };
+        let listing = || {
+            let mut names: Vec<_> = std::fs::read_dir(&packs)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+
+        pack(&["tests/data.bin"]);
+        // Any name is recognized, a pack of the same objects would otherwise be written anew.
+        for entry in listing() {
+            let name = entry.to_str().unwrap().replace("xtest-data", "earlier");
+            std::fs::rename(packs.join(&entry), packs.join(name)).unwrap();
+        }
+        let first = listing();
+        let data = packed_objects(&packs);
+
+        pack(&["tests/data.bin"]);
+        assert_eq!(listing(), first, "an unchanged fixture set writes no pack");
+
+        // An added fixture is packed on its own, without the objects packed before.
+        pack(&["tests/data.bin", "tests/more.bin"]);
+        assert_eq!(listing().len(), first.len() * 2);
+        let all = packed_objects(&packs);
+        let more = Command::new("git")
+            .args(["rev-parse", "HEAD:tests/more.bin"])
+            .current_dir(&repo.0)
+            .output()
+            .unwrap();
+        let more = String::from_utf8(more.stdout).unwrap();
+        assert!(!data.contains(more.trim()));
+        assert!(all.contains(more.trim()));
+        for line in data.lines() {
+            assert_eq!(all.matches(line).count(), 1, "{} is packed once", line);
+        }
+    }
+
+    #[test]
+    fn large_blob_is_not_packed() {
+        let repo = Repository::new();
+        repo.write("tests/small.bin", "small");
+        repo.write("tests/large.bin", &"large".repeat(1000));
+        repo.commit();
+
+        let blob = |path: &str| {
+            let out = Command::new("git")
+                .args(["rev-parse", &format!("HEAD:{}", path)])
+                .current_dir(&repo.0)
+                .output()
+                .unwrap();
+            String::from_utf8(out.stdout).unwrap().trim().to_string()
+        };
+        let (small, large) = (blob("tests/small.bin"), blob("tests/large.bin"));
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let dir = CrateDir::new(&repo.0, &git);
+        let packs = repo.0.join("pack");
+        std::fs::create_dir(&packs).unwrap();
+        let mut paths = std::iter::once(PathSpec::Path(Path::new("tests")));
+        dir.pack_objects(
+            &git,
+            &mut paths,
+            &[],
+            packs.clone().into_os_string(),
+            1,
+            Some(1000),
+        );
+
+        let packed = packed_objects(&packs);
+        assert!(packed.contains(&small));
+        assert!(!packed.contains(&large));
+    }
+
+    fn checkout_from(repo: &Repository) -> (ShallowBareRepository, PathBuf, super::Worktree) {
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let head = repo.head();
+
+        let bare = ShallowBareRepository {
+            path: repo.0.join(".git"),
+        };
+        let path = crate::unique_dir(&std::env::temp_dir(), "xtest-data-tree").unwrap();
+        let data = Path::new("tests");
+        let worktree = bare.checkout(
+            &git,
+            &path,
+            &head,
+            &mut std::iter::once(PathSpec::Path(data)),
+            false,
+        );
+        (bare, path, worktree)
+    }
+
+    #[test]
+    fn replaced_commit_is_detected() {
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let repo = Repository::new();
+        repo.write("tests/data.txt", "pinned");
+        repo.commit();
+        let pinned = repo.head();
+        let bare = ShallowBareRepository {
+            path: repo.0.join(".git"),
+        };
+        assert_eq!(bare.replaced_commit(&git, &pinned), None);
+
+        repo.write("tests/data.txt", "replacement");
+        repo.commit();
+        let replacement = repo.head();
+        repo.git(&["replace", &pinned.0, &replacement.0]);
+
+        let warning = bare
+            .replaced_commit(&git, &pinned)
+            .expect("the replacement is detected");
+        assert!(warning.contains("is replaced"), "{}", warning);
+        assert!(warning.contains(&pinned.0), "{}", warning);
+    }
+
+    #[test]
+    fn lock_of_git_dir_at_root() {
+        let beside = FileWaitLock::git_dir_lock_path(Path::new("/data/xtest-data-git"));
+        assert_eq!(beside, Path::new("/data/xtest-data.lock"));
+
+        // No lock file is created in the root, nor in the working directory.
+        let root = Path::new("/xtest-data-git");
+        let at_root = FileWaitLock::git_dir_lock_path(root);
+        assert!(
+            at_root.starts_with(std::env::temp_dir()),
+            "{}",
+            at_root.display()
+        );
+        assert_eq!(at_root, FileWaitLock::git_dir_lock_path(root));
+        assert_ne!(
+            at_root,
+            FileWaitLock::git_dir_lock_path(Path::new("/other-git"))
+        );
+        let relative = FileWaitLock::git_dir_lock_path(Path::new("xtest-data-git"));
+        assert!(
+            relative.starts_with(std::env::temp_dir()),
+            "{}",
+            relative.display()
+        );
+        assert_ne!(at_root, relative);
+
+        let lock = FileWaitLock::for_git_dir(root);
+        drop(lock);
+        let _ = std::fs::remove_file(at_root);
+    }
+
+    fn registered_worktrees(repo: &Repository) -> usize {
+        std::fs::read_dir(repo.0.join(".git/worktrees")).map_or(0, |dir| dir.count())
+    }
+
+    /// A `git` that hangs, as if waiting on an unreachable host.
+    #[test]
+    #[cfg(unix)]
+    fn hanging_git_is_killed() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, Instant};
+
+        let dir = crate::unique_dir(&std::env::temp_dir(), "xtest-data-hang").unwrap();
+        let bin = dir.join("git");
+        let script = "#!/bin/sh\necho \"$GIT_TERMINAL_PROMPT\" > \"$(dirname \"$0\")/prompt\"\nexec sleep 30\n";
+        std::fs::write(&bin, script).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let git = Git {
+            bin,
+            timeout: Duration::from_millis(200),
+            verbose: false,
+            config: vec![],
+            env: vec![],
+            fetch_retries: super::DEFAULT_FETCH_RETRIES,
+            version: None,
+            on_command: None,
+        };
+        let head = CommitId::from(&*"0".repeat(40));
+
+        let start = Instant::now();
+        let result =
+            std::panic::catch_unwind(AssertUnwindSafe(|| git.bare(dir.join("repo"), &head)));
+        let elapsed = start.elapsed();
+        let prompt = std::fs::read_to_string(dir.join("prompt"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(result.is_err());
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+        assert_eq!(prompt.unwrap().trim(), "0");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn env_reaches_git() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::unique_dir(&std::env::temp_dir(), "xtest-data-env").unwrap();
+        let bin = dir.join("git");
+        let script = "#!/bin/sh\necho \"$GIT_SSH_COMMAND;$GIT_ASKPASS;$SSH_ASKPASS;$GCM_INTERACTIVE;$GIT_TERMINAL_PROMPT\" > \"$(dirname \"$0\")/env\"\n";
+        std::fs::write(&bin, script).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let git = Git {
+            bin,
+            timeout: super::DEFAULT_TIMEOUT,
+            verbose: false,
+            config: vec![],
+            env: vec![
+                ("GIT_SSH_COMMAND".into(), "ssh -i deploy_key".into()),
+                ("GIT_TERMINAL_PROMPT".into(), "1".into()),
+                ("GCM_INTERACTIVE".into(), "always".into()),
+            ],
+            fetch_retries: super::DEFAULT_FETCH_RETRIES,
+            version: None,
+            on_command: None,
+        };
+
+        let status = git.status(&mut git.command()).unwrap();
+        let env = std::fs::read_to_string(dir.join("env"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(status.success());
+        // Our own variables are kept, and prompting remains disabled.
+        assert_eq!(env.unwrap().trim(), "ssh -i deploy_key;;;never;0");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn verbose_git_inherits_output() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::unique_dir(&std::env::temp_dir(), "xtest-data-verbose").unwrap();
+        let bin = dir.join("git");
+        let script = "#!/bin/sh\nout=$(readlink /proc/$$/fd/1)\necho \"$out\" > \"$(dirname \"$0\")/stdout\"\n";
+        std::fs::write(&bin, script).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut git = Git {
+            bin,
+            timeout: super::DEFAULT_TIMEOUT,
+            verbose: false,
+            config: vec![],
+            env: vec![],
+            fetch_retries: super::DEFAULT_FETCH_RETRIES,
+            version: None,
+            on_command: None,
+        };
+        let repo = ShallowBareRepository {
+            path: dir.join("repo"),
+        };
+
+        repo.prune_worktrees(&git);
+        let quiet = std::fs::read_to_string(dir.join("stdout"));
+        git.verbose = true;
+        repo.prune_worktrees(&git);
+        let verbose = std::fs::read_to_string(dir.join("stdout"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(quiet.unwrap().trim(), "/dev/null");
+        assert_ne!(verbose.unwrap().trim(), "/dev/null");
+    }
+
+    /// A `git` whose fetch fails with `message` the first `failures` times.
+    #[cfg(unix)]
+    fn flaky_fetch(failures: usize, message: &str) -> (std::thread::Result<()>, usize) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::unique_dir(&std::env::temp_dir(), "xtest-data-flaky").unwrap();
+        let bin = dir.join("git");
+        let script = format!(
+            "#!/bin/sh\n\
+             case \"$*\" in *fetch*) ;; *) exit 0 ;; esac\n\
+             count=\"$(dirname \"$0\")/count\"\n\
+             echo x >> \"$count\"\n\
+             if [ \"$(wc -l < \"$count\")\" -le {} ]; then echo '{}' >&2; exit 128; fi\n",
+            failures, message
+        );
+        std::fs::write(&bin, script).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let git = Git {
+            bin,
+            timeout: super::DEFAULT_TIMEOUT,
+            verbose: false,
+            config: vec![],
+            env: vec![],
+            fetch_retries: super::DEFAULT_FETCH_RETRIES,
+            version: None,
+            on_command: None,
+        };
+        let repo = ShallowBareRepository {
+            path: dir.join("repo"),
+        };
+        let head = CommitId::from(&*"0".repeat(40));
+
+        let result =
+            std::panic::catch_unwind(AssertUnwindSafe(|| repo.fetch_reference(&git, &dir, &head)));
+        let attempts =
+            std::fs::read_to_string(dir.join("count")).map_or(0, |count| count.lines().count());
+        let _ = std::fs::remove_dir_all(&dir);
+        (result, attempts)
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn transient_fetch_failure_is_retried() {
+        let message = "fatal: unable to access 'https://example.com/': Connection reset by peer";
+        let (result, attempts) = flaky_fetch(2, message);
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn permanent_fetch_failure_is_not_retried() {
+        let message = "fatal: couldn't find remote ref 0000000000000000000000000000000000000000";
+        let (result, attempts) = flaky_fetch(2, message);
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn unadvertised_commit_is_explained() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        let head = repo.head();
+        // The commit is no longer the tip of a branch.
+        repo.write("tests/data.bin", "changed");
+        repo.commit();
+
+        let mut git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        // A server of protocol version 2 serves any reachable commit.
+        git.config = vec![("protocol.version".into(), "0".into())];
+        let dir = crate::unique_dir(&std::env::temp_dir(), "xtest-data-unadvertised").unwrap();
+        let shallow = git.bare(dir.join("repo"), &head);
+
+        let reference = repo.0.clone();
+        let report = crate::tests::capture_report(AssertUnwindSafe(move || {
+            shallow.fetch_reference(&git, &reference, &head)
+        }));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(
+            report.contains("is not the tip of a branch or tag"),
+            "{}",
+            report
+        );
+        assert!(
+            report.contains("uploadpack.allowReachableSHA1InWant"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn config_is_forwarded() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::unique_dir(&std::env::temp_dir(), "xtest-data-config").unwrap();
+        let bin = dir.join("git");
+        let script = "#!/bin/sh\nprintf '%s\\n' \"$@\" > \"$(dirname \"$0\")/args\"\n";
+        std::fs::write(&bin, script).unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let git = Git {
+            bin,
+            timeout: super::DEFAULT_TIMEOUT,
+            verbose: false,
+            config: vec![
+                ("protocol.sso.allow".into(), "always".into()),
+                (
+                    "http.extraHeader".into(),
+                    "Authorization: Bearer hunter2".into(),
+                ),
+            ],
+            env: vec![],
+            fetch_retries: super::DEFAULT_FETCH_RETRIES,
+            version: None,
+            on_command: None,
+        };
+        let repo = ShallowBareRepository {
+            path: dir.join("repo"),
+        };
+
+        repo.prune_worktrees(&git);
+        let args = std::fs::read_to_string(dir.join("args"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let args: Vec<_> = args.unwrap().lines().map(str::to_owned).collect();
+        assert_eq!(
+            args[..4],
+            [
+                "-c",
+                "protocol.sso.allow=always",
+                "-c",
+                "http.extraHeader=Authorization: Bearer hunter2",
+            ]
+        );
+        assert_eq!(args[4], "--git-dir");
+    }
+
+    #[test]
+    fn secret_config_is_redacted() {
+        let git = Git {
+            bin: PathBuf::from("git"),
+            timeout: super::DEFAULT_TIMEOUT,
+            verbose: true,
+            config: vec![
+                ("http.proxy".into(), "http://proxy:3128".into()),
+                (
+                    "http.extraHeader".into(),
+                    "Authorization: Bearer hunter2".into(),
+                ),
+            ],
+            env: vec![],
+            fetch_retries: super::DEFAULT_FETCH_RETRIES,
+            version: None,
+            on_command: None,
+        };
+
+        let mut cmd = git.command();
+        cmd.arg("fetch");
+        let line = git.redacted(&cmd);
+        assert!(line.contains("http.proxy=http://proxy:3128"), "{}", line);
+        assert!(line.contains("http.extraHeader=<redacted>"), "{}", line);
+        assert!(!line.contains("hunter2"), "{}", line);
+
+        let git = Git {
+            env: vec![
+                ("GIT_SSH_COMMAND".into(), "ssh -i deploy_key".into()),
+                ("GITHUB_TOKEN".into(), "hunter3".into()),
+            ],
+            ..git
+        };
+        let line = git.redacted(&git.command());
+        assert!(line.contains("ssh -i deploy_key"), "{}", line);
+        assert!(!line.contains("hunter3"), "{}", line);
+    }
+
+    #[test]
+    fn checkout_is_byte_exact() {
+        let repo = Repository::new();
+        repo.write("tests/mixed.txt", "unix\nwindows\r\nunix\n");
+        repo.commit();
+        // Added later, so that the committed blob keeps its mixed line endings.
+        repo.write(".gitattributes", "* text=auto eol=crlf\n");
+        repo.commit();
+
+        let blob = Command::new("git")
+            .args(["cat-file", "blob", "HEAD:tests/mixed.txt"])
+            .current_dir(&repo.0)
+            .output()
+            .unwrap()
+            .stdout;
+
+        let (_, path, _worktree) = checkout_from(&repo);
+        let checked_out = std::fs::read(path.join("tests/mixed.txt")).unwrap();
+        let _ = std::fs::remove_dir_all(&path);
+        assert_eq!(checked_out, blob);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_is_preserved() {
+        let repo = Repository::new();
+        repo.write("tests/data.txt", "data");
+        std::os::unix::fs::symlink("data.txt", repo.0.join("tests/link.txt")).unwrap();
+        repo.commit();
+
+        let (_, path, _worktree) = checkout_from(&repo);
+        let link = path.join("tests/link.txt");
+        let is_symlink = std::fs::symlink_metadata(&link).map(|meta| meta.file_type().is_symlink());
+        let content = std::fs::read_to_string(&link);
+        let _ = std::fs::remove_dir_all(&path);
+
+        assert!(is_symlink.unwrap());
+        assert_eq!(content.unwrap(), "data");
+    }
+
+    #[test]
+    fn symlinks_from_stage() {
+        let stage = "100644 5d308e1d060b0c387d452cf4747f89ecb9935851 0\ttests/data.txt\0\
+120000 1de565933b05f74c75ff9a6520af5f9f8a5a2f1d 0\ttests/with space/link\0";
+        let links: Vec<_> = symlinks_in_stage(stage).collect();
+        assert_eq!(links, ["tests/with space/link"]);
+    }
+
+    #[test]
+    fn worktrees_from_list() {
+        let list = "worktree /tmp/repo.git\nbare\n\n\
+worktree /tmp/xtest-data-tree-1\nHEAD 5d308e1d060b0c387d452cf4747f89ecb9935851\ndetached\n\n\
+worktree /tmp/with space\nHEAD 1de565933b05f74c75ff9a6520af5f9f8a5a2f1d\ndetached\nprunable gitdir file points to non-existent location\n";
+        let worktrees = parse_worktree_list(list);
+        assert_eq!(
+            worktrees,
+            [
+                (
+                    PathBuf::from("/tmp/xtest-data-tree-1"),
+                    "5d308e1d060b0c387d452cf4747f89ecb9935851".to_string()
+                ),
+                (
+                    PathBuf::from("/tmp/with space"),
+                    "1de565933b05f74c75ff9a6520af5f9f8a5a2f1d".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn git_versions() {
+        let version = |st| parse_version(st).map(|version| version.to_string());
+        assert_eq!(version("git version 2.39.2\n").as_deref(), Some("2.39.2"));
+        assert_eq!(
+            version("git version 2.17.1.windows.2").as_deref(),
+            Some("2.17.1")
+        );
+        assert_eq!(
+            version("git version 2.39.3 (Apple Git-145)").as_deref(),
+            Some("2.39.3")
+        );
+        assert_eq!(version("git version 2.25.0-rc1").as_deref(), Some("2.25.0"));
+        assert_eq!(version("git version 1.8").as_deref(), Some("1.8.0"));
+        assert_eq!(version("hub version 2.14.2"), None);
+        assert_eq!(version("git version unknown"), None);
+
+        assert!(parse_version("git version 2.17.1").unwrap() < SPARSE_STDIN);
+        assert!(parse_version("git version 2.25.0").unwrap() >= SPARSE_STDIN);
+    }
+
+    #[test]
+    fn existing_worktree_is_reused() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let (bare, path, worktree) = checkout_from(&repo);
+        let _first = worktree.persistent();
+        let head = repo.head();
+        assert!(bare.is_intact_worktree(&git, &path, &head));
+
+        let data = || std::iter::once(PathSpec::Path(Path::new("tests")));
+        let _again = bare
+            .checkout(&git, &path, &head, &mut data(), false)
+            .persistent();
+        assert!(path.join("tests/data.bin").exists());
+        assert_eq!(registered_worktrees(&repo), 1);
+
+        // A damaged worktree is replaced instead.
+        std::fs::remove_file(path.join(".git")).unwrap();
+        assert!(!bare.is_intact_worktree(&git, &path, &head));
+        let _replaced = bare.checkout(&git, &path, &head, &mut data(), false);
+        assert!(path.join("tests/data.bin").exists());
+        assert_eq!(registered_worktrees(&repo), 1);
+    }
+
+    #[test]
+    fn additive_sparse_checkout() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.write("tests/tree/nested.bin", "nested");
+        repo.write("other/data.bin", "other");
+        repo.commit();
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let bare = ShallowBareRepository {
+            path: repo.0.join(".git"),
+        };
+        let head = repo.head();
+        let path = crate::unique_dir(&std::env::temp_dir(), "xtest-data-tree").unwrap();
+
+        let first = [Path::new("tests/tree")];
+        let mut first = first.iter().map(|path| PathSpec::Path(path));
+        let _first = bare
+            .checkout(&git, &path, &head, &mut first, true)
+            .persistent();
+
+        let second = [Path::new("tests/data.bin")];
+        let mut second = second.iter().map(|path| PathSpec::Path(path));
+        let _second = bare
+            .checkout(&git, &path, &head, &mut second, true)
+            .persistent();
+
+        let nested = path.join("tests/tree/nested.bin").exists();
+        let data = path.join("tests/data.bin").exists();
+        let other = path.join("other/data.bin").exists();
+        let _ = std::fs::remove_dir_all(&path);
+
+        assert!(nested && data, "both builds' paths are checked out");
+        assert!(!other);
+        assert_eq!(registered_worktrees(&repo), 1);
+    }
+
+    #[test]
+    fn recreated_worktree_is_not_reused() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let bare = ShallowBareRepository {
+            path: repo.0.join(".git"),
+        };
+        let head = repo.head();
+        // Within the repository, which git would find by searching upwards from the worktree.
+        let path = repo.0.join("checkout");
+        let data = || std::iter::once(PathSpec::Path(Path::new("tests")));
+        let _first = bare
+            .checkout(&git, &path, &head, &mut data(), false)
+            .persistent();
+
+        // Removed and created again, as by a concurrent build, while it is still registered.
+        std::fs::remove_dir_all(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        assert!(bare.is_worktree(&git, &path));
+        assert!(!bare.is_intact_worktree(&git, &path, &head));
+
+        let _replaced = bare
+            .checkout(&git, &path, &head, &mut data(), false)
+            .persistent();
+        assert!(path.join("tests/data.bin").exists());
+        // The enclosing repository was not checked out sparsely instead.
+        assert!(!repo.0.join(".git/info/sparse-checkout").exists());
+    }
+
+    #[test]
+    fn abbreviated_commit_is_resolved() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        let bare = ShallowBareRepository {
+            path: repo.0.join(".git"),
+        };
+        let head = repo.head();
+
+        let short = CommitId::abbreviated(&head.to_string()[..12].to_ascii_uppercase());
+        assert!(short.is_abbreviated());
+        let resolved = bare.resolve_commit(&git, &short);
+        assert_eq!(resolved.to_string(), head.to_string());
+        let resolved = git.resolve_in(&repo.0, &short);
+        assert_eq!(resolved.to_string(), head.to_string());
+
+        let unknown = CommitId::abbreviated("0000000");
+        let missing =
+            std::panic::catch_unwind(AssertUnwindSafe(|| bare.resolve_commit(&git, &unknown)));
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn worktree_removed_on_panic() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let mut checked_out = None;
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let (_, path, _worktree) = checkout_from(&repo);
+            assert!(path.join("tests/data.bin").exists());
+            checked_out = Some(path);
+            panic!("the test failed");
+        }));
+
+        assert!(result.is_err());
+        assert!(!checked_out.unwrap().exists());
+        assert_eq!(registered_worktrees(&repo), 0);
+    }
+
+    #[test]
+    fn stale_worktree_pruned() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let (bare, path, worktree) = checkout_from(&repo);
+        drop(worktree);
+        // Not panicking, so the data stays available.
+        assert!(path.join("tests/data.bin").exists());
+        assert_eq!(registered_worktrees(&repo), 1);
+
+        std::fs::remove_dir_all(&path).unwrap();
+        let git = Git::new().unwrap_or_else(|_| panic!("git is required"));
+        bare.prune_worktrees(&git);
+        assert_eq!(registered_worktrees(&repo), 0);
     }
 }
@@ -38,11 +38,17 @@
 //! CARGO_XTEST_DATA_FETCH=yes cargo test -- --nocapture
 //! ```
 //!
+//! To only collect the data a crate requires, without running its tests with the data, use the
+//! `plan` command of the `cargo-xtest-data` binary on the `.crate` file.
+//!
 //! ¹We need a place to store a shallow clone of the crate's source repository.
 #![forbid(unsafe_code)]
 mod git;
+mod sha256;
 
-use std::{borrow::Cow, env, ffi::OsString, fs, io, path::Path, path::PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use std::{env, ffi::OsStr, ffi::OsString, fs, io, path::Path, path::PathBuf};
 use tinyjson::JsonValue;
 
 /// A file or tree that was registered from [`Setup`].
@@ -57,8 +63,18 @@ pub struct Files {
 
 #[derive(Debug)]
 enum Managed {
-    // TODO: have a spec for the glob `<dir>/**.ext`?
     Files(PathBuf),
+    /// The files matching a pattern, such as `tests/*.bin`.
+    Glob(PathBuf),
+    /// A blob addressed by its object ID instead of a path in the tree.
+    Object(String),
+    /// A file or tree that the tests can do without.
+    Optional(PathBuf),
+    /// A file or tree that is presented at a local name of its own.
+    Renamed {
+        path: PathBuf,
+        name: PathBuf,
+    },
 }
 
 type FsItem<'lt> = &'lt mut PathBuf;
@@ -72,21 +88,153 @@ pub struct FsData {
     /// Map all configured items to their paths.
     /// This map will essentially be constant and we do not care about the VCS interpretation.
     map: Vec<PathBuf>,
+    /// Simple statistics about the data made available.
+    stats: FetchStats,
+    /// The repository that the data was attributed to, if not from a local checkout.
+    origin: Option<OsString>,
+    /// The worktree holding the data, removed when a test panics while it is still in use.
+    #[allow(dead_code)]
+    worktree: Option<git::Worktree>,
+    /// For each item of `map`, whether it was made available.
+    available: Vec<bool>,
+    /// For each item of `map`, the directory that it was materialized in.
+    roots: Vec<PathBuf>,
+    /// The version of the `git` that was used, if it could be determined.
+    git_version: Option<GitVersion>,
+    /// For each item of `map`, the paths of all files and directories within a tree.
+    contents: Vec<Vec<PathBuf>>,
+    /// For each item of `map`, the entries of `contents` relative to the item before mapping.
+    ///
+    /// These are sorted, unlike `contents` after a path map.
+    listing: Vec<Vec<PathBuf>>,
+    /// Where the build spent its time.
+    timings: BuildTimings,
+    /// Marks a fresh checkout as in use, so that [`Setup::retain()`] of other builds keeps it.
+    #[allow(dead_code)]
+    in_use: Option<git::FileWaitLock>,
+}
+
+/// What [`Setup::build()`] would do, as validated by [`Setup::build_dry_run()`].
+#[derive(Debug)]
+pub struct FetchPlan {
+    /// The repository URL of the crate.
+    pub repository: OsString,
+    /// The commit to check out, or `None` when using the files of a local checkout.
+    pub commit: Option<String>,
+    /// Whether `commit` is a full commit ID.
+    ///
+    /// An abbreviated ID of [`Setup::commit()`] is resolved with the repository of
+    /// [`Setup::reference_repo()`]. Otherwise only the build resolves it, with the objects it
+    /// fetches, and it is reported as given.
+    pub commit_resolved: bool,
+    /// Each registered path in registration order, with the pathspec that selects it.
+    pub resources: Vec<(PathBuf, String)>,
+    /// The pack objects to generate or to import.
+    pub pack_objects: Option<PathBuf>,
+}
+
+/// Statistics about the data that was made available by [`Setup::build()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FetchStats {
+    /// The number of objects imported from pack objects.
+    ///
+    /// For a local checkout this is the number of registered resources instead.
+    pub objects: usize,
+    /// The total size in bytes of the registered files and trees.
+    pub bytes: u64,
+    /// Whether the data was available without importing any objects, as in a local checkout.
+    pub from_cache: bool,
+}
+
+/// The time spent in each phase of [`Setup::build()`].
+///
+/// The phases do not overlap, so together they take at most the `total`. A phase that did not
+/// happen, such as a clone when testing from a local checkout, took zero time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuildTimings {
+    /// The time of the whole build.
+    pub total: Duration,
+    /// Writing pack objects, when testing from a local checkout.
+    pub pack: Duration,
+    /// Creating the shared bare repository, or opening it if it exists.
+    pub clone: Duration,
+    /// Fetching from the reference repository and importing pack objects.
+    pub fetch: Duration,
+    /// Adding the worktree and checking out with `sparse-checkout`.
+    pub checkout: Duration,
+    /// Checking out paths one by one, because `sparse-checkout` could not select them.
+    ///
+    /// This is `None` unless the fallback was used. It is slow with many paths or large trees,
+    /// prefer registering plain paths and using a version of git with `sparse-checkout`.
+    pub slow_checkout: Option<Duration>,
+}
+
+/// How many checkouts of earlier builds remain in the data directory, see [`Setup::retain()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// Never remove a checkout, which is the default.
+    KeepAll,
+    /// Keep the given number of the most recent checkouts, including that of the build.
+    KeepLatest(usize),
+    /// Remove all checkouts of earlier builds.
+    KeepNone,
+}
+
+/// How [`Setup::build()`] materializes the data of a packaged crate, see [`Setup::extract_mode()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtractMode {
+    /// Check out a worktree of the shared repository in the data directory, which is the default.
+    Checkout,
+    /// Extract an archive from `git archive --remote`, without a repository of our own.
+    RemoteArchive,
+}
+
+/// The outcome of [`Setup::validate()`], with one entry for each check that applies.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// The checks in the order they were performed.
+    pub checks: Vec<ValidationCheck>,
+}
+
+/// A single check of a [`ValidationReport`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationCheck {
+    /// A short name of what was checked, such as `repository` or `git`.
+    pub name: &'static str,
+    /// Whether the check passed.
+    pub passed: bool,
+    /// What was found, or why the check failed.
+    pub detail: String,
+}
+
+/// The version of `git` that was used, as reported by `git --version`.
+///
+/// Several features depend on it, for example `sparse-checkout` with patterns from `--stdin`
+/// requires git 2.25 or newer. Include it when reporting a failure to fetch data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GitVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
 }
 
 #[derive(Debug)]
-enum Source {
+enum Source<'paths> {
     /// The data source is the crate's repository at a specific commit id.
     VcsFromManifest {
         /// TODO: we should support other commit identifiers.
         commit_id: git::CommitId,
         /// Evidence how we plan to access the source.
-        git: git::Git,
+        git: git::Git<'paths>,
         /// The directory where we may put git-dir and checkout of the resources.
         datadir: PathBuf,
+        /// Whether the directory was chosen by the environment, not by `cargo`.
+        datadir_from_env: bool,
+        /// The directory of the crate within the repository, empty at its root.
+        path_in_vcs: PathBuf,
     },
     /// The data will be relative to the crate manifest.
-    Local(git::Git),
+    Local(git::Git<'paths>),
 }
 
 #[derive(Default, Debug)]
@@ -107,6 +255,21 @@ struct Resources<'paths> {
     /// those items. Basically, adding the crate should not be much more complex than making all
     /// paths a variable and then throwing a `xtest_data::setup!()` on top.
     unmanaged: Vec<FsItem<'paths>>,
+    /// The kind of file that registered items must have, by their key.
+    expected: Vec<(usize, Kind)>,
+    /// The keys of items whose blobs are left out of the pack objects.
+    lazy: Vec<usize>,
+    /// The keys of items whose files are made read-only after checking them out.
+    readonly: Vec<usize>,
+    /// The keys of items that are optional unless the commit contains them.
+    if_exists: Vec<usize>,
+}
+
+/// The kind of file expected by [`Setup::add_file()`] and [`Setup::add_tree()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    File,
+    Tree,
 }
 
 /// A builder to configure desired test data paths.
@@ -132,15 +295,53 @@ struct Resources<'paths> {
 #[derive(Debug)]
 pub struct Setup<'paths> {
     repository: OsString,
-    manifest: &'static str,
+    manifest: PathBuf,
     /// Have we determined to be local or in a crate?.
-    source: Source,
+    source: Source<'paths>,
     /// The resources that we store.
     resources: Resources<'paths>,
     /// A git pack archive with files.
     pack_objects: Option<OsString>,
+    /// The number of commits whose objects are included when generating pack objects.
+    pack_depth: usize,
+    /// The size of the largest blob that is included when generating pack objects.
+    pack_max_blob: Option<u64>,
+    /// A local repository from which to fetch the commit.
+    reference: Option<PathBuf>,
+    /// A fixed directory for the checkout, instead of a fresh one in the data directory.
+    checkout_dir: Option<PathBuf>,
+    /// Replace the previous contents of `checkout_dir`.
+    force_checkout: bool,
+    /// Add to the sparse-checkout patterns of a reused worktree instead of replacing them.
+    additive_sparse: bool,
+    /// Reject registered paths with uncommitted changes in a local checkout.
+    require_clean: bool,
+    /// Only warn when a generated pack differs from the data the tests used.
+    allow_dirty_pack: bool,
+    /// Trust the files of a local checkout without asking git whether they are tracked.
+    skip_tracked_check: bool,
+    /// The checkouts of earlier builds to keep in the data directory.
+    retention: Retention,
+    /// How the data of a packaged crate is materialized.
+    extract_mode: ExtractMode,
+    /// Why the remote may not be asked for an archive, if it may not.
+    fetch_refused: Option<&'static str>,
+    /// Decides for each resource whether it is checked out.
+    on_checkout: Option<CheckoutFilter<'paths>>,
+    /// Rewrites each final path before it is handed out.
+    map_paths: Option<PathMap<'paths>>,
+    /// Where to record the data that was made available.
+    lock_file: Option<PathBuf>,
+    /// The `CARGO_TARGET_TMPDIR` of the caller, if any.
+    target_tmpdir: Option<PathBuf>,
 }
 
+/// A caller provided callback, which is opaque to `Debug`.
+struct CheckoutFilter<'paths>(Box<dyn FnMut(&Path) -> bool + 'paths>);
+
+/// A caller provided path rewrite, which is opaque to `Debug`.
+struct PathMap<'paths>(Box<dyn FnMut(&Path) -> PathBuf + 'paths>);
+
 /// The options determined from the compile time environment of the crate that called us.
 ///
 /// This is every environment data we are gather from the `setup` macro, which allows us to get the
@@ -203,6 +404,50 @@ macro_rules! setup {
     };
 }
 
+/// Register and build several fixtures by name, as a struct with one path for each.
+///
+/// This is [`setup!`] followed by [`Setup::add()`] for each path and [`Setup::build()`]. Each
+/// field holds the [`FsData::path()`] of its fixture, which keeps names and paths in sync. Use
+/// the `Setup` directly for any other configuration.
+///
+/// The struct also owns the [`FsData`], so the paths stay valid and in use for as long as the
+/// struct lives.
+///
+/// # Example
+///
+/// ```
+/// let fixtures = xtest_data::fixtures! {
+///     data = "tests/data.zip",
+///     hello = "tests/tree/hello.txt",
+/// };
+///
+/// assert!(fixtures.data.ends_with("tests/data.zip"));
+/// assert_eq!(std::fs::read_to_string(&fixtures.hello).unwrap(), "Hello\n");
+/// ```
+///
+/// ## Panics
+///
+/// This panics for the same reasons as [`setup!`] and [`Setup::build()`].
+#[macro_export]
+macro_rules! fixtures {
+    ($($name:ident = $path:expr),* $(,)?) => {{
+        #[derive(Debug)]
+        struct Fixtures {
+            $(pub $name: ::std::path::PathBuf,)*
+            /// Holds the checkout, the paths above point into it.
+            _fs_data: $crate::FsData,
+        }
+
+        let mut vcs = $crate::setup!();
+        $(let $name = vcs.add($path);)*
+        let data = vcs.build();
+        Fixtures {
+            $($name: data.path(&$name).to_owned(),)*
+            _fs_data: data,
+        }
+    }};
+}
+
 #[doc(hidden)]
 pub fn _setup(options: EnvOptions) -> Setup<'static> {
     let EnvOptions {
@@ -210,25 +455,58 @@ pub fn _setup(options: EnvOptions) -> Setup<'static> {
         manifest_dir: manifest,
         target_tmpdir: tmpdir,
     } = options;
+    setup_from_parts(repository, Path::new(manifest), tmpdir.map(Path::new))
+}
+
+fn setup_from_parts(repository: &str, manifest: &Path, tmpdir: Option<&Path>) -> Setup<'static> {
+    // Allow the override, which also works around a missing repository.
+    let repository = env::var_os("CARGO_XTEST_DATA_REPOSITORY_ORIGIN")
+        .unwrap_or_else(|| OsString::from(repository));
+
     if repository.is_empty() {
-        inconclusive(&mut "The crate must have a valid URL in `package.repository`");
+        inconclusive(&mut concat!(
+            "The crate must have a valid URL in `package.repository`.\n",
+            "Add `repository = \"https://…\"` to the `[package]` section of its `Cargo.toml`. ",
+            "With `repository.workspace = true` the workspace must define it in `[workspace.package]`.\n",
+            "Alternatively, set `CARGO_XTEST_DATA_REPOSITORY_ORIGIN` to the URL of the repository.",
+        ));
     }
 
-    // Now allow the override.
-    let repository = OsString::from(repository);
-
     // Make sure this is an integration test, or at least we have the dir.
     // We don't want to block building over this (e.g. the crate itself here) but we _do_ want to
     // restrict running this `setup` function
-    let integration_test_tempdir = tmpdir.map(Path::new);
+    let integration_test_tempdir = tmpdir;
 
     let vcs_info_path = env::var_os("CARGO_XTEST_VCS_INFO");
     let force_vcs = vcs_info_path.is_some();
 
-    let vcs_info_path = vcs_info_path.as_ref().map_or_else(
-        || Path::new(manifest).join(".cargo_vcs_info.json"),
-        PathBuf::from,
-    );
+    let vcs_info_path = vcs_info_path
+        .as_ref()
+        .map_or_else(|| manifest.join(".cargo_vcs_info.json"), PathBuf::from);
+
+    let verbose = env::var_os("CARGO_XTEST_DATA_VERBOSE").map_or(false, |var| var == "1");
+    let allow_dirty_pack =
+        env::var_os("CARGO_XTEST_DATA_ALLOW_DIRTY").map_or(false, |var| var == "1");
+    let fetch_refused = fetch_refused(|name| env::var_os(name));
+
+    let pack_depth = match env::var_os("CARGO_XTEST_DATA_PACK_DEPTH") {
+        None => 1,
+        Some(depth) => depth
+            .to_str()
+            .and_then(|depth| depth.parse().ok())
+            .filter(|&depth| depth > 0)
+            .unwrap_or_else(|| {
+                inconclusive(&mut "`CARGO_XTEST_DATA_PACK_DEPTH` must be a positive number")
+            }),
+    };
+
+    let pack_max_blob = env::var_os("CARGO_XTEST_DATA_PACK_MAX_BLOB").map(|max| {
+        max.to_str()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or_else(|| {
+                inconclusive(&mut "`CARGO_XTEST_DATA_PACK_MAX_BLOB` must be a number of bytes")
+            })
+    });
 
     let (source, pack_objects);
     if vcs_info_path.exists() {
@@ -255,32 +533,36 @@ pub fn _setup(options: EnvOptions) -> Setup<'static> {
             .get::<String>()
             .map(|id| git::CommitId::from(&**id))
             .unwrap_or_else(|| inconclusive(&mut "VCS commit ID is not a string"));
+        // Recorded by cargo since 1.58, absent for a crate at the root of the repository before.
+        let path_in_vcs = vcs
+            .get_key("path_in_vcs")
+            .and_then(|path| path.get::<String>())
+            .map_or_else(PathBuf::new, PathBuf::from);
+
+        // The repository is only fetched from in this case. Otherwise a typo only surfaces as an
+        // opaque error of git, much later.
+        if let Some(url) = repository.to_str().filter(|url| !is_plausible_remote(url)) {
+            inconclusive(&mut format!(
+                "The repository URL looks malformed: {}\nExpected a URL such as `https://host/path`, `git@host:path`, `file:///path` or a local path.",
+                url
+            ));
+        }
 
         // Okay, that makes sense. We know _what_ to access.
         // Now let's also try to find out how we will access it. Let's find `git`.
         // To shell out to because we are lazy.
-        let git = git::Git::new().unwrap_or_else(|mut err| inconclusive(&mut err));
+        let mut git = git::Git::new().unwrap_or_else(|mut err| inconclusive(&mut err));
+        git.verbose = verbose;
 
-        let datadir = integration_test_tempdir
-            .map(Cow::Borrowed)
-            .or_else(|| {
-                    let environment_temp = std::env::var_os("CARGO_XTEST_DATA_TMPDIR")
-                        .or_else(|| std::env::var_os("TMPDIR"))
-                        .map(PathBuf::from)?;
-                    // TODO: nah, in this case we should have some distinguisher for the exact crate
-                    // name and version in the tmpdir. At least that would catch the gravest of errors
-                    // when testing many crates at the same time. (Although sharing the git dir would
-                    // be an advantage).
-                    Some(Cow::Owned(environment_temp))
-                })
-            .expect("This setup must only be called in an integration test or benchmark, or with an explicit TMPDIR")
-            .into_owned();
+        let datadir = select_datadir(integration_test_tempdir, |name| env::var_os(name));
 
         pack_objects = std::env::var_os("CARGO_XTEST_DATA_PACK_OBJECTS");
         source = Source::VcsFromManifest {
             commit_id,
             git,
             datadir,
+            datadir_from_env: integration_test_tempdir.is_none(),
+            path_in_vcs,
         };
     } else if force_vcs {
         inconclusive(&mut format!(
@@ -289,22 +571,57 @@ pub fn _setup(options: EnvOptions) -> Setup<'static> {
         ));
     } else {
         // Check that we can recognize tracked files.
-        let git = git::Git::new().unwrap_or_else(|mut err| inconclusive(&mut err));
+        let mut git = git::Git::new().unwrap_or_else(|mut err| inconclusive(&mut err));
+        git.verbose = verbose;
         source = Source::Local(git);
         pack_objects = std::env::var_os("CARGO_XTEST_DATA_PACK_OBJECTS");
     };
 
-    // And finally this must be valid.
-    if repository.is_empty() {
-        inconclusive(&mut "The repository must have a valid URL");
-    }
-
     Setup {
         repository,
-        manifest,
+        manifest: manifest.to_owned(),
         source,
         resources: Resources::default(),
         pack_objects,
+        pack_depth,
+        pack_max_blob,
+        reference: None,
+        checkout_dir: None,
+        force_checkout: false,
+        additive_sparse: false,
+        require_clean: false,
+        allow_dirty_pack,
+        skip_tracked_check: false,
+        retention: Retention::KeepAll,
+        extract_mode: ExtractMode::Checkout,
+        fetch_refused,
+        on_checkout: None,
+        map_paths: None,
+        lock_file: None,
+        target_tmpdir: tmpdir.map(Path::to_owned),
+    }
+}
+
+impl Setup<'static> {
+    /// Create a builder from explicit information, instead of the caller's build environment.
+    ///
+    /// This is for harnesses that run the tests of crates they did not author, where [`setup!`]
+    /// can not be expanded in the crate itself. The macro remains the preferred way for crate
+    /// authors.
+    ///
+    /// The caller must uphold the invariants that `cargo` provides to the macro:
+    /// * `repository` is the `package.repository` of the crate, a URL of its git repository.
+    /// * `manifest_dir` is the absolute path of the directory containing the crate's manifest.
+    ///   When this is an unpacked crate archive it must contain the `.cargo_vcs_info.json` of the
+    ///   archive, otherwise it must be within a git working tree.
+    /// * `data_dir` is a directory private to the tests of this crate, like `CARGO_TARGET_TMPDIR`.
+    ///   When `None` the `CARGO_XTEST_DATA_TMPDIR` or `TMPDIR` of the environment are used.
+    ///
+    /// ## Panics
+    ///
+    /// This panics under the same conditions as [`setup!`].
+    pub fn from_parts(repository: &str, manifest_dir: &Path, data_dir: Option<&Path>) -> Self {
+        setup_from_parts(repository, manifest_dir, data_dir)
     }
 }
 
@@ -312,7 +629,11 @@ impl<'lt> Setup<'lt> {
     /// Register some paths to rewrite their location.
     ///
     /// The paths should be relative to the crate's manifest. For example, to refer to data in your
-    /// `tests` directory you would use `PathBuf::from("tests/data.zip")`.
+    /// `tests` directory you would use `PathBuf::from("tests/data.zip")`. Each path is normalized
+    /// when registered, and this panics for a path that is absolute or that leaves the manifest
+    /// directory through `..` components. In particular, do not resolve a path against
+    /// `CARGO_MANIFEST_DIR` yourself, since the data may be checked out elsewhere. After the build
+    /// every rewritten path is absolute.
     ///
     /// The paths will be registered internally. If the repository is local they will be rewritten
     /// to be relative to the manifest location. If the repository is a crate distribution then the
@@ -321,6 +642,10 @@ impl<'lt> Setup<'lt> {
     ///
     /// Those actions will happen when you call [`Setup::build()`].
     ///
+    /// A path may name a file or a directory. A file is checked out on its own. A directory is
+    /// checked out with its complete subtree, so every file below it exists under the rewritten
+    /// path and can be listed with `std::fs::read_dir`.
+    ///
     /// # Example
     ///
     /// ```
@@ -333,7 +658,12 @@ impl<'lt> Setup<'lt> {
     /// assert!(path.exists(), "{}", path.display());
     /// ```
     pub fn rewrite(mut self, iter: impl IntoIterator<Item = &'lt mut PathBuf>) -> Self {
-        self.resources.unmanaged.extend(iter);
+        self.resources
+            .unmanaged
+            .extend(iter.into_iter().map(|path| {
+                *path = normalize_relative(std::mem::take(path));
+                path
+            }));
         self
     }
 
@@ -342,6 +672,9 @@ impl<'lt> Setup<'lt> {
     /// The return value is a key that can later be used in [`FsData`]. All the files under this
     /// location will be checked out when `Setup::build()` is called in a crate-build.
     ///
+    /// The same rules as for [`Setup::rewrite()`] apply to the path: it must be relative to the
+    /// manifest and must not leave its directory. An owned `PathBuf` is moved in without a copy.
+    ///
     /// # Example
     ///
     /// ```
@@ -352,149 +685,2565 @@ impl<'lt> Setup<'lt> {
     /// let path = testdata.path(&datazip);
     /// assert!(path.exists(), "{}", path.display());
     /// ```
-
-    pub fn add(&mut self, path: impl AsRef<Path>) -> Files {
-        fn path_impl(resources: &mut Resources, path: &Path) -> usize {
-            let item = Managed::Files(path.to_owned());
+    pub fn add(&mut self, path: impl Into<PathBuf>) -> Files {
+        fn path_impl(resources: &mut Resources, path: PathBuf) -> usize {
+            let item = Managed::Files(normalize_relative(path));
             let key = resources.relative_files.len();
             resources.relative_files.push(item);
             key
         }
 
-        let key = path_impl(&mut self.resources, path.as_ref());
+        let key = path_impl(&mut self.resources, path.into());
         Files { key }
     }
 
-    /// Run the final validation and perform rewrites.
+    /// Register the path of a single file.
     ///
-    /// Returns the frozen dictionary of file mappings that had been registered with
-    /// [`Setup::add()`]. This allows retrieving the final data paths for those items.
+    /// Like [`Setup::add()`], but the build fails if the path is a directory. A tree where a file
+    /// is expected would otherwise only surface as a confusing error of the test itself.
     ///
-    /// ## Panics
+    /// # Example
     ///
-    /// This will panic if:
-    /// * Any registered file or tree is not tracked in the VCS.
-    /// * You have not allowed retrieving data from the VCS.
-    /// * It was not possible to retrieve the data from the VCS.
-    pub fn build(self) -> FsData {
-        let mut map;
-        match self.source {
-            Source::Local(git) => {
-                let dir = git::CrateDir::new(self.manifest, &git);
-                let datapath = Path::new(self.manifest);
-                dir.tracked(&git, &mut self.resources.path_specs());
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let datazip = vcs.add_file("tests/data.zip");
+    /// let testdata = vcs.build();
+    ///
+    /// assert!(testdata.path(&datazip).is_file());
+    /// ```
+    pub fn add_file(&mut self, path: impl Into<PathBuf>) -> Files {
+        let file = self.add(path);
+        self.resources.expected.push((file.key, Kind::File));
+        file
+    }
 
-                if let Some(pack_objects) = self.pack_objects {
-                    std::fs::create_dir_all(&pack_objects)
-                        .unwrap_or_else(|mut err| inconclusive(&mut err));
-                    dir.pack_objects(&git, &mut self.resources.path_specs(), pack_objects);
-                }
+    /// Register the path of a tree of files.
+    ///
+    /// Like [`Setup::add()`], but the build fails if the path is not a directory.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let tree = vcs.add_tree("tests/tree");
+    /// let testdata = vcs.build();
+    ///
+    /// assert!(testdata.path(&tree).join("hello.txt").is_file());
+    /// ```
+    pub fn add_tree(&mut self, path: impl Into<PathBuf>) -> Files {
+        let tree = self.add(path);
+        self.resources.expected.push((tree.key, Kind::Tree));
+        tree
+    }
 
-                map = vec![];
-                self.resources.relative_files.iter().for_each(|path| {
-                    map.push(datapath.join(path.as_path()));
-                });
+    /// Register the path of a file or tree whose data is not in the pack objects.
+    ///
+    /// Like [`Setup::add()`], but when generating pack objects from a local checkout only the
+    /// trees leading to the path are written, not its blobs. This keeps a large asset, which few
+    /// tests read, out of the offline data while the small fixtures stay in it. Testing the crate
+    /// archive then retrieves the blobs when checking out the path, which requires a reference
+    /// repository that has them (see [`Setup::reference_repo()`]).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let datazip = vcs.add_lazy("tests/data.zip");
+    /// let testdata = vcs.build();
+    ///
+    /// assert!(testdata.path(&datazip).exists());
+    /// ```
+    pub fn add_lazy(&mut self, path: impl Into<PathBuf>) -> Files {
+        let file = self.add(path);
+        self.resources.lazy.push(file.key);
+        file
+    }
 
-                self.resources
-                    .unmanaged
-                    .into_iter()
-                    .for_each(|item| set_root(datapath, item));
-            }
-            Source::VcsFromManifest {
-                commit_id,
-                datadir,
-                git,
-            } => {
-                let origin = git::Origin {
-                    url: self.repository,
-                };
+    /// Register the path of a file or tree whose checked out files are read-only.
+    ///
+    /// Like [`Setup::add()`], but after checking out the data of a packaged crate the files are
+    /// made read-only: on unix the write permissions are removed, on Windows the read-only
+    /// attribute is set. A test that writes to a fixture by accident then fails loudly, instead of
+    /// corrupting a checkout that later tests reuse, such as that of [`Setup::checkout_into()`]
+    /// or [`Setup::prewarm()`]. Directories stay writable so the checkout can still be removed, on
+    /// Windows the attribute is cleared again before removing a checkout.
+    ///
+    /// The files of a local checkout are your sources, their permissions are left alone.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let datazip = vcs.add_readonly("tests/data.zip");
+    /// let testdata = vcs.build();
+    ///
+    /// assert!(testdata.path(&datazip).exists());
+    /// ```
+    pub fn add_readonly(&mut self, path: impl Into<PathBuf>) -> Files {
+        let file = self.add(path);
+        self.resources.readonly.push(file.key);
+        file
+    }
 
-                let gitpath = datadir.join("xtest-data-git");
-                let datapath = unique_dir(&datadir, "xtest-data-tree")
-                    .unwrap_or_else(|mut err| inconclusive(&mut err));
+    /// Register the path of a file or tree that the tests can do without.
+    ///
+    /// Unlike [`Setup::add()`] the setup does not fail if the path is not tracked, or not contained
+    /// in the commit of a crate-build. Query [`FsData::is_available()`] before accessing it. The
+    /// same rules for the path apply.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let fixture = vcs.add_optional("tests/large-fixture.bin");
+    /// let testdata = vcs.build();
+    ///
+    /// if !testdata.is_available(&fixture) {
+    ///     return;
+    /// }
+    /// ```
+    pub fn add_optional(&mut self, path: impl Into<PathBuf>) -> Files {
+        let item = Managed::Optional(normalize_relative(path.into()));
+        let key = self.resources.relative_files.len();
+        self.resources.relative_files.push(item);
+        Files { key }
+    }
 
-                let shallow;
-                if let Some(pack_objects) = self.pack_objects {
-                    shallow = git.bare(gitpath, &commit_id);
-                    shallow.unpack(&git, &pack_objects);
-                } else {
-                    panic!("Requested test data from {} but have no packed artifacts to load. Provide an explicit path to a directory to unpack via the `CARGO_XTEST_DATA_PACK_OBJECTS` environment variable", Path::new(&origin.url).display());
-                }
+    /// Register a path that must be available if, and only if, the commit contains it.
+    ///
+    /// Tests of a packaged crate use the data of its pinned commit, which may predate a fixture
+    /// that newer tests use. Before anything is checked out, `git cat-file -e` checks whether the
+    /// path exists at that commit, or at `HEAD` in a local checkout. If it does, this is the same
+    /// as [`Setup::add()`]. Otherwise it is unavailable as with [`Setup::add_optional()`], query
+    /// [`FsData::is_available()`] before accessing it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let fixture = vcs.add_if_exists("tests/data.zip");
+    /// let testdata = vcs.build();
+    ///
+    /// assert!(testdata.is_available(&fixture));
+    /// ```
+    pub fn add_if_exists(&mut self, path: impl Into<PathBuf>) -> Files {
+        let file = self.add(path);
+        self.resources.if_exists.push(file.key);
+        file
+    }
 
-                shallow.checkout(
-                    &git,
-                    &datapath,
-                    &commit_id,
-                    &mut self.resources.path_specs(),
-                );
-                map = vec![];
-                self.resources.relative_files.iter().for_each(|path| {
-                    map.push(datapath.join(path.as_path()));
-                });
-                self.resources
-                    .unmanaged
-                    .into_iter()
-                    .for_each(|item| set_root(&datapath, item));
-            }
+    /// Register the files matching a glob pattern.
+    ///
+    /// The pattern has the syntax of a `:(glob)` pathspec of git: `*` matches within one path
+    /// component, `**/` matches any number of directories, and `?` and `[…]` match a single
+    /// character. It matches the tracked files of a local checkout, or the files of the commit of
+    /// a packaged crate. List them with [`FsData::entries()`]. The path of the resource is its
+    /// directory before the first wildcard, so [`FsData::find()`] looks up matches relative to it.
+    ///
+    /// A pattern that matches no file is not an error, the list of entries is then empty. In a
+    /// packaged crate the matches are checked out one by one, without `sparse-checkout`, which is
+    /// slow for a large number of files.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let texts = vcs.add_glob("tests/tree/**/*.txt");
+    /// let testdata = vcs.build();
+    ///
+    /// assert_eq!(testdata.entries(&texts).len(), 2);
+    /// assert!(testdata.path(&texts).ends_with("tests/tree"));
+    /// ```
+    pub fn add_glob(&mut self, pattern: impl Into<PathBuf>) -> Files {
+        let item = Managed::Glob(normalize_relative(pattern.into()));
+        let key = self.resources.relative_files.len();
+        self.resources.relative_files.push(item);
+        Files { key }
+    }
+
+    /// Register the path of a file or tree, to be presented at a local name of its own.
+    ///
+    /// The path is checked out as with [`Setup::add()`], then copied to `local_name` in a separate
+    /// directory of the data, which is the path returned by [`FsData::path()`]. This gives a
+    /// fixture a stable name regardless of where it is kept in the repository. The same rules as
+    /// for the path apply to the local name.
+    ///
+    /// ## Panics
+    ///
+    /// If the local name, or a path that contains it or is contained in it, was already chosen
+    /// for another resource.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// let mut vcs = xtest_data::setup!();
+    /// let hello = vcs.add_with_rename("tests/tree/hello.txt", Path::new("greeting.txt"));
+    /// let testdata = vcs.build();
+    ///
+    /// assert!(testdata.path(&hello).ends_with("greeting.txt"));
+    /// ```
+    pub fn add_with_rename(&mut self, repo_path: &str, local_name: &Path) -> Files {
+        let path = normalize_relative(PathBuf::from(repo_path));
+        let name = normalize_relative(local_name.to_owned());
+
+        let taken = self
+            .resources
+            .renames()
+            .find(|(_, other)| other.starts_with(&name) || name.starts_with(other));
+        if let Some((other, taken)) = taken {
+            inconclusive(&mut format!(
+                "The local name `{}` for `{}` collides with `{}`, chosen for `{}`",
+                name.display(),
+                path.display(),
+                taken.display(),
+                other.display()
+            ));
         }
 
-        // In the end we just discard some information.
-        // We don't really need it anymore after the checks.
-        //
-        // TODO: of course we could avoid actually checking files onto the disk if we had some kind
-        // of `io::Read` abstraction that read them straight from `git cat` instead. But chances
-        // are you'll like your files and directory structures.
-        FsData { map }
+        let key = self.resources.relative_files.len();
+        self.resources
+            .relative_files
+            .push(Managed::Renamed { path, name });
+        Files { key }
     }
-}
 
-impl Resources<'_> {
-    pub fn path_specs(&self) -> impl Iterator<Item = git::PathSpec<'_>> {
-        let values = self.relative_files.iter().map(Managed::as_path_spec);
-        let unmanaged = self.unmanaged.iter().map(|x| git::PathSpec::Path(&**x));
-        values.chain(unmanaged)
+    /// Register several paths of files or trees at once.
+    ///
+    /// This is equivalent to calling [`Setup::add()`] for each path in order. The returned keys are
+    /// in the same order as the paths.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let keys = vcs.add_many(["tests/data.zip", "tests/tree/hello.txt", "tests/tree/nested"]);
+    /// let testdata = vcs.build();
+    ///
+    /// assert!(testdata.path(&keys[0]).ends_with("tests/data.zip"));
+    /// assert!(testdata.path(&keys[1]).ends_with("tests/tree/hello.txt"));
+    /// assert!(testdata.path(&keys[2]).join("world.txt").exists());
+    /// ```
+    pub fn add_many(&mut self, paths: impl IntoIterator<Item = impl Into<PathBuf>>) -> Vec<Files> {
+        paths.into_iter().map(|path| self.add(path)).collect()
     }
-}
 
-impl FsData {
-    /// Retrieve the rewritten path of a file or tree of files.
-    pub fn path(&self, file: &Files) -> &Path {
-        self.map.get(file.key).unwrap().as_path()
+    /// Register a blob by its git object ID instead of by a path.
+    ///
+    /// The blob is written to a file of its own, named by the ID, which is the path returned by
+    /// [`FsData::path()`]. It does not need to appear in the tree of any particular commit, which
+    /// suits test vectors that are addressed by their content. When testing from a local checkout
+    /// it is read from the repository and included when writing pack objects.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// let mut vcs = xtest_data::setup!();
+    /// let vector = vcs.add_object("ce013625030ba8dba906f756967f9e9ca394464a");
+    /// let testdata = vcs.build();
+    ///
+    /// let content = std::fs::read(testdata.path(&vector)).unwrap();
+    /// ```
+    ///
+    /// ## Notes and tags
+    ///
+    /// Test vectors attached as a git note, or as the target of a tag, are such blobs. Register
+    /// the ID of the note that `git notes list <commit>` prints, or that of the blob the tag
+    /// points to, from `git rev-parse <tag>^{}`. The message of an annotated tag is not a blob
+    /// itself: to use it, tag a blob holding the data instead, for example with
+    /// `git tag -a vectors $(git hash-object -w vectors.bin)`.
+    pub fn add_object(&mut self, oid: &str) -> Files {
+        let is_hex = oid.bytes().all(|b| b.is_ascii_hexdigit());
+        if !is_hex || !(oid.len() == 40 || oid.len() == 64) {
+            inconclusive(&mut format!("`{}` is not a git object ID", oid));
+        }
+
+        let key = self.resources.relative_files.len();
+        self.resources
+            .relative_files
+            .push(Managed::Object(oid.to_ascii_lowercase()));
+        Files { key }
     }
-}
 
-impl Managed {
-    pub fn as_path(&self) -> &Path {
-        match self {
-            Managed::Files(path) => path,
+    /// Pin the commit from which data is checked out.
+    ///
+    /// By default the commit recorded by cargo in `.cargo_vcs_info.json` is used, which makes the
+    /// test data reproducible for the packaged crate. Use this to reproduce an issue against the
+    /// fixtures of a different, for instance historical, commit instead. The objects of that commit
+    /// must be available in the pack objects.
+    ///
+    /// The ID may be abbreviated to at least 4 hexadecimal digits. It is resolved against the
+    /// reference repository, or else the pack objects, and the build panics if it is ambiguous.
+    ///
+    /// This has no effect when testing from a local checkout, where the files in the working tree
+    /// are used directly.
+    pub fn commit(mut self, id: &str) -> Self {
+        if let Source::VcsFromManifest { commit_id, .. } = &mut self.source {
+            *commit_id = git::CommitId::abbreviated(id);
         }
+
+        self
     }
 
-    fn as_path_spec(&self) -> git::PathSpec<'_> {
-        match self {
-            Managed::Files(path) => git::PathSpec::Path(path),
-        }
+    /// Fetch the data from a local repository, such as a mirror that CI keeps warm.
+    ///
+    /// The path must refer to a git repository, bare or with a working tree, that contains the
+    /// commit of the packaged crate. Its objects are fetched into our own repository which avoids
+    /// the network entirely and makes the pack objects optional.
+    ///
+    /// This has no effect when testing from a local checkout.
+    pub fn reference_repo(mut self, path: PathBuf) -> Self {
+        self.reference = Some(path);
+        self
     }
-}
 
-fn set_root(path: &Path, dir: &mut PathBuf) {
-    *dir = path.join(&*dir)
-}
+    /// Check out the data into a fixed directory, instead of a fresh one for each build.
+    ///
+    /// This gives a stable location that can be inspected after the test or reused by later steps,
+    /// and the files remain there even if the test fails. Concurrent builds into the same directory
+    /// are serialized. The checkout of an earlier build is updated in place. If the directory holds
+    /// anything else the build panics, unless replacing its contents is allowed with
+    /// [`Setup::force_checkout()`].
+    ///
+    /// This has no effect when testing from a local checkout.
+    pub fn checkout_into(mut self, dir: PathBuf) -> Self {
+        self.checkout_dir = Some(dir);
+        self
+    }
 
-// We do not use tempdir. This should already be done by our environment (e.g. cargo).
-fn unique_dir(base: &Path, prefix: &str) -> Result<PathBuf, std::io::Error> {
-    let mut rng = nanorand::tls::tls_rng();
-    assert!(matches!(
-        Path::new(prefix).components().next(),
-        Some(std::path::Component::Normal(_))
-    ));
-    assert!(Path::new(prefix).components().nth(1).is_none());
+    /// Allow [`Setup::checkout_into()`] to replace the existing contents of its directory.
+    pub fn force_checkout(mut self, force: bool) -> Self {
+        self.force_checkout = force;
+        self
+    }
 
-    let mut buffer = prefix.to_string();
-    let mut generate_name = move || -> PathBuf {
-        use nanorand::Rng;
+    /// Reject registered files and trees with uncommitted changes, staged or not.
+    ///
+    /// By default a local checkout only requires the resources to be tracked, and tests run with
+    /// their modified contents. Such a test may not be reproducible from the packaged crate, which
+    /// contains the committed data. Resources of [`Setup::add_optional()`] are not checked.
+    ///
+    /// This has no effect when testing from a crate archive.
+    pub fn require_clean(mut self) -> Self {
+        self.require_clean = true;
+        self
+    }
+
+    /// Use the files of a local checkout without checking that git tracks them.
+    ///
+    /// Each build otherwise runs `git status` over the registered paths, which adds up for suites
+    /// that register many paths. This trades that guarantee for speed while iterating on tests:
+    /// a test may pass with a file that is missing from the packaged crate. Only existence on disk
+    /// is still checked, and [`Setup::require_clean()`] has no effect.
+    ///
+    /// Never rely on this for a packaged crate, and do not enable it in CI. It is ignored when
+    /// testing from a crate archive, which always checks out the committed data.
+    pub fn skip_tracked_check(mut self) -> Self {
+        self.skip_tracked_check = true;
+        self
+    }
+
+    /// Choose how the data of a packaged crate is materialized.
+    ///
+    /// With [`ExtractMode::RemoteArchive`] the registered paths are requested in one round trip
+    /// with `git archive --remote` from the repository URL, and extracted into a fresh directory.
+    /// This skips creating the shared repository, which saves time for a small set of fixtures.
+    /// As it accesses the network, it is only used with `CARGO_XTEST_DATA_FETCH=yes` and never
+    /// while `CARGO_NET_OFFLINE` is set.
+    /// The server must support the archive protocol, and allow archiving the commit by setting
+    /// `uploadArchive.allowUnreachable`. Many hosting services do neither.
+    ///
+    /// Whenever the archive can not be used the data is checked out as usual: when the server
+    /// refuses the archive, when a path is missing from the commit, and for resources that need a
+    /// repository, which are those of [`Setup::add_object()`] and [`Setup::add_glob()`] as well
+    /// as [`Setup::checkout_into()`]. Extracted checkouts are not removed by [`Setup::retain()`].
+    ///
+    /// This has no effect when testing from a local checkout.
+    pub fn extract_mode(mut self, mode: ExtractMode) -> Self {
+        self.extract_mode = mode;
+        self
+    }
+
+    /// Remove the checkouts of earlier builds from the data directory.
+    ///
+    /// Each build of a packaged crate checks out its data into a fresh directory, and these
+    /// accumulate in the data directory until it is cleaned. With a limit, a build removes the
+    /// oldest checkouts beyond it, which bounds the disk usage of CI while the shared repository
+    /// still caches the objects. Checkouts that another build still uses are skipped, as are those
+    /// of [`Setup::checkout_into()`] and [`Setup::prewarm()`].
+    ///
+    /// This has no effect when testing from a local checkout.
+    pub fn retain(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Keep the files that earlier builds checked out into the directory of
+    /// [`Setup::checkout_into()`].
+    ///
+    /// A build that reuses the worktree of an earlier one replaces its `sparse-checkout` patterns
+    /// by default, so only the resources of the latest build remain. With this, the patterns of
+    /// the build are added to those already present, including any configured by yourself. One
+    /// worktree can then serve several tests that register different paths over time. The
+    /// patterns do not use cone mode, which is slower to match in large repositories.
+    pub fn additive_sparse(mut self) -> Self {
+        self.additive_sparse = true;
+        self
+    }
+
+    /// Inspect each resource just before it is checked out, skipping it when this returns `false`.
+    ///
+    /// The callback is called with the path relative to the manifest, as in [`FetchPlan`], so it
+    /// can be cross-referenced with a prior dry run. A skipped resource is not written to the data
+    /// directory, its path in [`FsData`] does not exist. In a local checkout nothing is written
+    /// and the callback is not called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!()
+    ///     .on_checkout(|path| path.starts_with("tests"));
+    /// let _ = vcs.add("tests/data.zip");
+    /// let testdata = vcs.build();
+    /// ```
+    pub fn on_checkout(mut self, filter: impl FnMut(&Path) -> bool + 'lt) -> Self {
+        self.on_checkout = Some(CheckoutFilter(Box::new(filter)));
+        self
+    }
+
+    /// Rewrite every final path before it is stored in the returned [`FsData`].
+    ///
+    /// The callback sees the absolute path of each resource, in the local checkout or the data
+    /// directory, and returns the path under which it is reported instead. This applies to the
+    /// paths in [`FsData`] as well as those rewritten in place by [`Setup::rewrite()`], in
+    /// both a local checkout and a packaged crate. The files themselves are not moved, the
+    /// callback is responsible for returning a path that still refers to them, e.g. a
+    /// canonicalized or symlinked location.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!()
+    ///     .map_paths(|path| path.canonicalize().unwrap_or_else(|_| path.to_owned()));
+    /// let datazip = vcs.add("tests/data.zip");
+    /// let testdata = vcs.build();
+    /// assert!(testdata.path(&datazip).is_absolute());
+    /// ```
+    pub fn map_paths(mut self, map: impl FnMut(&Path) -> PathBuf + 'lt) -> Self {
+        self.map_paths = Some(PathMap(Box::new(map)));
+        self
+    }
+
+    /// Record exactly which data was made available, as JSON in a file at `path`.
+    ///
+    /// The record is written by a successful [`Setup::build()`] and contains the origin, the
+    /// commit, and each resource with its pathspec. For each file of a resource, as it exists in
+    /// the data directory, it records the SHA-256 of the contents and the git object ID. Files are
+    /// named relative to [`FsData::root()`]. Comparing the records of two runs detects drift in
+    /// the test data. In a local checkout there is no origin or commit, the files are those of the
+    /// working tree.
+    pub fn write_lock(mut self, path: PathBuf) -> Self {
+        self.lock_file = Some(path);
+        self
+    }
+
+    /// Show the output of all `git` commands, for diagnosing a failing setup.
+    ///
+    /// This is also enabled by setting `CARGO_XTEST_DATA_VERBOSE=1` in the environment.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        match &mut self.source {
+            Source::Local(git) | Source::VcsFromManifest { git, .. } => git.verbose = verbose,
+        }
+
+        self
+    }
+
+    /// Report the command line of every invocation of `git`, before it is run.
+    ///
+    /// This is strictly diagnostic, for reproducing a failing command by hand. The line is the one
+    /// shown by [`Setup::verbose()`]: the program and its arguments, preceded by the environment
+    /// and working directory set for it if the Rust standard library shows those. Values of
+    /// configuration and environment that look like credentials are replaced by `<redacted>`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut commands = vec![];
+    /// let mut vcs = xtest_data::setup!()
+    ///     .on_git_command(|line| commands.push(line.to_owned()));
+    /// let _ = vcs.add("tests/data.zip");
+    /// let _ = vcs.build();
+    /// assert!(!commands.is_empty());
+    /// ```
+    pub fn on_git_command(mut self, hook: impl FnMut(&str) + 'lt) -> Self {
+        // All invocations share the hook through `&Git`. The cell is within the closure, a cell
+        // around it would tie the `Setup` to exactly the lifetime `'lt`.
+        let hook = std::cell::RefCell::new(hook);
+        let hook = git::CommandHook(Box::new(move |line| (hook.borrow_mut())(line)));
+        match &mut self.source {
+            Source::Local(git) | Source::VcsFromManifest { git, .. } => git.on_command = Some(hook),
+        }
+
+        self
+    }
+
+    /// Limit the time that each invocation of `git` may take, by default five minutes.
+    ///
+    /// A process that exceeds this, such as a fetch from an unreachable host, is killed and the
+    /// setup fails. Independent of this, `git` is never allowed to prompt for credentials.
+    pub fn git_timeout(mut self, timeout: std::time::Duration) -> Self {
+        match &mut self.source {
+            Source::Local(git) | Source::VcsFromManifest { git, .. } => git.timeout = timeout,
+        }
+
+        self
+    }
+
+    /// Retry a fetch from the reference repository this often, by default three times.
+    ///
+    /// Only failures that look transient, such as a dropped connection or a server error, are
+    /// retried, with an increasing delay. Failed authentication or a missing commit fail at once.
+    pub fn fetch_retries(mut self, retries: u32) -> Self {
+        match &mut self.source {
+            Source::Local(git) | Source::VcsFromManifest { git, .. } => git.fetch_retries = retries,
+        }
+
+        self
+    }
+
+    /// Pass additional configuration as `-c key=value` to every invocation of `git`.
+    ///
+    /// This is an escape hatch for remotes that need a custom transport, a proxy, or a credential
+    /// helper. Values of keys that look like credentials are not shown in verbose output.
+    pub fn git_config(mut self, config: Vec<(String, String)>) -> Self {
+        match &mut self.source {
+            Source::Local(git) | Source::VcsFromManifest { git, .. } => git.config.extend(config),
+        }
+
+        self
+    }
+
+    /// Set additional environment variables for every invocation of `git`.
+    ///
+    /// Some transport configuration must be environmental, such as `GIT_SSH_COMMAND` to select the
+    /// key for a private fetch or `GIT_CONFIG_GLOBAL` to select a configuration file. These are
+    /// added to the variables that we set ourselves, and may replace `GIT_ASKPASS` or
+    /// `SSH_ASKPASS`. Prompting on the terminal or by the credential manager stays disabled
+    /// regardless. Values of names that look like credentials are not
+    /// shown in verbose output.
+    pub fn git_env(mut self, env: Vec<(OsString, OsString)>) -> Self {
+        match &mut self.source {
+            Source::Local(git) | Source::VcsFromManifest { git, .. } => git.env.extend(env),
+        }
+
+        self
+    }
+
+    /// Use the pack objects in a directory, instead of `CARGO_XTEST_DATA_PACK_OBJECTS`.
+    ///
+    /// For a packaged crate the directory must contain the `.pack` files from which the data is
+    /// imported, such as those prepared by the `cargo-xtest-data` binary. When testing from a local
+    /// checkout this is the directory into which the pack files are written instead.
+    ///
+    /// A path with the extension `.pack` that is not a directory names a single pack file. It is
+    /// the only one imported, and when testing from a local checkout the objects of each build are
+    /// added to it. This makes the data a single file to distribute.
+    pub fn pack_objects(mut self, dir: PathBuf) -> Self {
+        self.pack_objects = Some(dir.into_os_string());
+        self
+    }
+
+    /// Build from the local checkout, writing the pack objects of the data into a path.
+    ///
+    /// This is [`Setup::pack_objects()`] followed by [`Setup::build()`], for a build script or
+    /// tool that prepares the pack files of a packaged crate without the `cargo-xtest-data`
+    /// binary. The path is a directory or a single `.pack` file, as for `pack_objects`.
+    ///
+    /// ## Panics
+    ///
+    /// This panics for the same reasons as [`Setup::build()`], and when testing a packaged crate
+    /// since there is no checkout to pack the objects from.
+    pub fn generate_pack(self, into: PathBuf) -> FsData {
+        if let Source::VcsFromManifest { .. } = self.source {
+            inconclusive(&mut concat!(
+                "Pack objects can only be generated from a local checkout of the repository.\n",
+                "A packaged crate imports the pack files instead, with `Setup::pack_objects`.",
+            ));
+        }
+
+        self.pack_objects(into).build()
+    }
+
+    /// Check out the data of several paths once, for the later builds of the same commit.
+    ///
+    /// Each [`Setup::build()`] of a packaged crate otherwise imports the pack objects and checks
+    /// out a worktree of its own. After prewarming, a build whose resources are all within the
+    /// prewarmed paths skips both and presents the files of the shared checkout instead. Builds
+    /// with [`Setup::checkout_into()`] or [`Setup::on_checkout()`] always check out on their own.
+    ///
+    /// Prewarming again adds to the paths, it never removes the files that another test may still
+    /// read. Concurrent calls are serialized by a lock file next to the shared checkout, which is
+    /// separate from the lock of the checkout itself, while builds reusing the data do not wait
+    /// for either lock. When testing from a local checkout this only validates the paths.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// xtest_data::setup!().prewarm(&["tests/data.zip", "tests/tree"]);
+    ///
+    /// let mut vcs = xtest_data::setup!();
+    /// let hello = vcs.add("tests/tree/hello.txt");
+    /// let testdata = vcs.build();
+    /// assert!(testdata.path(&hello).exists());
+    /// ```
+    pub fn prewarm(mut self, paths: &[&str]) {
+        let dir = match &self.source {
+            Source::Local(_) => {
+                self.add_many(paths);
+                let _ = self.build();
+                return;
+            }
+            Source::VcsFromManifest {
+                datadir, commit_id, ..
+            } => prewarm_dir(datadir, commit_id),
+        };
+
+        let list = prewarm_list(&dir);
+        let _lock = git::FileWaitLock::for_checkout(&list);
+        // Keep what was prewarmed before, builds may already rely on it.
+        let mut prewarmed = read_prewarm_list(&list);
+        prewarmed.extend(paths.iter().map(|path| normalize_relative(path.into())));
+        prewarmed.sort();
+        prewarmed.dedup();
+
+        self.add_many(&prewarmed);
+        self.checkout_dir = Some(dir);
+        self.force_checkout = false;
+        let _ = self.build();
+
+        let mut content = String::new();
+        for path in &prewarmed {
+            let path = path
+                .to_str()
+                .unwrap_or_else(|| inconclusive(&mut "Prewarmed paths must be valid UTF-8"));
+            content.push_str(path);
+            content.push('\n');
+        }
+
+        // Replace it as a whole, a build may read it concurrently.
+        let partial = list.with_extension("partial");
+        fs::write(&partial, content).unwrap_or_else(|mut err| inconclusive(&mut err));
+        fs::rename(&partial, &list).unwrap_or_else(|mut err| inconclusive(&mut err));
+    }
+
+    /// Whether the data comes from a packaged crate, instead of the files of a local checkout.
+    ///
+    /// This is decided when the builder is created, by the presence of `.cargo_vcs_info.json` in
+    /// the manifest directory or of `CARGO_XTEST_VCS_INFO`. A test may use it to skip work that
+    /// only makes sense on one side, without finding out the same itself.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let packaged = vcs.is_packaged();
+    /// let datazip = vcs.add("tests/data.zip");
+    /// let testdata = vcs.build();
+    ///
+    /// if packaged {
+    ///     // Checked out from the commit of the crate archive.
+    ///     assert!(testdata.origin().is_some());
+    /// } else {
+    ///     assert!(testdata.path(&datazip).starts_with(env!("CARGO_MANIFEST_DIR")));
+    /// }
+    /// ```
+    pub fn is_packaged(&self) -> bool {
+        matches!(self.source, Source::VcsFromManifest { .. })
+    }
+
+    /// Run the final validation but do not fetch, check out, or write anything.
+    ///
+    /// For a local checkout this performs the same check as [`Setup::build()`] that all
+    /// registered paths are tracked by the VCS, but does not generate pack objects. For a
+    /// packaged crate it determines the commit and pathspecs without creating a repository,
+    /// worktree or any directory. An abbreviated commit is resolved only with a reference
+    /// repository, see [`FetchPlan::commit_resolved`]. Paths registered with [`Setup::rewrite()`]
+    /// are not modified.
+    ///
+    /// ## Panics
+    ///
+    /// This will panic if:
+    /// * Any registered file or tree is not tracked in the VCS.
+    /// * There is no source of objects for a packaged crate: neither pack objects, a reference
+    ///   repository, nor a permitted [`ExtractMode::RemoteArchive`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let _ = vcs.add("tests/data.zip");
+    /// let plan = vcs.build_dry_run();
+    ///
+    /// assert_eq!(plan.resources[0].0, std::path::Path::new("tests/data.zip"));
+    /// ```
+    pub fn build_dry_run(self) -> FetchPlan {
+        let commit = match &self.source {
+            Source::Local(git) => {
+                if !self.skip_tracked_check {
+                    let dir = git::CrateDir::new(&self.manifest, git);
+                    dir.tracked(
+                        git,
+                        &mut self.resources.required().map(|(_, spec)| spec),
+                        self.require_clean,
+                    );
+                }
+                None
+            }
+            Source::VcsFromManifest { commit_id, git, .. } => {
+                // As for the build, an archive of the remote needs no other source of objects.
+                let archived = matches!(
+                    (self.extract_mode, &self.checkout_dir, self.fetch_refused),
+                    (ExtractMode::RemoteArchive, None, None)
+                );
+                if !archived && self.pack_objects.is_none() && self.reference.is_none() {
+                    inconclusive(&mut "Requested test data but have no packed artifacts to load");
+                }
+
+                match &self.reference {
+                    Some(reference) if commit_id.is_abbreviated() => {
+                        Some(git.resolve_in(reference, commit_id))
+                    }
+                    _ => Some(commit_id.clone()),
+                }
+            }
+        };
+        let commit_resolved = commit.as_ref().map_or(true, |id| !id.is_abbreviated());
+
+        let resources = self
+            .resources
+            .entries()
+            .map(|(path, spec)| (path.to_owned(), spec.to_string()))
+            .collect();
+
+        FetchPlan {
+            repository: self.repository,
+            commit: commit.map(|id| id.to_string()),
+            commit_resolved,
+            resources,
+            pack_objects: self.pack_objects.map(PathBuf::from),
+        }
+    }
+
+    /// Run all checks of the configuration and report each outcome, without failing on any.
+    ///
+    /// This is a preflight for a binary or script that runs independently of `cargo test`. It
+    /// checks that the repository URL is plausible, that `git` is available and supports
+    /// `sparse-checkout` from `--stdin`, that the registered paths are tracked in a local checkout
+    /// or have a source of objects in a packaged crate, and that the directory which
+    /// [`Setup::build()`] writes to is writable with enough space for the pack objects.
+    ///
+    /// Nothing is fetched, checked out or rewritten. Only the writable check creates and removes
+    /// an empty directory.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let _ = vcs.add("tests/data.zip");
+    /// let report = vcs.validate();
+    ///
+    /// assert!(report.passed(), "{}", report);
+    /// ```
+    pub fn validate(&self) -> ValidationReport {
+        let mut checks = vec![];
+        let mut check = |name, passed, detail| {
+            checks.push(ValidationCheck {
+                name,
+                passed,
+                detail,
+            })
+        };
+
+        let url = self.repository.to_string_lossy();
+        // A local checkout never fetches from the repository.
+        let plausible = match &self.source {
+            Source::Local(_) => true,
+            Source::VcsFromManifest { .. } => {
+                self.repository.to_str().map_or(true, is_plausible_remote)
+            }
+        };
+        check("repository", plausible, url.into_owned());
+
+        let git = match &self.source {
+            Source::Local(git) | Source::VcsFromManifest { git, .. } => git,
+        };
+        let required = git::SPARSE_STDIN;
+        match git.version {
+            Some(version) if version < required => check(
+                "git",
+                false,
+                format!(
+                    "git {} is older than {}, which `sparse-checkout` needs",
+                    version, required,
+                ),
+            ),
+            Some(version) => check("git", true, format!("git {}", version)),
+            None => check(
+                "git",
+                false,
+                "the output of `git --version` was not understood".to_string(),
+            ),
+        }
+
+        let data_dir = match &self.source {
+            Source::Local(git) => {
+                let unclean = git::CrateDir::open(&self.manifest, git).and_then(|dir| {
+                    if self.skip_tracked_check {
+                        return Ok(None);
+                    }
+
+                    let mut specs = self.resources.required().map(|(_, spec)| spec);
+                    dir.try_first_unclean(git, &mut specs, self.require_clean)
+                });
+
+                match (unclean, self.resources.missing(&self.manifest)) {
+                    (Err(err), _) => check("paths", false, err),
+                    (Ok(Some((path, reason))), _) => {
+                        check("paths", false, format!("{}: {}", reason, path))
+                    }
+                    (Ok(None), Some(missing)) => check(
+                        "paths",
+                        false,
+                        format!("`{}` does not exist on disk", missing.display()),
+                    ),
+                    (Ok(None), None) => check("paths", true, "all paths are tracked".to_string()),
+                }
+
+                self.pack_objects.as_ref().map(|pack| {
+                    let pack = Path::new(pack);
+                    if git::is_pack_file(pack) {
+                        pack.parent().unwrap_or(pack).to_owned()
+                    } else {
+                        pack.to_owned()
+                    }
+                })
+            }
+            Source::VcsFromManifest { datadir, .. } => {
+                let packs = self.pack_objects.as_ref().map(Path::new);
+                match (packs, &self.reference) {
+                    (Some(packs), _) if !packs.exists() => check(
+                        "paths",
+                        false,
+                        format!("the pack objects {} do not exist", packs.display()),
+                    ),
+                    (Some(packs), _) => check(
+                        "paths",
+                        true,
+                        format!("resolved from the pack objects {}", packs.display()),
+                    ),
+                    (None, Some(reference)) if !reference.exists() => check(
+                        "paths",
+                        false,
+                        format!(
+                            "the reference repository {} does not exist",
+                            reference.display()
+                        ),
+                    ),
+                    (None, Some(reference)) => check(
+                        "paths",
+                        true,
+                        format!(
+                            "resolved from the reference repository {}",
+                            reference.display()
+                        ),
+                    ),
+                    (None, None) => check(
+                        "paths",
+                        false,
+                        "there are no pack objects, set `CARGO_XTEST_DATA_PACK_OBJECTS`"
+                            .to_string(),
+                    ),
+                }
+
+                Some(datadir.clone())
+            }
+        };
+
+        if let Some(data_dir) = data_dir {
+            // The directory is created by the build, so probe the closest one that exists.
+            let existing = data_dir.ancestors().find(|dir| dir.is_dir());
+            let probe = existing.map(|dir| unique_dir(dir, "xtest-data-validate"));
+            match probe {
+                Some(Ok(probe)) => {
+                    let _ = fs::remove_dir(&probe);
+                    check("data directory", true, data_dir.display().to_string());
+                }
+                Some(Err(err)) => check(
+                    "data directory",
+                    false,
+                    format!("{} is not writable: {}", data_dir.display(), err),
+                ),
+                None => check(
+                    "data directory",
+                    false,
+                    format!("{} has no existing parent", data_dir.display()),
+                ),
+            }
+
+            let packs = match &self.source {
+                Source::VcsFromManifest { .. } => self.pack_objects.as_ref(),
+                Source::Local(_) => None,
+            };
+            let needed = packs.map(|packs| disk_usage(Path::new(packs)));
+            let available = existing.and_then(available_space_in);
+            match (needed, available) {
+                (Some(needed), Some(available)) if available < needed => check(
+                    "space",
+                    false,
+                    format!(
+                        "{} bytes available but the data needs about {} bytes",
+                        available, needed
+                    ),
+                ),
+                (_, Some(available)) => {
+                    check("space", true, format!("{} bytes available", available))
+                }
+                (_, None) => check(
+                    "space",
+                    true,
+                    "the capacity could not be checked".to_string(),
+                ),
+            }
+        }
+
+        ValidationReport { checks }
+    }
+
+    /// Run the final validation and perform rewrites.
+    ///
+    /// Returns the frozen dictionary of file mappings that had been registered with
+    /// [`Setup::add()`]. This allows retrieving the final data paths for those items.
+    ///
+    /// ## Panics
+    ///
+    /// This will panic if:
+    /// * Any registered file or tree is not tracked in the VCS.
+    /// * Any registered file or tree is tracked but was removed from a local checkout.
+    /// * Any registered file or tree was modified in a local checkout, with
+    ///   [`Setup::require_clean()`].
+    /// * You have not allowed retrieving data from the VCS.
+    /// * It was not possible to retrieve the data from the VCS.
+    pub fn build(mut self) -> FsData {
+        let started = Instant::now();
+        let mut map;
+        let stats;
+        let available;
+        let mut roots;
+        let globbed;
+        let mut worktree = None;
+        let mut in_use = None;
+        let mut origin = None;
+        let mut timings = BuildTimings::default();
+        let (git_version, verbose) = match &self.source {
+            Source::Local(git) | Source::VcsFromManifest { git, .. } => (git.version, git.verbose),
+        };
+        let mut map_paths = self.map_paths;
+        match self.source {
+            Source::Local(git) => {
+                let dir = git::CrateDir::new(&self.manifest, &git);
+                let datapath = &*self.manifest;
+                self.resources
+                    .settle_if_exists(|path| dir.contains(&git, path));
+                if !self.skip_tracked_check {
+                    dir.tracked(
+                        &git,
+                        &mut self.resources.required().map(|(_, spec)| spec),
+                        self.require_clean,
+                    );
+                }
+
+                // A deletion that is not yet committed passes the check for tracked files.
+                if let Some(missing) = self.resources.missing(datapath) {
+                    inconclusive(&mut format!(
+                        "The registered path `{}` is tracked but does not exist on disk",
+                        missing.display()
+                    ));
+                }
+
+                // Only the tracked files that exist, an untracked match failed the check above.
+                globbed = self.resources.globs(|pattern| {
+                    dir.glob(&git, pattern)
+                        .into_iter()
+                        .filter(|path| datapath.join(path).exists())
+                        .collect()
+                });
+
+                let skip_tracked_check = self.skip_tracked_check;
+                available = self.resources.availability(|path| {
+                    let mut spec = std::iter::once(git::PathSpec::Path(path));
+                    (skip_tracked_check || dir.first_untracked(&git, &mut spec).is_none())
+                        && datapath.join(path).exists()
+                });
+
+                let objects = self.resources.objects();
+                if let Some(pack_objects) = self.pack_objects {
+                    // The pack has the data of `HEAD`, which must be what the tests have seen.
+                    let matched = globbed.iter().flat_map(|(_, files)| files);
+                    let dirty = dir.first_unclean(
+                        &git,
+                        &mut self
+                            .resources
+                            .available_specs(&available)
+                            .chain(matched.map(|path| git::PathSpec::Path(path))),
+                        true,
+                    );
+                    if let Some((path, reason)) = dirty {
+                        let mut message = format!(
+                            "{}: `{}`\nThe pack has the data committed at `HEAD`, which differs from the data that the tests used. Commit the changes, or allow this with `CARGO_XTEST_DATA_ALLOW_DIRTY=1` (`--allow-dirty` of the xtask).",
+                            reason, path
+                        );
+                        if self.allow_dirty_pack {
+                            eprintln!("xtest-data: warning: {}", message);
+                        } else {
+                            inconclusive(&mut message);
+                        }
+                    }
+
+                    let phase = Instant::now();
+                    let pack_dir = Path::new(&pack_objects);
+                    let pack_dir = match pack_dir.parent() {
+                        Some(parent) if git::is_pack_file(pack_dir) => parent,
+                        _ => pack_dir,
+                    };
+                    std::fs::create_dir_all(pack_dir)
+                        .unwrap_or_else(|mut err| inconclusive(&mut err));
+                    // Each glob is packed as the files that it matches.
+                    let matched = globbed.iter().flat_map(|(_, files)| files);
+                    dir.pack_objects(
+                        &git,
+                        &mut self
+                            .resources
+                            .available_specs(&available)
+                            .chain(matched.map(|path| git::PathSpec::Path(path))),
+                        &objects,
+                        pack_objects,
+                        self.pack_depth,
+                        self.pack_max_blob,
+                    );
+                    timings.pack = phase.elapsed();
+                }
+
+                // Blobs have no place in the working tree, they are written to a temporary file.
+                let tmpdir = self.target_tmpdir.clone().unwrap_or_else(|| {
+                    std::env::var_os("CARGO_XTEST_DATA_TMPDIR")
+                        .or_else(|| std::env::var_os("TMPDIR"))
+                        .map_or_else(std::env::temp_dir, PathBuf::from)
+                });
+                let objects_dir = write_objects(&tmpdir, &objects, |oid, dest| {
+                    dir.write_blob(&git, oid, dest)
+                });
+
+                let renamed_dir = write_renamed(&tmpdir, datapath, self.resources.renames());
+
+                map = self
+                    .resources
+                    .materialized(datapath, &objects_dir, &renamed_dir);
+                roots = self.resources.roots(datapath, &objects_dir, &renamed_dir);
+
+                stats = FetchStats {
+                    objects: self.resources.entries().count(),
+                    bytes: self.resources.disk_usage(datapath),
+                    from_cache: true,
+                };
+
+                if let Some(lock_file) = &self.lock_file {
+                    write_lock_file(
+                        lock_file,
+                        &git,
+                        None,
+                        datapath,
+                        &self.resources,
+                        &roots,
+                        &globbed,
+                    );
+                }
+
+                self.resources
+                    .unmanaged
+                    .into_iter()
+                    .for_each(|item| set_root(datapath, item, &mut map_paths));
+            }
+            Source::VcsFromManifest {
+                mut commit_id,
+                datadir,
+                datadir_from_env,
+                git,
+                path_in_vcs,
+            } => {
+                let cleared = git::Origin {
+                    url: self.repository,
+                };
+
+                let mut on_checkout = self.on_checkout;
+                // A single archive of the remote, when the resources need no repository.
+                let phase = Instant::now();
+                let archived = match (self.extract_mode, &self.checkout_dir, self.fetch_refused) {
+                    (ExtractMode::RemoteArchive, None, Some(reason)) => {
+                        eprintln!(
+                            "xtest-data: {}, checking out instead of asking the remote",
+                            reason
+                        );
+                        None
+                    }
+                    (ExtractMode::RemoteArchive, None, None) => remote_archive(
+                        &git,
+                        &cleared.url,
+                        &datadir,
+                        &commit_id,
+                        &path_in_vcs,
+                        &self.resources,
+                        &mut on_checkout,
+                    ),
+                    _ => None,
+                };
+
+                let _checkout_lock;
+                let (datapath, shallow, objects, checkout_commit) = match archived {
+                    Some(dir) => {
+                        timings.checkout = phase.elapsed();
+                        in_use = Some(git::FileWaitLock::in_use(&dir));
+                        (dir, None, 0, commit_id.clone())
+                    }
+                    None => {
+                        let packaged = Packaged {
+                            git: &git,
+                            url: &cleared.url,
+                            datadir: &datadir,
+                            datadir_from_env,
+                            path_in_vcs: &path_in_vcs,
+                            checkout_dir: self.checkout_dir.as_deref(),
+                            force_checkout: self.force_checkout,
+                            additive_sparse: self.additive_sparse,
+                            reference: self.reference.as_deref(),
+                            pack_objects: self.pack_objects.as_ref(),
+                            retention: self.retention,
+                        };
+                        let checkout = checkout_packaged(
+                            packaged,
+                            &mut commit_id,
+                            &mut self.resources,
+                            &mut on_checkout,
+                            &mut timings,
+                        );
+                        worktree = checkout.worktree;
+                        in_use = checkout.in_use;
+                        _checkout_lock = checkout.lock;
+                        let shallow = Some(checkout.shallow);
+                        (
+                            checkout.datapath,
+                            shallow,
+                            checkout.objects,
+                            checkout.commit,
+                        )
+                    }
+                };
+
+                let objects_dir =
+                    write_objects(&datadir, &self.resources.objects(), |oid, dest| {
+                        // Resources with objects by ID are never extracted from an archive.
+                        if let Some(shallow) = &shallow {
+                            shallow.write_blob(&git, oid, dest)
+                        }
+                    });
+                let renamed_dir = write_renamed(&datadir, &datapath, self.resources.renames());
+                map = self
+                    .resources
+                    .materialized(&datapath, &objects_dir, &renamed_dir);
+                roots = self.resources.roots(&datapath, &objects_dir, &renamed_dir);
+                globbed = match &shallow {
+                    Some(shallow) => self.resources.globs(|pattern| {
+                        shallow
+                            .glob(&git, &datapath, &checkout_commit, pattern)
+                            .into_iter()
+                            .filter(|path| datapath.join(path).exists())
+                            .collect()
+                    }),
+                    None => vec![],
+                };
+                // An optional resource that is not in the commit was not checked out.
+                available = self
+                    .resources
+                    .availability(|path| datapath.join(path).exists());
+                for &key in &self.resources.readonly {
+                    // Skipped by the checkout filter or not in the commit, nothing to protect.
+                    if !available[key] || fs::symlink_metadata(&map[key]).is_err() {
+                        continue;
+                    }
+
+                    set_readonly(&map[key], true).unwrap_or_else(|err| {
+                        inconclusive(&mut format!(
+                            "Making {} read-only failed: {}",
+                            map[key].display(),
+                            err
+                        ))
+                    });
+                }
+
+                stats = FetchStats {
+                    objects,
+                    bytes: self.resources.disk_usage(&datapath),
+                    from_cache: shallow.is_some() && objects == 0,
+                };
+
+                if let Some(lock_file) = &self.lock_file {
+                    let origin = Some((&*cleared.url, &commit_id));
+                    write_lock_file(
+                        lock_file,
+                        &git,
+                        origin,
+                        &datapath,
+                        &self.resources,
+                        &roots,
+                        &globbed,
+                    );
+                }
+                origin = Some(cleared.url);
+
+                self.resources
+                    .unmanaged
+                    .into_iter()
+                    .for_each(|item| set_root(&datapath, item, &mut map_paths));
+            }
+        }
+
+        // In the end we just discard some information.
+        // We don't really need it anymore after the checks.
+        //
+        // TODO: of course we could avoid actually checking files onto the disk if we had some kind
+        // of `io::Read` abstraction that read them straight from `git cat` instead. But chances
+        // are you'll like your files and directory structures.
+        check_kinds(&map, &self.resources.expected);
+        let mut contents: Vec<_> = map.iter().map(|path| tree_contents(path)).collect();
+        for (key, files) in globbed {
+            contents[key] = files.iter().map(|path| roots[key].join(path)).collect();
+        }
+        // Looked up by the paths as built, which a path map need not keep in order.
+        let listing = contents
+            .iter()
+            .zip(&map)
+            .map(|(entries, base)| {
+                entries
+                    .iter()
+                    .map(|path| path.strip_prefix(base).unwrap_or(path).to_owned())
+                    .collect()
+            })
+            .collect();
+
+        if let Some(PathMap(map_path)) = &mut map_paths {
+            map.iter_mut().for_each(|path| *path = map_path(path));
+            roots.iter_mut().for_each(|path| *path = map_path(path));
+            contents
+                .iter_mut()
+                .flatten()
+                .for_each(|path| *path = map_path(path));
+        }
+
+        timings.total = started.elapsed();
+        if let Some(slow) = timings.slow_checkout {
+            eprintln!(
+                "xtest-data: paths were checked out one by one, without sparse-checkout, taking {:?}",
+                slow
+            );
+        }
+        if verbose {
+            eprintln!("xtest-data: {:?}", timings);
+        }
+
+        FsData {
+            map,
+            stats,
+            origin,
+            worktree,
+            available,
+            roots,
+            git_version,
+            contents,
+            listing,
+            timings,
+            in_use,
+        }
+    }
+}
+
+impl std::fmt::Debug for CheckoutFilter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("CheckoutFilter")
+    }
+}
+
+impl std::fmt::Debug for PathMap<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("PathMap")
+    }
+}
+
+impl Resources<'_> {
+    /// All registered resources in registration order, each paired with the pathspec selecting it.
+    ///
+    /// This is the one source of truth for what gets checked out. Anything reporting on the
+    /// resources must be derived from here so that labels and checkout can not diverge.
+    pub fn entries(&self) -> impl Iterator<Item = (&Path, git::PathSpec<'_>)> {
+        self.select(true)
+    }
+
+    /// Like [`Resources::entries()`], without those registered with [`Setup::add_optional()`].
+    fn required(&self) -> impl Iterator<Item = (&Path, git::PathSpec<'_>)> {
+        self.select(false)
+    }
+
+    fn select(&self, with_optional: bool) -> impl Iterator<Item = (&Path, git::PathSpec<'_>)> {
+        let values = self
+            .relative_files
+            .iter()
+            .filter(move |item| with_optional || !matches!(item, Managed::Optional(_)))
+            .filter_map(|item| Some((item.as_path()?, item.as_path_spec()?)));
+        let unmanaged = self
+            .unmanaged
+            .iter()
+            .map(|x| (x.as_path(), git::PathSpec::Path(x)));
+        values.chain(unmanaged)
+    }
+
+    /// The pathspecs of all resources to pack, except the optional ones that are not `available`.
+    ///
+    /// Resources of [`Setup::add_lazy()`] are not packed. Neither are globs, whose files must be
+    /// listed by the caller.
+    fn available_specs<'a>(
+        &'a self,
+        available: &'a [bool],
+    ) -> impl Iterator<Item = git::PathSpec<'a>> {
+        let values = self
+            .relative_files
+            .iter()
+            .zip(available)
+            .enumerate()
+            .filter(move |(key, (_, &available))| available && !self.lazy.contains(key))
+            .filter(|(_, (item, _))| !matches!(item, Managed::Glob(_)))
+            .filter_map(|(_, (item, _))| item.as_path_spec());
+        let unmanaged = self.unmanaged.iter().map(|x| git::PathSpec::Path(x));
+        values.chain(unmanaged)
+    }
+
+    /// Make those registered with [`Setup::add_if_exists()`] optional, unless `exists` finds them.
+    fn settle_if_exists(&mut self, mut exists: impl FnMut(&Path) -> bool) {
+        for &key in &self.if_exists {
+            if let Managed::Files(path) = &self.relative_files[key] {
+                if !exists(path) {
+                    self.relative_files[key] = Managed::Optional(path.clone());
+                }
+            }
+        }
+    }
+
+    /// Whether each registered item is available, judged by `is_available` for optional ones.
+    fn availability(&self, mut is_available: impl FnMut(&Path) -> bool) -> Vec<bool> {
+        self.relative_files
+            .iter()
+            .map(|item| match item {
+                Managed::Optional(path) => is_available(path),
+                Managed::Files(_)
+                | Managed::Glob(_)
+                | Managed::Object(_)
+                | Managed::Renamed { .. } => true,
+            })
+            .collect()
+    }
+
+    /// The object IDs of all blobs registered with [`Setup::add_object()`].
+    pub fn objects(&self) -> Vec<&str> {
+        self.relative_files
+            .iter()
+            .filter_map(|item| match item {
+                Managed::Object(oid) => Some(oid.as_str()),
+                Managed::Files(_)
+                | Managed::Glob(_)
+                | Managed::Optional(_)
+                | Managed::Renamed { .. } => None,
+            })
+            .collect()
+    }
+
+    /// The path and local name of all resources registered with [`Setup::add_with_rename()`].
+    fn renames(&self) -> impl Iterator<Item = (&Path, &Path)> {
+        self.relative_files.iter().filter_map(|item| match item {
+            Managed::Renamed { path, name } => Some((path.as_path(), name.as_path())),
+            Managed::Files(_) | Managed::Glob(_) | Managed::Optional(_) | Managed::Object(_) => {
+                None
+            }
+        })
+    }
+
+    /// The materialized path of each registered item, in order of their keys.
+    fn materialized(&self, root: &Path, objects: &Path, renamed: &Path) -> Vec<PathBuf> {
+        self.relative_files
+            .iter()
+            .map(|item| match item {
+                Managed::Files(path) | Managed::Optional(path) => root.join(path),
+                Managed::Glob(pattern) => root.join(glob_base(pattern)),
+                Managed::Object(oid) => objects.join(oid),
+                Managed::Renamed { name, .. } => renamed.join(name),
+            })
+            .collect()
+    }
+
+    /// The directory of each registered item, in order of their keys.
+    fn roots(&self, root: &Path, objects: &Path, renamed: &Path) -> Vec<PathBuf> {
+        self.relative_files
+            .iter()
+            .map(|item| match item {
+                Managed::Files(_) | Managed::Optional(_) | Managed::Glob(_) => root.to_owned(),
+                Managed::Object(_) => objects.to_owned(),
+                Managed::Renamed { .. } => renamed.to_owned(),
+            })
+            .collect()
+    }
+
+    /// The first registered path that does not exist relative to `root`.
+    fn missing(&self, root: &Path) -> Option<&Path> {
+        self.required()
+            .filter(|(_, spec)| !matches!(spec, git::PathSpec::Glob(_)))
+            .map(|(path, _)| path)
+            .find(|path| !root.join(path).exists())
+    }
+
+    /// The files matched by each glob, relative to the root, as listed by `matches`.
+    fn globs(&self, mut matches: impl FnMut(&Path) -> Vec<PathBuf>) -> Vec<(usize, Vec<PathBuf>)> {
+        self.relative_files
+            .iter()
+            .enumerate()
+            .filter_map(|(key, item)| match item {
+                Managed::Glob(pattern) => Some((key, matches(pattern))),
+                Managed::Files(_)
+                | Managed::Optional(_)
+                | Managed::Object(_)
+                | Managed::Renamed { .. } => None,
+            })
+            .collect()
+    }
+
+    /// The total size of all resources, as materialized below `root`.
+    fn disk_usage(&self, root: &Path) -> u64 {
+        self.entries()
+            .map(|(path, _)| disk_usage(&root.join(path)))
+            .sum()
+    }
+}
+
+impl FsData {
+    /// Retrieve the rewritten path of a file or tree of files.
+    ///
+    /// When the data was checked out, the files are byte-for-byte the blobs of the commit. Neither
+    /// `core.autocrlf` nor the `.gitattributes` of the repository apply line ending conversions or
+    /// filters to them. Symlinks are checked out as symlinks. Where git can not create them, such
+    /// as on Windows without `core.symlinks`, the setup fails instead of writing a plain file.
+    pub fn path(&self, file: &Files) -> &Path {
+        self.map.get(file.key).unwrap().as_path()
+    }
+
+    /// Copy a resource to a path of your choice, returning that path.
+    ///
+    /// Unlike [`Setup::add_with_rename()`] this happens after the build, for example to hand a
+    /// fixture to an external process at a location it expects. A tree is copied recursively,
+    /// and permissions as well as symlinks are preserved. Existing files at `dest` are replaced.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let datazip = vcs.add("tests/data.zip");
+    /// let testdata = vcs.build();
+    ///
+    /// let dest = std::env::temp_dir().join("xtest-data-doctest/data.zip");
+    /// let copy = testdata.materialize_into(&datazip, &dest);
+    /// assert_eq!(std::fs::read(copy).unwrap(), std::fs::read(testdata.path(&datazip)).unwrap());
+    /// # std::fs::remove_dir_all(dest.parent().unwrap()).unwrap();
+    /// ```
+    pub fn materialize_into(&self, file: &Files, dest: &Path) -> PathBuf {
+        copy_tree(self.path(file), dest).unwrap_or_else(|err| {
+            inconclusive(&mut format!(
+                "Copying {} to {} failed: {}",
+                self.path(file).display(),
+                dest.display(),
+                err
+            ))
+        });
+
+        dest.to_owned()
+    }
+
+    /// Find a file or directory within a registered tree, by its path relative to the tree.
+    ///
+    /// Returns `None` if the tree does not contain it, or if the resource is not a tree. The
+    /// contents of trees are listed once, when the data is built, so files created afterwards are
+    /// not found.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// let mut vcs = xtest_data::setup!();
+    /// let tree = vcs.add("tests/tree");
+    /// let testdata = vcs.build();
+    ///
+    /// let hello = testdata.find(&tree, Path::new("hello.txt")).unwrap();
+    /// assert_eq!(hello, testdata.path(&tree).join("hello.txt"));
+    /// assert!(testdata.find(&tree, Path::new("missing.txt")).is_none());
+    /// ```
+    pub fn find(&self, file: &Files, relative: &Path) -> Option<&Path> {
+        use std::path::Component;
+        // Only descend, a path must not resolve to a file outside the tree.
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        let idx = self.listing[file.key]
+            .binary_search_by(|entry| entry.as_path().cmp(relative))
+            .ok()?;
+        Some(&self.contents[file.key][idx])
+    }
+
+    /// The files matched by a glob of [`Setup::add_glob()`], in sorted order.
+    ///
+    /// For a tree these are all files and directories within it, and for a file there are none.
+    /// As for [`FsData::find()`], the entries are listed once when the data is built. They are
+    /// sorted by their paths before [`Setup::map_paths()`] rewrote them.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let texts = vcs.add_glob("tests/tree/*.txt");
+    /// let testdata = vcs.build();
+    ///
+    /// for text in testdata.entries(&texts) {
+    ///     assert_eq!(text.extension().unwrap(), "txt");
+    /// }
+    /// ```
+    pub fn entries(&self, file: &Files) -> &[PathBuf] {
+        &self.contents[file.key]
+    }
+
+    /// The data root that contains a resource.
+    ///
+    /// Files and trees are relative to the root at the paths they were registered with, while
+    /// objects of [`Setup::add_object()`] and the local names of [`Setup::add_with_rename()`] are
+    /// each in a separate directory. Compute paths relative to it to present a resource
+    /// independent of where the data was checked out.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut vcs = xtest_data::setup!();
+    /// let hello = vcs.add("tests/tree/hello.txt");
+    /// let testdata = vcs.build();
+    ///
+    /// let root = testdata.root(&hello);
+    /// let relative = testdata.path(&hello).strip_prefix(root).unwrap();
+    /// assert_eq!(relative, std::path::Path::new("tests/tree/hello.txt"));
+    /// ```
+    pub fn root(&self, file: &Files) -> &Path {
+        &self.roots[file.key]
+    }
+
+    /// Whether a resource is available at its [`FsData::path()`].
+    ///
+    /// This is `false` only for a resource registered with [`Setup::add_optional()`] that was not
+    /// tracked or not contained in the commit. All other resources are always available.
+    pub fn is_available(&self, file: &Files) -> bool {
+        self.available[file.key]
+    }
+
+    /// Statistics about the objects and bytes that were made available.
+    pub fn stats(&self) -> FetchStats {
+        self.stats
+    }
+
+    /// The repository URL of the checked out data, or `None` for a local checkout.
+    ///
+    /// This is `package.repository` unless overridden with `CARGO_XTEST_DATA_REPOSITORY_ORIGIN`.
+    pub fn origin(&self) -> Option<&OsStr> {
+        self.origin.as_deref()
+    }
+
+    /// Where [`Setup::build()`] spent its time, for diagnosing a slow setup.
+    ///
+    /// These are also shown with [`Setup::verbose()`].
+    pub fn timings(&self) -> BuildTimings {
+        self.timings
+    }
+
+    /// The version of `git` that was used, or `None` if `git --version` was not understood.
+    pub fn git_version(&self) -> Option<GitVersion> {
+        self.git_version
+    }
+}
+
+impl ValidationReport {
+    /// Whether every check passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    /// The checks that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &ValidationCheck> {
+        self.checks.iter().filter(|check| !check.passed)
+    }
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for check in &self.checks {
+            let outcome = if check.passed { "ok" } else { "FAILED" };
+            writeln!(f, "{}: {}: {}", outcome, check.name, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for GitVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Managed {
+    pub fn as_path(&self) -> Option<&Path> {
+        match self {
+            Managed::Files(path) | Managed::Optional(path) | Managed::Glob(path) => Some(path),
+            Managed::Renamed { path, .. } => Some(path),
+            Managed::Object(_) => None,
+        }
+    }
+
+    fn as_path_spec(&self) -> Option<git::PathSpec<'_>> {
+        match self {
+            Managed::Files(path) | Managed::Optional(path) => Some(git::PathSpec::Path(path)),
+            Managed::Glob(pattern) => Some(git::PathSpec::Glob(pattern)),
+            Managed::Renamed { path, .. } => Some(git::PathSpec::Path(path)),
+            Managed::Object(_) => None,
+        }
+    }
+}
+
+/// The directory for the data of a packaged crate: `tmpdir` or else from the environment.
+fn select_datadir(tmpdir: Option<&Path>, var: impl Fn(&str) -> Option<OsString>) -> PathBuf {
+    if let Some(tmpdir) = tmpdir {
+        return tmpdir.to_owned();
+    }
+
+    // TODO: nah, in this case we should have some distinguisher for the exact crate name and
+    // version in the tmpdir. At least that would catch the gravest of errors when testing many
+    // crates at the same time. (Although sharing the git dir would be an advantage).
+    var("CARGO_XTEST_DATA_TMPDIR")
+        .or_else(|| var("TMPDIR"))
+        .map_or_else(
+            || {
+                inconclusive(&mut "There is no directory for the test data. Outside of integration tests and benchmarks, which have `CARGO_TARGET_TMPDIR`, set `CARGO_XTEST_DATA_TMPDIR` to a directory that may be filled with test data. A minimal container might also be lacking `TMPDIR`, which is the fallback.")
+            },
+            PathBuf::from,
+        )
+}
+
+/// Why the remote may not be contacted for [`ExtractMode::RemoteArchive`], if it may not.
+///
+/// This needs the consent of `CARGO_XTEST_DATA_FETCH`. Like for cargo, `CARGO_NET_OFFLINE` forbids
+/// network access and takes precedence.
+fn fetch_refused(var: impl Fn(&str) -> Option<OsString>) -> Option<&'static str> {
+    let enabled = |name: &str| {
+        var(name).map_or(false, |value| {
+            let value = value.to_string_lossy();
+            let value = value.trim();
+            value == "1" || value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("yes")
+        })
+    };
+
+    if enabled("CARGO_NET_OFFLINE") {
+        Some("`CARGO_NET_OFFLINE` is set")
+    } else if !enabled("CARGO_XTEST_DATA_FETCH") {
+        Some("network access needs `CARGO_XTEST_DATA_FETCH=yes`")
+    } else {
+        None
+    }
+}
+
+/// Whether a repository URL is in one of the forms that git understands as a remote.
+///
+/// This is permissive about schemes, since git supports many and remote helpers add their own.
+/// It rejects what clearly is not a remote: whitespace in a URL, a scheme that lost a slash, or a
+/// single word that is neither a URL nor an existing local path.
+fn is_plausible_remote(url: &str) -> bool {
+    const SCHEMES: &[&str] = &["http", "https", "git", "ssh", "file", "ftp", "ftps"];
+
+    let is_scheme = |scheme: &str| {
+        let mut chars = scheme.chars();
+        chars.next().map_or(false, |ch| ch.is_ascii_alphabetic())
+            && chars.all(|ch| ch.is_ascii_alphanumeric() || "+.-".contains(ch))
+    };
+
+    // A remote helper, as in `<transport>::<address>`.
+    if let Some((transport, address)) = url.split_once("::") {
+        return is_scheme(transport) && !address.is_empty();
+    }
+
+    if let Some((scheme, rest)) = url.split_once("://") {
+        let local = scheme.eq_ignore_ascii_case("file");
+        return is_scheme(scheme)
+            && !rest.is_empty()
+            && (local || !rest.contains(char::is_whitespace));
+    }
+
+    let path = Path::new(url);
+    if path.is_absolute() || url.starts_with("./") || url.starts_with("../") || path.exists() {
+        return true;
+    }
+
+    // The scp-like syntax `[user@]host:path`, where no slash comes before the colon.
+    match url.split_once(':') {
+        Some((host, path)) => {
+            let host = host.rsplit('@').next().unwrap_or(host);
+            !host.is_empty()
+                && !host.contains('/')
+                && !SCHEMES
+                    .iter()
+                    .any(|scheme| host.eq_ignore_ascii_case(scheme))
+                && !path.is_empty()
+                && !url.contains(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+/// The directory of a glob pattern before its first component with a wildcard.
+fn glob_base(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|component| {
+            let component = component.as_os_str().to_string_lossy();
+            !component.contains(|ch| matches!(ch, '*' | '?' | '['))
+        })
+        .collect()
+}
+
+/// Lexically normalize a path that is relative to the manifest.
+///
+/// Paths that are absolute, or that traverse above the manifest directory, are rejected. They
+/// would read outside the crate in a local build and form invalid pathspecs for the VCS.
+fn normalize_relative(path: PathBuf) -> PathBuf {
+    use std::path::Component;
+    // The common case, which we keep without reallocating.
+    if path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return path;
+    }
+
+    let mut normal = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(part) => normal.push(part),
+            Component::ParentDir => {
+                if !normal.pop() {
+                    inconclusive(&mut format!(
+                        "The path `{}` leaves the crate's manifest directory",
+                        path.display()
+                    ));
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                inconclusive(&mut format!(
+                    "The path `{}` must be relative to the crate's manifest directory.\nRegister it as it appears in the repository, such as `tests/data.zip`, instead of a resolved path. The result of the build is then absolute.",
+                    path.display()
+                ));
+            }
+        }
+    }
+
+    normal
+}
+
+/// The size of a file, or of all files in a tree. Links are not followed.
+fn disk_usage(path: &Path) -> u64 {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return 0,
+    };
+
+    if !meta.is_dir() {
+        return meta.len();
+    }
+
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| disk_usage(&entry.path()))
+        .sum()
+}
+
+/// Ensure that resources of [`Setup::add_file()`] and [`Setup::add_tree()`] have their kind.
+fn check_kinds(map: &[PathBuf], expected: &[(usize, Kind)]) {
+    for &(key, kind) in expected {
+        let path = &map[key];
+        let is_dir = match fs::metadata(path) {
+            Ok(meta) => meta.is_dir(),
+            // Reported by the build, or unavailable in an optional way.
+            Err(_) => continue,
+        };
+
+        let (registered, found) = match kind {
+            Kind::File if is_dir => ("a file with `Setup::add_file`", "a directory"),
+            Kind::Tree if !is_dir => ("a tree with `Setup::add_tree`", "not a directory"),
+            Kind::File | Kind::Tree => continue,
+        };
+
+        inconclusive(&mut format!(
+            "The path `{}` was registered as {} but is {}",
+            path.display(),
+            registered,
+            found
+        ));
+    }
+}
+
+/// All paths within a directory, sorted, or none if the path is not a directory.
+fn tree_contents(path: &Path) -> Vec<PathBuf> {
+    fn walk(dir: &Path, contents: &mut Vec<PathBuf>) {
+        let entries = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok);
+
+        for entry in entries {
+            let path = entry.path();
+            // Do not follow symlinks, they are entries of their own.
+            if entry.file_type().map_or(false, |ty| ty.is_dir()) {
+                walk(&path, contents);
+            }
+
+            contents.push(path);
+        }
+    }
+
+    let mut contents = vec![];
+    walk(path, &mut contents);
+    contents.sort();
+    contents
+}
+
+fn set_root(path: &Path, dir: &mut PathBuf, map_paths: &mut Option<PathMap>) {
+    *dir = path.join(&*dir);
+    if let Some(PathMap(map_path)) = map_paths {
+        *dir = map_path(dir);
+    }
+}
+
+/// Ensure the caller's checkout directory exists and is empty or a worktree of ours, returning its
+/// absolute path.
+fn prepare_checkout_dir(
+    git: &git::Git,
+    shallow: &git::ShallowBareRepository,
+    dir: &Path,
+    force: bool,
+) -> PathBuf {
+    let occupied = fs::read_dir(dir).map_or(false, |mut entries| entries.next().is_some());
+    // Our own checkout is updated in place, or replaced if it is damaged or of another commit.
+    if occupied && !shallow.is_worktree(git, dir) {
+        if !force {
+            inconclusive(&mut format!(
+                "The checkout directory {} is not empty, allow replacing it with `force_checkout`",
+                dir.display()
+            ));
+        }
+
+        shallow.clear_worktree(git, dir);
+    }
+
+    fs::create_dir_all(dir).unwrap_or_else(|mut err| inconclusive(&mut err));
+    fs::canonicalize(dir).unwrap_or_else(|mut err| inconclusive(&mut err))
+}
+
+/// Warn if the data plausibly does not fit into the directory chosen through the environment.
+///
+/// A small ramdisk as `TMPDIR` otherwise fails deep inside `git` with an obscure out-of-space
+/// error. This is only advisory, the space could be freed or the estimate could be wrong.
+fn warn_on_unsuitable_datadir(datadir: &Path, needed: Option<u64>) {
+    let kind = filesystem_type(datadir);
+    let available = available_space_in(datadir);
+
+    let kind_note = match kind.as_deref() {
+        Some(kind) if is_memory_or_network(kind) => format!(" on a `{}` filesystem", kind),
+        _ => String::new(),
+    };
+
+    if let (Some(needed), Some(available)) = (needed, available) {
+        if available < needed {
+            eprintln!(
+                "xtest-data: warning: the data directory {}{} has {} bytes available but the data needs about {} bytes.",
+                datadir.display(),
+                kind_note,
+                available,
+                needed,
+            );
+            eprintln!("xtest-data: Consider pointing `CARGO_XTEST_DATA_TMPDIR` to a larger disk.");
+        }
+    } else if !kind_note.is_empty() {
+        eprintln!(
+            "xtest-data: warning: the data directory {} is{}, whose capacity could not be checked.",
+            datadir.display(),
+            kind_note,
+        );
+    }
+}
+
+/// The available bytes on the filesystem of `dir`, if `df` could tell.
+fn available_space_in(dir: &Path) -> Option<u64> {
+    Command::new("df")
+        .arg("-P")
+        .arg("-k")
+        .arg(dir)
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| available_space(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse the available kilobytes from the output of `df -P -k`, in bytes.
+fn available_space(df: &str) -> Option<u64> {
+    let line = df.lines().nth(1)?;
+    let kilobytes: u64 = line.split_whitespace().nth(3)?.parse().ok()?;
+    Some(kilobytes.saturating_mul(1024))
+}
+
+fn is_memory_or_network(kind: &str) -> bool {
+    const KINDS: &[&str] = &[
+        "tmpfs",
+        "ramfs",
+        "nfs",
+        "nfs4",
+        "cifs",
+        "smb3",
+        "smbfs",
+        "9p",
+        "fuse.sshfs",
+    ];
+    KINDS.contains(&kind)
+}
+
+#[cfg(target_os = "linux")]
+fn filesystem_type(path: &Path) -> Option<String> {
+    let path = fs::canonicalize(path).ok()?;
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    mount_type(&mounts, &path).map(str::to_owned)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn filesystem_type(_: &Path) -> Option<String> {
+    None
+}
+
+/// Find the type of the innermost mount containing `path`, from the format of `/proc/mounts`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn mount_type<'mounts>(mounts: &'mounts str, path: &Path) -> Option<&'mounts str> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let _device = fields.next()?;
+            // Spaces in the mount point are escaped in octal.
+            let target = PathBuf::from(fields.next()?.replace("\\040", " "));
+            let kind = fields.next()?;
+            Some((target, kind))
+        })
+        .filter(|(target, _)| path.starts_with(target))
+        // Of equally long matches the last one is the mount that is currently visible.
+        .fold(
+            None,
+            |best: Option<(PathBuf, &str)>, (target, kind)| match best {
+                Some(best) if best.0.as_os_str().len() > target.as_os_str().len() => Some(best),
+                _ => Some((target, kind)),
+            },
+        )
+        .map(|(_, kind)| kind)
+}
+
+/// Write each blob to a file named by its object ID, in a directory below `base`.
+///
+/// The files are named by their content, so the directory is shared by all builds and a blob
+/// that an earlier build wrote is reused.
+fn write_objects(
+    base: &Path,
+    objects: &[&str],
+    mut write_blob: impl FnMut(&str, &Path),
+) -> PathBuf {
+    if objects.is_empty() {
+        return base.to_owned();
+    }
+
+    let dir = base.join("xtest-data-objects");
+    fs::create_dir_all(&dir).unwrap_or_else(|mut err| inconclusive(&mut err));
+    for oid in objects {
+        let dest = dir.join(oid);
+        if !dest.exists() {
+            replace_file(&dest, |partial| {
+                write_blob(oid, partial);
+                Ok(())
+            })
+            .unwrap_or_else(|mut err| inconclusive(&mut err));
+        }
+    }
+
+    dir
+}
+
+/// Copy each file or tree below `root` to its local name, in a directory below `base`.
+///
+/// The directory is named by the root and the renames, so the same setup reuses it instead of
+/// leaving a new copy behind on each build. The copies are refreshed file by file, such that a
+/// concurrent build never sees a partial file. A resource that was not checked out, such as one
+/// rejected by [`Setup::on_checkout()`], is skipped.
+fn write_renamed<'a>(
+    base: &Path,
+    root: &Path,
+    renames: impl Iterator<Item = (&'a Path, &'a Path)>,
+) -> PathBuf {
+    let renames: Vec<_> = renames.collect();
+    if renames.is_empty() {
+        return base.to_owned();
+    }
+
+    let mut key = root.to_string_lossy().into_owned();
+    for (path, name) in &renames {
+        key.push_str(&format!("\0{}\0{}", path.display(), name.display()));
+    }
+
+    let dir = base.join(format!(
+        "xtest-data-renamed-{:016x}",
+        stable_hash(key.as_bytes())
+    ));
+    for (path, name) in renames {
+        let source = root.join(path);
+        if fs::symlink_metadata(&source).is_err() {
+            continue;
+        }
+
+        copy_tree(&source, &dir.join(name)).unwrap_or_else(|mut err| inconclusive(&mut err));
+    }
+
+    dir
+}
+
+/// Write the file `dest` with `write` to a partial file first, then move it into place.
+fn replace_file(dest: &Path, write: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+    let parent = dest.parent().unwrap_or_else(|| Path::new("."));
+    let partial = unique_dir(parent, "xtest-data-partial")?;
+    let file = partial.join("file");
+    let result = write(&file).and_then(|()| fs::rename(&file, dest));
+    let _ = fs::remove_dir_all(&partial);
+    result
+}
+
+/// A hash of `bytes` that is the same for every build and platform, as FNV-1a.
+///
+/// Names derived from it are shared between processes, which may have been compiled differently.
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Copy a file, or recursively all files of a tree, creating the parents of `dest`.
+///
+/// Permissions are preserved, and symlinks are copied as symlinks where they are supported.
+fn copy_tree(source: &Path, dest: &Path) -> io::Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let meta = fs::symlink_metadata(source)?;
+    #[cfg(unix)]
+    {
+        if meta.file_type().is_symlink() {
+            if fs::symlink_metadata(dest).is_ok() {
+                fs::remove_file(dest)?;
+            }
+
+            return std::os::unix::fs::symlink(fs::read_link(source)?, dest);
+        }
+    }
+
+    if !meta.is_dir() {
+        return replace_file(dest, |partial| fs::copy(source, partial).map(drop));
+    }
+
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        copy_tree(&entry.path(), &dest.join(entry.file_name()))?;
+    }
+
+    // Only after the contents, a read-only directory could not be filled otherwise.
+    fs::set_permissions(dest, meta.permissions())
+}
+
+/// Make the files at `path`, recursively for a tree, read-only. Directories and symlinks stay.
+///
+/// Clearing the flag is only needed on Windows, where the attribute also prevents the removal of
+/// a file. On unix it would make the files writable by everyone.
+pub(crate) fn set_readonly(path: &Path, readonly: bool) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            set_readonly(&entry?.path(), readonly)?;
+        }
+    } else if meta.is_file() {
+        let mut permissions = meta.permissions();
+        permissions.set_readonly(readonly);
+        fs::set_permissions(path, permissions)?;
+    }
+
+    Ok(())
+}
+
+/// The settings of a [`Setup`] for checking out the data of a packaged crate.
+struct Packaged<'a, 'paths> {
+    git: &'a git::Git<'paths>,
+    url: &'a OsStr,
+    datadir: &'a Path,
+    datadir_from_env: bool,
+    path_in_vcs: &'a Path,
+    checkout_dir: Option<&'a Path>,
+    force_checkout: bool,
+    additive_sparse: bool,
+    reference: Option<&'a Path>,
+    pack_objects: Option<&'a OsString>,
+    retention: Retention,
+}
+
+/// The data of a packaged crate, checked out from the shared repository.
+struct PackagedCheckout {
+    datapath: PathBuf,
+    shallow: git::ShallowBareRepository,
+    /// The number of objects imported from the pack.
+    objects: usize,
+    /// The commit that was checked out, of the crate's directory within the repository.
+    commit: git::CommitId,
+    /// The worktree to remove when a test panics, unless the checkout is shared.
+    worktree: Option<git::Worktree>,
+    /// Marks a fresh checkout as used by this process.
+    in_use: Option<git::FileWaitLock>,
+    /// Excludes other builds from the caller's checkout directory.
+    lock: Option<git::FileWaitLock>,
+}
+
+/// Clone the shared repository into the data directory and check out the resources from it.
+///
+/// Resolves an abbreviated `commit_id` on the way.
+fn checkout_packaged(
+    packaged: Packaged,
+    commit_id: &mut git::CommitId,
+    resources: &mut Resources,
+    on_checkout: &mut Option<CheckoutFilter>,
+    timings: &mut BuildTimings,
+) -> PackagedCheckout {
+    let Packaged {
+        git,
+        url,
+        datadir,
+        datadir_from_env,
+        path_in_vcs,
+        checkout_dir,
+        force_checkout,
+        additive_sparse,
+        reference,
+        pack_objects,
+        retention,
+    } = packaged;
+
+    let gitpath = datadir.join("xtest-data-git");
+    let phase = Instant::now();
+    let shallow = git.bare(gitpath, commit_id);
+    timings.clone = phase.elapsed();
+
+    let prewarmed = match (checkout_dir, &on_checkout) {
+        (None, None) => prewarmed_checkout(datadir, commit_id, resources),
+        _ => None,
+    };
+
+    let mut lock = None;
+    let mut in_use = None;
+    let datapath = match (&prewarmed, checkout_dir) {
+        (Some(dir), _) => dir.clone(),
+        (None, Some(dir)) => {
+            if let Some(parent) = dir.parent() {
+                fs::create_dir_all(parent).unwrap_or_else(|mut err| inconclusive(&mut err));
+            }
+
+            lock = Some(git::FileWaitLock::for_checkout(dir));
+            prepare_checkout_dir(git, &shallow, dir, force_checkout)
+        }
+        (None, None) => {
+            let dir = unique_dir(datadir, "xtest-data-tree")
+                .unwrap_or_else(|mut err| inconclusive(&mut err));
+            in_use = Some(git::FileWaitLock::in_use(&dir));
+            dir
+        }
+    };
+    let phase = Instant::now();
+    if let (Some(reference), None) = (reference, &prewarmed) {
+        if commit_id.is_abbreviated() {
+            *commit_id = git.resolve_in(reference, commit_id);
+            shallow.mark_shallow(commit_id);
+        }
+
+        shallow.fetch_reference(git, reference, commit_id);
+    }
+
+    let objects;
+    if prewarmed.is_some() {
+        // The objects were imported when prewarming.
+        objects = 0;
+    } else if let Some(pack_objects) = pack_objects {
+        objects = shallow.unpack(git, pack_objects);
+    } else if reference.is_some() {
+        objects = 0;
+    } else {
+        report_plan(commit_id, resources);
+        panic!("Requested test data from {} but have no packed artifacts to load. Provide an explicit path to a directory to unpack via the `CARGO_XTEST_DATA_PACK_OBJECTS` environment variable", Path::new(url).display());
+    }
+    timings.fetch = phase.elapsed();
+
+    if commit_id.is_abbreviated() {
+        *commit_id = shallow.resolve_commit(git, commit_id);
+        shallow.mark_shallow(commit_id);
+    }
+
+    if let Some(warning) = shallow.replaced_commit(git, commit_id) {
+        eprintln!("xtest-data: warning: {}", warning);
+    }
+
+    resources.settle_if_exists(|path| shallow.contains(git, commit_id, &path_in_vcs.join(path)));
+
+    if datadir_from_env {
+        let needed = shallow
+            .disk_usage(git, commit_id)
+            .or_else(|| pack_objects.map(|packs| disk_usage(Path::new(packs))));
+        warn_on_unsuitable_datadir(datadir, needed);
+    }
+
+    let mut path_specs = resources
+        .entries()
+        .filter(|(path, _)| on_checkout.as_mut().map_or(true, |filter| (filter.0)(path)))
+        .map(|(_, spec)| spec);
+    // Present the files relative to the manifest, not to the root of the repository.
+    let checkout_commit = if path_in_vcs.as_os_str().is_empty() {
+        commit_id.clone()
+    } else {
+        shallow.subtree_commit(git, commit_id, path_in_vcs)
+    };
+
+    // The shared checkout is not ours to remove when the test fails.
+    let mut worktree = None;
+    if prewarmed.is_none() {
+        let phase = Instant::now();
+        let checkout = shallow.checkout(
+            git,
+            &datapath,
+            &checkout_commit,
+            &mut path_specs,
+            additive_sparse,
+        );
+        timings.slow_checkout = checkout.slow_checkout();
+        timings.checkout = phase.elapsed() - timings.slow_checkout.unwrap_or_default();
+        worktree = Some(if checkout_dir.is_some() {
+            checkout.persistent()
+        } else {
+            checkout
+        });
+    }
+    drop(path_specs);
+
+    let keep = match retention {
+        Retention::KeepAll => None,
+        Retention::KeepLatest(latest) => Some(latest),
+        Retention::KeepNone => Some(0),
+    };
+    if let Some(keep) = keep {
+        shallow.remove_checkouts(git, datadir, "xtest-data-tree", &datapath, keep);
+    }
+
+    PackagedCheckout {
+        datapath,
+        shallow,
+        objects,
+        commit: checkout_commit,
+        worktree,
+        in_use,
+        lock,
+    }
+}
+
+/// Extract the resources from `git archive --remote` into a fresh directory of the data.
+///
+/// Returns `None` if the resources need a repository or the server refuses the archive, the data
+/// is then checked out instead.
+fn remote_archive(
+    git: &git::Git,
+    url: &OsStr,
+    datadir: &Path,
+    commit: &git::CommitId,
+    path_in_vcs: &Path,
+    resources: &Resources,
+    on_checkout: &mut Option<CheckoutFilter>,
+) -> Option<PathBuf> {
+    // These need the objects of the commit, which an archive does not provide.
+    if !resources.objects().is_empty()
+        || !resources.globs(|_| vec![]).is_empty()
+        || !resources.if_exists.is_empty()
+    {
+        return None;
+    }
+
+    let specs: Vec<_> = resources
+        .entries()
+        .filter(|(path, _)| on_checkout.as_mut().map_or(true, |filter| (filter.0)(path)))
+        .map(|(_, spec)| spec)
+        .collect();
+    // Present the files relative to the manifest, not to the root of the repository.
+    let treeish = if path_in_vcs.as_os_str().is_empty() {
+        commit.to_string()
+    } else {
+        format!("{}:{}", commit, path_in_vcs.display())
+    };
+
+    let dir =
+        unique_dir(datadir, "xtest-data-tree").unwrap_or_else(|mut err| inconclusive(&mut err));
+    // Without any path the archive would contain the whole tree.
+    if specs.is_empty() {
+        return Some(dir);
+    }
+
+    match git.remote_archive(url, &treeish, &specs, &dir) {
+        Ok(()) => Some(dir),
+        Err(err) => {
+            eprintln!(
+                "xtest-data: the remote did not provide an archive, checking out instead: {}",
+                err
+            );
+            let _ = fs::remove_dir_all(&dir);
+            None
+        }
+    }
+}
+
+/// The shared checkout of [`Setup::prewarm()`] for a commit.
+///
+/// The data of a commit does not change, so later test runs reuse the checkout instead of
+/// leaving one behind for each process.
+fn prewarm_dir(datadir: &Path, commit: &git::CommitId) -> PathBuf {
+    datadir.join(format!("xtest-data-prewarm-{}", commit))
+}
+
+/// The file listing the paths within the shared checkout at `dir`.
+fn prewarm_list(dir: &Path) -> PathBuf {
+    dir.with_extension("paths")
+}
+
+fn read_prewarm_list(list: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(list)
+        .unwrap_or_default()
+        .lines()
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// The shared checkout of [`Setup::prewarm()`], if it contains all resources.
+fn prewarmed_checkout(
+    datadir: &Path,
+    commit: &git::CommitId,
+    resources: &Resources,
+) -> Option<PathBuf> {
+    let dir = prewarm_dir(datadir, commit);
+    let prewarmed = read_prewarm_list(&prewarm_list(&dir));
+    if prewarmed.is_empty() || !dir.join(".git").is_file() {
+        return None;
+    }
+
+    let covered = resources.entries().all(|(path, _)| {
+        prewarmed
+            .iter()
+            .any(|prewarmed| path.starts_with(prewarmed))
+    });
+    if covered {
+        Some(dir)
+    } else {
+        None
+    }
+}
+
+/// Write the record of [`Setup::write_lock()`].
+///
+/// Each resource lists its files relative to the directory it was materialized in, see `roots`.
+fn write_lock_file(
+    lock_file: &Path,
+    git: &git::Git,
+    origin: Option<(&OsStr, &git::CommitId)>,
+    root: &Path,
+    resources: &Resources,
+    roots: &[PathBuf],
+    globbed: &[(usize, Vec<PathBuf>)],
+) {
+    fn collect_files(root: &Path, relative: &Path, files: &mut Vec<PathBuf>) {
+        let meta = match fs::symlink_metadata(root.join(relative)) {
+            Ok(meta) => meta,
+            Err(_) => return,
+        };
+
+        if meta.is_file() {
+            files.push(relative.to_owned());
+        } else if meta.is_dir() {
+            let entries =
+                fs::read_dir(root.join(relative)).unwrap_or_else(|mut err| inconclusive(&mut err));
+            for entry in entries {
+                let entry = entry.unwrap_or_else(|mut err| inconclusive(&mut err));
+                collect_files(root, &relative.join(entry.file_name()), files);
+            }
+        }
+    }
+
+    let string = |value: String| JsonValue::String(value);
+    let record = |root: &Path, relative: &[&Path], fields: Vec<(&str, String)>| {
+        let mut files = vec![];
+        relative
+            .iter()
+            .for_each(|path| collect_files(root, path, &mut files));
+        files.sort();
+        files.dedup();
+
+        let ids = if files.is_empty() {
+            vec![]
+        } else {
+            git.hash_objects(root, &files)
+        };
+        let files = files
+            .iter()
+            .zip(ids)
+            .map(|(path, id)| {
+                let digest = sha256::file_digest(&root.join(path)).unwrap_or_else(|err| {
+                    inconclusive(&mut format!("Hashing {} failed: {}", path.display(), err))
+                });
+                let mut file = std::collections::HashMap::new();
+                file.insert("sha256".to_string(), string(digest));
+                file.insert("oid".to_string(), string(id));
+                (path.display().to_string(), JsonValue::Object(file))
+            })
+            .collect();
+
+        let mut resource: std::collections::HashMap<_, _> = fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), string(value)))
+            .collect();
+        resource.insert("files".to_string(), JsonValue::Object(files));
+        JsonValue::Object(resource)
+    };
+
+    let mut specs = vec![];
+    for (key, item) in resources.relative_files.iter().enumerate() {
+        let mut fields = vec![];
+        if let (Some(path), Some(spec)) = (item.as_path(), item.as_path_spec()) {
+            fields.push(("path", path.display().to_string()));
+            fields.push(("pathspec", spec.to_string()));
+        }
+
+        let matched: Vec<&Path>;
+        let relative = match item {
+            Managed::Files(path) | Managed::Optional(path) => vec![path.as_path()],
+            Managed::Glob(_) => {
+                matched = globbed
+                    .iter()
+                    .filter(|(glob, _)| *glob == key)
+                    .flat_map(|(_, files)| files.iter().map(PathBuf::as_path))
+                    .collect();
+                matched
+            }
+            Managed::Object(oid) => {
+                fields.push(("object", oid.clone()));
+                vec![Path::new(oid)]
+            }
+            Managed::Renamed { name, .. } => {
+                fields.push(("name", name.display().to_string()));
+                vec![name.as_path()]
+            }
+        };
+        specs.push(record(&roots[key], &relative, fields));
+    }
+
+    for item in &resources.unmanaged {
+        let path: &Path = item;
+        let fields = vec![
+            ("path", path.display().to_string()),
+            ("pathspec", git::PathSpec::Path(path).to_string()),
+        ];
+        specs.push(record(root, &[path], fields));
+    }
+
+    let mut lock = std::collections::HashMap::new();
+    lock.insert(
+        "origin".to_string(),
+        origin.map_or(JsonValue::Null, |(url, _)| {
+            string(url.to_string_lossy().into_owned())
+        }),
+    );
+    lock.insert(
+        "commit".to_string(),
+        origin.map_or(JsonValue::Null, |(_, id)| string(id.to_string())),
+    );
+    lock.insert("resources".to_string(), JsonValue::Array(specs));
+
+    let lock = JsonValue::Object(lock)
+        .format()
+        .unwrap_or_else(|mut err| inconclusive(&mut err));
+    fs::write(lock_file, lock).unwrap_or_else(|mut err| inconclusive(&mut err));
+}
+
+/// Print the resources that would have been fetched, one line each.
+///
+/// The lines have the form `xtest-data-plan: <commit> <path>` which the `xtask` collects from the
+/// output of a test run to learn the data requirements of a crate.
+fn report_plan(commit: &git::CommitId, resources: &Resources) {
+    for (path, _) in resources.entries() {
+        eprintln!("xtest-data-plan: {} {}", commit, path.display());
+    }
+}
+
+// We do not use tempdir. This should already be done by our environment (e.g. cargo).
+fn unique_dir(base: &Path, prefix: &str) -> Result<PathBuf, std::io::Error> {
+    assert!(matches!(
+        Path::new(prefix).components().next(),
+        Some(std::path::Component::Normal(_))
+    ));
+    assert!(Path::new(prefix).components().nth(1).is_none());
+
+    let mut buffer = prefix.to_string();
+    let mut generate_name = move || -> PathBuf {
         const TABLE: &str = "0123456789abcdef";
-        let num: [u8; 8] = rng.rand();
+        let num = name_bytes();
 
         buffer.clear();
         buffer.push_str(prefix);
@@ -518,10 +3267,1276 @@ fn unique_dir(base: &Path, prefix: &str) -> Result<PathBuf, std::io::Error> {
     }
 }
 
+/// Bytes that distinguish the names of our directories.
+#[cfg(feature = "nanorand")]
+fn name_bytes() -> [u8; 8] {
+    use nanorand::Rng;
+    nanorand::tls::tls_rng().rand()
+}
+
+/// Bytes that distinguish the names of our directories.
+///
+/// Without randomness we use the process id and a counter. The names never repeat within one
+/// process, and the collision loop of `unique_dir` skips names left behind by other processes.
+#[cfg(not(feature = "nanorand"))]
+fn name_bytes() -> [u8; 8] {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let pid = std::process::id();
+
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&pid.to_be_bytes());
+    bytes[4..].copy_from_slice(&count.to_be_bytes());
+    bytes
+}
+
+std::thread_local! {
+    /// Where the current thread reports setup failures, standard error if `None`.
+    static ERROR_SINK: std::cell::RefCell<Option<Box<dyn io::Write>>> = std::cell::RefCell::new(None);
+}
+
+/// Redirect the reports of setup failures on the current thread, returning the previous sink.
+///
+/// By default, and when `None` is passed, the reasons for a failed setup are written to standard
+/// error before panicking. A harness can capture them to present them differently. The sink is
+/// per thread, as is the default test runner of `cargo`, so each test can capture its own reports.
+pub fn set_error_sink(sink: Option<Box<dyn io::Write>>) -> Option<Box<dyn io::Write>> {
+    ERROR_SINK.with(|current| current.replace(sink))
+}
+
 #[cold]
 #[track_caller]
 fn inconclusive(err: &mut dyn std::fmt::Display) -> ! {
-    eprintln!("xtest-data failed to setup.");
-    eprintln!("Information: {}", err);
+    ERROR_SINK.with(|sink| match &mut *sink.borrow_mut() {
+        Some(sink) => {
+            // The panic follows regardless, we can not report a failure to report.
+            let _ = writeln!(sink, "xtest-data failed to setup.");
+            let _ = writeln!(sink, "Information: {}", err);
+            let _ = sink.flush();
+        }
+        None => {
+            eprintln!("xtest-data failed to setup.");
+            eprintln!("Information: {}", err);
+        }
+    });
     panic!();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{available_space, mount_type, ExtractMode, Managed, Resources, Retention, Setup};
+    use crate::git::tests::Repository;
+    use std::path::{Path, PathBuf};
+
+    /// Unpack the crate as if it was packaged from the current `HEAD` of the repository.
+    fn packaged_crate(repo: &Repository, scratch: &Path) -> PathBuf {
+        let unpacked = scratch.join("foo-0.1.0");
+        std::fs::create_dir(&unpacked).unwrap();
+        let vcs_info = format!(r#"{{ "git": {{ "sha1": "{}" }} }}"#, repo.head());
+        std::fs::write(unpacked.join(".cargo_vcs_info.json"), vcs_info).unwrap();
+        unpacked
+    }
+
+    #[test]
+    fn entries_align_resources_and_pathspecs() {
+        let mut first = PathBuf::from("tests/rewritten.bin");
+        let mut second = PathBuf::from("tests/assets");
+
+        let mut resources = Resources::default();
+        resources
+            .relative_files
+            .push(Managed::Files("tests/data.zip".into()));
+        resources.unmanaged.push(&mut first);
+        resources
+            .relative_files
+            .push(Managed::Files("tests/other.zip".into()));
+        resources.unmanaged.push(&mut second);
+
+        let entries: Vec<_> = resources.entries().collect();
+        let paths: Vec<_> = entries.iter().map(|(path, _)| *path).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("tests/data.zip"),
+                Path::new("tests/other.zip"),
+                Path::new("tests/rewritten.bin"),
+                Path::new("tests/assets"),
+            ]
+        );
+
+        for (path, spec) in &entries {
+            assert_eq!(spec.as_encompassing_path(), Some(*path));
+        }
+
+        assert_eq!(resources.required().count(), entries.len());
+    }
+
+    #[test]
+    fn available_space_from_df() {
+        let df = "\
+Filesystem     1024-blocks  Used Available Capacity Mounted on
+tmpfs               65536  1024     64512       2% /tmp
+";
+        assert_eq!(available_space(df), Some(64512 * 1024));
+        assert_eq!(available_space(""), None);
+    }
+
+    #[test]
+    fn innermost_mount_type() {
+        let mounts = "\
+/dev/vda / ext4 rw,relatime 0 0
+tmpfs /tmp tmpfs rw,nosuid,nodev 0 0
+server:/export /tmp/shared\\040data nfs4 rw 0 0
+";
+        assert_eq!(mount_type(mounts, Path::new("/home/user")), Some("ext4"));
+        assert_eq!(mount_type(mounts, Path::new("/tmp/xtest")), Some("tmpfs"));
+        assert_eq!(
+            mount_type(mounts, Path::new("/tmp/shared data/x")),
+            Some("nfs4")
+        );
+        assert_eq!(mount_type(mounts, Path::new("/tmpfoo")), Some("ext4"));
+    }
+
+    #[test]
+    fn missing_tracked_file_is_reported() {
+        let repo = Repository::new();
+        repo.write("tests/kept.bin", "kept");
+        repo.write("tests/removed.bin", "removed");
+        repo.commit();
+        std::fs::remove_file(repo.0.join("tests/removed.bin")).unwrap();
+
+        let mut resources = Resources::default();
+        resources
+            .relative_files
+            .push(Managed::Files("tests/kept.bin".into()));
+        resources
+            .relative_files
+            .push(Managed::Files("tests/removed.bin".into()));
+        assert_eq!(
+            resources.missing(&repo.0),
+            Some(Path::new("tests/removed.bin"))
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn build_rejects_missing_tracked_file() {
+        let repo = Repository::new();
+        repo.write("tests/removed.bin", "removed");
+        repo.commit();
+        std::fs::remove_file(repo.0.join("tests/removed.bin")).unwrap();
+
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, None);
+        let _ = vcs.add("tests/removed.bin");
+        let _ = vcs.build();
+    }
+
+    #[test]
+    fn subdirectory_crate_is_checked_out_relative_to_manifest() {
+        let repo = Repository::new();
+        repo.write("crates/foo/tests/data.zip", "foo");
+        repo.write("tests/data.zip", "root");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-subdir").unwrap();
+        let pack = scratch.join("pack");
+        let repository = "https://example.com/repo";
+
+        // Pack from the source, where the crate is in a subdirectory of the repository.
+        let mut vcs = Setup::from_parts(repository, &repo.0.join("crates/foo"), Some(&scratch))
+            .pack_objects(pack.clone());
+        let _ = vcs.add("tests/data.zip");
+        let _ = vcs.build();
+
+        // Then test as the crate packaged from that subdirectory.
+        let unpacked = packaged_crate(&repo, &scratch);
+        let vcs_info = format!(
+            r#"{{ "git": {{ "sha1": "{}" }}, "path_in_vcs": "crates/foo" }}"#,
+            repo.head()
+        );
+        std::fs::write(unpacked.join(".cargo_vcs_info.json"), vcs_info).unwrap();
+
+        let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch)).pack_objects(pack);
+        let datazip = vcs.add("tests/data.zip");
+        let testdata = vcs.build();
+
+        let path = testdata.path(&datazip).to_owned();
+        let content = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        let checkout = path.strip_prefix(&scratch).unwrap();
+        assert_eq!(checkout.components().count(), 3, "{}", path.display());
+        assert!(checkout.ends_with("tests/data.zip"), "{}", path.display());
+        assert_eq!(content.unwrap(), "foo");
+    }
+
+    #[test]
+    fn single_pack_file() {
+        let repo = Repository::new();
+        repo.write("tests/first.bin", "first");
+        repo.write("tests/second.bin", "second");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-single").unwrap();
+        let pack = scratch.join("packs/data.pack");
+        let repository = "https://example.com/repo";
+
+        // Each build adds its objects to the same file.
+        for path in ["tests/first.bin", "tests/second.bin"] {
+            let mut vcs =
+                Setup::from_parts(repository, &repo.0, Some(&scratch)).pack_objects(pack.clone());
+            let _ = vcs.add(path);
+            let _ = vcs.build();
+        }
+
+        let packs: Vec<_> = std::fs::read_dir(pack.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(packs, ["data.pack"]);
+
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch)).pack_objects(pack);
+        let first = vcs.add("tests/first.bin");
+        let second = vcs.add("tests/second.bin");
+        let testdata = vcs.build();
+
+        let first = std::fs::read_to_string(testdata.path(&first));
+        let second = std::fs::read_to_string(testdata.path(&second));
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(first.unwrap(), "first");
+        assert_eq!(second.unwrap(), "second");
+    }
+
+    #[test]
+    fn generate_pack_then_unpack() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-generate").unwrap();
+        let pack = scratch.join("pack");
+        let repository = "https://example.com/repo";
+
+        let mut vcs = Setup::from_parts(repository, &repo.0, Some(&scratch));
+        let _ = vcs.add("tests/data.bin");
+        let _ = vcs.generate_pack(pack.clone());
+
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        // Generating again from the packaged crate has nothing to pack from.
+        let report = capture_report(|| {
+            let _ = Setup::from_parts(repository, &unpacked, Some(&scratch))
+                .generate_pack(scratch.join("other"));
+        });
+
+        let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch)).pack_objects(pack);
+        let data = vcs.add("tests/data.bin");
+        let testdata = vcs.build();
+
+        let content = std::fs::read_to_string(testdata.path(&data));
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(content.unwrap(), "data");
+        assert!(
+            report.contains("only be generated from a local checkout"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn lazy_path_is_not_packed() {
+        let repo = Repository::new();
+        repo.write("tests/small.bin", "small");
+        repo.write("tests/asset.bin", "asset");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-lazy").unwrap();
+        let repository = "https://example.com/repo";
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        // Pack with the asset registered eagerly or lazily, then test the packaged crate.
+        let test = |lazy: bool| {
+            let data = scratch.join(if lazy { "lazy" } else { "eager" });
+            std::fs::create_dir(&data).unwrap();
+            let pack = data.join("pack");
+
+            let mut vcs = Setup::from_parts(repository, &repo.0, Some(&data));
+            let _ = vcs.add("tests/small.bin");
+            let _ = match lazy {
+                true => vcs.add_lazy("tests/asset.bin"),
+                false => vcs.add("tests/asset.bin"),
+            };
+            let _ = vcs.generate_pack(pack.clone());
+
+            let mut vcs = Setup::from_parts(repository, &unpacked, Some(&data))
+                .pack_objects(pack)
+                .reference_repo(repo.0.clone());
+            let small = vcs.add("tests/small.bin");
+            let asset = vcs.add_lazy("tests/asset.bin");
+            let testdata = vcs.build();
+
+            assert_eq!(std::fs::read(testdata.path(&small)).unwrap(), b"small");
+            assert_eq!(std::fs::read(testdata.path(&asset)).unwrap(), b"asset");
+            testdata.stats().objects
+        };
+
+        let eager = test(false);
+        let lazy = test(true);
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(eager, lazy + 1, "only the blob of the asset is missing");
+    }
+
+    #[test]
+    fn note_as_object() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        repo.git(&[
+            "-c",
+            "user.name=xtest-data",
+            "-c",
+            "user.email=xtest-data@localhost",
+            "notes",
+            "add",
+            "-m",
+            "test vector",
+            "HEAD",
+        ]);
+        let note = std::process::Command::new("git")
+            .args(["notes", "list", "HEAD"])
+            .current_dir(&repo.0)
+            .output()
+            .unwrap();
+        let note = String::from_utf8(note.stdout).unwrap();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-note").unwrap();
+        let pack = scratch.join("pack");
+        let repository = "https://example.com/repo";
+
+        let mut vcs = Setup::from_parts(repository, &repo.0, Some(&scratch));
+        let vector = vcs.add_object(note.trim());
+        let testdata = vcs.generate_pack(pack.clone());
+        let local = std::fs::read_to_string(testdata.path(&vector));
+
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch)).pack_objects(pack);
+        let vector = vcs.add_object(note.trim());
+        let testdata = vcs.build();
+        let packaged = std::fs::read_to_string(testdata.path(&vector));
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(local.unwrap(), "test vector\n");
+        assert_eq!(packaged.unwrap(), "test vector\n");
+    }
+
+    #[test]
+    fn objects_and_renames_are_reused() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        let blob = std::process::Command::new("git")
+            .args(["rev-parse", "HEAD:tests/data.bin"])
+            .current_dir(&repo.0)
+            .output()
+            .unwrap();
+        let blob = String::from_utf8(blob.stdout).unwrap();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-reuse").unwrap();
+        let build = || {
+            let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, Some(&scratch));
+            let object = vcs.add_object(blob.trim());
+            let renamed = vcs.add_with_rename("tests/data.bin", Path::new("big.bin"));
+            let testdata = vcs.build();
+            (
+                testdata.path(&object).to_owned(),
+                testdata.path(&renamed).to_owned(),
+            )
+        };
+
+        let first = build();
+        let second = build();
+        let contents = std::fs::read_to_string(&second.1);
+        let entries = std::fs::read_dir(&scratch).unwrap().count();
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(first, second);
+        assert_eq!(contents.unwrap(), "data");
+        assert_eq!(entries, 2);
+    }
+
+    #[test]
+    fn lock_covers_every_resource() {
+        use std::collections::HashMap;
+        use tinyjson::JsonValue;
+
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.write("tests/glob/x.txt", "data");
+        repo.commit();
+        let blob = std::process::Command::new("git")
+            .args(["rev-parse", "HEAD:tests/data.bin"])
+            .current_dir(&repo.0)
+            .output()
+            .unwrap();
+        let blob = String::from_utf8(blob.stdout).unwrap();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-lock").unwrap();
+        let lock_file = scratch.join("xtest-data.lock");
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, Some(&scratch))
+            .write_lock(lock_file.clone());
+        let _ = vcs.add("tests/data.bin");
+        let _ = vcs.add_glob("tests/glob/*.txt");
+        let _ = vcs.add_object(blob.trim());
+        let _ = vcs.add_with_rename("tests/data.bin", Path::new("big.bin"));
+        let _ = vcs.build();
+
+        let lock: JsonValue = std::fs::read_to_string(&lock_file)
+            .unwrap()
+            .parse()
+            .unwrap();
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        let lock: &HashMap<_, _> = lock.get().unwrap();
+        let resources: &Vec<JsonValue> = lock["resources"].get().unwrap();
+        let files: Vec<Vec<(String, String)>> = resources
+            .iter()
+            .map(|resource| {
+                let resource: &HashMap<_, _> = resource.get().unwrap();
+                let files: &HashMap<_, _> = resource["files"].get().unwrap();
+                files
+                    .iter()
+                    .map(|(path, file)| {
+                        let file: &HashMap<_, _> = file.get().unwrap();
+                        let digest: &String = file["sha256"].get().unwrap();
+                        (path.clone(), digest.clone())
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // The digest of `data`, as printed by `sha256sum`.
+        let digest = "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7".to_string();
+        assert_eq!(
+            files,
+            [
+                vec![("tests/data.bin".to_string(), digest.clone())],
+                vec![("tests/glob/x.txt".to_string(), digest.clone())],
+                vec![(blob.trim().to_string(), digest.clone())],
+                vec![("big.bin".to_string(), digest)],
+            ]
+        );
+    }
+
+    #[test]
+    fn readonly_checkout() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.write("tests/tree/nested.bin", "nested");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-readonly").unwrap();
+        let pack = scratch.join("pack");
+        let repository = "https://example.com/repo";
+
+        let mut vcs = Setup::from_parts(repository, &repo.0, Some(&scratch));
+        let local = vcs.add_readonly("tests/data.bin");
+        let _ = vcs.add_readonly("tests/tree");
+        let testdata = vcs.generate_pack(pack.clone());
+        let readonly = |path: &Path| std::fs::metadata(path).unwrap().permissions().readonly();
+        // The sources are left alone.
+        assert!(!readonly(testdata.path(&local)));
+
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch)).pack_objects(pack);
+        let data = vcs.add_readonly("tests/data.bin");
+        let tree = vcs.add_readonly("tests/tree");
+        let testdata = vcs.build();
+
+        let data = testdata.path(&data).to_owned();
+        let tree = testdata.path(&tree).to_owned();
+        let protected = readonly(&data) && readonly(&tree.join("nested.bin")) && !readonly(&tree);
+        // A superuser writes to read-only files regardless, probe for that first.
+        let probe = scratch.join("probe");
+        std::fs::write(&probe, "probe").unwrap();
+        super::set_readonly(&probe, true).unwrap();
+        let privileged = std::fs::write(&probe, "written").is_ok();
+        let written = std::fs::write(&data, "overwritten").is_ok();
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert!(protected);
+        assert!(privileged || !written);
+    }
+
+    #[test]
+    fn readonly_skipped_by_filter() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.write("tests/skipped.bin", "skipped");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-readonly").unwrap();
+        let pack = scratch.join("pack");
+        let repository = "https://example.com/repo";
+
+        let mut vcs = Setup::from_parts(repository, &repo.0, Some(&scratch));
+        let _ = vcs.add("tests/data.bin");
+        let _ = vcs.add("tests/skipped.bin");
+        let _ = vcs.generate_pack(pack.clone());
+
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch))
+            .pack_objects(pack)
+            .on_checkout(|path| path != Path::new("tests/skipped.bin"));
+        let data = vcs.add_readonly("tests/data.bin");
+        let skipped = vcs.add_readonly("tests/skipped.bin");
+        let testdata = vcs.build();
+
+        let data = testdata.path(&data).to_owned();
+        let skipped = testdata.path(&skipped).exists();
+        let protected = std::fs::metadata(&data).unwrap().permissions().readonly();
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert!(protected);
+        assert!(!skipped);
+    }
+
+    #[test]
+    fn glob_in_local_checkout() {
+        let repo = Repository::new();
+        repo.write("tests/a.bin", "a");
+        repo.write("tests/b.bin", "b");
+        repo.write("tests/c.txt", "c");
+        repo.write("tests/nested/d.bin", "d");
+        repo.commit();
+
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, None);
+        let bins = vcs.add_glob("tests/*.bin");
+        let none = vcs.add_glob(Path::new("tests/*.png"));
+        let testdata = vcs.build();
+
+        let tests = repo.0.join("tests");
+        assert_eq!(testdata.path(&bins), tests);
+        assert_eq!(
+            testdata.entries(&bins),
+            [tests.join("a.bin"), tests.join("b.bin")]
+        );
+        assert_eq!(
+            testdata.find(&bins, Path::new("b.bin")),
+            Some(&*tests.join("b.bin"))
+        );
+        assert!(testdata.entries(&none).is_empty());
+    }
+
+    #[test]
+    fn map_paths_in_local_checkout() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.write("tests/tree/a.txt", "a");
+        repo.commit();
+
+        let mut rewritten = PathBuf::from("tests/data.bin");
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, None)
+            .rewrite([&mut rewritten])
+            .map_paths(|path| {
+                path.components()
+                    .map(|component| match component.as_os_str().to_str() {
+                        Some("tests") => "TESTS".as_ref(),
+                        _ => component.as_os_str(),
+                    })
+                    .collect()
+            });
+        let data = vcs.add("tests/data.bin");
+        let tree = vcs.add("tests/tree");
+        let testdata = vcs.build();
+
+        let tests = repo.0.join("TESTS");
+        assert_eq!(testdata.path(&data), tests.join("data.bin"));
+        assert_eq!(testdata.path(&tree), tests.join("tree"));
+        assert_eq!(
+            testdata.find(&tree, Path::new("a.txt")),
+            Some(&*tests.join("tree/a.txt"))
+        );
+        assert_eq!(rewritten, tests.join("data.bin"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn map_paths_and_find() {
+        let repo = Repository::new();
+        repo.write("tests/tree/z.txt", "z");
+        std::os::unix::fs::symlink("z.txt", repo.0.join("tests/tree/b_link")).unwrap();
+        repo.commit();
+
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, None)
+            .map_paths(|path| path.canonicalize().unwrap_or_else(|_| path.to_owned()));
+        let tree = vcs.add("tests/tree");
+        let testdata = vcs.build();
+
+        // The link resolves to its target, which sorts after the link itself.
+        let target = repo.0.join("tests/tree/z.txt").canonicalize().unwrap();
+        assert_eq!(testdata.find(&tree, Path::new("b_link")), Some(&*target));
+        assert_eq!(testdata.find(&tree, Path::new("z.txt")), Some(&*target));
+        assert_eq!(testdata.find(&tree, Path::new("a.txt")), None);
+    }
+
+    #[test]
+    fn glob_in_packaged_crate() {
+        let repo = Repository::new();
+        repo.write("tests/a.bin", "a");
+        repo.write("tests/c.txt", "c");
+        repo.write("tests/nested/d.bin", "d");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-glob").unwrap();
+        let pack = scratch.join("pack");
+        let repository = "https://example.com/repo";
+        let mut vcs = Setup::from_parts(repository, &repo.0, Some(&scratch));
+        let _ = vcs.add_glob("tests/**/*.bin");
+        let local = vcs.generate_pack(pack.clone());
+        let local: Vec<_> = local.contents[0]
+            .iter()
+            .map(|path| path.strip_prefix(&repo.0).unwrap().to_owned())
+            .collect();
+
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch)).pack_objects(pack);
+        let bins = vcs.add_glob("tests/**/*.bin");
+        let testdata = vcs.build();
+        let root = testdata.root(&bins).to_owned();
+        let packaged: Vec<_> = testdata
+            .entries(&bins)
+            .iter()
+            .map(|path| path.strip_prefix(&root).unwrap().to_owned())
+            .collect();
+        let data = std::fs::read_to_string(root.join("tests/nested/d.bin"));
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(
+            local,
+            [Path::new("tests/a.bin"), Path::new("tests/nested/d.bin")]
+        );
+        assert_eq!(packaged, local);
+        assert_eq!(data.unwrap(), "d");
+    }
+
+    #[test]
+    fn if_exists_at_older_commit() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-exists").unwrap();
+        let pack = scratch.join("pack");
+        let repository = "https://example.com/repo";
+        let mut vcs = Setup::from_parts(repository, &repo.0, Some(&scratch));
+        let _ = vcs.add("tests/data.bin");
+        let _ = vcs.generate_pack(pack.clone());
+
+        // Newer tests use a fixture that the pinned commit predates.
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch)).pack_objects(pack);
+        let data = vcs.add_if_exists("tests/data.bin");
+        let newer = vcs.add_if_exists("tests/newer.bin");
+        let testdata = vcs.build();
+        let content = std::fs::read_to_string(testdata.path(&data));
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert!(testdata.is_available(&data));
+        assert_eq!(content.unwrap(), "data");
+        assert!(!testdata.is_available(&newer));
+    }
+
+    #[test]
+    fn remote_archive_or_checkout() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        let remote = repo.0.to_str().unwrap().to_owned();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-archive").unwrap();
+        let pack = scratch.join("pack");
+        let mut vcs = Setup::from_parts(&remote, &repo.0, Some(&scratch));
+        let _ = vcs.add("tests/data.bin");
+        let _ = vcs.generate_pack(pack.clone());
+
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        let build = |datadir: &Path, pack: Option<&Path>, refused: Option<&'static str>| {
+            std::fs::create_dir(datadir).unwrap();
+            let mut vcs = Setup::from_parts(&remote, &unpacked, Some(datadir))
+                .extract_mode(ExtractMode::RemoteArchive);
+            vcs.fetch_refused = refused;
+            if let Some(pack) = pack {
+                vcs = vcs.pack_objects(pack.to_owned());
+            }
+            let data = vcs.add("tests/data.bin");
+            let testdata = vcs.build();
+            let content = std::fs::read_to_string(testdata.path(&data)).unwrap();
+            (content, datadir.join("xtest-data-git").exists())
+        };
+
+        // By default the server refuses to archive a commit that is not named by a ref.
+        let refused = build(&scratch.join("refused"), Some(&pack), None);
+        repo.git(&["config", "uploadArchive.allowUnreachable", "true"]);
+        let archived = build(&scratch.join("archived"), None, None);
+        // Without consent the remote is not asked at all.
+        let offline = build(&scratch.join("offline"), Some(&pack), Some("offline"));
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(refused, ("data".to_string(), true));
+        assert_eq!(archived, ("data".to_string(), false));
+        assert_eq!(offline, ("data".to_string(), true));
+    }
+
+    #[test]
+    fn dry_run_of_remote_archive() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        let remote = repo.0.to_str().unwrap().to_owned();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-archive").unwrap();
+        let unpacked = packaged_crate(&repo, &scratch);
+        let plan = |refused: Option<&'static str>| {
+            let mut vcs = Setup::from_parts(&remote, &unpacked, Some(&scratch))
+                .extract_mode(ExtractMode::RemoteArchive);
+            vcs.fetch_refused = refused;
+            let _ = vcs.add("tests/data.bin");
+            vcs.build_dry_run()
+        };
+
+        // Without pack objects or a reference the archive is the only source.
+        let archived = plan(None);
+        let report = capture_report(|| {
+            let _ = plan(Some("offline"));
+        });
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(archived.commit, Some(repo.head().to_string()));
+        assert!(archived.commit_resolved);
+        assert!(report.contains("no packed artifacts"), "{}", report);
+    }
+
+    #[test]
+    fn remote_archive_needs_consent() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| std::ffi::OsString::from(value))
+            }
+        };
+
+        assert!(super::fetch_refused(env(&[])).is_some());
+        assert!(super::fetch_refused(env(&[("CARGO_XTEST_DATA_FETCH", "no")])).is_some());
+        assert_eq!(
+            super::fetch_refused(env(&[("CARGO_XTEST_DATA_FETCH", "yes")])),
+            None
+        );
+        assert_eq!(
+            super::fetch_refused(env(&[("CARGO_XTEST_DATA_FETCH", "1")])),
+            None
+        );
+
+        // Offline takes precedence over the consent.
+        let offline = super::fetch_refused(env(&[
+            ("CARGO_NET_OFFLINE", "true"),
+            ("CARGO_XTEST_DATA_FETCH", "yes"),
+        ]));
+        assert!(offline.unwrap().contains("`CARGO_NET_OFFLINE`"));
+    }
+
+    #[test]
+    fn validate_clean_crate() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        let repository = "https://example.com/repo";
+
+        let mut vcs = Setup::from_parts(repository, &repo.0, None);
+        let _ = vcs.add("tests/data.bin");
+        let report = vcs.validate();
+        assert!(report.passed(), "{}", report);
+        let names: Vec<_> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, ["repository", "git", "paths"]);
+
+        repo.write("tests/untracked.bin", "untracked");
+        let _ = vcs.add("tests/untracked.bin");
+        let report = vcs.validate();
+        let failures: Vec<_> = report.failures().map(|check| check.name).collect();
+        assert_eq!(failures, ["paths"]);
+        assert!(report.to_string().contains("FAILED: paths: "), "{}", report);
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-validate").unwrap();
+        let unpacked = packaged_crate(&repo, &scratch);
+        let vcs = Setup::from_parts(repository, &unpacked, Some(&scratch))
+            .pack_objects(scratch.join("pack"));
+        let missing = vcs.validate();
+        std::fs::create_dir(scratch.join("pack")).unwrap();
+        let report = vcs.validate();
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(missing.failures().count(), 1, "{}", missing);
+        assert!(report.passed(), "{}", report);
+        let names: Vec<_> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(
+            names,
+            ["repository", "git", "paths", "data directory", "space"]
+        );
+    }
+
+    #[test]
+    fn validate_broken_repository() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+
+        // Reported instead of panicking, since nothing was committed yet.
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, None);
+        let _ = vcs.add("tests/data.bin");
+        let report = vcs.validate();
+        let failures: Vec<_> = report.failures().map(|check| check.name).collect();
+        assert_eq!(failures, ["paths"]);
+        assert!(report.to_string().contains("no commits yet"), "{}", report);
+    }
+
+    #[test]
+    fn packaged_or_local() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        let repository = "https://example.com/repo";
+        assert!(!Setup::from_parts(repository, &repo.0, None).is_packaged());
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-packaged").unwrap();
+        let unpacked = packaged_crate(&repo, &scratch);
+        let packaged = Setup::from_parts(repository, &unpacked, Some(&scratch)).is_packaged();
+        let _ = std::fs::remove_dir_all(&scratch);
+        assert!(packaged);
+    }
+
+    #[test]
+    fn retain_latest_checkouts() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-retain").unwrap();
+        let pack = scratch.join("pack");
+        let repository = "https://example.com/repo";
+        let mut vcs = Setup::from_parts(repository, &repo.0, Some(&scratch));
+        let _ = vcs.add("tests/data.bin");
+        let _ = vcs.generate_pack(pack.clone());
+
+        let unpacked = packaged_crate(&repo, &scratch);
+
+        let build = |retention| {
+            let mut vcs = Setup::from_parts(repository, &unpacked, Some(&scratch))
+                .pack_objects(pack.clone())
+                .retain(retention);
+            let data = vcs.add("tests/data.bin");
+            let testdata = vcs.build();
+            let path = testdata.path(&data).to_owned();
+            (testdata, path)
+        };
+        let checkouts = || {
+            std::fs::read_dir(&scratch)
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .filter(|entry| entry.file_type().unwrap().is_dir())
+                .filter(|entry| {
+                    entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with("xtest-data-tree")
+                })
+                .count()
+        };
+
+        for _ in 0..4 {
+            let _ = build(Retention::KeepAll);
+        }
+        assert_eq!(checkouts(), 4);
+
+        let (_, latest) = build(Retention::KeepLatest(2));
+        assert_eq!(checkouts(), 2);
+        assert!(latest.exists());
+
+        // A checkout still in use is kept.
+        let (in_use, used) = build(Retention::KeepAll);
+        let (_, latest) = build(Retention::KeepNone);
+        let remaining = checkouts();
+        let survived = used.exists() && latest.exists();
+        drop(in_use);
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(remaining, 2);
+        assert!(survived);
+    }
+
+    /// Run `f`, which must fail the setup, and return what it reported.
+    pub(crate) fn capture_report(f: impl FnOnce() + std::panic::UnwindSafe) -> String {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Capture(Rc<RefCell<Vec<u8>>>);
+
+        impl std::io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Rc::new(RefCell::new(vec![]));
+        let previous = super::set_error_sink(Some(Box::new(Capture(captured.clone()))));
+        let result = std::panic::catch_unwind(f);
+        let _ = super::set_error_sink(previous);
+
+        assert!(result.is_err());
+        let report = captured.take();
+        String::from_utf8(report).unwrap()
+    }
+
+    #[test]
+    fn error_sink_captures_report() {
+        let report = capture_report(|| {
+            let _ = Setup::from_parts("", Path::new(env!("CARGO_MANIFEST_DIR")), None);
+        });
+
+        assert!(report.starts_with(
+            "xtest-data failed to setup.\n\
+             Information: The crate must have a valid URL in `package.repository`."
+        ));
+    }
+
+    #[test]
+    fn missing_data_dir_is_explained() {
+        let tmpdir = Path::new("/tmp/xtest-data");
+        let selected = super::select_datadir(Some(tmpdir), |_| None);
+        assert_eq!(selected, tmpdir);
+
+        let report = capture_report(|| {
+            let _ = super::select_datadir(None, |_| None);
+        });
+        assert!(
+            report.contains("set `CARGO_XTEST_DATA_TMPDIR`"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn colliding_renames_are_rejected() {
+        let report = capture_report(|| {
+            let mut vcs = crate::setup!();
+            let _ = vcs.add_with_rename("tests/data.zip", Path::new("big.bin"));
+            let _ = vcs.add_with_rename("tests/tree/hello.txt", Path::new("big.bin"));
+        });
+
+        assert!(
+            report.contains(
+                "The local name `big.bin` for `tests/tree/hello.txt` collides with `big.bin`, chosen for `tests/data.zip`"
+            ),
+            "{}",
+            report
+        );
+
+        // A name within the tree of another one collides as well.
+        let report = capture_report(|| {
+            let mut vcs = crate::setup!();
+            let _ = vcs.add_with_rename("tests/tree", Path::new("fixtures"));
+            let _ = vcs.add_with_rename("tests/data.zip", Path::new("fixtures/data.zip"));
+        });
+        assert!(report.contains("collides with `fixtures`"), "{}", report);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn copy_tree_preserves_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::unique_dir(&std::env::temp_dir(), "xtest-data-copy").unwrap();
+        let source = dir.join("source");
+        std::fs::create_dir_all(source.join("nested")).unwrap();
+        std::fs::write(source.join("nested/run.sh"), "#!/bin/sh\n").unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let set_mode = |path: &Path, mode| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+        set_mode(&source.join("nested/run.sh"), 0o750);
+        std::os::unix::fs::symlink("nested/run.sh", source.join("link")).unwrap();
+
+        let dest = dir.join("copy");
+        super::copy_tree(&source, &dest).unwrap();
+        assert_eq!(mode(&dest.join("nested/run.sh")), 0o750);
+        assert_eq!(
+            std::fs::read_link(dest.join("link")).unwrap(),
+            Path::new("nested/run.sh")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repository_without_commits() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.git(&["add", "tests/data.bin"]);
+
+        let manifest = repo.0.clone();
+        let report = capture_report(move || {
+            let mut vcs = Setup::from_parts("https://example.com/repo", &manifest, None);
+            let _ = vcs.add("tests/data.bin");
+            let _ = vcs.build();
+        });
+
+        assert!(
+            report.contains("has no commits yet; commit your fixtures first"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn plausible_remotes() {
+        use super::is_plausible_remote;
+
+        for url in [
+            "https://github.com/HeroicKatora/xtest-data",
+            "ssh://git@example.com:2222/repo.git",
+            "git@github.com:HeroicKatora/xtest-data.git",
+            "example.com:repo.git",
+            "file:///srv/git/repo.git",
+            "/srv/git/repo.git",
+            "./mirror",
+            "persistent-https::https://example.com/repo",
+        ] {
+            assert!(is_plausible_remote(url), "{}", url);
+        }
+
+        for url in [
+            "not a repository",
+            "https:/github.com/HeroicKatora/xtest-data",
+            "https://github.com/Heroic Katora/xtest-data",
+            "github.com/HeroicKatora/xtest-data",
+            "git@github.com:",
+            "://example.com",
+        ] {
+            assert!(!is_plausible_remote(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn malformed_repository_url() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        let repository = "htps//example.com";
+
+        // Nothing is fetched from the repository of a local checkout.
+        let local = Setup::from_parts(repository, &repo.0, None);
+        assert!(local.validate().passed());
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-malformed").unwrap();
+        let unpacked = packaged_crate(&repo, &scratch);
+        let report = capture_report(|| {
+            let _ = Setup::from_parts(repository, &unpacked, Some(&scratch));
+        });
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert!(
+            report.contains("The repository URL looks malformed: htps//example.com"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn require_clean_rejects_modified_fixture() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        repo.write("tests/data.bin", "modified");
+
+        // The default is lenient and uses the modified data.
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, None);
+        let data = vcs.add("tests/data.bin");
+        let testdata = vcs.build();
+        assert_eq!(std::fs::read(testdata.path(&data)).unwrap(), b"modified");
+
+        let manifest = repo.0.clone();
+        let report = capture_report(move || {
+            let mut vcs = Setup::from_parts("https://example.com/repo", &manifest, None);
+            let _ = vcs.add("tests/data.bin");
+            let _ = vcs.require_clean().build();
+        });
+
+        assert!(
+            report.contains("Your test depends on file(s) with uncommitted changes"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn dirty_fixture_is_not_packed() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        repo.write("tests/data.bin", "modified");
+
+        let scratch = super::unique_dir(&std::env::temp_dir(), "xtest-data-dirty").unwrap();
+        let pack = scratch.join("pack");
+        let manifest = repo.0.clone();
+        let dirty_pack = pack.clone();
+        let report = capture_report(move || {
+            let mut vcs = Setup::from_parts("https://example.com/repo", &manifest, None);
+            vcs.allow_dirty_pack = false;
+            let _ = vcs.add("tests/data.bin");
+            let _ = vcs.generate_pack(dirty_pack);
+        });
+        assert!(
+            report.contains("uncommitted changes: `tests/data.bin`"),
+            "{}",
+            report
+        );
+
+        // Allowed, the pack is still generated from the committed data.
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, Some(&scratch));
+        vcs.allow_dirty_pack = true;
+        let data = vcs.add("tests/data.bin");
+        let testdata = vcs.generate_pack(pack.clone());
+        let has_pack = std::fs::read_dir(&pack).unwrap().next().is_some();
+        let _ = std::fs::remove_dir_all(&scratch);
+
+        assert_eq!(std::fs::read(testdata.path(&data)).unwrap(), b"modified");
+        assert!(has_pack);
+    }
+
+    #[test]
+    fn git_commands_are_reported() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+
+        let mut commands = vec![];
+        let config = vec![("http.extraHeader".to_owned(), "Bearer hunter2".to_owned())];
+        let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, None)
+            .git_config(config)
+            .on_git_command(|line| commands.push(line.to_owned()));
+        let _ = vcs.add("tests/data.bin");
+        let _ = vcs.build();
+
+        assert!(
+            commands.iter().any(|line| line.contains("\"status\"")),
+            "{:?}",
+            commands
+        );
+        assert!(
+            commands.iter().all(|line| !line.contains("hunter2")),
+            "{:?}",
+            commands
+        );
+    }
+
+    #[test]
+    fn skip_tracked_check_runs_no_status() {
+        let repo = Repository::new();
+        repo.write("tests/data.bin", "data");
+        repo.commit();
+        repo.write("tests/untracked.bin", "untracked");
+
+        // Record the invocations of git, and return them.
+        let trace = repo.0.join("trace");
+        let build = |skip: bool| {
+            let env = vec![("GIT_TRACE".into(), trace.clone().into_os_string())];
+            let mut vcs = Setup::from_parts("https://example.com/repo", &repo.0, None).git_env(env);
+            let data = vcs.add("tests/data.bin");
+            if skip {
+                let _ = vcs.add("tests/untracked.bin");
+                vcs = vcs.skip_tracked_check();
+            }
+            let testdata = vcs.build();
+            assert_eq!(std::fs::read(testdata.path(&data)).unwrap(), b"data");
+            let invocations = std::fs::read_to_string(&trace).unwrap_or_default();
+            let _ = std::fs::remove_file(&trace);
+            invocations
+        };
+
+        let checked = build(false);
+        assert!(checked.contains(" status "), "{}", checked);
+        let skipped = build(true);
+        assert!(!skipped.contains(" status "), "{}", skipped);
+    }
+
+    #[test]
+    fn rewrite_relative_path() {
+        let mut path = PathBuf::from("tests/./tree/../data.zip");
+        let _ = setup!().rewrite([&mut path]).build();
+
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(path, manifest.join("tests/data.zip"));
+    }
+
+    #[test]
+    fn rewrite_rejects_absolute_path() {
+        let report = capture_report(|| {
+            let mut path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data.zip");
+            let _ = setup!().rewrite([&mut path]);
+        });
+
+        assert!(
+            report.contains("must be relative to the crate's manifest directory"),
+            "{}",
+            report
+        );
+        assert!(report.contains("instead of a resolved path"), "{}", report);
+    }
+
+    #[test]
+    fn tree_registered_as_file() {
+        let report = capture_report(|| {
+            let mut vcs = setup!();
+            let _ = vcs.add_file("tests/tree");
+            let _ = vcs.build();
+        });
+
+        assert!(
+            report.contains("was registered as a file with `Setup::add_file` but is a directory"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn file_registered_as_tree() {
+        let report = capture_report(|| {
+            let mut vcs = setup!();
+            let _ = vcs.add_tree("tests/data.zip");
+            let _ = vcs.build();
+        });
+
+        assert!(
+            report
+                .contains("was registered as a tree with `Setup::add_tree` but is not a directory"),
+            "{}",
+            report
+        );
+        assert!(report.contains("tests/data.zip"), "{}", report);
+    }
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[test]
 fn integration_test_ourselves() {
@@ -17,3 +17,234 @@ fn simple_integration() {
     // 'Magically' changed.
     assert!(path.exists(), "{}", path.display());
 }
+
+#[test]
+fn rewrite_directory() {
+    let mut path = PathBuf::from("tests/tree");
+    xtest_data::setup!().rewrite([&mut path]).build();
+
+    let mut entries: Vec<_> = std::fs::read_dir(&path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    entries.sort();
+
+    assert_eq!(entries, ["hello.txt", "nested"]);
+    assert!(path.join("nested/world.txt").exists());
+}
+
+#[test]
+fn normalized_nested_path() {
+    let mut vcs = xtest_data::setup!();
+    let datazip = vcs.add("tests/./tree/../data.zip");
+    let testdata = vcs.build();
+
+    let path = testdata.path(&datazip);
+    assert!(path.ends_with("tests/data.zip"), "{}", path.display());
+    assert!(path.exists(), "{}", path.display());
+}
+
+#[test]
+#[should_panic]
+fn reject_escaping_path() {
+    let mut vcs = xtest_data::setup!();
+    let _ = vcs.add("tests/../../escape");
+}
+
+#[test]
+#[should_panic]
+fn reject_absolute_path() {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data.zip");
+    let _ = xtest_data::setup!().rewrite([&mut path]);
+}
+
+#[test]
+fn local_stats() {
+    let mut vcs = xtest_data::setup!();
+    let _ = vcs.add("tests/data.zip");
+    let stats = vcs.build().stats();
+
+    assert_eq!(stats.objects, 1);
+    assert!(stats.bytes > 0);
+    assert!(stats.from_cache);
+}
+
+#[test]
+fn from_parts_without_macro() {
+    // The runtime environment of the test, not the compile time environment of the caller.
+    let manifest = std::env::var_os("CARGO_MANIFEST_DIR").unwrap();
+    let tmpdir = std::env::var_os("CARGO_TARGET_TMPDIR");
+
+    let mut datazip = PathBuf::from("tests/data.zip");
+    xtest_data::Setup::from_parts(
+        "https://github.com/HeroicKatora/xtest-data",
+        Path::new(&manifest),
+        tmpdir.as_deref().map(Path::new),
+    )
+    .rewrite([&mut datazip])
+    .build();
+
+    assert!(datazip.exists(), "{}", datazip.display());
+}
+
+#[test]
+fn add_owned_path() {
+    let mut vcs = xtest_data::setup!();
+    let owned = vcs.add(PathBuf::from("tests/data.zip"));
+    let borrowed = vcs.add(Path::new("tests/data.zip"));
+    let testdata = vcs.build();
+
+    assert_eq!(testdata.path(&owned), testdata.path(&borrowed));
+    assert!(testdata.path(&owned).exists());
+}
+
+#[test]
+fn local_has_no_origin() {
+    let testdata = xtest_data::setup!().build();
+    assert!(testdata.origin().is_none());
+}
+
+#[test]
+fn git_version_is_recorded() {
+    let testdata = xtest_data::setup!().build();
+    let version = testdata
+        .git_version()
+        .expect("the version of git to be parsed");
+    assert!(version.major >= 2, "{}", version);
+}
+
+#[test]
+fn local_object_by_id() {
+    let hello = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tree/hello.txt");
+    let output = std::process::Command::new("git")
+        .arg("hash-object")
+        .arg(&hello)
+        .output()
+        .unwrap();
+    let oid = String::from_utf8(output.stdout).unwrap();
+
+    let mut vcs = xtest_data::setup!();
+    let object = vcs.add_object(oid.trim());
+    let testdata = vcs.build();
+
+    let path = testdata.path(&object);
+    assert!(path.ends_with(oid.trim()), "{}", path.display());
+    assert_eq!(std::fs::read(path).unwrap(), std::fs::read(hello).unwrap());
+}
+
+#[test]
+fn optional_resources() {
+    let mut vcs = xtest_data::setup!();
+    let present = vcs.add_optional("tests/data.zip");
+    let absent = vcs.add_optional(Path::new("tests/does-not-exist.bin"));
+    let testdata = vcs.build();
+
+    assert!(testdata.is_available(&present));
+    assert!(testdata.path(&present).exists());
+    assert!(!testdata.is_available(&absent));
+}
+
+#[test]
+fn roots_of_files_and_objects() {
+    let hello = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tree/hello.txt");
+    let output = std::process::Command::new("git")
+        .arg("hash-object")
+        .arg(&hello)
+        .output()
+        .unwrap();
+    let oid = String::from_utf8(output.stdout).unwrap();
+
+    let mut vcs = xtest_data::setup!();
+    let datazip = vcs.add("tests/data.zip");
+    let tree = vcs.add("tests/tree");
+    let object = vcs.add_object(oid.trim());
+    let testdata = vcs.build();
+
+    assert_eq!(testdata.root(&datazip), testdata.root(&tree));
+    assert_ne!(testdata.root(&datazip), testdata.root(&object));
+
+    let relative = testdata
+        .path(&datazip)
+        .strip_prefix(testdata.root(&datazip));
+    assert_eq!(relative, Ok(Path::new("tests/data.zip")));
+    assert_eq!(
+        testdata.path(&object).parent(),
+        Some(testdata.root(&object))
+    );
+}
+
+#[test]
+fn renamed_resources() {
+    let mut vcs = xtest_data::setup!();
+    let file = vcs.add_with_rename("tests/tree/hello.txt", Path::new("greeting.txt"));
+    let tree = vcs.add_with_rename("tests/tree", Path::new("fixtures/tree"));
+    let testdata = vcs.build();
+
+    let path = testdata.path(&file);
+    assert_eq!(
+        path.strip_prefix(testdata.root(&file)),
+        Ok(Path::new("greeting.txt"))
+    );
+    let original = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tree/hello.txt");
+    assert_eq!(
+        std::fs::read(path).unwrap(),
+        std::fs::read(original).unwrap()
+    );
+
+    assert!(testdata.path(&tree).ends_with("fixtures/tree"));
+    assert!(testdata.path(&tree).join("hello.txt").exists());
+}
+
+#[test]
+fn local_build_timings() {
+    let mut vcs = xtest_data::setup!();
+    let _ = vcs.add("tests/data.zip");
+    let timings = vcs.build().timings();
+
+    // Nothing is cloned or checked out in a local checkout.
+    assert!(timings.total > std::time::Duration::ZERO);
+    assert_eq!(
+        timings.clone + timings.fetch + timings.checkout,
+        Default::default()
+    );
+    assert_eq!(timings.slow_checkout, None);
+}
+
+#[test]
+fn find_within_tree() {
+    let mut vcs = xtest_data::setup!();
+    let tree = vcs.add("tests/tree");
+    let file = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+
+    let world = testdata.find(&tree, Path::new("nested/world.txt"));
+    assert_eq!(world, Some(&*testdata.path(&tree).join("nested/world.txt")));
+    assert!(world.unwrap().exists());
+    assert!(testdata.find(&tree, Path::new("nested")).is_some());
+
+    assert_eq!(testdata.find(&tree, Path::new("nested/missing.txt")), None);
+    assert_eq!(testdata.find(&tree, Path::new("../data.zip")), None);
+    assert_eq!(testdata.find(&file, Path::new("data.zip")), None);
+}
+
+#[test]
+fn materialize_into_chosen_location() {
+    let mut vcs = xtest_data::setup!();
+    let datazip = vcs.add("tests/data.zip");
+    let tree = vcs.add("tests/tree");
+    let testdata = vcs.build();
+
+    let dest = Path::new(env!("CARGO_TARGET_TMPDIR")).join("materialize_into");
+    let _ = std::fs::remove_dir_all(&dest);
+
+    let copy = testdata.materialize_into(&datazip, &dest.join("fixture.zip"));
+    assert_eq!(copy, dest.join("fixture.zip"));
+    assert_eq!(
+        std::fs::read(&copy).unwrap(),
+        std::fs::read(testdata.path(&datazip)).unwrap()
+    );
+
+    let copy = testdata.materialize_into(&tree, &dest.join("tree"));
+    assert!(copy.join("hello.txt").is_file());
+    assert!(copy.join("nested").is_dir());
+}
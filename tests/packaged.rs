@@ -0,0 +1,613 @@
+//! Test the path of a packaged crate, with data from the pack objects of our own `HEAD`.
+//!
+//! The setup is configured through the environment so all tests here are serialized.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static ENVIRONMENT: AtomicBool = AtomicBool::new(false);
+
+/// Exclusive access to the environment, released even when the test panics.
+struct EnvironmentLock;
+
+struct Packaged {
+    dir: PathBuf,
+}
+
+impl Packaged {
+    /// Pack the objects for `paths` at `HEAD` and pretend to be a crate packaged at `commit`.
+    fn new(name: &str, paths: &[&str], commit: &str) -> Self {
+        Self::with_objects(name, paths, &[], commit)
+    }
+
+    /// Like `new`, also packing the blobs with the given object IDs.
+    fn with_objects(name: &str, paths: &[&str], objects: &[&str], commit: &str) -> Self {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        let pack = dir.join("pack");
+        std::fs::create_dir_all(&pack).unwrap();
+
+        std::env::set_var("CARGO_XTEST_DATA_PACK_OBJECTS", &pack);
+        let mut vcs = xtest_data::setup!();
+        vcs.add_many(paths);
+        for object in objects {
+            let _ = vcs.add_object(object);
+        }
+        let _ = vcs.build();
+
+        let vcs_info = dir.join("cargo_vcs_info.json");
+        let vcs_data = format!(r#"{{ "git": {{ "sha1": "{}" }} }}"#, commit);
+        std::fs::write(&vcs_info, vcs_data).unwrap();
+        std::env::set_var("CARGO_XTEST_VCS_INFO", &vcs_info);
+
+        Packaged { dir }
+    }
+}
+
+impl Drop for Packaged {
+    fn drop(&mut self) {
+        std::env::remove_var("CARGO_XTEST_VCS_INFO");
+        std::env::remove_var("CARGO_XTEST_DATA_PACK_OBJECTS");
+        std::env::remove_var("CARGO_XTEST_DATA_REPOSITORY_ORIGIN");
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn head() -> String {
+    rev_parse("HEAD")
+}
+
+fn rev_parse(rev: &str) -> String {
+    let output = Command::new("git")
+        .args(["rev-parse", rev])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+}
+
+fn lock() -> EnvironmentLock {
+    while ENVIRONMENT
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        std::thread::yield_now();
+    }
+
+    EnvironmentLock
+}
+
+impl Drop for EnvironmentLock {
+    fn drop(&mut self) {
+        ENVIRONMENT.store(false, Ordering::Release);
+    }
+}
+
+#[test]
+fn checkout_from_pack() {
+    let _lock = lock();
+    let _packaged = Packaged::new("checkout_from_pack", &["tests/data.zip"], &head());
+
+    let mut vcs = xtest_data::setup!();
+    let datazip = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+
+    let stats = testdata.stats();
+    assert!(stats.objects > 0);
+    assert_eq!(
+        stats.bytes,
+        std::fs::metadata("tests/data.zip").unwrap().len()
+    );
+
+    let path = testdata.path(&datazip);
+    assert!(!path.starts_with(env!("CARGO_MANIFEST_DIR").to_owned() + "/tests"));
+    assert_eq!(
+        std::fs::read(path).unwrap(),
+        std::fs::read("tests/data.zip").unwrap()
+    );
+}
+
+#[test]
+fn build_timings() {
+    let _lock = lock();
+    let _packaged = Packaged::new("build_timings", &["tests/data.zip"], &head());
+
+    let mut vcs = xtest_data::setup!();
+    let _ = vcs.add("tests/data.zip");
+    let timings = vcs.build().timings();
+
+    assert!(timings.clone > Duration::ZERO, "{:?}", timings);
+    assert!(timings.fetch > Duration::ZERO, "{:?}", timings);
+    assert!(timings.checkout > Duration::ZERO, "{:?}", timings);
+    assert_eq!(timings.pack, Duration::ZERO);
+    assert_eq!(timings.slow_checkout, None);
+
+    let phases = timings.pack + timings.clone + timings.fetch + timings.checkout;
+    assert!(phases <= timings.total, "{:?}", timings);
+}
+
+#[test]
+fn prewarmed_builds() {
+    let _lock = lock();
+    let paths = ["tests/data.zip", "tests/tree", "Cargo.toml"];
+    let _packaged = Packaged::new("prewarmed_builds", &paths, &head());
+    // A commit that no other test names, so no other build reuses the prewarmed checkout.
+    let head = head();
+    let commit = &head[..11];
+
+    xtest_data::setup!()
+        .commit(commit)
+        .prewarm(&["tests/data.zip", "tests/tree"]);
+
+    let mut vcs = xtest_data::setup!().commit(commit);
+    let datazip = vcs.add("tests/data.zip");
+    let first = vcs.build();
+
+    let mut vcs = xtest_data::setup!().commit(commit);
+    let hello = vcs.add("tests/tree/hello.txt");
+    let second = vcs.build();
+
+    // Both share the prewarmed checkout, without importing objects again.
+    assert_eq!(first.root(&datazip), second.root(&hello));
+    assert_eq!(first.stats().objects, 0);
+    assert_eq!(second.stats().objects, 0);
+    assert_eq!(
+        std::fs::read(second.path(&hello)).unwrap(),
+        std::fs::read("tests/tree/hello.txt").unwrap()
+    );
+
+    // A path that was not prewarmed is checked out on its own.
+    let mut vcs = xtest_data::setup!().commit(commit);
+    let manifest = vcs.add("Cargo.toml");
+    let third = vcs.build();
+    assert_ne!(third.root(&manifest), first.root(&datazip));
+    assert!(third.stats().objects > 0);
+    assert!(third.path(&manifest).exists());
+}
+
+#[test]
+fn commit_override() {
+    let _lock = lock();
+    let unknown = "0".repeat(40);
+    let _packaged = Packaged::new("commit_override", &["tests/data.zip"], &unknown);
+
+    let mut vcs = xtest_data::setup!().commit(&head());
+    let datazip = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+
+    assert!(testdata.path(&datazip).exists());
+}
+
+#[test]
+fn abbreviated_commit_override() {
+    use std::collections::HashMap;
+    use tinyjson::JsonValue;
+
+    let _lock = lock();
+    let unknown = "0".repeat(40);
+    let packaged = Packaged::new("abbreviated_commit_override", &["tests/data.zip"], &unknown);
+    let lock_file = packaged.dir.join("xtest-data.lock");
+
+    let full = head();
+    let mut vcs = xtest_data::setup!()
+        .commit(&full[..12])
+        .write_lock(lock_file.clone());
+    let datazip = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+    assert!(testdata.path(&datazip).exists());
+
+    let lock: JsonValue = std::fs::read_to_string(&lock_file)
+        .unwrap()
+        .parse()
+        .unwrap();
+    let lock: &HashMap<_, _> = lock.get().unwrap();
+    assert_eq!(lock["commit"].get::<String>(), Some(&full));
+}
+
+#[test]
+fn reference_repository() {
+    let _lock = lock();
+    // Only the reference has the objects of a commit that we never pack.
+    let _packaged = Packaged::new(
+        "reference_repository",
+        &["tests/data.zip"],
+        &rev_parse("HEAD~1"),
+    );
+    std::env::remove_var("CARGO_XTEST_DATA_PACK_OBJECTS");
+
+    let mut vcs = xtest_data::setup!().reference_repo(PathBuf::from(env!("CARGO_MANIFEST_DIR")));
+    let datazip = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+
+    assert!(testdata.path(&datazip).exists());
+}
+
+#[test]
+fn cone_mode_for_directories() {
+    let _lock = lock();
+    let dirs = ["tests/tree", "tests/tree/nested"];
+    let _packaged = Packaged::new("cone_mode_for_directories", &dirs, &head());
+
+    let mut vcs = xtest_data::setup!();
+    let keys = vcs.add_many(dirs);
+    let testdata = vcs.build();
+
+    let tree = testdata.path(&keys[0]);
+    assert!(tree.join("hello.txt").exists());
+    assert!(testdata.path(&keys[1]).join("world.txt").exists());
+
+    let worktree = tree.parent().unwrap().parent().unwrap();
+    let output = Command::new("git")
+        .args(["config", "--get", "core.sparseCheckoutCone"])
+        .current_dir(worktree)
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "true");
+}
+
+fn listing(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    entries
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+    let _lock = lock();
+    let _packaged = Packaged::new("dry_run_writes_nothing", &["tests/data.zip"], &head());
+    let tmpdir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let before = listing(tmpdir);
+
+    let mut vcs = xtest_data::setup!();
+    let _ = vcs.add("tests/data.zip");
+    let plan = vcs.build_dry_run();
+
+    assert_eq!(listing(tmpdir), before);
+    assert_eq!(plan.commit, Some(head()));
+    assert_eq!(
+        plan.resources,
+        [(
+            PathBuf::from("tests/data.zip"),
+            ":(top,literal)tests/data.zip".to_owned()
+        )]
+    );
+}
+
+#[test]
+fn dry_run_resolves_with_reference() {
+    let _lock = lock();
+    let _packaged = Packaged::new(
+        "dry_run_resolves_with_reference",
+        &["tests/data.zip"],
+        &head(),
+    );
+    let full = head();
+
+    let mut vcs = xtest_data::setup!().commit(&full[..12]);
+    let _ = vcs.add("tests/data.zip");
+    let unresolved = vcs.build_dry_run();
+
+    let mut vcs = xtest_data::setup!()
+        .commit(&full[..12])
+        .reference_repo(PathBuf::from(env!("CARGO_MANIFEST_DIR")));
+    let _ = vcs.add("tests/data.zip");
+    let resolved = vcs.build_dry_run();
+
+    assert_eq!(unresolved.commit.as_deref(), Some(&full[..12]));
+    assert!(!unresolved.commit_resolved);
+    assert_eq!(resolved.commit, Some(full));
+    assert!(resolved.commit_resolved);
+}
+
+#[test]
+fn pack_objects_without_environment() {
+    let _lock = lock();
+    let _packaged = Packaged::new(
+        "pack_objects_without_environment",
+        &["tests/data.zip"],
+        &head(),
+    );
+    let pack = std::env::var_os("CARGO_XTEST_DATA_PACK_OBJECTS").unwrap();
+    std::env::remove_var("CARGO_XTEST_DATA_PACK_OBJECTS");
+
+    let mut vcs = xtest_data::setup!().pack_objects(PathBuf::from(pack));
+    let datazip = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+
+    assert!(testdata.stats().objects > 0);
+    assert!(testdata.path(&datazip).exists());
+}
+
+#[test]
+#[should_panic]
+fn pack_objects_without_packs() {
+    let _lock = lock();
+    let packaged = Packaged::new("pack_objects_without_packs", &["tests/data.zip"], &head());
+    std::env::remove_var("CARGO_XTEST_DATA_PACK_OBJECTS");
+    let empty = packaged.dir.join("empty");
+    std::fs::create_dir_all(&empty).unwrap();
+
+    let mut vcs = xtest_data::setup!().pack_objects(empty);
+    let _ = vcs.add("tests/data.zip");
+    let _ = vcs.build();
+}
+
+#[test]
+fn origin_override_is_reported() {
+    let _lock = lock();
+    let _packaged = Packaged::new("origin_override_is_reported", &["tests/data.zip"], &head());
+
+    let testdata = xtest_data::setup!().build();
+    assert_eq!(
+        testdata.origin(),
+        Some(std::ffi::OsStr::new(env!("CARGO_PKG_REPOSITORY")))
+    );
+
+    let mirror = "https://mirror.example.com/xtest-data";
+    std::env::set_var("CARGO_XTEST_DATA_REPOSITORY_ORIGIN", mirror);
+    let mut vcs = xtest_data::setup!();
+    let _ = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+
+    assert_eq!(testdata.origin(), Some(std::ffi::OsStr::new(mirror)));
+}
+
+#[test]
+fn checkout_into_directory() {
+    let _lock = lock();
+    let packaged = Packaged::new("checkout_into_directory", &["tests/data.zip"], &head());
+    let checkout = packaged.dir.join("checkout");
+
+    let mut vcs = xtest_data::setup!().checkout_into(checkout.clone());
+    let datazip = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+
+    let expected = checkout.canonicalize().unwrap().join("tests/data.zip");
+    assert_eq!(testdata.path(&datazip), expected);
+    assert!(expected.exists());
+
+    // Replacing the directory explicitly is always possible.
+    let mut vcs = xtest_data::setup!()
+        .checkout_into(checkout.clone())
+        .force_checkout(true);
+    let datazip = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+
+    assert_eq!(testdata.path(&datazip), expected);
+    assert!(expected.exists());
+}
+
+#[test]
+fn checkout_into_directory_twice() {
+    let _lock = lock();
+    let packaged = Packaged::new(
+        "checkout_into_directory_twice",
+        &["tests/data.zip", "tests/tree/hello.txt"],
+        &head(),
+    );
+    let checkout = packaged.dir.join("checkout");
+
+    let mut vcs = xtest_data::setup!().checkout_into(checkout.clone());
+    let datazip = vcs.add("tests/data.zip");
+    let testdata = vcs.build();
+    assert!(testdata.path(&datazip).exists());
+    drop(testdata);
+
+    // The checkout of the same commit is reused, even with other resources.
+    let mut vcs = xtest_data::setup!().checkout_into(checkout.clone());
+    let hello = vcs.add("tests/tree/hello.txt");
+    let testdata = vcs.build();
+    assert!(testdata.path(&hello).exists());
+}
+
+#[test]
+fn concurrent_builds() {
+    let _lock = lock();
+    let packaged = Packaged::new(
+        "concurrent_builds",
+        &["tests/data.zip", "tests/tree"],
+        &head(),
+    );
+    let expected = std::fs::read("tests/data.zip").unwrap();
+
+    let threads: Vec<_> = (0..8)
+        .map(|idx| {
+            let shared = packaged.dir.join("checkout");
+            std::thread::spawn(move || {
+                let mut vcs = xtest_data::setup!();
+                // Half of them pin the same commit by an abbreviation, resolved concurrently.
+                if idx % 2 == 1 {
+                    vcs = vcs.commit(&head()[..12]);
+                }
+                // Some share one checkout directory, which is reused by all but the first.
+                if idx % 4 >= 2 {
+                    vcs = vcs.checkout_into(shared);
+                }
+                let datazip = vcs.add("tests/data.zip");
+                let tree = vcs.add("tests/tree");
+                let testdata = vcs.build();
+                assert!(testdata.path(&tree).join("hello.txt").exists());
+                std::fs::read(testdata.path(&datazip)).unwrap()
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), expected);
+    }
+}
+
+#[test]
+#[should_panic]
+fn checkout_into_occupied_directory() {
+    let _lock = lock();
+    let packaged = Packaged::new(
+        "checkout_into_occupied_directory",
+        &["tests/data.zip"],
+        &head(),
+    );
+    let checkout = packaged.dir.join("checkout");
+    std::fs::create_dir_all(&checkout).unwrap();
+    std::fs::write(checkout.join("unrelated.txt"), "keep me").unwrap();
+
+    let mut vcs = xtest_data::setup!().checkout_into(checkout);
+    let _ = vcs.add("tests/data.zip");
+    let _ = vcs.build();
+}
+
+#[test]
+fn skipped_resource_is_not_checked_out() {
+    let _lock = lock();
+    let _packaged = Packaged::new(
+        "skipped_resource_is_not_checked_out",
+        &["tests/data.zip", "tests/tree/hello.txt"],
+        &head(),
+    );
+
+    let mut seen = vec![];
+    let mut vcs = xtest_data::setup!().on_checkout(|path| {
+        seen.push(path.to_owned());
+        path != Path::new("tests/data.zip")
+    });
+    let datazip = vcs.add("tests/data.zip");
+    let hello = vcs.add("tests/tree/hello.txt");
+    let testdata = vcs.build();
+
+    assert!(!testdata.path(&datazip).exists());
+    assert!(testdata.path(&hello).exists());
+    assert_eq!(
+        seen,
+        [
+            PathBuf::from("tests/data.zip"),
+            PathBuf::from("tests/tree/hello.txt")
+        ]
+    );
+}
+
+#[test]
+fn optional_resource_missing_from_commit() {
+    let _lock = lock();
+    let _packaged = Packaged::new(
+        "optional_resource_missing_from_commit",
+        &["tests/data.zip"],
+        &head(),
+    );
+
+    let mut vcs = xtest_data::setup!();
+    let datazip = vcs.add_optional("tests/data.zip");
+    let absent = vcs.add_optional("tests/does-not-exist.bin");
+    let testdata = vcs.build();
+
+    assert!(testdata.is_available(&datazip));
+    assert!(testdata.path(&datazip).exists());
+    assert!(!testdata.is_available(&absent));
+    assert!(!testdata.path(&absent).exists());
+}
+
+#[test]
+fn renamed_resource_from_pack() {
+    let _lock = lock();
+    let _packaged = Packaged::new("renamed_resource_from_pack", &["tests/data.zip"], &head());
+
+    let mut vcs = xtest_data::setup!();
+    let datazip = vcs.add("tests/data.zip");
+    let renamed = vcs.add_with_rename("tests/data.zip", Path::new("big.bin"));
+    let testdata = vcs.build();
+
+    assert!(testdata.path(&renamed).ends_with("big.bin"));
+    assert_eq!(
+        std::fs::read(testdata.path(&renamed)).unwrap(),
+        std::fs::read(testdata.path(&datazip)).unwrap()
+    );
+}
+
+#[test]
+fn lock_records_fetched_files() {
+    use std::collections::HashMap;
+    use tinyjson::JsonValue;
+
+    let _lock = lock();
+    let packaged = Packaged::new(
+        "lock_records_fetched_files",
+        &["tests/data.zip", "tests/tree"],
+        &head(),
+    );
+    let lock_file = packaged.dir.join("xtest-data.lock");
+
+    let mut vcs = xtest_data::setup!().write_lock(lock_file.clone());
+    vcs.add_many(["tests/data.zip", "tests/tree"]);
+    let _ = vcs.build();
+
+    let lock: JsonValue = std::fs::read_to_string(&lock_file)
+        .unwrap()
+        .parse()
+        .unwrap();
+    let lock: &HashMap<_, _> = lock.get().unwrap();
+    let string = |value: &JsonValue| value.get::<String>().unwrap().clone();
+
+    assert_eq!(string(&lock["commit"]), head());
+    assert_eq!(string(&lock["origin"]), env!("CARGO_PKG_REPOSITORY"));
+
+    let resources: &Vec<JsonValue> = lock["resources"].get().unwrap();
+    let specs: Vec<_> = resources
+        .iter()
+        .map(|resource| {
+            let resource: &HashMap<_, _> = resource.get().unwrap();
+            string(&resource["pathspec"])
+        })
+        .collect();
+    assert_eq!(
+        specs,
+        [":(top,literal)tests/data.zip", ":(top,literal)tests/tree"]
+    );
+
+    let mut paths = vec![];
+    for resource in resources {
+        let resource: &HashMap<_, _> = resource.get().unwrap();
+        let files: &HashMap<_, _> = resource["files"].get().unwrap();
+        for (path, file) in files {
+            let file: &HashMap<_, _> = file.get().unwrap();
+            assert_eq!(string(&file["oid"]), rev_parse(&format!("HEAD:{}", path)));
+            assert_eq!(string(&file["sha256"]).len(), 64);
+            paths.push(path.clone());
+        }
+    }
+
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "tests/data.zip",
+            "tests/tree/hello.txt",
+            "tests/tree/nested/world.txt"
+        ]
+    );
+}
+
+#[test]
+fn object_by_id_from_pack() {
+    let _lock = lock();
+    let hello = rev_parse("HEAD:tests/tree/hello.txt");
+    let _packaged = Packaged::with_objects(
+        "object_by_id_from_pack",
+        &["tests/data.zip"],
+        &[&hello],
+        &head(),
+    );
+
+    let mut vcs = xtest_data::setup!();
+    let object = vcs.add_object(&hello);
+    let testdata = vcs.build();
+
+    let expected =
+        std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tree/hello.txt"));
+    assert_eq!(
+        std::fs::read(testdata.path(&object)).unwrap(),
+        expected.unwrap()
+    );
+}